@@ -1,9 +1,16 @@
 #include "wardenclyffe/android/socket.h"
 
+#include <inttypes.h>
+#include <stdio.h>
+
+#include <iterator>
 #include <thread>
 
+#include <android-base/logging.h>
 #include <android-base/strings.h>
 #include <binder/IPCThreadState.h>
+#include <binder/IServiceManager.h>
+#include <utils/String16.h>
 
 #include "wardenclyffe/android/socket.h"
 #include "wardenclyffe/android/video/video.h"
@@ -11,7 +18,8 @@
 
 static std::once_flag once;
 
-WardenclyffeSocket wardenclyffe_create_socket(const char* path_str) {
+WardenclyffeSocket wardenclyffe_create_socket(const char* path_str,
+                                              const WardenclyffePeerInfo* peer) {
   std::call_once(once, []() {
     // Start Binder thread pool.  MediaCodec needs to be able to receive
     // messages from mediaserver.
@@ -19,6 +27,12 @@ WardenclyffeSocket wardenclyffe_create_socket(const char* path_str) {
     self->startThreadPool();
   });
 
+  if (peer->address) {
+    LOG(INFO) << "creating socket " << path_str << " for " << peer->address;
+  } else {
+    LOG(INFO) << "creating socket " << path_str << " for uid " << peer->uid << ", pid " << peer->pid;
+  }
+
   std::string_view path(path_str);
   if (android::base::ConsumePrefix(&path, "/video/")) {
     return VideoSocket::Create(path);
@@ -31,6 +45,42 @@ WardenclyffeSocket wardenclyffe_create_socket(const char* path_str) {
   return nullptr;
 }
 
+WardenclyffeSocketList wardenclyffe_list_sockets() {
+  static const WardenclyffeSocketInfo kSockets[] = {
+      {.path = "/video/h264/", .flags = WARDENCLYFFE_SOCKET_READ, .content_type = "video/h264"},
+      {.path = "/video/jpeg/", .flags = WARDENCLYFFE_SOCKET_READ, .content_type = "image/jpeg"},
+  };
+  return {.sockets = kSockets, .count = std::size(kSockets)};
+}
+
+bool wardenclyffe_authorize(const char* path_str, const WardenclyffePeerInfo* peer, char* reason,
+                            size_t reason_len) {
+  // Remote peers have already been authenticated by the server, but local callers are only
+  // identified by their credentials, so hold them to the same permissions as an app would be.
+  if (peer->address) {
+    return true;
+  }
+
+  std::string_view path(path_str);
+  if (android::base::StartsWith(path, "/video/")) {
+    static const android::String16 kPermission("android.permission.READ_FRAME_BUFFER");
+    if (!android::checkPermission(kPermission, peer->pid, peer->uid)) {
+      snprintf(reason, reason_len, "uid %" PRId64 " lacks READ_FRAME_BUFFER", peer->uid);
+      return false;
+    }
+  }
+
+  return true;
+}
+
+bool wardenclyffe_authenticate([[maybe_unused]] const char* credentials,
+                               [[maybe_unused]] const WardenclyffePeerInfo* peer,
+                               [[maybe_unused]] char* scopes, [[maybe_unused]] size_t scopes_len) {
+  // There's no account system to check credentials against yet, so only the server's own tokens
+  // are accepted.
+  return false;
+}
+
 void wardenclyffe_destroy_socket(WardenclyffeSocket socket) {
   auto s = static_cast<Socket*>(socket);
   s->Destroy();
@@ -41,8 +91,8 @@ bool wardenclyffe_supports_read(WardenclyffeSocket socket) {
   return static_cast<Socket*>(socket)->SupportsRead();
 }
 
-WardenclyffeReads wardenclyffe_read(WardenclyffeSocket socket) {
-  return static_cast<Socket*>(socket)->Read();
+WardenclyffeReads wardenclyffe_read_timeout(WardenclyffeSocket socket, uint32_t millis) {
+  return static_cast<Socket*>(socket)->ReadTimeout(std::chrono::milliseconds(millis));
 }
 
 bool wardenclyffe_supports_write(WardenclyffeSocket socket) {
@@ -52,3 +102,19 @@ bool wardenclyffe_supports_write(WardenclyffeSocket socket) {
 bool wardenclyffe_write(WardenclyffeSocket socket, const void* data, size_t len) {
   return static_cast<Socket*>(socket)->Write(data, len);
 }
+
+bool wardenclyffe_set_parameter(WardenclyffeSocket socket, const char* name, const char* value) {
+  return static_cast<Socket*>(socket)->SetParameter(name, value);
+}
+
+void wardenclyffe_pause(WardenclyffeSocket socket) {
+  static_cast<Socket*>(socket)->Pause();
+}
+
+void wardenclyffe_resume(WardenclyffeSocket socket) {
+  static_cast<Socket*>(socket)->Resume();
+}
+
+int32_t wardenclyffe_socket_error(WardenclyffeSocket socket, char* reason, size_t reason_len) {
+  return static_cast<Socket*>(socket)->Error(reason, reason_len);
+}
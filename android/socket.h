@@ -4,6 +4,7 @@
 #include <sys/types.h>
 #include <unistd.h>
 
+#include <chrono>
 #include <string_view>
 #include <utility>
 
@@ -16,10 +17,29 @@ struct Socket {
   virtual void Destroy() {}
 
   virtual WardenclyffeReads Read() { return {.reads = nullptr, .read_count = 0}; }
+
+  // Like Read, but gives up with WARDENCLYFFE_READ_TIMEOUT if nothing arrives within `timeout`.
+  virtual WardenclyffeReads ReadTimeout([[maybe_unused]] std::chrono::milliseconds timeout) {
+    return Read();
+  }
   virtual bool SupportsRead() { return false; }
 
   virtual bool Write([[maybe_unused]] const void* data, [[maybe_unused]] size_t len) {
     return false;
   }
   virtual bool SupportsWrite() { return false; }
+
+  // Change a parameter of the stream at the client's request, see wardenclyffe_set_parameter.
+  virtual bool SetParameter([[maybe_unused]] const char* name, [[maybe_unused]] const char* value) {
+    return false;
+  }
+
+  // Stop producing data until Resume, because the client is falling behind. See wardenclyffe_pause.
+  virtual void Pause() {}
+  virtual void Resume() {}
+
+  // Describe why the last Read or Write failed, see wardenclyffe_socket_error.
+  virtual int32_t Error([[maybe_unused]] char* reason, [[maybe_unused]] size_t reason_len) {
+    return 0;
+  }
 };
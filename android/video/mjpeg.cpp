@@ -77,6 +77,7 @@ void JPEGSocket::onFrameReceived() {
     std::lock_guard<std::mutex> lock(frame_mutex_);
     frame.data = std::move(buf);
     frame.timestamp = item.mTimestamp;
+    frame.capture_time_ns = item.mTimestamp;
     frames_.push_back(std::move(frame));
   }
   cv_.notify_one();
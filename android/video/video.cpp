@@ -75,10 +75,11 @@ VideoSocket* VideoSocket::Create(std::string_view path) {
   return result;
 }
 
-WardenclyffeReads VideoSocket::Read() {
+WardenclyffeReads VideoSocket::ReadUntil(
+    std::optional<std::chrono::steady_clock::time_point> deadline) {
   std::unique_lock<std::mutex> lock(frame_mutex_);
   base::ScopedLockAssertion lock_assertion(frame_mutex_);
-  cv_.wait(lock, [this]() {
+  auto ready = [this]() {
     base::ScopedLockAssertion lock_assertion(frame_mutex_);
     if (!running_) return true;
     if (reads_.empty()) {
@@ -86,7 +87,13 @@ WardenclyffeReads VideoSocket::Read() {
     } else {
       return frames_.size() > 1;
     }
-  });
+  };
+
+  if (!deadline) {
+    cv_.wait(lock, ready);
+  } else if (!cv_.wait_until(lock, *deadline, ready)) {
+    return {.reads = nullptr, .read_count = WARDENCLYFFE_READ_TIMEOUT};
+  }
 
   WardenclyffeReads result = {.reads = nullptr, .read_count = -1};
 
@@ -132,6 +139,7 @@ WardenclyffeReads VideoSocket::Read() {
       .data = frame.data.data(),
       .size = frame.data.size(),
       .oob = false,
+      .timestamp_ns = frame.capture_time_ns,
   });
   result.reads = reads_.data();
   result.read_count = reads_.size();
@@ -469,6 +477,8 @@ bool MediaCodecSocket::startEncoder() {
                 data.insert(data.end(), p, p + size);
                 frame.data = std::move(data);
                 frame.timestamp = pts_usec;
+                // Surface input is timestamped with the buffer's monotonic capture time.
+                frame.capture_time_ns = pts_usec * 1000;
                 frames_.push_back(std::move(frame));
                 new_frame = true;
               }
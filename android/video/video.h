@@ -7,6 +7,7 @@
 #include <deque>
 #include <future>
 #include <mutex>
+#include <optional>
 #include <string>
 #include <vector>
 
@@ -28,6 +29,9 @@ struct Frame {
   std::vector<char> data;
   FrameType type;
   int64_t timestamp;
+
+  // When the frame was captured, in nanoseconds on CLOCK_MONOTONIC.
+  int64_t capture_time_ns;
 };
 
 struct FrameTimer {
@@ -51,7 +55,10 @@ struct VideoSocket : public Socket {
 
   static VideoSocket* Create(std::string_view path);
 
-  virtual WardenclyffeReads Read() final;
+  virtual WardenclyffeReads Read() final { return ReadUntil(std::nullopt); }
+  virtual WardenclyffeReads ReadTimeout(std::chrono::milliseconds timeout) final {
+    return ReadUntil(std::chrono::steady_clock::now() + timeout);
+  }
   virtual bool SupportsRead() final { return true; }
 
   bool Initialize() EXCLUDES(buffer_queue_mutex_) {
@@ -68,6 +75,8 @@ struct VideoSocket : public Socket {
   virtual void DestroyLocked() REQUIRES(buffer_queue_mutex_) { destroyVirtualDisplay(); }
 
  protected:
+  WardenclyffeReads ReadUntil(std::optional<std::chrono::steady_clock::time_point> deadline);
+
   bool fetchDisplayParameters();
   bool createVirtualDisplay() REQUIRES(buffer_queue_mutex_);
   bool prepareVirtualDisplay() REQUIRES(buffer_queue_mutex_);
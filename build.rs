@@ -1,7 +1,27 @@
 extern crate cbindgen;
 
+/// The commit being built, if this is a git checkout.
+fn git_hash() -> Option<String> {
+  let output = std::process::Command::new("git")
+    .args(["rev-parse", "--short=12", "HEAD"])
+    .output()
+    .ok()?;
+  let hash = String::from_utf8(output.stdout).ok()?;
+  (output.status.success() && !hash.trim().is_empty()).then(|| hash.trim().to_string())
+}
+
 fn main() {
   let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+
+  println!(
+    "cargo:rustc-env=WARDENCLYFFE_GIT_HASH={}",
+    git_hash().unwrap_or_else(|| "unknown".into())
+  );
+  // Declaring any of these replaces the default of rerunning whenever anything in the package
+  // changes, so the sources the bindings are generated from have to be listed too.
+  for path in ["src", "cbindgen.toml", ".git/HEAD", ".git/refs", ".git/packed-refs"] {
+    println!("cargo:rerun-if-changed={path}");
+  }
   let config = cbindgen::Config::from_root_or_default(&crate_dir);
   match cbindgen::Builder::new()
     .with_config(config)
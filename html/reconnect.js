@@ -0,0 +1,41 @@
+// Open a WebSocket that reconnects with exponential backoff whenever its connection drops, resuming
+// the same session on the server if the socket's policy allows it.
+//
+// `setup` is called with each new WebSocket, to add its event listeners.
+export function reconnectingWebSocket(url, setup, { minDelay = 250, maxDelay = 10000 } = {}) {
+  const session = Array.from(crypto.getRandomValues(new Uint8Array(16)))
+    .map((b) => b.toString(16).padStart(2, "0"))
+    .join("");
+  const sessionUrl = new URL(url);
+  sessionUrl.searchParams.set("session", session);
+
+  let delay = minDelay;
+  let closed = false;
+  let socket = null;
+
+  function connect() {
+    socket = new WebSocket(sessionUrl);
+    socket.addEventListener("open", () => {
+      delay = minDelay;
+    });
+    socket.addEventListener("close", (event) => {
+      // The server closing the session (e.g. at EOF) is final, only retry lost connections.
+      if (closed || event.wasClean) {
+        return;
+      }
+      const jittered = delay * (0.5 + Math.random());
+      console.warn(`WebSocket connection lost, reconnecting in ${jittered.toFixed(0)}ms`);
+      setTimeout(connect, jittered);
+      delay = Math.min(delay * 2, maxDelay);
+    });
+    setup(socket);
+  }
+
+  connect();
+  return {
+    close() {
+      closed = true;
+      socket.close();
+    },
+  };
+}
@@ -10,12 +10,94 @@
 #include <new>
 
 
+/// Version of the API between the library and the backend, incremented whenever functions or
+/// structures in this header change incompatibly.
+constexpr const uint32_t WARDENCLYFFE_API_VERSION = 1;
+
+/// Error codes with a conventional meaning that `wardenclyffe_socket_error` can return, which are
+/// reported to clients with a matching WebSocket close code unless configured otherwise. Backends
+/// may use other codes, which are passed through as-is.
+constexpr const int32_t WARDENCLYFFE_ERROR_BUSY = 1;
+
+constexpr const int32_t WARDENCLYFFE_ERROR_INTERNAL = 3;
+
+constexpr const int32_t WARDENCLYFFE_ERROR_UNAUTHORIZED = 2;
+
+/// `WardenclyffeReads::read_count` returned by `wardenclyffe_read_timeout` when nothing was read.
+constexpr const ptrdiff_t WARDENCLYFFE_READ_TIMEOUT = -2;
+
+/// Flags of a socket listed by `wardenclyffe_list_sockets`.
+constexpr const uint32_t WARDENCLYFFE_SOCKET_READ = (1 << 0);
+
+/// Several clients can have the socket open at once.
+constexpr const uint32_t WARDENCLYFFE_SOCKET_SHAREABLE = (1 << 2);
+
+constexpr const uint32_t WARDENCLYFFE_SOCKET_WRITE = (1 << 1);
+
+/// Checks a message from a client before it's written to a socket, as registered with
+/// `wardenclyffe_add_validator`. Returns true if the message is valid, and otherwise may write a
+/// NUL-terminated reason (of at most `reason_len` bytes, including the terminator) into `reason`,
+/// which is passed along to the client.
+using WardenclyffeValidator = bool(*)(void *context,
+                                      const void *data,
+                                      size_t size,
+                                      char *reason,
+                                      size_t reason_len);
+
+/// Identity of the client a socket is being created for.
+struct WardenclyffePeerInfo {
+  /// Remote address of the peer, or NULL for peers on local sockets.
+  const char *address;
+  /// Kernel-provided credentials of peers on local sockets, or -1 if unknown.
+  int64_t uid;
+  int64_t gid;
+  int64_t pid;
+  /// What was negotiated for peers connected over TLS (e.g. "TLSv1_3", "TLS13_AES_128_GCM_SHA256",
+  /// "h2"), or NULL. The client certificate's subject is NULL if it didn't present one.
+  const char *tls_version;
+  const char *tls_cipher;
+  const char *tls_alpn;
+  const char *tls_client_subject;
+  /// The client's scopes, space-separated (e.g. "read:/logcat write:/input"), or NULL for anonymous
+  /// clients. The admin token has the "admin" scope, and grants have read and write scopes for
+  /// their path.
+  const char *scopes;
+};
+
 using WardenclyffeSocket = void*;
 
+/// A socket that clients can open.
+struct WardenclyffeSocketInfo {
+  const char *path;
+  uint32_t flags;
+  /// MIME type of the data read from the socket (e.g. "video/h264"), or NULL if unknown.
+  const char *content_type;
+};
+
+struct WardenclyffeSocketList {
+  const WardenclyffeSocketInfo *sockets;
+  size_t count;
+};
+
 struct WardenclyffeRead {
   const void *data;
   size_t size;
   uint8_t oob;
+  /// Nonzero if this read is a fragment of a larger frame, whose remainder follows in subsequent
+  /// (non-oob) reads. The read that completes the frame has this set to zero.
+  uint8_t more;
+  /// For the first fragment of a frame, its total size in bytes, or zero if unknown.
+  size_t total_size;
+  /// Nonzero if the data is in shared memory (e.g. a memfd or ashmem region) instead of at `data`:
+  /// `size` bytes at `offset` in `fd`. The fd must remain open, and the region unmodified, until the
+  /// next read from the socket, just like `data`.
+  uint8_t shm;
+  int32_t fd;
+  uint64_t offset;
+  /// When the data was captured, in nanoseconds on CLOCK_MONOTONIC (e.g. a graphics buffer's
+  /// timestamp), or zero if the backend doesn't know, in which case the time it was read is used.
+  /// For fragmented frames, only the first fragment's is used.
+  int64_t timestamp_ns;
 };
 
 struct WardenclyffeReads {
@@ -26,16 +108,129 @@ struct WardenclyffeReads {
 
 extern "C" {
 
-extern WardenclyffeSocket wardenclyffe_create_socket(const char *path);
+/// Check what clients write to sockets with paths matching `path` (a glob, where `*` matches any
+/// sequence of characters) with `validator`, which is passed `context`, rejecting messages it
+/// returns false for instead of writing them to the socket. Applies to sessions opened afterwards.
+///
+/// `validator` is called from arbitrary threads, and `context` must stay valid for as long as the
+/// process runs.
+void wardenclyffe_add_validator(const char *path, WardenclyffeValidator validator, void *context);
+
+/// Ask the platform to authenticate a bearer token that the server doesn't recognize itself, when
+/// `auth.platform` is set, e.g. against its own account system. This may block for as long as
+/// it needs to (e.g. for the user to confirm on the lockscreen).
+///
+/// On success, the platform writes the scopes it grants, space-separated and in the same form as
+/// `auth.roles` (e.g. "read:/video/* admin"), as a NUL-terminated string of at most `scopes_len`
+/// bytes (including the terminator) into `scopes`.
+extern bool wardenclyffe_authenticate(const char *credentials,
+                                      const WardenclyffePeerInfo *peer,
+                                      char *scopes,
+                                      size_t scopes_len);
+
+/// Ask the platform whether `peer` may open `path`, before any socket is created.
+///
+/// On denial, the backend may write a NUL-terminated reason (of at most `reason_len` bytes,
+/// including the terminator) into `reason`, which is reported to the client.
+extern bool wardenclyffe_authorize(const char *path,
+                                   const WardenclyffePeerInfo *peer,
+                                   char *reason,
+                                   size_t reason_len);
+
+extern WardenclyffeSocket wardenclyffe_create_socket(const char *path,
+                                                     const WardenclyffePeerInfo *peer);
 
 extern void wardenclyffe_destroy_socket(WardenclyffeSocket socket);
 
-extern WardenclyffeReads wardenclyffe_read(WardenclyffeSocket socket);
+/// End the session on `socket` from the backend, e.g. because the screen was locked, so that the
+/// client is told why with `code` (as from `wardenclyffe_socket_error`) and `reason` rather than
+/// just seeing the socket hit EOF. The session's connection is closed once what's already been read
+/// has been sent; call this before returning EOF from reads.
+///
+/// May be called from any thread until the socket is destroyed. Returns false if the socket isn't
+/// open.
+bool wardenclyffe_end_session(WardenclyffeSocket socket, int32_t code, const char *reason);
+
+/// Install a UI bundle (a zip file) into the running server, after verifying its Ed25519 signature.
+///
+/// Returns false if no server is running, UI bundle updates aren't enabled, or installation failed.
+bool wardenclyffe_install_ui_bundle(const uint8_t *bundle,
+                                    size_t bundle_len,
+                                    const uint8_t *signature,
+                                    size_t signature_len);
+
+/// List the sockets that clients can open, for discovery. The list must remain valid until the
+/// next call, and calls are never concurrent.
+extern WardenclyffeSocketList wardenclyffe_list_sockets();
+
+/// Run the server with a JSON configuration, instead of parsing arguments and a configuration file.
+///
+/// Returns nonzero if the configuration is invalid or the server fails.
+int32_t wardenclyffe_main_with_config(const char *json);
+
+/// Mint a relative URL granting access to `path` for `ttl_secs` seconds, writing it to `out` as a
+/// NUL-terminated string.
+///
+/// Returns the length of the URL (excluding the terminator), or -1 if no server is running or `out`
+/// is too small.
+ptrdiff_t wardenclyffe_mint_grant(const char *path,
+                                  uint32_t ttl_secs,
+                                  bool read_only,
+                                  char *out,
+                                  size_t out_len);
+
+extern void wardenclyffe_pause(WardenclyffeSocket socket);
+
+extern WardenclyffeReads wardenclyffe_read_timeout(WardenclyffeSocket socket, uint32_t millis);
+
+/// Make a socket available to clients at runtime, e.g. when a second display becomes capturable,
+/// announcing it to clients listening on `/api/events`. It's listed with the sockets from
+/// `wardenclyffe_list_sockets` until it's unregistered. `info` is copied.
+bool wardenclyffe_register_socket(const WardenclyffeSocketInfo *info);
+
+extern void wardenclyffe_resume(WardenclyffeSocket socket);
+
+/// Tell the server that a platform condition, like `screen_off` or `user_switched`, started or
+/// ended. Sessions on sockets whose policy lists the condition in `suspend_on` stop reading while
+/// it's active, and resume reading once it's over.
+///
+/// May be called from any thread, at any time.
+void wardenclyffe_set_condition(const char *condition, bool active);
+
+/// Set `key` to `value` in the metadata of the WebSocket session on `socket`, which is shown in the
+/// admin sessions listing, or remove it if `value` is NULL. Keys are up to 64 characters from
+/// `[A-Za-z0-9_.-]`, and values up to 256 bytes.
+///
+/// May be called from any thread until the socket is destroyed. Returns false if the socket isn't
+/// a WebSocket session's, or the entry is invalid.
+bool wardenclyffe_set_metadata(WardenclyffeSocket socket, const char *key, const char *value);
+
+/// Ask the backend to stop producing data for `socket` until `wardenclyffe_resume`, because the
+/// client is falling behind. Backends that can't pause cheaply can ignore this, and have data
+/// dropped instead. Must not block; may be called concurrently with reads.
+/// Set a parameter of what `socket` produces, like its resolution or frame rate, at the client's
+/// request. Returns false if the backend refuses, with the reason from `wardenclyffe_socket_error`.
+extern bool wardenclyffe_set_parameter(WardenclyffeSocket socket,
+                                       const char *name,
+                                       const char *value);
+
+/// Describe why the most recent read or write on `socket` failed.
+///
+/// The backend may write a NUL-terminated reason (of at most `reason_len` bytes, including the
+/// terminator) into `reason`. Returns an error code that is passed along to the client, or 0.
+extern int32_t wardenclyffe_socket_error(WardenclyffeSocket socket,
+                                         char *reason,
+                                         size_t reason_len);
 
 extern bool wardenclyffe_supports_read(WardenclyffeSocket socket);
 
 extern bool wardenclyffe_supports_write(WardenclyffeSocket socket);
 
+/// Announce that a socket is no longer available, whether it was registered with
+/// `wardenclyffe_register_socket` or listed by `wardenclyffe_list_sockets`. Returns whether it had
+/// been registered.
+bool wardenclyffe_unregister_socket(const char *path);
+
 extern bool wardenclyffe_write(WardenclyffeSocket socket, const void *data, size_t len);
 
 } // extern "C"
@@ -0,0 +1,547 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Body, Client, HeaderMap, Method, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rcgen::{Certificate, CertificateParams, CustomExtension, DistinguishedName, KeyPair, PKCS_ECDSA_P256_SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde_json::{json, Value};
+
+use crate::config;
+use crate::roots;
+use crate::selfsigned::SelfSignedResolver;
+use crate::store::write_file;
+use crate::task;
+use crate::tls::{self, CertificateSource};
+
+/// The ALPN protocol ACME CAs offer when validating TLS-ALPN-01 challenges (RFC 8737).
+pub const ALPN_ACME_TLS: &[u8] = b"acme-tls/1";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+
+// How long to wait before trying again after failing to get a certificate.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Key authorizations for HTTP-01 challenges the CA is checking, by token, served by the redirect
+/// listener.
+static HTTP_CHALLENGES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// The key authorization to answer an HTTP-01 challenge for `token` with, while the CA is checking.
+pub fn http_challenge(token: &str) -> Option<String> {
+  HTTP_CHALLENGES.lock().unwrap().get(token).cloned()
+}
+
+// The longest to sleep between checks of whether the certificate is due for renewal, in case the
+// clock jumps.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+fn cert_path(cache_dir: &Path) -> PathBuf {
+  cache_dir.join("cert.pem")
+}
+
+fn key_path(cache_dir: &Path) -> PathBuf {
+  cache_dir.join("key.pem")
+}
+
+fn account_key_path(cache_dir: &Path) -> PathBuf {
+  cache_dir.join("account.pk8")
+}
+
+fn now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs() as i64)
+    .unwrap_or(0)
+}
+
+/// The certificate chain and key last issued to `cache_dir`, if there is one.
+pub fn load_cached(cache_dir: &Path) -> Result<Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>> {
+  let cert_path = cert_path(cache_dir);
+  if !cert_path.exists() {
+    return Ok(None);
+  }
+  let chain: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path)?))?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+  if chain.is_empty() {
+    bail!("no certificate in {}", cert_path.display());
+  }
+  Ok(Some((chain, tls::load_private_key(&key_path(cache_dir), None)?)))
+}
+
+/// When a certificate expires, and whether it names every one of `domains`.
+pub fn validity(cert: &rustls::Certificate, domains: &[String]) -> Result<(i64, bool)> {
+  let (_, parsed) =
+    x509_parser::parse_x509_certificate(&cert.0).map_err(|e| anyhow!("failed to parse certificate: {e}"))?;
+  let names: Vec<_> = parsed
+    .subject_alternative_name()
+    .ok()
+    .flatten()
+    .map(|san| {
+      san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+          x509_parser::extensions::GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+          _ => None,
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  let covered = domains
+    .iter()
+    .all(|domain| names.contains(&domain.to_ascii_lowercase()));
+  Ok((parsed.validity().not_after.timestamp(), covered))
+}
+
+struct Issued {
+  certified: Arc<CertifiedKey>,
+  not_after: i64,
+  covered: bool,
+}
+
+/// Serves a certificate issued by an ACME CA, renewing it in the background before it expires, and
+/// answers the CA's TLS-ALPN-01 challenges (or HTTP-01, with the redirect listener). Until a
+/// certificate has been issued, a self-signed one is served instead.
+pub struct AcmeResolver {
+  config: config::Acme,
+  http_challenges: bool,
+  issued: RwLock<Option<Issued>>,
+  fallback: SelfSignedResolver,
+
+  /// Certificates proving control of domains to the CA, by domain, while it's checking.
+  challenges: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl AcmeResolver {
+  /// Serve the certificate issued by a previous run, if there is one. HTTP-01 challenges are
+  /// preferred if `http_challenges` is set, since the redirect listener answers them.
+  pub fn new(config: &config::Acme, http_challenges: bool) -> Result<AcmeResolver> {
+    let resolver = AcmeResolver {
+      config: config.clone(),
+      http_challenges,
+      issued: RwLock::new(None),
+      fallback: SelfSignedResolver::new(None)?,
+      challenges: Mutex::new(HashMap::new()),
+    };
+    let cached = load_cached(&config.cache_dir).and_then(|cached| match cached {
+      Some((chain, key)) => resolver.install(chain, key).map(|()| true),
+      None => Ok(false),
+    });
+    match cached {
+      Ok(true) => {}
+      Ok(false) => info!("no ACME certificate issued yet, serving a self-signed one until there is"),
+      Err(e) => warn!("failed to load cached ACME certificate: {e:?}"),
+    }
+    Ok(resolver)
+  }
+
+  fn install(&self, chain: Vec<rustls::Certificate>, key: rustls::PrivateKey) -> Result<()> {
+    let (not_after, covered) = validity(&chain[0], &self.config.domains)?;
+    let key = rustls::sign::any_supported_type(&key)?;
+    *self.issued.write().unwrap() = Some(Issued {
+      certified: Arc::new(CertifiedKey::new(chain, key)),
+      not_after,
+      covered,
+    });
+    Ok(())
+  }
+
+  /// When the certificate should be renewed, in seconds since the Unix epoch: right away if there
+  /// isn't one, or it doesn't cover the configured domains.
+  fn renew_at(&self) -> i64 {
+    let renew_before = self.config.renew_before_days.unwrap_or(30) as i64 * 24 * 60 * 60;
+    match &*self.issued.read().unwrap() {
+      Some(issued) if issued.covered => issued.not_after - renew_before,
+      _ => 0,
+    }
+  }
+
+  async fn renew(self: Arc<Self>) {
+    loop {
+      let wait = self.renew_at() - now();
+      if wait > 0 {
+        tokio::time::sleep(Duration::from_secs(wait as u64).min(CHECK_INTERVAL)).await;
+        continue;
+      }
+
+      info!("requesting ACME certificate for {:?}", self.config.domains);
+      let result = self.issue().await;
+      self.challenges.lock().unwrap().clear();
+      match result {
+        Ok(not_after) => info!(
+          "issued ACME certificate for {:?}, valid until {not_after}",
+          self.config.domains
+        ),
+        Err(e) => {
+          error!("failed to get ACME certificate, retrying in {RETRY_INTERVAL:?}: {e:?}");
+          tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+      }
+    }
+  }
+
+  /// Order a certificate, answer the CA's challenges, and install and cache what it issues.
+  async fn issue(&self) -> Result<i64> {
+    let mut client = AcmeClient::new(&self.config).await?;
+    let identifiers: Vec<_> = self
+      .config
+      .domains
+      .iter()
+      .map(|domain| json!({ "type": "dns", "value": domain }))
+      .collect();
+    let new_order = client.directory.new_order.clone();
+    let (headers, order) = client
+      .post(&new_order, Some(json!({ "identifiers": identifiers })))
+      .await?;
+    let order_url = location(&headers)?;
+    let order: Value = serde_json::from_slice(&order)?;
+
+    for authorization in order["authorizations"].as_array().into_iter().flatten() {
+      let authorization = authorization.as_str().ok_or_else(|| anyhow!("invalid authorization"))?;
+      self.authorize(&mut client, authorization).await?;
+    }
+
+    // The CA signs a request with a new key, with the names being ordered.
+    let mut params = CertificateParams::new(self.config.domains.clone());
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?);
+    params.distinguished_name = DistinguishedName::new();
+    let request = Certificate::from_params(params)?;
+    let csr = URL_SAFE_NO_PAD.encode(request.serialize_request_der()?);
+    let finalize = order["finalize"]
+      .as_str()
+      .ok_or_else(|| anyhow!("order has no finalize URL"))?;
+    client.post(finalize, Some(json!({ "csr": csr }))).await?;
+
+    let order = client.poll(&order_url, "order").await?;
+    let certificate = order["certificate"]
+      .as_str()
+      .ok_or_else(|| anyhow!("order has no certificate URL"))?;
+    let (_, chain_pem) = client.post(certificate, None).await?;
+    let key_pem = request.serialize_private_key_pem();
+
+    let chain: Vec<_> = rustls_pemfile::certs(&mut &chain_pem[..])?
+      .into_iter()
+      .map(rustls::Certificate)
+      .collect();
+    if chain.is_empty() {
+      bail!("CA issued no certificate");
+    }
+    let key = rustls::PrivateKey(request.serialize_private_key_der());
+    self.install(chain, key)?;
+
+    fs::create_dir_all(&self.config.cache_dir)?;
+    write_file(&key_path(&self.config.cache_dir), key_pem.as_bytes())?;
+    write_file(&cert_path(&self.config.cache_dir), &chain_pem)?;
+    Ok(self.issued.read().unwrap().as_ref().unwrap().not_after)
+  }
+
+  /// Prove control of an authorization's domain with an HTTP-01 or TLS-ALPN-01 challenge.
+  async fn authorize(&self, client: &mut AcmeClient, url: &str) -> Result<()> {
+    let (_, authorization) = client.post(url, None).await?;
+    let authorization: Value = serde_json::from_slice(&authorization)?;
+    if authorization["status"] == "valid" {
+      return Ok(());
+    }
+
+    let domain = authorization["identifier"]["value"]
+      .as_str()
+      .ok_or_else(|| anyhow!("authorization has no identifier"))?;
+    let challenges = authorization["challenges"].as_array();
+    let find = |ty: &str| {
+      challenges
+        .into_iter()
+        .flatten()
+        .find(|challenge| challenge["type"] == ty)
+    };
+    let challenge = self
+      .http_challenges
+      .then(|| find("http-01"))
+      .flatten()
+      .or_else(|| find("tls-alpn-01"))
+      .ok_or_else(|| anyhow!("CA doesn't offer a supported challenge for {domain}"))?;
+    let (Some(token), Some(challenge_url)) = (challenge["token"].as_str(), challenge["url"].as_str()) else {
+      bail!("invalid {} challenge for {domain}", challenge["type"]);
+    };
+
+    let key_authorization = format!("{token}.{}", client.thumbprint());
+    let http = challenge["type"] == "http-01";
+    if http {
+      HTTP_CHALLENGES
+        .lock()
+        .unwrap()
+        .insert(token.to_string(), key_authorization);
+    } else {
+      self.challenges.lock().unwrap().insert(
+        domain.to_ascii_lowercase(),
+        challenge_certificate(domain, &key_authorization)?,
+      );
+    }
+    let result = async {
+      client.post(challenge_url, Some(json!({}))).await?;
+      client.poll(url, "authorization").await
+    }
+    .await;
+    if http {
+      HTTP_CHALLENGES.lock().unwrap().remove(token);
+    } else {
+      self.challenges.lock().unwrap().remove(&domain.to_ascii_lowercase());
+    }
+    result.map(|_| ())
+  }
+}
+
+/// A self-signed certificate for `domain`, carrying the digest of the key authorization in the
+/// acmeIdentifier extension, as TLS-ALPN-01 requires.
+fn challenge_certificate(domain: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>> {
+  let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+  let mut params = CertificateParams::new(vec![domain.to_string()]);
+  params.alg = &PKCS_ECDSA_P256_SHA256;
+  params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+  let cert = Certificate::from_params(params)?;
+  let key = rustls::sign::any_supported_type(&rustls::PrivateKey(cert.serialize_private_key_der()))?;
+  Ok(Arc::new(CertifiedKey::new(
+    vec![rustls::Certificate(cert.serialize_der()?)],
+    key,
+  )))
+}
+
+impl ResolvesServerCert for AcmeResolver {
+  fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    let validating = client_hello
+      .alpn()
+      .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ALPN_ACME_TLS));
+    if validating {
+      let domain = client_hello.server_name()?.to_ascii_lowercase();
+      return self.challenges.lock().unwrap().get(&domain).cloned();
+    }
+    Some(self.current())
+  }
+}
+
+impl CertificateSource for AcmeResolver {
+  fn current(&self) -> Arc<CertifiedKey> {
+    match &*self.issued.read().unwrap() {
+      Some(issued) => issued.certified.clone(),
+      None => self.fallback.current(),
+    }
+  }
+
+  fn start(self: Arc<Self>) {
+    task::spawn("acme renewal", self.renew());
+  }
+}
+
+fn location(headers: &HeaderMap) -> Result<String> {
+  let location = headers
+    .get(LOCATION)
+    .ok_or_else(|| anyhow!("response has no Location"))?;
+  Ok(location.to_str()?.to_string())
+}
+
+struct Directory {
+  new_nonce: String,
+  new_account: String,
+  new_order: String,
+}
+
+/// A session with an ACME CA, as an account identified by its key (RFC 8555).
+struct AcmeClient {
+  http: Client<HttpsConnector<HttpConnector>>,
+  directory: Directory,
+  key: EcdsaKeyPair,
+  rng: SystemRandom,
+
+  /// The account's URL, once it's been registered.
+  kid: Option<String>,
+  nonce: Option<String>,
+}
+
+impl AcmeClient {
+  /// Register the account with the CA, with the key from a previous run if there is one.
+  async fn new(config: &config::Acme) -> Result<AcmeClient> {
+    let tls = rustls::ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(roots::load(config.ca_certs.as_deref())?)
+      .with_no_client_auth();
+    let connector = HttpsConnectorBuilder::new()
+      .with_tls_config(tls)
+      .https_only()
+      .enable_http1()
+      .build();
+    let http = Client::builder().build(connector);
+
+    let (status, _, directory) = request(&http, Method::GET, config.directory_url.as_ref().unwrap(), None).await?;
+    if !status.is_success() {
+      bail!("failed to fetch ACME directory: {status}");
+    }
+    let directory: Value = serde_json::from_slice(&directory)?;
+    let url = |name: &str| {
+      directory[name]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("ACME directory has no {name}"))
+    };
+    let directory = Directory {
+      new_nonce: url("newNonce")?,
+      new_account: url("newAccount")?,
+      new_order: url("newOrder")?,
+    };
+
+    let rng = SystemRandom::new();
+    let key_path = account_key_path(&config.cache_dir);
+    let pkcs8 = match fs::read(&key_path) {
+      Ok(pkcs8) => pkcs8,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+          .map_err(|_| anyhow!("failed to generate account key"))?;
+        fs::create_dir_all(&config.cache_dir)?;
+        write_file(&key_path, pkcs8.as_ref())?;
+        pkcs8.as_ref().to_vec()
+      }
+      Err(e) => return Err(e).with_context(|| format!("failed to read {}", key_path.display())),
+    };
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+      .map_err(|_| anyhow!("invalid account key in {}", key_path.display()))?;
+
+    let mut client = AcmeClient {
+      http,
+      directory,
+      key,
+      rng,
+      kid: None,
+      nonce: None,
+    };
+    let mut account = json!({ "termsOfServiceAgreed": true });
+    if let Some(contact) = &config.contact {
+      account["contact"] = json!(contact);
+    }
+    let new_account = client.directory.new_account.clone();
+    let (headers, _) = client.post(&new_account, Some(account)).await?;
+    client.kid = Some(location(&headers)?);
+    Ok(client)
+  }
+
+  /// The account key, as a JWK with its members in the order RFC 7638 thumbprints them.
+  fn jwk(&self) -> String {
+    // The public key is an uncompressed point: 0x04, then x and y.
+    let point = self.key.public_key().as_ref();
+    format!(
+      r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+      URL_SAFE_NO_PAD.encode(&point[1..33]),
+      URL_SAFE_NO_PAD.encode(&point[33..65])
+    )
+  }
+
+  fn thumbprint(&self) -> String {
+    URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, self.jwk().as_bytes()))
+  }
+
+  async fn nonce(&mut self) -> Result<String> {
+    if let Some(nonce) = self.nonce.take() {
+      return Ok(nonce);
+    }
+    let (_, headers, _) = request(&self.http, Method::HEAD, &self.directory.new_nonce, None).await?;
+    self.nonce = replay_nonce(&headers);
+    self.nonce.take().ok_or_else(|| anyhow!("CA didn't provide a nonce"))
+  }
+
+  /// POST a JWS-signed payload to the CA, or an empty one, for a POST-as-GET.
+  async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<(HeaderMap, Bytes)> {
+    // A nonce can go stale, in which case the CA sends a fresh one to try again with.
+    let mut retried = false;
+    loop {
+      let mut protected = json!({ "alg": "ES256", "nonce": self.nonce().await?, "url": url });
+      match &self.kid {
+        Some(kid) => protected["kid"] = json!(kid),
+        None => protected["jwk"] = serde_json::from_str(&self.jwk())?,
+      }
+      let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+      let payload = payload
+        .as_ref()
+        .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+        .unwrap_or_default();
+      let signature = self
+        .key
+        .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+        .map_err(|_| anyhow!("failed to sign ACME request"))?;
+      let body = json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+      });
+
+      let (status, headers, body) = request(&self.http, Method::POST, url, Some(body.to_string())).await?;
+      self.nonce = replay_nonce(&headers);
+      if status.is_success() {
+        return Ok((headers, body));
+      }
+
+      let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+      if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+        retried = true;
+        continue;
+      }
+      bail!("ACME request to {url} failed with {status}: {problem}");
+    }
+  }
+
+  /// Wait for an order or authorization to become valid.
+  async fn poll(&mut self, url: &str, what: &str) -> Result<Value> {
+    for _ in 0..MAX_POLLS {
+      let (_, body) = self.post(url, None).await?;
+      let object: Value = serde_json::from_slice(&body)?;
+      match object["status"].as_str() {
+        Some("valid") => return Ok(object),
+        Some("pending" | "ready" | "processing") => tokio::time::sleep(POLL_INTERVAL).await,
+        status => bail!("{what} is {}: {object}", status.unwrap_or("invalid")),
+      }
+    }
+    bail!("timed out waiting for {what}");
+  }
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+  headers
+    .get("replay-nonce")
+    .and_then(|nonce| nonce.to_str().ok())
+    .map(str::to_string)
+}
+
+async fn request(
+  http: &Client<HttpsConnector<HttpConnector>>,
+  method: Method,
+  url: &str,
+  body: Option<String>,
+) -> Result<(StatusCode, HeaderMap, Bytes)> {
+  let mut req = Request::builder().method(method).uri(url);
+  if body.is_some() {
+    req = req.header(CONTENT_TYPE, "application/jose+json");
+  }
+  let req = req.body(body.map(Body::from).unwrap_or_else(Body::empty))?;
+  let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+    let response = http.request(req).await?;
+    let (parts, body) = response.into_parts();
+    Ok::<_, anyhow::Error>((parts.status, parts.headers, hyper::body::to_bytes(body).await?))
+  })
+  .await
+  .map_err(|_| anyhow!("ACME request to {url} timed out"))??;
+  Ok(response)
+}
@@ -0,0 +1,263 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE, HOST};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::audit::AuditEvent;
+use crate::auth::query_param;
+use crate::config::TLS;
+use crate::export;
+use crate::logging::{self, LogFilter};
+use crate::peer::Peer;
+use crate::replay::ReplayLimits;
+use crate::server::{json_response, socket_policy, text_response, ServerState};
+
+const DEFAULT_GRANT_TTL: Duration = Duration::from_secs(10 * 60);
+
+fn mint_grant(state: &ServerState, req: &Request<Body>) -> Response<Body> {
+  let Some(path) = query_param(req, "path") else {
+    return text_response(StatusCode::BAD_REQUEST, "missing path");
+  };
+
+  let max_ttl = state.config.auth.as_ref().unwrap().max_grant_ttl_secs.unwrap();
+  let ttl = match query_param(req, "ttl").map(|ttl| ttl.parse::<u64>()) {
+    None => DEFAULT_GRANT_TTL.min(Duration::from_secs(max_ttl)),
+    Some(Ok(secs)) if secs <= max_ttl => Duration::from_secs(secs),
+    Some(Ok(_)) => {
+      return text_response(
+        StatusCode::BAD_REQUEST,
+        format!("ttl exceeds the maximum of {max_ttl} seconds"),
+      )
+    }
+    Some(Err(_)) => return text_response(StatusCode::BAD_REQUEST, "invalid ttl"),
+  };
+  let read_only = query_param(req, "read_only").map(|v| v != "false").unwrap_or(true);
+
+  let host = req
+    .headers()
+    .get(HOST)
+    .and_then(|h| h.to_str().ok())
+    .or_else(|| req.uri().authority().map(|a| a.as_str()))
+    .unwrap_or("localhost");
+  let scheme = if state.config.tls == Some(TLS::Disabled) {
+    "ws"
+  } else {
+    "wss"
+  };
+
+  let grant = state.auth.mint(&path, ttl, read_only);
+  json_response(&json!({
+    "url": format!("{scheme}://{host}{path}?grant={grant}"),
+    "grant": grant,
+    "ttl": ttl.as_secs(),
+    "read_only": read_only,
+  }))
+}
+
+async fn install_ui_bundle(state: &ServerState, req: Request<Body>) -> Result<Response<Body>> {
+  let Some(installer) = &state.ui_bundle else {
+    return Ok(text_response(
+      StatusCode::NOT_FOUND,
+      "UI bundle updates are not enabled",
+    ));
+  };
+
+  let signature = match req
+    .headers()
+    .get("x-signature")
+    .map(|sig| STANDARD.decode(sig.as_bytes()))
+  {
+    Some(Ok(signature)) => signature,
+    _ => return Ok(text_response(StatusCode::BAD_REQUEST, "missing or invalid x-signature")),
+  };
+
+  let bundle = hyper::body::to_bytes(req.into_body()).await?;
+  Ok(match installer.install_bundle(&bundle, &signature) {
+    Ok(()) => text_response(StatusCode::OK, "UI bundle installed"),
+    Err(e) => {
+      error!("failed to install UI bundle: {e:?}");
+      text_response(StatusCode::BAD_REQUEST, format!("failed to install UI bundle: {e}"))
+    }
+  })
+}
+
+fn rollback_ui_bundle(state: &ServerState) -> Response<Body> {
+  let Some(installer) = &state.ui_bundle else {
+    return text_response(StatusCode::NOT_FOUND, "UI bundle updates are not enabled");
+  };
+
+  match installer.rollback() {
+    Ok(()) => text_response(StatusCode::OK, "UI bundle rolled back"),
+    Err(e) => text_response(StatusCode::CONFLICT, format!("failed to roll back UI bundle: {e}")),
+  }
+}
+
+#[derive(Deserialize)]
+struct Revocation {
+  token: String,
+}
+
+async fn revoke_token(state: &ServerState, req: Request<Body>) -> Result<Response<Body>> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let Ok(Revocation { token }) = serde_json::from_slice(&body) else {
+    return Ok(text_response(StatusCode::BAD_REQUEST, "expected {\"token\": ...}"));
+  };
+  state.auth.revoke(&token)?;
+  Ok(text_response(StatusCode::OK, "token revoked"))
+}
+
+fn rotate_signing_key(state: &ServerState, req: &Request<Body>) -> Result<Response<Body>> {
+  // There's no point accepting the old key for longer than any grant it signed can last.
+  let max_grace = state.config.auth.as_ref().unwrap().max_grant_ttl_secs.unwrap();
+  let grace = match query_param(req, "grace").map(|grace| grace.parse::<u64>()) {
+    None => Duration::ZERO,
+    Some(Ok(secs)) if secs <= max_grace => Duration::from_secs(secs),
+    Some(Ok(_)) => {
+      return Ok(text_response(
+        StatusCode::BAD_REQUEST,
+        format!("grace exceeds the maximum of {max_grace} seconds"),
+      ))
+    }
+    Some(Err(_)) => return Ok(text_response(StatusCode::BAD_REQUEST, "invalid grace")),
+  };
+  let persisted = state.auth.rotate_key(grace)?;
+  Ok(json_response(&json!({
+    "grace": grace.as_secs(),
+    "persisted": persisted,
+  })))
+}
+
+fn failed_attempts(state: &ServerState) -> Response<Body> {
+  let sources: Vec<_> = state
+    .failed_attempts
+    .snapshot()
+    .into_iter()
+    .map(|(source, summary)| {
+      let mut entry = serde_json::to_value(summary).unwrap();
+      entry["source"] = source.into();
+      entry
+    })
+    .collect();
+  json_response(&json!({ "sources": sources }))
+}
+
+fn sessions(state: &ServerState) -> Response<Body> {
+  let sessions: Vec<_> = state
+    .websocket_sessions
+    .list()
+    .iter()
+    .map(|session| {
+      json!({
+        "socket": session.socket_path,
+        "identity": session.identity,
+        "peer": session.peer.to_string(),
+        "opened_at": session.opened_at,
+        "user_agent": session.user_agent,
+        "client": *session.client.lock().unwrap(),
+        "protocol": session.protocol.name(),
+        "resumable": session.id.is_some(),
+        "read": session.supports_read,
+        "write": session.supports_write,
+        "metadata": session.metadata.snapshot(),
+      })
+    })
+    .collect();
+  json_response(&json!({ "sessions": sessions }))
+}
+
+#[derive(Deserialize)]
+struct LogLevel {
+  filter: String,
+}
+
+fn log_level() -> Response<Body> {
+  json_response(&json!({ "filter": logging::filter().to_string() }))
+}
+
+/// Replace the log filter, e.g. with `{"filter": "debug,wardenclyffe::tls=trace"}` to capture
+/// debug logs from a device without restarting it.
+async fn set_log_level(req: Request<Body>) -> Result<Response<Body>> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let Ok(LogLevel { filter }) = serde_json::from_slice(&body) else {
+    return Ok(text_response(StatusCode::BAD_REQUEST, "expected {\"filter\": ...}"));
+  };
+  let filter = match LogFilter::parse(&filter) {
+    Ok(filter) => filter,
+    Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, e.to_string())),
+  };
+  warn!("log filter changed to {filter}");
+  logging::set_filter(filter);
+  Ok(log_level())
+}
+
+/// Export the data kept for replay on a socket, for analysis tools: as a HAR log with `format=har`
+/// (the default), or a pcapng capture with `format=pcapng`.
+fn export_replay(state: &ServerState, req: &Request<Body>) -> Response<Body> {
+  let Some(path) = query_param(req, "path") else {
+    return text_response(StatusCode::BAD_REQUEST, "missing path");
+  };
+  let Some(limits) = ReplayLimits::from_policy(socket_policy(&state.config, &path)) else {
+    return text_response(StatusCode::NOT_FOUND, "socket doesn't keep data for replay");
+  };
+  let recording = state.replay.backlog(&path, limits, None);
+  match query_param(req, "format").as_deref() {
+    None | Some("har") => json_response(&export::har(&path, &recording)),
+    Some("pcapng") => Response::builder()
+      .header(CONTENT_TYPE, "application/vnd.tcpdump.pcap")
+      .header(CONTENT_DISPOSITION, "attachment; filename=\"replay.pcapng\"")
+      .body(Body::from(export::pcapng(&path, &recording)))
+      .unwrap(),
+    Some(_) => text_response(StatusCode::BAD_REQUEST, "format must be har or pcapng"),
+  }
+}
+
+/// Handle a request for /admin/<path>.
+pub async fn handle_admin(
+  state: Arc<ServerState>,
+  req: Request<Body>,
+  peer: Peer,
+  path: &str,
+) -> Result<Response<Body>> {
+  if !state.auth.is_admin(&req) {
+    state.audit.record(
+      &peer,
+      AuditEvent::AuthFailure {
+        path: req.uri().path(),
+        reason: "invalid admin token".into(),
+      },
+    );
+    return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+  }
+
+  state.audit.record(
+    &peer,
+    AuditEvent::AdminAction {
+      action: path,
+      detail: req.method().to_string(),
+    },
+  );
+
+  let response = match (req.method(), path) {
+    (&Method::POST, "grants") => mint_grant(&state, &req),
+    (&Method::PUT, "ui-bundle") => install_ui_bundle(&state, req).await?,
+    (&Method::POST, "ui-bundle/rollback") => rollback_ui_bundle(&state),
+    (&Method::GET, "sessions") => sessions(&state),
+    (&Method::GET, "replay/export") => export_replay(&state, &req),
+    (&Method::GET, "log-level") => log_level(),
+    (&Method::PUT, "log-level") => set_log_level(req).await?,
+    (&Method::GET, "failed-attempts") => failed_attempts(&state),
+    (&Method::DELETE, "failed-attempts") => {
+      state.failed_attempts.clear();
+      text_response(StatusCode::NO_CONTENT, "")
+    }
+    (&Method::POST, "revoke") => revoke_token(&state, req).await?,
+    (&Method::POST, "signing-key/rotate") => rotate_signing_key(&state, &req)?,
+    _ => text_response(StatusCode::NOT_FOUND, format!("Unknown admin endpoint: {path}")),
+  };
+  Ok(response)
+}
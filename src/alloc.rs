@@ -0,0 +1,61 @@
+use serde_json::{json, Value};
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Statistics from the global allocator, for the stats endpoint.
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> Value {
+  use tikv_jemalloc_ctl::{epoch, stats};
+
+  // jemalloc caches its statistics until the epoch is advanced.
+  if let Err(e) = epoch::advance() {
+    warn!("failed to refresh jemalloc stats: {e}");
+  }
+  json!({
+    "allocator": "jemalloc",
+    "allocated_bytes": stats::allocated::read().ok(),
+    "active_bytes": stats::active::read().ok(),
+    "resident_bytes": stats::resident::read().ok(),
+    "mapped_bytes": stats::mapped::read().ok(),
+  })
+}
+
+/// Statistics from the global allocator, for the stats endpoint.
+#[cfg(feature = "mimalloc")]
+pub fn stats() -> Value {
+  let (mut current_rss, mut peak_rss, mut current_commit, mut peak_commit) = (0, 0, 0, 0);
+  unsafe {
+    libmimalloc_sys::mi_process_info(
+      std::ptr::null_mut(),
+      std::ptr::null_mut(),
+      std::ptr::null_mut(),
+      &mut current_rss,
+      &mut peak_rss,
+      &mut current_commit,
+      &mut peak_commit,
+      std::ptr::null_mut(),
+    );
+  }
+  json!({
+    "allocator": "mimalloc",
+    "resident_bytes": current_rss,
+    "peak_resident_bytes": peak_rss,
+    "committed_bytes": current_commit,
+    "peak_committed_bytes": peak_commit,
+  })
+}
+
+/// Statistics from the global allocator, for the stats endpoint.
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn stats() -> Value {
+  json!({ "allocator": "system" })
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::{Message, Role};
+
+use crate::api::{backend_sockets, SocketDescription};
+use crate::peer::Peer;
+use crate::peerlimit::PeerPermit;
+use crate::server::{switching_protocols, text_response, virtual_host, ServerState};
+use crate::task;
+
+/// The path of the WebSocket that announces sockets as the backend registers and unregisters them.
+pub const PATH: &str = "/api/events";
+
+// Announcements a slow listener can fall behind by, before it's sent the whole list again.
+const ANNOUNCEMENT_BACKLOG: usize = 64;
+
+// Sockets the backend has registered at runtime, by path.
+static REGISTERED: Mutex<BTreeMap<String, SocketDescription>> = Mutex::new(BTreeMap::new());
+
+static ANNOUNCEMENTS: OnceLock<broadcast::Sender<Announcement>> = OnceLock::new();
+
+#[derive(Clone)]
+enum Announcement {
+  Added(SocketDescription),
+  Removed(String),
+}
+
+fn announcements() -> &'static broadcast::Sender<Announcement> {
+  ANNOUNCEMENTS.get_or_init(|| broadcast::channel(ANNOUNCEMENT_BACKLOG).0)
+}
+
+/// Sockets the backend has registered at runtime.
+pub fn registered() -> Vec<SocketDescription> {
+  REGISTERED.lock().unwrap().values().cloned().collect()
+}
+
+/// Make a socket available to clients, and tell those listening for announcements about it.
+pub fn register(socket: SocketDescription) {
+  info!("backend registered socket {}", socket.path);
+  REGISTERED.lock().unwrap().insert(socket.path.clone(), socket.clone());
+  let _ = announcements().send(Announcement::Added(socket));
+}
+
+/// Tell clients listening for announcements that a socket is no longer available. Returns whether
+/// it had been registered, rather than listed by `wardenclyffe_list_sockets`.
+pub fn unregister(path: &str) -> bool {
+  info!("backend unregistered socket {path}");
+  let registered = REGISTERED.lock().unwrap().remove(path).is_some();
+  let _ = announcements().send(Announcement::Removed(path.to_string()));
+  registered
+}
+
+/// Upgrade a request to listen for announcements. Listeners are sent the sockets available to
+/// them, and then each socket that's added or removed, like `/api/sockets` describes them.
+pub async fn handle_events(
+  state: Arc<ServerState>,
+  mut req: Request<Body>,
+  peer: Peer,
+  permit: PeerPermit,
+  accept_key: String,
+) -> Result<Response<Body>> {
+  if state.auth.required() && !state.auth.is_admin(&req) {
+    warn!("{peer}: unauthorized request for announcements");
+    return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+  }
+
+  let ver = req.version();
+  task::spawn("websocket announcements", async move {
+    let _permit = permit;
+    match hyper::upgrade::on(&mut req).await {
+      Ok(upgraded) => {
+        let ws_stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        if let Err(e) = send_announcements(&state, ws_stream, &req).await {
+          warn!("{peer}: announcements failed: {e}");
+        }
+        info!("{peer}: stopped listening for announcements");
+      }
+      Err(e) => error!("upgrade error: {}", e),
+    }
+  });
+  Ok(switching_protocols(ver, accept_key, None))
+}
+
+async fn send_announcements(
+  state: &ServerState,
+  ws_stream: WebSocketStream<hyper::upgrade::Upgraded>,
+  req: &Request<Body>,
+) -> Result<()> {
+  let vhost = virtual_host(&state.config, req);
+  let keepalive_interval =
+    Duration::from_millis(state.config.websocket.as_ref().unwrap().keepalive_interval_ms.unwrap());
+  let (mut sink, mut incoming) = ws_stream.split();
+
+  // Subscribe first, so nothing announced while the list is being sent is missed.
+  let mut receiver = announcements().subscribe();
+  let mut send_list = true;
+  loop {
+    if send_list {
+      let sockets: Vec<_> = backend_sockets()
+        .await?
+        .iter()
+        .filter_map(|socket| socket.to_json(&state.config, vhost))
+        .collect();
+      let msg = json!({ "event": "sockets", "sockets": sockets });
+      sink.send(Message::Text(msg.to_string())).await?;
+      send_list = false;
+    }
+
+    let msg = tokio::select! {
+      announcement = receiver.recv() => match announcement {
+        Ok(Announcement::Added(socket)) => match socket.to_json(&state.config, vhost) {
+          Some(socket) => json!({ "event": "socket_added", "socket": socket }),
+          None => continue,
+        },
+        Ok(Announcement::Removed(path)) => {
+          let socket = SocketDescription {
+            path,
+            flags: 0,
+            content_type: None,
+          };
+          match socket.to_json(&state.config, vhost) {
+            Some(socket) => json!({ "event": "socket_removed", "path": socket["path"] }),
+            None => continue,
+          }
+        }
+        Err(broadcast::error::RecvError::Lagged(_)) => {
+          send_list = true;
+          continue;
+        }
+        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+      },
+
+      msg = incoming.next() => match msg {
+        Some(Ok(Message::Close(_))) | None => return Ok(()),
+        Some(Ok(_)) => continue,
+        Some(Err(e)) => return Err(e.into()),
+      },
+
+      _ = tokio::time::sleep(keepalive_interval) => {
+        sink.send(Message::Ping(Vec::new())).await?;
+        continue;
+      }
+    };
+    sink.send(Message::Text(msg.to_string())).await?;
+  }
+}
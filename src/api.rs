@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::{Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use hyper::{
+  header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, RANGE,
+  },
+  Body, Method, Request, Response, StatusCode,
+};
+use ring::digest;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::announce;
+use crate::audit::AuditEvent;
+use crate::auth::query_param;
+use crate::config::{Config, VirtualHost};
+use crate::ffi::*;
+use crate::peer::Peer;
+use crate::server::{
+  json_response, mounted_files, socket_allowed, socket_policy, text_response, virtual_host, ServerState,
+};
+use crate::task;
+use crate::upload::handle_upload;
+use crate::version;
+
+// Serializes calls to wardenclyffe_list_sockets, whose result is only valid until the next call.
+static LIST_SOCKETS: Mutex<()> = Mutex::new(());
+
+// Hashes of files that have been asked about, with the size and modification time they were
+// computed for, since hashing large files is slow. Hashes still being computed are shared by
+// everyone asking for them, and finish even if nobody's waiting anymore.
+type FileHash = Shared<BoxFuture<'static, Result<String, String>>>;
+static FILE_HASHES: Mutex<BTreeMap<PathBuf, (u64, SystemTime, FileHash)>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone)]
+pub struct SocketDescription {
+  pub path: String,
+  pub flags: u32,
+  pub content_type: Option<String>,
+}
+
+impl SocketDescription {
+  /// Read a description passed in by the backend.
+  ///
+  /// # Safety
+  /// `info.path` must be a valid C string, as must `info.content_type` if it isn't NULL.
+  pub unsafe fn from_ffi(info: &WardenclyffeSocketInfo) -> SocketDescription {
+    SocketDescription {
+      path: CStr::from_ptr(info.path).to_string_lossy().into_owned(),
+      flags: info.flags,
+      content_type: (!info.content_type.is_null())
+        .then(|| CStr::from_ptr(info.content_type).to_string_lossy().into_owned()),
+    }
+  }
+
+  /// The MIME type of the data read from the socket, as declared by its socket policy, or else by
+  /// the backend.
+  pub fn content_type<'a>(&'a self, config: &'a Config) -> Option<&'a str> {
+    socket_policy(config, &self.path)
+      .and_then(|p| p.content_type.as_deref())
+      .or(self.content_type.as_deref())
+  }
+
+  /// Describe the socket to clients of a host, at the path they use for it, if they can use it.
+  pub fn to_json(&self, config: &Config, vhost: Option<&VirtualHost>) -> Option<Value> {
+    let prefix = vhost.and_then(|v| v.socket_prefix.as_deref()).unwrap_or("");
+    if !socket_allowed(vhost, &self.path) {
+      return None;
+    }
+
+    let path = self.path.strip_prefix(prefix)?;
+    Some(json!({
+      "path": path,
+      "read": self.flags & WARDENCLYFFE_SOCKET_READ != 0,
+      "write": self.flags & WARDENCLYFFE_SOCKET_WRITE != 0,
+      "shareable": self.flags & WARDENCLYFFE_SOCKET_SHAREABLE != 0,
+      "content_type": self.content_type(config),
+    }))
+  }
+}
+
+/// Ask the backend which sockets exist, including those it's registered since it started.
+pub async fn backend_sockets() -> Result<Vec<SocketDescription>> {
+  let sockets = task::spawn_blocking("wardenclyffe_list_sockets", || {
+    let _lock = LIST_SOCKETS.lock().unwrap();
+    let list = unsafe { wardenclyffe_list_sockets() };
+    if list.count == 0 {
+      return Vec::new();
+    }
+
+    let sockets = unsafe { std::slice::from_raw_parts(list.sockets, list.count) };
+    sockets
+      .iter()
+      .filter(|socket| !socket.path.is_null())
+      .map(|socket| unsafe { SocketDescription::from_ffi(socket) })
+      .collect()
+  })
+  .await?;
+
+  let mut sockets: Vec<SocketDescription> = sockets;
+  for registered in announce::registered() {
+    if !sockets.iter().any(|socket| socket.path == registered.path) {
+      sockets.push(registered);
+    }
+  }
+  Ok(sockets)
+}
+
+/// The MIME type of the data read from a socket, if its socket policy or the backend declares one.
+pub async fn content_type(config: &Config, path: &str) -> Option<String> {
+  if let Some(content_type) = socket_policy(config, path).and_then(|p| p.content_type.clone()) {
+    return Some(content_type);
+  }
+  match backend_sockets().await {
+    Ok(sockets) => sockets
+      .into_iter()
+      .find(|socket| socket.path == path)
+      .and_then(|socket| socket.content_type),
+    Err(e) => {
+      warn!("failed to list sockets: {e:?}");
+      None
+    }
+  }
+}
+
+/// List the sockets available to the request's host, at the paths that its clients use for them.
+async fn list_sockets(state: &ServerState, req: &Request<Body>) -> Result<Response<Body>> {
+  let vhost = virtual_host(&state.config, req);
+  let sockets: Vec<_> = backend_sockets()
+    .await?
+    .iter()
+    .filter_map(|socket| socket.to_json(&state.config, vhost))
+    .collect();
+  Ok(json_response(&json!({ "sockets": sockets })))
+}
+
+/// Compute the SHA-256 of a file, as hex.
+pub fn hash_file(path: &Path) -> Result<String> {
+  let mut file = File::open(path)?;
+  let mut context = digest::Context::new(&digest::SHA256);
+  let mut buf = vec![0; 1024 * 1024];
+  loop {
+    let len = file.read(&mut buf)?;
+    if len == 0 {
+      break;
+    }
+    context.update(&buf[..len]);
+  }
+  Ok(context.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// The SHA-256 of a file, as hex, which is only computed again once the file changes. Files larger
+/// than `limits.max_hashed_file_bytes` aren't hashed.
+pub async fn file_sha256(config: &Config, path: &Path, metadata: &Metadata) -> Result<Option<String>> {
+  let size = metadata.len();
+  if size > config.limits.as_ref().unwrap().max_hashed_file_bytes.unwrap() {
+    return Ok(None);
+  }
+
+  let modified = metadata.modified()?;
+  let hash = {
+    let mut hashes = FILE_HASHES.lock().unwrap();
+    match hashes.get(path) {
+      Some((s, m, hash)) if *s == size && *m == modified => hash.clone(),
+      _ => {
+        let file_path = path.to_path_buf();
+        let hash = task::spawn_blocking("hash file", move || hash_file(&file_path))
+          .map(|result| match result {
+            Ok(Ok(hash)) => Ok(hash),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(e.to_string()),
+          })
+          .boxed()
+          .shared();
+        hashes.insert(path.to_path_buf(), (size, modified, hash.clone()));
+        hash
+      }
+    }
+  };
+
+  match hash.clone().await {
+    Ok(hash) => Ok(Some(hash)),
+    Err(e) => {
+      // Try again next time, rather than remembering the failure.
+      let mut hashes = FILE_HASHES.lock().unwrap();
+      if hashes.get(path).is_some_and(|(_, _, cached)| cached.ptr_eq(&hash)) {
+        hashes.remove(path);
+      }
+      Err(anyhow!("failed to hash {}: {e}", path.display()))
+    }
+  }
+}
+
+/// Describe a file served from a directory mount, for clients to decide whether to fetch it.
+async fn file_metadata(state: &ServerState, req: &Request<Body>) -> Result<Response<Body>> {
+  let Some(request_path) = query_param(req, "path") else {
+    return Ok(text_response(StatusCode::BAD_REQUEST, "missing path"));
+  };
+
+  for path in mounted_files(&state.config, &request_path) {
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+      continue;
+    };
+    if !metadata.is_file() {
+      continue;
+    }
+
+    let size = metadata.len();
+    let modified = metadata.modified()?;
+    let hash = file_sha256(&state.config, &path, &metadata).await?;
+
+    return Ok(json_response(&json!({
+      "path": request_path,
+      "size": size,
+      "mtime": modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok(),
+      "sha256": hash,
+    })));
+  }
+  Ok(text_response(StatusCode::NOT_FOUND, "no such file"))
+}
+
+/// An identifier for a version of a file, which changes whenever it's modified.
+fn etag(metadata: &Metadata) -> String {
+  let modified = metadata
+    .modified()
+    .ok()
+    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    .unwrap_or_default();
+  format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos())
+}
+
+/// The part of a file requested with a Range header.
+enum ByteRange {
+  Full,
+  /// The first and last offsets requested, inclusive.
+  Partial(u64, u64),
+  Unsatisfiable,
+}
+
+/// Parse the Range header of a request for a file of `size` bytes.
+///
+/// Only single ranges are supported, and anything else gets the whole file, as does a range of a
+/// different version of the file than `etag` (according to If-Range).
+fn byte_range(req: &Request<Body>, etag: &str, size: u64) -> ByteRange {
+  let Some(range) = req.headers().get(RANGE).and_then(|h| h.to_str().ok()) else {
+    return ByteRange::Full;
+  };
+  if req.headers().get(IF_RANGE).is_some_and(|if_range| if_range != etag) {
+    return ByteRange::Full;
+  }
+  let Some((start, end)) = range
+    .strip_prefix("bytes=")
+    .filter(|spec| !spec.contains(','))
+    .and_then(|spec| spec.trim().split_once('-'))
+  else {
+    return ByteRange::Full;
+  };
+
+  let last = size.saturating_sub(1);
+  let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+    (Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+    (Ok(start), Err(_)) if end.is_empty() => (start, last),
+    (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (size.saturating_sub(suffix), last),
+    (Err(_), Ok(_)) if start.is_empty() => return ByteRange::Unsatisfiable,
+    _ => return ByteRange::Full,
+  };
+  if start >= size {
+    ByteRange::Unsatisfiable
+  } else {
+    ByteRange::Partial(start, end)
+  }
+}
+
+/// Serve a file from a directory mount as a download, which can be resumed with Range requests.
+async fn download(state: &ServerState, req: &Request<Body>, request_path: &str) -> Result<Response<Body>> {
+  for path in mounted_files(&state.config, request_path) {
+    let Ok(mut file) = tokio::fs::File::open(&path).await else {
+      continue;
+    };
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+      continue;
+    }
+
+    let size = metadata.len();
+    let etag = etag(&metadata);
+    let (status, start, len) = match byte_range(req, &etag, size) {
+      ByteRange::Full => (StatusCode::OK, 0, size),
+      ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+      ByteRange::Unsatisfiable => {
+        let mut response = text_response(StatusCode::RANGE_NOT_SATISFIABLE, "Range not satisfiable");
+        response
+          .headers_mut()
+          .insert(CONTENT_RANGE, HeaderValue::try_from(format!("bytes */{size}"))?);
+        return Ok(response);
+      }
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = Body::wrap_stream(ReaderStream::with_capacity(file.take(len), 64 * 1024));
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+
+    let filename = path
+      .file_name()
+      .map(|name| name.to_string_lossy().replace(['"', '\\'], "_"))
+      .unwrap_or_default();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(ETAG, HeaderValue::try_from(etag)?);
+    if let Ok(value) = HeaderValue::try_from(format!("attachment; filename=\"{filename}\"")) {
+      headers.insert(CONTENT_DISPOSITION, value);
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+      let range = format!("bytes {start}-{}/{size}", start + len - 1);
+      headers.insert(CONTENT_RANGE, HeaderValue::try_from(range)?);
+    }
+    return Ok(response);
+  }
+  Ok(text_response(StatusCode::NOT_FOUND, "no such file"))
+}
+
+/// Handle a request for /api/<path>.
+///
+/// These endpoints are read-only and public, unless authentication is required, in which case they
+/// need the admin token. Uploads always need the admin token, or a role allowed to upload.
+pub async fn handle_api(state: Arc<ServerState>, req: Request<Body>, peer: Peer, path: &str) -> Result<Response<Body>> {
+  if let Some(name) = path.strip_prefix("upload/") {
+    let Some(uploads) = state.config.uploads.as_ref() else {
+      return Ok(text_response(StatusCode::NOT_FOUND, "Uploads are disabled"));
+    };
+    if req.method() != Method::PUT {
+      return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+    }
+    if !state.auth.can_upload(&req) {
+      state.audit.record(
+        &peer,
+        AuditEvent::AuthFailure {
+          path: req.uri().path(),
+          reason: "not allowed to upload".into(),
+        },
+      );
+      return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    }
+    state.audit.record(
+      &peer,
+      AuditEvent::AdminAction {
+        action: "upload",
+        detail: name.to_string(),
+      },
+    );
+    return handle_upload(uploads, req, peer, name).await;
+  }
+
+  if state.auth.required() && !state.auth.is_admin(&req) {
+    warn!("{peer}: unauthorized API request for {path}");
+    return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+  }
+
+  let response = match (req.method(), path) {
+    (&Method::GET, "stats") => json_response(&state.stats.snapshot(&state.memory)),
+    (&Method::GET, "version") => json_response(&serde_json::to_value(version::BUILD)?),
+    (&Method::GET, "sockets") => list_sockets(&state, &req).await?,
+    (&Method::GET, "files") => file_metadata(&state, &req).await?,
+    (&Method::GET | &Method::HEAD, path) if path.starts_with("download/") => {
+      download(&state, &req, &path["download".len()..]).await?
+    }
+    _ => text_response(StatusCode::NOT_FOUND, format!("Unknown API endpoint: {path}")),
+  };
+  Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn range(range: Option<&str>, if_range: Option<&str>, size: u64) -> ByteRange {
+    let mut req = Request::get("/api/download/file");
+    if let Some(range) = range {
+      req = req.header(RANGE, range);
+    }
+    if let Some(if_range) = if_range {
+      req = req.header(IF_RANGE, if_range);
+    }
+    byte_range(&req.body(Body::empty()).unwrap(), "\"etag\"", size)
+  }
+
+  fn partial(range_header: &str, size: u64) -> Option<(u64, u64)> {
+    match range(Some(range_header), None, size) {
+      ByteRange::Partial(start, end) => Some((start, end)),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn parses_single_ranges() {
+    assert_eq!(partial("bytes=0-99", 1000), Some((0, 99)));
+    assert_eq!(partial("bytes=500-", 1000), Some((500, 999)));
+    assert_eq!(partial("bytes=-100", 1000), Some((900, 999)));
+    assert_eq!(partial("bytes=990-2000", 1000), Some((990, 999)));
+    assert_eq!(partial("bytes=-2000", 1000), Some((0, 999)));
+    assert_eq!(partial("bytes=999-999", 1000), Some((999, 999)));
+  }
+
+  #[test]
+  fn rejects_unsatisfiable_ranges() {
+    assert!(matches!(
+      range(Some("bytes=1000-"), None, 1000),
+      ByteRange::Unsatisfiable
+    ));
+    assert!(matches!(
+      range(Some("bytes=1000-1001"), None, 1000),
+      ByteRange::Unsatisfiable
+    ));
+    assert!(matches!(range(Some("bytes=-0"), None, 1000), ByteRange::Unsatisfiable));
+    assert!(matches!(range(Some("bytes=0-"), None, 0), ByteRange::Unsatisfiable));
+    assert!(matches!(range(Some("bytes=-5"), None, 0), ByteRange::Unsatisfiable));
+  }
+
+  #[test]
+  fn serves_the_whole_file_otherwise() {
+    assert!(matches!(range(None, None, 1000), ByteRange::Full));
+    assert!(matches!(range(Some("bytes=0-1,5-6"), None, 1000), ByteRange::Full));
+    assert!(matches!(range(Some("bytes=10-5"), None, 1000), ByteRange::Full));
+    assert!(matches!(range(Some("bytes=a-b"), None, 1000), ByteRange::Full));
+    assert!(matches!(range(Some("items=0-1"), None, 1000), ByteRange::Full));
+    assert!(matches!(range(Some("bytes=-"), None, 1000), ByteRange::Full));
+    assert!(matches!(
+      range(Some("bytes=18446744073709551616-"), None, 1000),
+      ByteRange::Full
+    ));
+  }
+
+  #[test]
+  fn checks_if_range() {
+    assert!(matches!(
+      range(Some("bytes=0-1"), Some("\"etag\""), 1000),
+      ByteRange::Partial(0, 1)
+    ));
+    assert!(matches!(
+      range(Some("bytes=0-1"), Some("\"stale\""), 1000),
+      ByteRange::Full
+    ));
+  }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{Context, Result};
+use zip::ZipArchive;
+
+/// Static content served out of a zip file.
+pub struct Archive {
+  /// Index of files in the archive, keyed by their path relative to the content root.
+  index: HashMap<String, usize>,
+  zip: Mutex<ZipArchive<BufReader<File>>>,
+}
+
+impl Archive {
+  /// Open a zip file. If it's an APK, only its assets are served.
+  pub fn open(path: &Path) -> Result<Archive> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut zip = ZipArchive::new(BufReader::new(file))?;
+    let root = if path.extension().map(|ext| ext == "apk").unwrap_or(false) {
+      "assets/"
+    } else {
+      ""
+    };
+
+    let mut index = HashMap::new();
+    for i in 0..zip.len() {
+      let entry = zip.by_index_raw(i)?;
+      if entry.is_dir() {
+        continue;
+      }
+      if let Some(name) = entry.name().strip_prefix(root) {
+        index.insert(name.to_string(), i);
+      }
+    }
+    info!("loaded {} files from {}", index.len(), path.display());
+
+    Ok(Archive {
+      index,
+      zip: Mutex::new(zip),
+    })
+  }
+
+  /// Read a file, given its path relative to the content root.
+  pub fn read(&self, path: &str) -> Option<Vec<u8>> {
+    let &i = self.index.get(path)?;
+    let mut zip = self.zip.lock().unwrap();
+    let mut entry = zip.by_index(i).ok()?;
+    let mut contents = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut contents).ok()?;
+    Some(contents)
+  }
+}
+
+/// The archives being served, keyed by path, which can be replaced while running.
+#[derive(Default)]
+pub struct Archives(RwLock<HashMap<PathBuf, Arc<Archive>>>);
+
+impl Archives {
+  pub fn get(&self, path: &Path) -> Option<Arc<Archive>> {
+    self.0.read().unwrap().get(path).cloned()
+  }
+
+  pub fn contains(&self, path: &Path) -> bool {
+    self.0.read().unwrap().contains_key(path)
+  }
+
+  pub fn insert(&self, path: PathBuf, archive: Archive) {
+    self.0.write().unwrap().insert(path, Arc::new(archive));
+  }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::lockout::Lockout;
+use crate::peer::{Host, Peer};
+use crate::store::StateStore;
+
+// How often the table is written to the state store, at most.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failed attempts from one source.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SourceSummary {
+  pub auth_failures: u64,
+  pub tls_failures: u64,
+
+  /// Milliseconds since the Unix epoch.
+  pub first_seen: u64,
+  pub last_seen: u64,
+
+  /// Why the most recent attempt failed.
+  pub last_reason: String,
+}
+
+/// Recent failed authentication and TLS attempts, summarized by source address, so that scanning
+/// and misconfigured clients stand out.
+pub struct FailedAttempts {
+  sources: Mutex<BTreeMap<String, SourceSummary>>,
+  max_sources: usize,
+
+  /// Where to persist the table, if it should survive restarts, and when it was last saved.
+  store: Option<(Arc<StateStore>, Mutex<Instant>)>,
+
+  /// Locks out sources that fail to authenticate too often, if configured.
+  lockout: Option<Lockout>,
+}
+
+/// The address that attempts from a peer are attributed to: its IP address (and zone), without the
+/// port, or the UID of a local peer.
+fn source(peer: &Peer) -> String {
+  match peer {
+    Peer::Inet(addr) => Host::of(addr).to_string(),
+    Peer::Local { uid, .. } => format!("local(uid={uid})"),
+  }
+}
+
+fn now_ms() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+impl FailedAttempts {
+  pub fn new(
+    config: &config::FailedAttempts,
+    store: Option<Arc<StateStore>>,
+    lockout: Option<Lockout>,
+  ) -> FailedAttempts {
+    let store = store.filter(|_| config.persist.unwrap());
+    let sources = store
+      .as_ref()
+      .map(|store| store.get(|state| state.failed_attempts.clone()))
+      .unwrap_or_default();
+    FailedAttempts {
+      sources: Mutex::new(sources),
+      max_sources: config.max_sources.unwrap(),
+      store: store.map(|store| (store, Mutex::new(Instant::now()))),
+      lockout,
+    }
+  }
+
+  pub fn record_auth_failure(&self, peer: &Peer, reason: &str) {
+    self.record(peer, reason, |summary| summary.auth_failures += 1);
+    if let Some(lockout) = &self.lockout {
+      lockout.record_failure(peer);
+    }
+  }
+
+  /// How much longer a peer is locked out for, if it is.
+  pub fn locked_out(&self, peer: &Peer) -> Option<Duration> {
+    self.lockout.as_ref()?.remaining(peer)
+  }
+
+  pub fn record_tls_failure(&self, peer: &Peer, reason: &str) {
+    self.record(peer, reason, |summary| summary.tls_failures += 1);
+  }
+
+  fn record(&self, peer: &Peer, reason: &str, count: impl FnOnce(&mut SourceSummary)) {
+    let now = now_ms();
+    let mut sources = self.sources.lock().unwrap();
+    let summary = sources.entry(source(peer)).or_insert_with(|| SourceSummary {
+      first_seen: now,
+      ..Default::default()
+    });
+    count(summary);
+    summary.last_seen = now;
+    summary.last_reason = reason.to_string();
+
+    // Forget whoever's been quiet the longest.
+    while sources.len() > self.max_sources {
+      let oldest = sources
+        .iter()
+        .min_by_key(|(_, summary)| summary.last_seen)
+        .map(|(source, _)| source.clone())
+        .unwrap();
+      sources.remove(&oldest);
+    }
+
+    if let Some((store, last_saved)) = &self.store {
+      let mut last_saved = last_saved.lock().unwrap();
+      if last_saved.elapsed() >= SAVE_INTERVAL {
+        *last_saved = Instant::now();
+        let sources = sources.clone();
+        if let Err(e) = store.update(|state| state.failed_attempts = sources) {
+          error!("failed to save failed attempts: {e:?}");
+        }
+      }
+    }
+  }
+
+  /// Every source, most recently seen first.
+  pub fn snapshot(&self) -> Vec<(String, SourceSummary)> {
+    let mut sources: Vec<_> = self
+      .sources
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(source, summary)| (source.clone(), summary.clone()))
+      .collect();
+    sources.sort_by_key(|(_, summary)| std::cmp::Reverse(summary.last_seen));
+    sources
+  }
+
+  pub fn clear(&self) {
+    self.sources.lock().unwrap().clear();
+    if let Some((store, _)) = &self.store {
+      if let Err(e) = store.update(|state| state.failed_attempts.clear()) {
+        error!("failed to save failed attempts: {e:?}");
+      }
+    }
+  }
+}
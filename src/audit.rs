@@ -0,0 +1,106 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::attempts::FailedAttempts;
+use crate::peer::Peer;
+use crate::webhook::Webhook;
+
+/// A security-relevant event, recorded separately from debug logging.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+  AuthSuccess {
+    path: &'a str,
+    identity: String,
+  },
+  AuthFailure {
+    path: &'a str,
+    reason: String,
+  },
+  AdminAction {
+    action: &'a str,
+    detail: String,
+  },
+  SessionOpened {
+    path: &'a str,
+    identity: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+  },
+  SessionClosed {
+    path: &'a str,
+    identity: String,
+  },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+  /// Milliseconds since the Unix epoch.
+  time: u128,
+  peer: String,
+  #[serde(flatten)]
+  event: AuditEvent<'a>,
+}
+
+/// Append-only audit log, written as JSON lines to a file (if configured) and the `audit` log target.
+pub struct AuditLog {
+  file: Option<Mutex<File>>,
+
+  /// Where authentication failures are also summarized.
+  failed_attempts: Arc<FailedAttempts>,
+
+  /// Where events are also sent, if configured.
+  webhook: Option<Arc<Webhook>>,
+}
+
+impl AuditLog {
+  pub fn new(
+    path: Option<&Path>,
+    failed_attempts: Arc<FailedAttempts>,
+    webhook: Option<Arc<Webhook>>,
+  ) -> Result<AuditLog> {
+    let file = match path {
+      Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+      None => None,
+    };
+    Ok(AuditLog {
+      file,
+      failed_attempts,
+      webhook,
+    })
+  }
+
+  pub fn record(&self, peer: &Peer, event: AuditEvent) {
+    if let AuditEvent::AuthFailure { reason, .. } = &event {
+      self.failed_attempts.record_auth_failure(peer, reason);
+    }
+
+    let time = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis())
+      .unwrap_or(0);
+    let record = AuditRecord {
+      time,
+      peer: peer.to_string(),
+      event,
+    };
+    let line = serde_json::to_string(&record).unwrap();
+    info!(target: "audit", "{line}");
+    if let Some(webhook) = &self.webhook {
+      webhook.notify(serde_json::to_value(&record).unwrap());
+    }
+
+    if let Some(file) = &self.file {
+      let mut file = file.lock().unwrap();
+      if let Err(e) = writeln!(file, "{line}") {
+        error!("failed to write audit log: {e}");
+      }
+    }
+  }
+}
@@ -0,0 +1,733 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_char, CStr, CString};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use base64::{
+  engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+  Engine,
+};
+use hyper::{header::AUTHORIZATION, Body, Request};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::config;
+use crate::ffi::wardenclyffe_authenticate;
+use crate::peer::{Peer, PeerInfo};
+use crate::server::glob_match;
+use crate::store::StateStore;
+use crate::task;
+use crate::tls::TlsInfo;
+
+// The role of tokens authenticated by the platform.
+const PLATFORM_ROLE: &str = "platform";
+
+const DEFAULT_PLATFORM_TTL_MS: u64 = 5 * 60 * 1000;
+
+// The authenticator of the currently running server, used to mint grants from outside of it.
+static AUTHENTICATOR: RwLock<Option<Arc<Authenticator>>> = RwLock::new(None);
+
+/// A signed, expiring permission to access a single socket path.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Grant {
+  pub path: String,
+  /// Expiration time, in seconds since the Unix epoch.
+  pub expires: u64,
+  pub read_only: bool,
+}
+
+/// What a token with a role may do at the path it's being used for.
+#[derive(Debug)]
+pub struct RoleAccess {
+  pub role: String,
+  pub read: bool,
+  pub write: bool,
+  pub upload: bool,
+
+  /// All of the role's scopes, as configured, for backends.
+  pub scopes: String,
+}
+
+#[derive(Debug)]
+pub enum Access {
+  Admin,
+  Role(RoleAccess),
+  Grant(Grant),
+  Anonymous,
+}
+
+impl Access {
+  pub fn read_only(&self) -> bool {
+    matches!(
+      self,
+      Access::Grant(Grant { read_only: true, .. }) | Access::Role(RoleAccess { write: false, .. })
+    )
+  }
+
+  pub fn write_only(&self) -> bool {
+    matches!(self, Access::Role(RoleAccess { read: false, .. }))
+  }
+
+  /// Whether the client may upload files, which only the admin and roles allowed to may.
+  pub fn can_upload(&self) -> bool {
+    matches!(self, Access::Admin | Access::Role(RoleAccess { upload: true, .. }))
+  }
+
+  /// A short description of who is accessing, for logging.
+  pub fn identity(&self) -> String {
+    match self {
+      Access::Admin => "admin".into(),
+      Access::Role(access) => format!("role({})", access.role),
+      Access::Grant(grant) => format!("grant({}, expires = {})", grant.path, grant.expires),
+      Access::Anonymous => "anonymous".into(),
+    }
+  }
+
+  /// The scopes that the client has, space-separated, for backends to make their own decisions
+  /// with. Anonymous clients have none.
+  pub fn scopes(&self) -> Option<String> {
+    match self {
+      Access::Admin => Some("admin".into()),
+      Access::Role(access) => Some(access.scopes.clone()),
+      Access::Grant(grant) if grant.read_only => Some(format!("read:{}", grant.path)),
+      Access::Grant(grant) => Some(format!("read:{0} write:{0}", grant.path)),
+      Access::Anonymous => None,
+    }
+  }
+}
+
+/// Something a role allows.
+enum Scope {
+  Admin,
+  Upload,
+  Read(String),
+  Write(String),
+}
+
+impl Scope {
+  fn parse(scope: &str) -> Result<Scope> {
+    match scope {
+      "admin" => return Ok(Scope::Admin),
+      "upload" => return Ok(Scope::Upload),
+      _ => {}
+    }
+    match scope.split_once(':') {
+      Some(("read", glob)) => Ok(Scope::Read(glob.into())),
+      Some(("write", glob)) => Ok(Scope::Write(glob.into())),
+      _ => bail!("invalid scope '{scope}'"),
+    }
+  }
+}
+
+struct Role {
+  name: String,
+  scopes: Vec<Scope>,
+  description: String,
+}
+
+impl Role {
+  fn is_admin(&self) -> bool {
+    self.scopes.iter().any(|scope| matches!(scope, Scope::Admin))
+  }
+
+  fn can_upload(&self) -> bool {
+    self.is_admin() || self.scopes.iter().any(|scope| matches!(scope, Scope::Upload))
+  }
+
+  fn access(&self, path: &str) -> RoleAccess {
+    let allows = |f: fn(&Scope) -> Option<&String>| {
+      self.is_admin() || self.scopes.iter().filter_map(f).any(|glob| glob_match(glob, path))
+    };
+    RoleAccess {
+      role: self.name.clone(),
+      read: allows(|scope| match scope {
+        Scope::Read(glob) => Some(glob),
+        _ => None,
+      }),
+      write: allows(|scope| match scope {
+        Scope::Write(glob) => Some(glob),
+        _ => None,
+      }),
+      upload: self.can_upload(),
+      scopes: self.description.clone(),
+    }
+  }
+}
+
+/// The keys that grants are signed with.
+struct SigningKeys {
+  current: hmac::Key,
+
+  /// The key before the last rotation, and until when grants it signed are still accepted.
+  previous: Option<(hmac::Key, u64)>,
+}
+
+pub struct Authenticator {
+  admin_token: Option<String>,
+  required: bool,
+  keys: RwLock<SigningKeys>,
+
+  /// Where the signing key and revoked tokens are kept, if anywhere.
+  store: Option<Arc<StateStore>>,
+
+  /// Whether the signing key comes from the configuration, in which case a rotated key only lasts
+  /// until the server restarts.
+  configured_key: bool,
+
+  /// Revoked tokens, as in `PersistentState::revoked_tokens`.
+  revoked: RwLock<BTreeMap<String, u64>>,
+
+  /// Signalled whenever a token is revoked, so that sessions using it can be closed.
+  revocations: watch::Sender<()>,
+
+  /// Tokens with roles, and their roles.
+  tokens: Vec<(String, Arc<Role>)>,
+
+  /// How long the platform's word on a token is trusted, if it's asked at all.
+  platform_ttl: Option<Duration>,
+
+  /// Tokens the platform has authenticated, by digest, with the roles it gave them and when they
+  /// need to be authenticated again.
+  platform_tokens: Mutex<HashMap<String, (Arc<Role>, Instant)>>,
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("system time before Unix epoch")
+    .as_secs()
+}
+
+/// Returns the value of a query parameter in the request's URI, if present.
+pub fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+  let query = req.uri().query()?;
+  form_urlencoded::parse(query.as_bytes())
+    .find(|(key, _)| key == name)
+    .map(|(_, value)| value.into_owned())
+}
+
+/// The token a client was authorized with, if any.
+pub fn credential(req: &Request<Body>, access: &Access) -> Option<String> {
+  match access {
+    Access::Admin | Access::Role(_) => bearer_token(req),
+    Access::Grant(_) => query_param(req, "grant"),
+    Access::Anonymous => None,
+  }
+}
+
+/// The key a revoked token is remembered by.
+fn token_digest(token: &str) -> String {
+  let digest = digest::digest(&digest::SHA256, token.as_bytes());
+  digest.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn random_key() -> Result<[u8; 32]> {
+  let mut key = [0u8; 32];
+  SystemRandom::new()
+    .fill(&mut key)
+    .map_err(|_| anyhow!("failed to generate signing key"))?;
+  Ok(key)
+}
+
+/// Returns the bearer token presented in the Authorization header or `token` query parameter.
+pub fn bearer_token(req: &Request<Body>) -> Option<String> {
+  let header = req
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|h| h.to_str().ok())
+    .and_then(|h| h.strip_prefix("Bearer "))
+    .map(str::to_string);
+
+  // Browsers can't set headers on WebSocket connections, so also accept the token as a parameter.
+  header.or_else(|| query_param(req, "token"))
+}
+
+impl Authenticator {
+  pub fn new(config: &config::Auth, store: Option<Arc<StateStore>>) -> Result<Authenticator> {
+    let key = match (&config.signing_key, &store) {
+      (Some(key), _) => hmac::Key::new(hmac::HMAC_SHA256, &STANDARD.decode(key)?),
+      (None, Some(store)) => {
+        let key = match store.get(|state| state.signing_key.clone()) {
+          Some(key) => STANDARD.decode(key)?,
+          None => {
+            let key = random_key()?;
+            store.update(|state| state.signing_key = Some(STANDARD.encode(key)))?;
+            key.to_vec()
+          }
+        };
+        hmac::Key::new(hmac::HMAC_SHA256, &key)
+      }
+      (None, None) => hmac::Key::new(hmac::HMAC_SHA256, &random_key()?),
+    };
+    let revoked = store
+      .as_ref()
+      .map(|store| store.get(|state| state.revoked_tokens.clone()))
+      .unwrap_or_default();
+
+    let mut roles = BTreeMap::new();
+    for (name, scopes) in config.roles.iter().flatten() {
+      let role = Role {
+        name: name.clone(),
+        scopes: scopes.iter().map(|scope| Scope::parse(scope)).collect::<Result<_>>()?,
+        description: scopes.join(" "),
+      };
+      roles.insert(name.as_str(), Arc::new(role));
+    }
+    let mut tokens = Vec::new();
+    for token in config.tokens.iter().flatten() {
+      let Some(role) = roles.get(token.role.as_str()) else {
+        bail!("token has unknown role '{}'", token.role);
+      };
+      tokens.push((token.token.clone(), role.clone()));
+    }
+
+    Ok(Authenticator {
+      admin_token: config.admin_token.clone(),
+      required: config.required.unwrap_or(false),
+      keys: RwLock::new(SigningKeys {
+        current: key,
+        previous: None,
+      }),
+      store,
+      configured_key: config.signing_key.is_some(),
+      revoked: RwLock::new(revoked),
+      revocations: watch::channel(()).0,
+      tokens,
+      platform_ttl: config
+        .platform
+        .unwrap_or(false)
+        .then(|| Duration::from_millis(config.platform_ttl_ms.unwrap_or(DEFAULT_PLATFORM_TTL_MS))),
+      platform_tokens: Default::default(),
+    })
+  }
+
+  /// Make this the authenticator used by `mint_grant`.
+  pub fn install(self: &Arc<Self>) {
+    *AUTHENTICATOR.write().unwrap() = Some(self.clone());
+  }
+
+  /// Whether clients must authenticate to use the server.
+  pub fn required(&self) -> bool {
+    self.required
+  }
+
+  pub fn is_admin(&self, req: &Request<Body>) -> bool {
+    let Some(token) = bearer_token(req) else {
+      return false;
+    };
+    let is_admin_token = self.admin_token.as_ref().is_some_and(|expected| {
+      ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok()
+    });
+    (is_admin_token && !self.is_revoked(&token)) || self.role(&token).is_some_and(|role| role.is_admin())
+  }
+
+  /// Whether a request may upload files: with the admin token, or a role allowed to.
+  pub fn can_upload(&self, req: &Request<Body>) -> bool {
+    self.is_admin(req)
+      || bearer_token(req)
+        .and_then(|token| self.role(&token))
+        .is_some_and(|role| role.can_upload())
+  }
+
+  /// The role of a bearer token, if it has one and hasn't been revoked.
+  fn role(&self, token: &str) -> Option<Arc<Role>> {
+    if self.is_revoked(token) {
+      return None;
+    }
+    let configured = self
+      .tokens
+      .iter()
+      .find(|(expected, _)| ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok())
+      .map(|(_, role)| role.clone());
+    configured.or_else(|| {
+      let platform_tokens = self.platform_tokens.lock().unwrap();
+      let (role, expires) = platform_tokens.get(&token_digest(token))?;
+      (*expires > Instant::now()).then(|| role.clone())
+    })
+  }
+
+  /// Ask the platform to authenticate a bearer token that isn't otherwise recognized, if it's
+  /// configured to, so that the role it gives the token applies to the request.
+  pub async fn authenticate_platform(&self, req: &Request<Body>, peer: Peer) {
+    let Some(ttl) = self.platform_ttl else {
+      return;
+    };
+    let Some(token) = bearer_token(req) else {
+      return;
+    };
+    let is_admin_token = self.admin_token.as_ref().is_some_and(|expected| {
+      ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok()
+    });
+    if is_admin_token || self.is_revoked(&token) || self.role(&token).is_some() {
+      return;
+    }
+
+    let Ok(credentials) = CString::new(token.as_str()) else {
+      return;
+    };
+    let tls = req.extensions().get::<TlsInfo>().cloned();
+    let scopes = task::spawn_blocking("wardenclyffe_authenticate", move || {
+      let peer_info = PeerInfo::new(&peer, tls.as_ref(), None);
+      let mut scopes = [0 as c_char; 1024];
+      let authenticated = unsafe {
+        wardenclyffe_authenticate(
+          credentials.as_ptr(),
+          &peer_info.as_ffi(),
+          scopes.as_mut_ptr(),
+          scopes.len(),
+        )
+      };
+      authenticated.then(|| {
+        unsafe { CStr::from_ptr(scopes.as_ptr()) }
+          .to_string_lossy()
+          .into_owned()
+      })
+    })
+    .await
+    .expect("failed to join");
+    let Some(scopes) = scopes else {
+      return;
+    };
+
+    let parsed: Result<Vec<Scope>> = scopes.split_whitespace().map(Scope::parse).collect();
+    let role = match parsed {
+      Ok(parsed) => Role {
+        name: PLATFORM_ROLE.into(),
+        scopes: parsed,
+        description: scopes,
+      },
+      Err(e) => {
+        error!("{peer}: platform granted invalid scopes: {e}");
+        return;
+      }
+    };
+    debug!(
+      "{peer}: platform authenticated token with scopes '{}'",
+      role.description
+    );
+
+    let now = Instant::now();
+    let mut platform_tokens = self.platform_tokens.lock().unwrap();
+    platform_tokens.retain(|_, (_, expires)| *expires > now);
+    platform_tokens.insert(token_digest(&token), (Arc::new(role), now + ttl));
+  }
+
+  pub fn mint(&self, path: &str, ttl: Duration, read_only: bool) -> String {
+    let grant = Grant {
+      path: path.to_string(),
+      expires: now().saturating_add(ttl.as_secs()),
+      read_only,
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&grant).unwrap());
+    let tag = hmac::sign(&self.keys.read().unwrap().current, payload.as_bytes());
+    format!("{payload}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+  }
+
+  pub fn verify(&self, token: &str) -> Result<Grant> {
+    let Some((payload, tag)) = token.split_once('.') else {
+      bail!("malformed grant");
+    };
+
+    let tag = URL_SAFE_NO_PAD.decode(tag)?;
+    let keys = self.keys.read().unwrap();
+    let previous = keys
+      .previous
+      .as_ref()
+      .filter(|(_, until)| now() < *until)
+      .map(|(key, _)| key);
+    std::iter::once(&keys.current)
+      .chain(previous)
+      .find(|key| hmac::verify(key, payload.as_bytes(), &tag).is_ok())
+      .ok_or_else(|| anyhow!("invalid grant signature"))?;
+
+    let grant: Grant = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    if grant.expires < now() {
+      bail!("grant expired");
+    }
+    if self.is_revoked(token) {
+      bail!("grant revoked");
+    }
+    Ok(grant)
+  }
+
+  pub fn is_revoked(&self, token: &str) -> bool {
+    self.revoked.read().unwrap().contains_key(&token_digest(token))
+  }
+
+  /// Revoke a token (a grant, or a bearer token), and close the sessions using it.
+  pub fn revoke(&self, token: &str) -> Result<()> {
+    // Grants only need to be remembered until they'd have expired anyway.
+    let expires = token
+      .split_once('.')
+      .and_then(|(payload, _)| URL_SAFE_NO_PAD.decode(payload).ok())
+      .and_then(|payload| serde_json::from_slice::<Grant>(&payload).ok())
+      .map(|grant| grant.expires)
+      .unwrap_or(u64::MAX);
+
+    let revoked = {
+      let mut revoked = self.revoked.write().unwrap();
+      let now = now();
+      revoked.retain(|_, expires| *expires >= now);
+      revoked.insert(token_digest(token), expires);
+      revoked.clone()
+    };
+    if let Some(store) = &self.store {
+      store.update(|state| state.revoked_tokens = revoked)?;
+    }
+    self.revocations.send_replace(());
+    Ok(())
+  }
+
+  /// Wait until `token` is revoked, or forever if there's no token.
+  pub async fn revoked(&self, token: Option<&str>) {
+    let mut revocations = self.revocations.subscribe();
+    loop {
+      if token.is_some_and(|token| self.is_revoked(token)) {
+        return;
+      }
+      if revocations.changed().await.is_err() {
+        return std::future::pending().await;
+      }
+    }
+  }
+
+  /// Sign grants with a new key. Grants signed with the old one are still accepted for `grace`.
+  ///
+  /// Returns whether the new key was persisted, which it isn't if the key is configured rather than
+  /// generated, or there's nowhere to keep it.
+  pub fn rotate_key(&self, grace: Duration) -> Result<bool> {
+    let key = random_key()?;
+    let persisted = match (&self.store, self.configured_key) {
+      (Some(store), false) => {
+        store.update(|state| state.signing_key = Some(STANDARD.encode(key)))?;
+        true
+      }
+      _ => false,
+    };
+
+    let mut keys = self.keys.write().unwrap();
+    let previous = std::mem::replace(&mut keys.current, hmac::Key::new(hmac::HMAC_SHA256, &key));
+    keys.previous = (!grace.is_zero()).then(|| (previous, now().saturating_add(grace.as_secs())));
+    Ok(persisted)
+  }
+
+  /// Determine what a request is allowed to access at `path`, which may differ from the request's
+  /// own path (e.g. for long polling).
+  pub fn authorize(&self, req: &Request<Body>, path: &str) -> Result<Access> {
+    if let Some(role) = bearer_token(req).as_deref().and_then(|token| self.role(token)) {
+      let access = role.access(path);
+      if !access.read && !access.write {
+        bail!("role '{}' has no access to '{path}'", role.name);
+      }
+      return Ok(Access::Role(access));
+    }
+
+    if self.is_admin(req) {
+      return Ok(Access::Admin);
+    }
+
+    if let Some(token) = query_param(req, "grant") {
+      let grant = self.verify(&token)?;
+      if grant.path != path {
+        bail!("grant is for '{}'", grant.path);
+      }
+      return Ok(Access::Grant(grant));
+    }
+
+    if self.required {
+      bail!("authentication required");
+    }
+    Ok(Access::Anonymous)
+  }
+}
+
+/// Mint a relative URL granting access to `path` for `ttl`, signed by the running server.
+pub fn mint_grant_url(path: &str, ttl: Duration, read_only: bool) -> Result<String> {
+  let authenticator = AUTHENTICATOR.read().unwrap();
+  let Some(authenticator) = authenticator.as_ref() else {
+    bail!("server not running");
+  };
+
+  let grant = authenticator.mint(path, ttl, read_only);
+  Ok(format!("{path}?grant={grant}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn authenticator(auth: serde_json::Value) -> Authenticator {
+    Authenticator::new(&serde_json::from_value(auth).unwrap(), None).unwrap()
+  }
+
+  fn request(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+  }
+
+  /// Sign a grant the way `mint` does, with whatever expiry.
+  fn sign(auth: &Authenticator, grant: &Grant) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(grant).unwrap());
+    let tag = hmac::sign(&auth.keys.read().unwrap().current, payload.as_bytes());
+    format!("{payload}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+  }
+
+  #[test]
+  fn verifies_minted_grants() {
+    let auth = authenticator(json!({}));
+    let grant = auth
+      .verify(&auth.mint("/socket", Duration::from_secs(60), true))
+      .unwrap();
+    assert_eq!(grant.path, "/socket");
+    assert!(grant.read_only);
+    assert!(grant.expires >= now() + 59);
+  }
+
+  #[test]
+  fn rejects_forged_grants() {
+    let auth = authenticator(json!({}));
+    let grant = auth.mint("/socket", Duration::from_secs(60), true);
+    let (payload, tag) = grant.split_once('.').unwrap();
+
+    // Another server's grants, and grants whose payload has been changed, aren't accepted.
+    let other = authenticator(json!({})).mint("/socket", Duration::from_secs(60), true);
+    assert!(auth.verify(&other).is_err());
+    let writable = Grant {
+      path: "/socket".into(),
+      expires: now() + 60,
+      read_only: false,
+    };
+    let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&writable).unwrap());
+    assert!(auth.verify(&format!("{forged}.{tag}")).is_err());
+
+    assert!(auth.verify(payload).is_err());
+    assert!(auth.verify(&format!("{payload}.")).is_err());
+    assert!(auth.verify(&format!("{payload}.!!!")).is_err());
+    assert!(auth.verify("").is_err());
+  }
+
+  #[test]
+  fn rejects_expired_grants() {
+    let auth = authenticator(json!({}));
+    let expired = Grant {
+      path: "/socket".into(),
+      expires: now() - 1,
+      read_only: true,
+    };
+    assert!(auth.verify(&sign(&auth, &expired)).is_err());
+  }
+
+  #[test]
+  fn long_grants_saturate() {
+    let auth = authenticator(json!({}));
+    let grant = auth
+      .verify(&auth.mint("/socket", Duration::from_secs(u64::MAX), true))
+      .unwrap();
+    assert_eq!(grant.expires, u64::MAX);
+  }
+
+  #[test]
+  fn grants_are_for_one_path() {
+    let auth = authenticator(json!({ "required": true }));
+    let grant = auth.mint("/socket", Duration::from_secs(60), true);
+    assert!(auth
+      .authorize(&request(&format!("/socket?grant={grant}")), "/socket")
+      .is_ok());
+    assert!(auth
+      .authorize(&request(&format!("/other?grant={grant}")), "/other")
+      .is_err());
+    assert!(auth
+      .authorize(&request(&format!("/socket/sub?grant={grant}")), "/socket/sub")
+      .is_err());
+    assert!(auth.authorize(&request("/socket"), "/socket").is_err());
+  }
+
+  #[test]
+  fn revoked_tokens_are_rejected() {
+    let auth = authenticator(json!({
+      "admin_token": "admin",
+      "roles": { "reader": ["read:/socket"] },
+      "tokens": [{ "token": "reader", "role": "reader" }],
+    }));
+    let grant = auth.mint("/socket", Duration::from_secs(60), true);
+    let other = auth.mint("/socket", Duration::from_secs(60), false);
+    auth.revoke(&grant).unwrap();
+    assert!(auth.verify(&grant).is_err());
+    assert!(auth.verify(&other).is_ok());
+
+    assert!(auth.is_admin(&request("/?token=admin")));
+    auth.revoke("admin").unwrap();
+    assert!(!auth.is_admin(&request("/?token=admin")));
+
+    assert!(auth.authorize(&request("/socket?token=reader"), "/socket").is_ok());
+    auth.revoke("reader").unwrap();
+    assert!(matches!(
+      auth.authorize(&request("/socket?token=reader"), "/socket"),
+      Ok(Access::Anonymous)
+    ));
+  }
+
+  #[test]
+  fn rotated_keys_are_accepted_for_their_grace() {
+    let auth = authenticator(json!({}));
+    let before = auth.mint("/socket", Duration::from_secs(60), true);
+    auth.rotate_key(Duration::from_secs(60)).unwrap();
+    let after = auth.mint("/socket", Duration::from_secs(60), true);
+    assert!(auth.verify(&before).is_ok());
+    assert!(auth.verify(&after).is_ok());
+
+    auth.rotate_key(Duration::ZERO).unwrap();
+    assert!(auth.verify(&before).is_err());
+    assert!(auth.verify(&after).is_err());
+    assert!(auth
+      .verify(&auth.mint("/socket", Duration::from_secs(60), true))
+      .is_ok());
+  }
+
+  #[test]
+  fn long_rotation_grace_saturates() {
+    let auth = authenticator(json!({}));
+    let before = auth.mint("/socket", Duration::from_secs(60), true);
+    auth.rotate_key(Duration::from_secs(u64::MAX)).unwrap();
+    assert_eq!(auth.keys.read().unwrap().previous.as_ref().unwrap().1, u64::MAX);
+    assert!(auth.verify(&before).is_ok());
+  }
+
+  #[test]
+  fn uploads_need_admin_or_upload_scope() {
+    let auth = authenticator(json!({
+      "admin_token": "admin",
+      "roles": { "writer": ["write:/socket"], "uploader": ["read:/socket", "upload"] },
+      "tokens": [{ "token": "writer", "role": "writer" }, { "token": "uploader", "role": "uploader" }],
+    }));
+
+    // Unauthenticated V2 sessions are anonymous, and can't upload.
+    let req = request("/socket");
+    let access = auth.authorize(&req, "/socket").unwrap();
+    assert!(matches!(access, Access::Anonymous));
+    assert!(!access.can_upload());
+    assert!(!auth.can_upload(&req));
+
+    let grant = auth.mint("/socket", Duration::from_secs(60), false);
+    let access = auth
+      .authorize(&request(&format!("/socket?grant={grant}")), "/socket")
+      .unwrap();
+    assert!(!access.read_only());
+    assert!(!access.can_upload());
+
+    let req = request("/socket?token=writer");
+    assert!(!auth.authorize(&req, "/socket").unwrap().can_upload());
+    assert!(!auth.can_upload(&req));
+
+    let req = request("/socket?token=uploader");
+    assert!(auth.authorize(&req, "/socket").unwrap().can_upload());
+    assert!(auth.can_upload(&req));
+
+    let req = request("/socket?token=admin");
+    assert!(auth.authorize(&req, "/socket").unwrap().can_upload());
+    assert!(auth.can_upload(&req));
+  }
+}
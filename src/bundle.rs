@@ -0,0 +1,103 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::archive::{Archive, Archives};
+use crate::config::{HttpContent, UiBundle};
+use crate::store::with_suffix;
+
+// The installer of the currently running server, used to install bundles from outside of it.
+static INSTALLER: RwLock<Option<Arc<BundleInstaller>>> = RwLock::new(None);
+
+/// Replaces the UI archive with signed bundles.
+pub struct BundleInstaller {
+  public_key: Vec<u8>,
+  path: PathBuf,
+  archives: Arc<Archives>,
+
+  // Serializes installs, so that their file renames don't interleave.
+  lock: Mutex<()>,
+}
+
+impl BundleInstaller {
+  pub fn new(config: &UiBundle, content: &HttpContent, archives: Arc<Archives>) -> Result<BundleInstaller> {
+    let HttpContent::Archive(path) = content else {
+      bail!("UI bundle updates require http_content to be an Archive");
+    };
+
+    Ok(BundleInstaller {
+      public_key: STANDARD.decode(&config.public_key)?,
+      path: path.clone(),
+      archives,
+      lock: Mutex::new(()),
+    })
+  }
+
+  pub fn install(self: &Arc<Self>) {
+    *INSTALLER.write().unwrap() = Some(self.clone());
+  }
+
+  /// Verify a bundle and, if it's valid, atomically replace the served archive with it.
+  ///
+  /// The previous archive is kept, to be restored with `rollback`.
+  pub fn install_bundle(&self, bundle: &[u8], signature: &[u8]) -> Result<()> {
+    UnparsedPublicKey::new(&ED25519, &self.public_key)
+      .verify(bundle, signature)
+      .map_err(|_| anyhow!("invalid signature"))?;
+
+    let _guard = self.lock.lock().unwrap();
+    let staging = with_suffix(&self.path, ".new");
+    let mut file = File::create(&staging)?;
+    file.write_all(bundle)?;
+    file.sync_all()?;
+
+    // Make sure the bundle is actually usable before swapping it in.
+    let archive = match Archive::open(&staging) {
+      Ok(archive) => archive,
+      Err(e) => {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+      }
+    };
+
+    let previous = with_suffix(&self.path, ".prev");
+    fs::rename(&self.path, &previous)?;
+    if let Err(e) = fs::rename(&staging, &self.path) {
+      fs::rename(&previous, &self.path)?;
+      return Err(e.into());
+    }
+
+    self.archives.insert(self.path.clone(), archive);
+    info!("installed UI bundle ({} bytes)", bundle.len());
+    Ok(())
+  }
+
+  /// Restore the archive replaced by the last installed bundle.
+  pub fn rollback(&self) -> Result<()> {
+    let _guard = self.lock.lock().unwrap();
+    let previous = with_suffix(&self.path, ".prev");
+    if !previous.exists() {
+      bail!("no previous UI bundle");
+    }
+
+    let archive = Archive::open(&previous)?;
+    fs::rename(&previous, &self.path)?;
+    self.archives.insert(self.path.clone(), archive);
+    info!("rolled back UI bundle");
+    Ok(())
+  }
+}
+
+/// Install a UI bundle into the running server.
+pub fn install_ui_bundle(bundle: &[u8], signature: &[u8]) -> Result<()> {
+  let installer = INSTALLER.read().unwrap();
+  let Some(installer) = installer.as_ref() else {
+    bail!("server not running, or UI bundle updates aren't enabled");
+  };
+  installer.install_bundle(bundle, signature)
+}
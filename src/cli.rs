@@ -1,11 +1,11 @@
 use std::ffi::{c_char, CStr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 
 use crate::{
   config::{Config, TLS},
-  Server,
+  doctor, include, profile, Server,
 };
 
 #[derive(Parser, Debug)]
@@ -15,20 +15,40 @@ struct Args {
   #[arg(short = 'C', default_value = "/data/local/tmp/wardenclyffe/config.json")]
   config: PathBuf,
 
+  /// Profile of the configuration file to use, instead of the one selected by the
+  /// persist.wardenclyffe.profile property, or `default`.
+  #[arg(long)]
+  profile: Option<String>,
+
   #[arg(short = 'p')]
   port: Option<u16>,
 
+  /// Address to listen on, e.g. `fe80::1%usb0` for a link-local address on a debug link.
+  #[arg(long)]
+  listen_address: Option<String>,
+
   #[arg(short = 'c')]
   cert: Option<PathBuf>,
 
   #[arg(short = 'k')]
   private_key: Option<PathBuf>,
 
+  /// PEM file, or directory of them, of the CAs that must have issued clients' certificates.
+  /// Requires `-c` and `-k`.
+  #[arg(long)]
+  client_ca: Option<PathBuf>,
+
   #[arg(long, default_value_t = false)]
   dump_config: bool,
+
+  /// Check that the server could start, print a JSON report of the checks, and exit.
+  #[arg(long, default_value_t = false)]
+  doctor: bool,
 }
 
-#[export_name = "main"]
+// libtest provides its own main.
+#[cfg_attr(not(test), export_name = "main")]
+#[cfg_attr(test, allow(dead_code))]
 extern "C" fn wardenclyffe_main(argc: i32, argv: *mut *mut c_char) -> i32 {
   let args = unsafe {
     let slice = std::slice::from_raw_parts(argv, argc as usize);
@@ -39,7 +59,14 @@ extern "C" fn wardenclyffe_main(argc: i32, argv: *mut *mut c_char) -> i32 {
   let args = Args::parse_from(args);
 
   let mut config = match std::fs::read(&args.config) {
-    Ok(config_file) => serde_json::from_slice(&config_file).expect("failed to parse config file"),
+    Ok(config_file) => {
+      let config = serde_json::from_slice(&config_file).expect("failed to parse config file");
+      let base_dir = args.config.parent().unwrap_or(Path::new("."));
+      let config = include::resolve(config, base_dir).expect("failed to include config files");
+      let profile = args.profile.clone().or_else(profile::from_property);
+      let config = profile::resolve(config, profile.as_deref()).expect("failed to select profile");
+      Config::from_value(config).expect("failed to parse config file")
+    }
 
     Err(err) => {
       eprintln!("failed to open '{:?}', falling back to defaults: {err}", args.config);
@@ -48,16 +75,29 @@ extern "C" fn wardenclyffe_main(argc: i32, argv: *mut *mut c_char) -> i32 {
   };
 
   config.port = args.port.or(config.port);
+  config.listen_address = args.listen_address.or(config.listen_address);
 
-  match (args.cert, args.private_key) {
-    (Some(c), Some(k)) => {
+  match (args.cert, args.private_key, args.client_ca) {
+    (Some(c), Some(k), None) => {
       config.tls = Some(TLS::Certificate {
         cert_path: c,
         private_key_path: k,
       })
     }
 
-    (None, None) => {}
+    (Some(c), Some(k), Some(ca)) => {
+      config.tls = Some(TLS::CertificateWithClientAuth {
+        cert_path: c,
+        private_key_path: k,
+        client_ca_path: ca,
+      })
+    }
+
+    (None, None, None) => {}
+
+    (None, None, Some(_)) => {
+      panic!("--client_ca must be specified with --cert and --private_key");
+    }
 
     _ => {
       panic!("--cert must be specified with --private_key");
@@ -68,8 +108,41 @@ extern "C" fn wardenclyffe_main(argc: i32, argv: *mut *mut c_char) -> i32 {
   if args.dump_config {
     println!("{}", serde_json::to_string_pretty(&config).unwrap());
   }
+  if args.doctor {
+    return doctor::run(&config);
+  }
 
   let server = Server::from_config(config);
   server.run().expect("failed to serve");
   0
 }
+
+/// Run the server with a JSON configuration, instead of parsing arguments and a configuration file.
+///
+/// Returns nonzero if the configuration is invalid or the server fails.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_main_with_config(json: *const c_char) -> i32 {
+  let json = match CStr::from_ptr(json).to_str() {
+    Ok(json) => json,
+    Err(err) => {
+      eprintln!("configuration not UTF-8: {err}");
+      return 1;
+    }
+  };
+
+  let server = match Server::from_json(json) {
+    Ok(server) => server,
+    Err(err) => {
+      eprintln!("failed to parse configuration: {err}");
+      return 1;
+    }
+  };
+
+  match server.run() {
+    Ok(()) => 0,
+    Err(err) => {
+      eprintln!("failed to serve: {err:?}");
+      1
+    }
+  }
+}
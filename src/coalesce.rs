@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use crate::memory::Reservation;
+
+/// Data accumulated from one or more reads, to be sent as a single message.
+pub struct Batch {
+  pub data: Vec<u8>,
+
+  /// When the batch's first read was captured, in microseconds since the Unix epoch.
+  pub timestamp_us: u64,
+
+  // Held until the batch has been sent.
+  reservations: Vec<Reservation>,
+}
+
+impl Batch {
+  pub fn new(data: Vec<u8>, timestamp_us: u64, reservation: Option<Reservation>) -> Batch {
+    Batch {
+      data,
+      timestamp_us,
+      reservations: reservation.into_iter().collect(),
+    }
+  }
+}
+
+/// A logical frame being put back together from the fragments the backend read it in.
+pub struct Assembly {
+  buf: Vec<u8>,
+  timestamp_us: u64,
+  reservations: Vec<Reservation>,
+}
+
+impl Assembly {
+  /// Start assembling a frame, with the backend's hint of its total size (or zero, if unknown), and
+  /// when its first fragment was captured.
+  pub fn with_capacity(size_hint: usize, timestamp_us: u64) -> Assembly {
+    Assembly {
+      buf: Vec::with_capacity(size_hint),
+      timestamp_us,
+      reservations: Vec::new(),
+    }
+  }
+
+  pub fn push(&mut self, data: &[u8], reservation: Option<Reservation>) {
+    self.buf.extend_from_slice(data);
+    self.reservations.extend(reservation);
+  }
+
+  pub fn finish(self) -> Batch {
+    Batch {
+      data: self.buf,
+      timestamp_us: self.timestamp_us,
+      reservations: self.reservations,
+    }
+  }
+}
+
+/// Batches up reads from a socket, so that many small reads don't each cost a WebSocket message.
+///
+/// Data is held until either `interval` has passed since the first buffered read, or `max_bytes`
+/// have been buffered. A zero interval disables coalescing.
+pub struct Coalescer {
+  interval: Duration,
+  max_bytes: usize,
+  buf: Vec<u8>,
+  timestamp_us: u64,
+  reservations: Vec<Reservation>,
+  since: Option<Instant>,
+}
+
+impl Coalescer {
+  pub fn new(interval: Duration, max_bytes: usize) -> Coalescer {
+    Coalescer {
+      interval,
+      max_bytes,
+      buf: Vec::new(),
+      timestamp_us: 0,
+      reservations: Vec::new(),
+      since: None,
+    }
+  }
+
+  pub fn enabled(&self) -> bool {
+    !self.interval.is_zero()
+  }
+
+  pub fn push(&mut self, batch: Batch) {
+    if self.since.is_none() {
+      self.since = Some(Instant::now());
+      self.timestamp_us = batch.timestamp_us;
+    }
+    self.buf.extend_from_slice(&batch.data);
+    self.reservations.extend(batch.reservations);
+  }
+
+  /// How long until the buffered data is due to be sent, if there is any.
+  pub fn time_left(&self) -> Option<Duration> {
+    self.since.map(|since| self.interval.saturating_sub(since.elapsed()))
+  }
+
+  fn due(&self) -> bool {
+    self.buf.len() >= self.max_bytes || self.time_left() == Some(Duration::ZERO)
+  }
+
+  /// Take the buffered data, if there is any.
+  pub fn take(&mut self) -> Option<Batch> {
+    self.since.take()?;
+    Some(Batch {
+      data: std::mem::take(&mut self.buf),
+      timestamp_us: self.timestamp_us,
+      reservations: std::mem::take(&mut self.reservations),
+    })
+  }
+
+  /// Take the buffered data, if it's due to be sent.
+  pub fn take_if_due(&mut self) -> Option<Batch> {
+    if self.due() {
+      self.take()
+    } else {
+      None
+    }
+  }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeSet;
+use std::sync::OnceLock;
+
+use tokio::sync::watch;
+
+/// Conditions the platform has said are active with `wardenclyffe_set_condition`, like the screen
+/// being off.
+static CONDITIONS: OnceLock<watch::Sender<BTreeSet<String>>> = OnceLock::new();
+
+fn conditions() -> &'static watch::Sender<BTreeSet<String>> {
+  CONDITIONS.get_or_init(|| watch::channel(BTreeSet::new()).0)
+}
+
+/// Record that a condition started or ended.
+pub fn set(condition: &str, active: bool) {
+  conditions().send_if_modified(|conditions| {
+    if active {
+      conditions.insert(condition.to_string())
+    } else {
+      conditions.remove(condition)
+    }
+  });
+}
+
+/// The conditions that suspend a session, and which of them is active.
+pub struct Suspension {
+  conditions: Vec<String>,
+  changes: watch::Receiver<BTreeSet<String>>,
+}
+
+impl Suspension {
+  /// Watch for any of `conditions`, or return None if there aren't any.
+  pub fn new(conditions: Option<&[String]>) -> Option<Suspension> {
+    let conditions = conditions.filter(|conditions| !conditions.is_empty())?;
+    Some(Suspension {
+      conditions: conditions.to_vec(),
+      changes: self::conditions().subscribe(),
+    })
+  }
+
+  /// The first of the conditions that's active, if any is.
+  pub fn active(&self) -> Option<String> {
+    let active = self.changes.borrow();
+    self.conditions.iter().find(|c| active.contains(*c)).cloned()
+  }
+
+  /// Wait for conditions to change.
+  pub async fn changed(&mut self) {
+    // The sender is static, so it's never dropped.
+    let _ = self.changes.changed().await;
+  }
+}
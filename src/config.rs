@@ -1,35 +1,848 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum TLS {
   Disabled,
   SelfSigned,
+
+  /// A self-signed certificate, as with `SelfSigned`, whose key and certificate are kept in
+  /// `cache_dir` and reused across restarts, so that clients that pinned or accepted it keep
+  /// trusting the server.
+  PersistentSelfSigned {
+    cache_dir: PathBuf,
+  },
   Certificate {
     cert_path: PathBuf,
     private_key_path: PathBuf,
   },
+
+  /// A certificate, as with `Certificate`, and requiring clients to present certificates issued by
+  /// one of the CAs in `client_ca_path` (a PEM file, or directory of them) to connect at all.
+  CertificateWithClientAuth {
+    cert_path: PathBuf,
+    private_key_path: PathBuf,
+    client_ca_path: PathBuf,
+  },
+
+  /// A certificate issued and renewed by an ACME CA, like Let's Encrypt.
+  Acme(Acme),
+}
+
+/// Certificates from an ACME CA, proven with TLS-ALPN-01 challenges answered by the server itself,
+/// which the CA must be able to reach on port 443 of each domain.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Acme {
+  /// Names the certificate is for.
+  pub domains: Vec<String>,
+
+  /// Where issued certificates, their keys, and the account key are kept across restarts.
+  pub cache_dir: PathBuf,
+
+  /// The CA's directory. Defaults to Let's Encrypt's production CA.
+  pub directory_url: Option<String>,
+
+  /// Contact URLs for the account, e.g. `mailto:ops@example.com`.
+  pub contact: Option<Vec<String>>,
+
+  /// Renew the certificate this many days before it expires. 30 by default.
+  pub renew_before_days: Option<u64>,
+
+  /// PEM file, or directory of them, of the CAs to trust when talking to the ACME CA. Defaults to the
+  /// system's.
+  pub ca_certs: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SelfSignedCertificate {
+  /// Hostnames and IP addresses to name in addition to the device's own hostname and addresses,
+  /// e.g. those of a port forward or tunnel that clients connect through.
+  pub subject_alt_names: Option<Vec<String>>,
+
+  /// Common name of the subject. Defaults to the device's hostname.
+  pub common_name: Option<String>,
+
+  /// How long certificates are valid for. They're reissued once less than a quarter of this is
+  /// left. Certificates are valid practically forever if unset.
+  pub validity_days: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TlsPolicy {
+  /// The oldest version of TLS allowed, `1.2` or `1.3`. Defaults to 1.2.
+  pub min_version: Option<String>,
+
+  /// Cipher suites allowed, by their IANA names (e.g. `TLS13_AES_256_GCM_SHA384`), in order of
+  /// preference. Suites of versions older than `min_version` are ignored. Defaults to all of those
+  /// rustls supports.
+  pub cipher_suites: Option<Vec<String>>,
+}
+
+/// A secret, like a private key's passphrase, and where it's kept.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub enum Passphrase {
+  Value(String),
+
+  /// The value of an environment variable.
+  Env(String),
+
+  /// The contents of a file, without a trailing newline.
+  File(PathBuf),
 }
 
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum HttpContent {
   Embedded,
   Path(PathBuf),
+
+  /// A zip file (or the assets of an APK), indexed at startup.
+  Archive(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Auth {
+  /// Bearer token granting access to the /admin/ endpoints. Admin endpoints are disabled if unset.
+  pub admin_token: Option<String>,
+
+  /// Base64-encoded key used to sign access grants. If unset, a random key is generated and kept in
+  /// `state_path`, or generated at every startup (invalidating issued grants) if that's unset too.
+  pub signing_key: Option<String>,
+
+  /// Reject WebSocket sessions that don't present a grant or the admin token.
+  pub required: Option<bool>,
+
+  /// Longest `ttl`, in seconds, that grants can be minted with at `POST /admin/grants`, and so the
+  /// longest `grace` that the old key is accepted for after rotating it. Defaults to a day.
+  pub max_grant_ttl_secs: Option<u64>,
+
+  /// Roles that tokens can have, by name, each a list of scopes: `admin` for everything the admin
+  /// token allows, `read:<glob>` and `write:<glob>` for the socket paths the role can read from
+  /// and write to, where `*` matches any sequence of characters, and `upload` for uploading files.
+  pub roles: Option<BTreeMap<String, Vec<String>>>,
+
+  /// Bearer tokens that have one of `roles`, for access narrower than the admin token's.
+  pub tokens: Option<Vec<RoleToken>>,
+
+  /// Ask the platform to authenticate bearer tokens that aren't the admin token or in `tokens`,
+  /// with `wardenclyffe_authenticate`, instead of rejecting them.
+  pub platform: Option<bool>,
+
+  /// How long the platform's answer for a token is trusted before asking again. Defaults to five
+  /// minutes.
+  pub platform_ttl_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleToken {
+  pub token: String,
+  pub role: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LocalListener {
+  /// Path of the Unix domain socket to listen on, or an abstract socket name prefixed with '@'.
+  pub path: String,
+
+  /// UIDs allowed to connect. All UIDs are allowed if unset.
+  pub allowed_uids: Option<Vec<u32>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RedirectListener {
+  /// Port to listen on, on the same address as the TLS listener. Defaults to 8080.
+  pub port: Option<u16>,
+
+  /// Answer ACME HTTP-01 challenges, and prefer them to TLS-ALPN-01 when the CA offers both. The
+  /// CA must be able to reach this listener on port 80 of each domain. Defaults to false.
+  pub acme_challenges: Option<bool>,
+}
+
+/// Long-polling access to sockets, for clients behind middleboxes that strip WebSocket upgrades.
+#[derive(Serialize, Deserialize, Default)]
+pub struct LongPoll {
+  /// How long a poll waits for data before returning with none. Polls return a second before
+  /// `limits.request_timeout_ms` at the latest, so that clients don't see them time out.
+  pub poll_timeout_ms: Option<u64>,
+
+  /// Close sessions that haven't been polled for this long.
+  pub idle_timeout_ms: Option<u64>,
+}
+
+/// How sessions ended by a backend error code are closed.
+#[derive(Serialize, Deserialize)]
+pub struct ErrorCloseCode {
+  /// Error code returned by `wardenclyffe_socket_error`.
+  pub code: i32,
+
+  /// WebSocket close code to send, e.g. 1013 (try again later) or a private code from 4000-4999.
+  pub close_code: u16,
+
+  /// Reason sent in the Close frame, instead of the backend's.
+  pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct WebSocket {
+  /// Maximum time to block in a single backend read, before checking on the connection.
+  pub read_timeout_ms: Option<u32>,
+
+  /// Send a ping after this long without sending anything to the client.
+  pub keepalive_interval_ms: Option<u64>,
+
+  /// After closing a connection, how long to wait for the client to acknowledge it.
+  pub close_timeout_ms: Option<u64>,
+
+  /// Close codes for backend error codes, overriding the defaults for the well-known ones.
+  pub error_close_codes: Option<Vec<ErrorCloseCode>>,
+
+  /// Allow V2 clients to download files from directory mounts over the connection, and to upload
+  /// them into `uploads` if that's set and they may upload there over HTTP.
+  pub file_transfer: Option<bool>,
+
+  /// Refuse WebSocket connections without TLS unless they use the `wardenclyffe.v2+hmac`
+  /// subprotocol, which authenticates every message with a key derived from the client's
+  /// credentials.
+  pub require_integrity: Option<bool>,
+
+  /// Maximum number of sockets a client can read from at once with a merged stream (`/merge`),
+  /// 8 by default.
+  pub max_merged_sockets: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Mount {
+  /// Path prefix at which the content is served, e.g. `/traces`.
+  pub prefix: String,
+
+  pub content: HttpContent,
+
+  /// Names of the files to serve for directories under this mount, tried in order, instead of
+  /// the global `index_names`.
+  pub index_names: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CachePolicy {
+  /// Glob matched against the request path, where `*` matches any sequence of characters.
+  pub path: String,
+
+  /// Value of the Cache-Control header sent with matching static content.
+  pub cache_control: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BodyLimit {
+  /// Glob matched against the request path, where `*` matches any sequence of characters.
+  pub path: String,
+
+  /// Maximum size of matching request bodies, in bytes.
+  pub max_bytes: u64,
+}
+
+/// Settings for WebSocket sessions on matching socket paths.
+#[derive(Serialize, Deserialize)]
+pub struct SocketPolicy {
+  /// Glob matched against the socket path, where `*` matches any sequence of characters.
+  pub path: String,
+
+  /// Hold data read from the socket for up to this long, to send it to the client in fewer, larger
+  /// messages. Data is sent as soon as it's read if unset or zero.
+  pub coalesce_ms: Option<u64>,
+
+  /// Send held data once this much has accumulated, regardless of `coalesce_ms`.
+  pub coalesce_bytes: Option<usize>,
+
+  /// Send frames that the backend reads in fragments as WebSocket fragments as they arrive, rather
+  /// than assembling them into a single message first.
+  pub stream_fragments: Option<bool>,
+
+  /// Number and checksum each payload sent to V2 clients, to tell data lost on the way from data
+  /// the backend produced wrong when debugging. Gaps and mismatches in what clients send (and in
+  /// what they report with `checksum_report`) are counted in the stats.
+  pub checksums: Option<bool>,
+
+  /// Queue data for the client without blocking the read loop, and ask the backend to pause (with
+  /// `wardenclyffe_pause`) once this many bytes are waiting to be sent, instead of reading and
+  /// dropping what the client can't keep up with. For backends that can cheaply stop capturing.
+  pub outgoing_high_watermark_bytes: Option<usize>,
+
+  /// Resume the backend once the outgoing queue drains to this many bytes, half the high watermark
+  /// by default.
+  pub outgoing_low_watermark_bytes: Option<usize>,
+
+  /// Let clients filter what's read from the socket, which should be text like a log, line by line
+  /// with the `filter`, `filter_regex` and `min_level` query parameters.
+  pub text_filters: Option<bool>,
+
+  /// Regular expression for a log line's level, for `min_level`: the first group that matches is
+  /// the level, one of `VDIWEFA`. Matches logcat's `threadtime` and `brief` formats by default.
+  pub level_pattern: Option<String>,
+
+  /// Send V2 clients what's read from the socket as patches to the last payload sent in full,
+  /// for backends that repeatedly send large, mostly identical blobs like state snapshots.
+  pub delta_encoding: Option<bool>,
+
+  /// Names of the parameters that V2 clients may set with `set-params` control messages, which are
+  /// passed to the backend with `wardenclyffe_set_parameter`. Any parameter may be set if unset.
+  pub parameters: Option<Vec<String>>,
+
+  /// Maximum number of messages from the client waiting to be written to the socket, beyond which
+  /// we stop reading from the client until the backend catches up.
+  pub inbound_queue: Option<usize>,
+
+  /// Limit on the rate at which the client can write to the socket, in bytes per second.
+  pub inbound_bytes_per_sec: Option<u64>,
+
+  /// Wait up to this long for more messages from the client before writing to the socket, and
+  /// write them all at once, for chatty clients sending many small messages. Only for backends that
+  /// treat what's written as a stream, since messages written together aren't separated. V2 clients
+  /// can have what's waiting written right away with a `flush` control message.
+  pub write_batch_ms: Option<u64>,
+
+  /// Write batched messages once there are this many bytes of them. 64 KiB by default, if
+  /// `write_batch_ms` is set.
+  pub write_batch_bytes: Option<usize>,
+
+  /// Keep the socket open for this long after a client's connection drops, for the client to resume
+  /// the session by reconnecting with the same `session` query parameter. Sessions can't be resumed
+  /// if unset.
+  pub resume_window_ms: Option<u64>,
+
+  /// Keep what's been read from the socket in the last this many milliseconds, for new clients to
+  /// ask for with the `replay` query parameter, e.g. so a log viewer shows recent history rather
+  /// than starting empty. Limited to `replay_bytes`, or 1 MiB, either way.
+  pub replay_ms: Option<u64>,
+
+  /// Keep up to this many bytes of what's been read from the socket for replay, regardless of age
+  /// if `replay_ms` is unset.
+  pub replay_bytes: Option<usize>,
+
+  /// Data read while waiting for a session to be resumed is buffered, up to this many bytes (1 MiB
+  /// by default) after which the session can no longer be resumed.
+  pub resume_buffer_bytes: Option<usize>,
+
+  /// Conditions the platform signals with `wardenclyffe_set_condition` (e.g. `screen_off`), during
+  /// any of which reading from the socket is suspended rather than left to fail. V2 clients are told
+  /// with `suspended` and `resumed` control messages. Also applies to long-poll sessions.
+  pub suspend_on: Option<Vec<String>>,
+
+  /// End sessions once they've been open this long, e.g. so that a forgotten dashboard doesn't keep
+  /// capturing the screen overnight. Resumed sessions count from when they were first opened. Also
+  /// applies to long-poll sessions.
+  pub max_session_ms: Option<u64>,
+
+  /// Warn V2 clients this long before their session reaches `max_session_ms`, with a
+  /// `session_expiring` control message. A minute by default.
+  pub session_warning_ms: Option<u64>,
+
+  /// Read from the socket on a thread of each session's own, rather than one shared with other
+  /// sessions, to keep latency steady for real-time streams like input, audio and video.
+  pub realtime: Option<bool>,
+
+  /// MIME type of the data read from the socket (e.g. `video/h264` or `text/plain; charset=utf-8`),
+  /// for generic clients to know how to show it. Listed with the socket and sent to V2 clients when
+  /// they connect, in place of the type the backend gives in `wardenclyffe_list_sockets`, if any.
+  pub content_type: Option<String>,
+
+  /// Checks on what clients write to the socket, before it's passed to the backend. Also applies to
+  /// long-poll sessions, which answer writes that fail them with 422 Unprocessable Entity.
+  pub validation: Option<InboundValidation>,
+}
+
+/// Checks on messages from clients. Messages that fail them are dropped rather than written to the
+/// socket, and V2 clients are told why with a `rejected` control message.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InboundValidation {
+  /// Largest message allowed, in bytes.
+  pub max_bytes: Option<usize>,
+
+  /// Require messages to be valid UTF-8.
+  pub utf8: Option<bool>,
+
+  /// JSON schema that messages must match, which also requires them to be JSON. Supports the
+  /// keywords describing the shape of a message, like `type`, `properties`, `required` and `items`.
+  pub json_schema: Option<Value>,
+
+  /// Most messages allowed per second, with bursts of up to a second's worth.
+  pub max_messages_per_sec: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VirtualHost {
+  /// Static content served to this host, instead of the global `http_content`.
+  pub http_content: Option<HttpContent>,
+
+  /// Prefix prepended to WebSocket paths before they're passed to the backend.
+  pub socket_prefix: Option<String>,
+
+  /// Backend socket path prefixes (after applying `socket_prefix`) this host may open, matched on
+  /// whole segments: `/cast` allows `/cast` and `/cast/video`, but not `/castle`. All paths are
+  /// allowed if unset.
+  pub allowed_sockets: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProxyRoute {
+  /// Request path prefix to forward.
+  pub prefix: String,
+
+  /// Base URL of the upstream HTTP server, e.g. `http://127.0.0.1:9001/`. WebSocket connections on
+  /// matching paths are relayed to the same URL with a `ws://` scheme.
+  pub upstream: String,
+
+  /// Remove `prefix` from the path before forwarding. Defaults to true.
+  pub strip_prefix: Option<bool>,
+
+  /// Headers to set on forwarded requests.
+  pub request_headers: Option<BTreeMap<String, String>>,
+
+  /// Headers to set on responses from the upstream.
+  pub response_headers: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Uploads {
+  /// Directory that files are uploaded into.
+  pub directory: PathBuf,
+
+  /// Maximum size of an uploaded file. Unlimited if unset, other than by `limits`.
+  pub max_file_bytes: Option<u64>,
+
+  /// Maximum total size of the files in the directory, beyond which uploads are refused.
+  pub quota_bytes: Option<u64>,
+}
+
+/// Where to POST JSON events about the server's activity, like sessions opening and closing, for
+/// orchestration systems that would otherwise have to poll the admin API.
+#[derive(Serialize, Deserialize)]
+pub struct Webhook {
+  /// An http:// or https:// URL.
+  pub url: String,
+
+  /// Names of the events to send, from `server_started` and the audit log's events (e.g.
+  /// `session_opened`, `session_closed`, `auth_failure`). All of them if unset.
+  pub events: Option<Vec<String>>,
+
+  /// Extra headers sent with each event, e.g. for authorization.
+  pub headers: Option<BTreeMap<String, String>>,
+
+  /// PEM file, or directory of them, of the CAs to trust for https:// URLs. Defaults to the system's.
+  pub ca_certs: Option<PathBuf>,
+
+  /// How many times to try sending an event before giving up on it, backing off between attempts.
+  pub max_attempts: Option<u32>,
+
+  /// Events waiting to be sent, beyond which new ones are dropped.
+  pub max_queued: Option<usize>,
+}
+
+/// A signed manifest of the certificate being served, for companion apps that pin it.
+#[derive(Serialize, Deserialize)]
+pub struct Pinning {
+  /// Base64-encoded PKCS#8 Ed25519 key that the manifest is signed with, whose public key clients
+  /// pin.
+  pub signing_key: String,
+
+  /// When the certificate is next due to be replaced, in seconds since the epoch.
+  pub next_rotation: Option<u64>,
+
+  /// Base64 SHA-256 hashes of the SubjectPublicKeyInfo of keys to be used after the next rotation,
+  /// for clients to accept ahead of time.
+  pub upcoming_spki_sha256: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UiBundle {
+  /// Base64-encoded Ed25519 public key that UI bundles must be signed with.
+  pub public_key: String,
+}
+
+/// Scheduling applied to a group of threads. Unset fields leave the inherited value alone.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ThreadPolicy {
+  /// Nice value, from -20 (highest priority) to 19.
+  pub nice: Option<i32>,
+
+  /// Run with SCHED_FIFO at this priority (1-99), where permitted.
+  pub realtime_priority: Option<i32>,
+
+  /// CPUs the threads may run on.
+  pub cpu_affinity: Option<Vec<usize>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Threads {
+  /// Policy for the tokio worker threads.
+  pub workers: Option<ThreadPolicy>,
+
+  /// Policy for threads performing blocking reads from sockets. Threads of tokio's blocking pool only
+  /// have it while they're reading, since they also do other work, like hashing files.
+  pub blocking: Option<ThreadPolicy>,
+
+  /// Read from sockets on this many dedicated threads, rather than on tokio's blocking pool, where
+  /// reads can end up waiting behind file I/O.
+  pub read_threads: Option<usize>,
+
+  /// Reads that can wait for a dedicated thread, after which sessions wait to queue theirs. 256 by
+  /// default.
+  pub read_queue: Option<usize>,
+
+  /// Policy for the threads of sessions on real-time sockets. The blocking policy if unset.
+  pub realtime: Option<ThreadPolicy>,
+}
+
+/// Limits protecting the server from misbehaving or malicious clients.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Limits {
+  /// Time a client may take to complete the TLS handshake.
+  pub tls_handshake_timeout_ms: Option<u64>,
+
+  /// Maximum number of TLS handshakes in progress at once. Further connections are dropped.
+  pub max_tls_handshakes: Option<usize>,
+
+  /// Time a client may take to send the headers of an HTTP/1 request.
+  pub header_read_timeout_ms: Option<u64>,
+
+  /// Time allowed to handle a request, including reading its body. For WebSocket upgrades, this only
+  /// bounds the handshake.
+  pub request_timeout_ms: Option<u64>,
+
+  /// Maximum size of request bodies, in bytes, unless overridden by `body_limits`.
+  pub max_body_bytes: Option<u64>,
+
+  /// Per-path request body limits, of which the first matching one applies.
+  pub body_limits: Option<Vec<BodyLimit>>,
+
+  /// Total bytes that may be buffered for WebSocket messages and static responses. Over budget,
+  /// data messages to WebSocket clients are dropped, and static content is refused with a 503.
+  pub memory_budget_bytes: Option<u64>,
+
+  /// Largest file whose SHA-256 is computed for /api/files and file transfers, which describe larger
+  /// files without one. Defaults to 256 MiB.
+  pub max_hashed_file_bytes: Option<u64>,
+
+  /// Maximum number of HTTP requests from one client address being handled at once, beyond which
+  /// requests are refused with a 429. Unlimited if unset.
+  pub max_requests_per_peer: Option<usize>,
+
+  /// Maximum number of WebSocket sessions open at once from one client address, beyond which
+  /// upgrades are refused with a 429. Unlimited if unset.
+  pub max_websocket_sessions_per_peer: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct FailedAttempts {
+  /// How many sources to remember, forgetting the least recently seen beyond that. Defaults to 256.
+  pub max_sources: Option<usize>,
+
+  /// Keep the table in `state_path` across restarts. It's saved at most once a minute.
+  pub persist: Option<bool>,
+}
+
+/// Refusing requests from addresses that keep failing to authenticate.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Lockout {
+  /// How many authentication failures from one address within `window_ms` lock it out. Defaults
+  /// to 10.
+  pub max_failures: Option<usize>,
+
+  /// Defaults to a minute.
+  pub window_ms: Option<u64>,
+
+  /// How long an address stays locked out, during which its requests are refused with a 429.
+  /// Defaults to five minutes.
+  pub ban_ms: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Config {
   pub port: Option<u16>,
+
+  /// Address to listen on, which may be an IPv6 link-local address with its zone (an interface
+  /// name or index), e.g. `fe80::1%usb0`. Defaults to every IPv4 address.
+  pub listen_address: Option<String>,
   pub tls: Option<TLS>,
+
+  /// Passphrase of the private key in `private_key_path`, if it's encrypted (PKCS#8, with PBES2 and
+  /// AES-CBC).
+  pub private_key_passphrase: Option<Passphrase>,
+
+  /// What self-signed certificates name, and how long they're valid, with `SelfSigned` and
+  /// `PersistentSelfSigned`.
+  pub self_signed: Option<SelfSignedCertificate>,
+
+  /// Restrict the TLS versions and cipher suites that clients can negotiate, which are those rustls
+  /// considers safe by default.
+  pub tls_policy: Option<TlsPolicy>,
   pub http_content: Option<HttpContent>,
+
+  /// Additional static content mounted at path prefixes, tried in order before `http_content`.
+  pub mounts: Option<Vec<Mount>>,
+
+  /// Names of the files to serve for a directory, tried in order. Defaults to `index.html`.
+  pub index_names: Option<Vec<String>>,
+
+  pub auth: Option<Auth>,
+
+  /// File to append security audit events to, in addition to the `audit` log target.
+  pub audit_log: Option<PathBuf>,
+
+  /// File in which state that must survive restarts is kept.
+  pub state_path: Option<PathBuf>,
+
+  /// Summaries of recent failed authentication and TLS attempts, by source address, listed at
+  /// /admin/failed-attempts.
+  pub failed_attempts: Option<FailedAttempts>,
+
+  /// Lock out addresses after repeated authentication failures. Disabled if unset.
+  pub lockout: Option<Lockout>,
+
+  /// Additional plaintext listener on a Unix domain socket, for on-device clients.
+  pub local_listener: Option<LocalListener>,
+
+  /// Additional plaintext HTTP listener that redirects to HTTPS. Ignored if TLS is disabled.
+  pub redirect_listener: Option<RedirectListener>,
+
+  pub websocket: Option<WebSocket>,
+
+  /// Serve sockets to long-polling clients at `/lp/<socket path>`.
+  pub long_poll: Option<LongPoll>,
+
+  /// Redirect requests for directories without a trailing slash to the same path with one.
+  pub redirect_directories: Option<bool>,
+
+  /// Cache-Control policies for static content, of which the first matching one applies.
+  pub cache_policies: Option<Vec<CachePolicy>>,
+
+  /// Extra headers added to responses, keyed by request path prefix. When several prefixes match,
+  /// headers from longer prefixes take precedence.
+  pub response_headers: Option<BTreeMap<String, BTreeMap<String, String>>>,
+
+  /// Per-host overrides, keyed by the hostname (without port) from the Host header.
+  pub virtual_hosts: Option<BTreeMap<String, VirtualHost>>,
+
+  /// Routes forwarded to other HTTP servers, of which the first matching one applies. Requests are
+  /// authorized as if the route's path were a socket's.
+  pub proxy_routes: Option<Vec<ProxyRoute>>,
+
+  /// Per-socket settings, of which the first matching one applies.
+  pub socket_policies: Option<Vec<SocketPolicy>>,
+
+  pub limits: Option<Limits>,
+
+  pub threads: Option<Threads>,
+
+  /// Allow replacing the UI at runtime with signed bundles. Requires `http_content` to be an
+  /// `Archive`, which is replaced by the bundle. Bundles uploaded to /admin/ui-bundle are subject
+  /// to `limits`, which will usually need a `body_limits` entry for them.
+  pub ui_bundle: Option<UiBundle>,
+
+  /// Allow uploading files with `PUT /api/upload/<name>`, which requires the admin token or a role
+  /// with the `upload` scope. Uploads
+  /// are subject to `limits`, which will usually need a `body_limits` entry and a longer
+  /// `request_timeout_ms` for them.
+  pub uploads: Option<Uploads>,
+
+  /// Serve a signed description of the certificate at /.well-known/wardenclyffe/pins.json.
+  pub pinning: Option<Pinning>,
+
+  pub webhook: Option<Webhook>,
+
+  /// Refuse to start with fields that aren't recognized, e.g. because of a typo, instead of
+  /// ignoring them.
+  pub strict: Option<bool>,
+}
+
+/// Collect the paths of the fields of `input` that didn't survive being parsed into `parsed`.
+fn unknown_fields(input: &Value, parsed: &Value, path: &str, unknown: &mut Vec<String>) {
+  match (input, parsed) {
+    (Value::Object(input), Value::Object(parsed)) => {
+      for (key, value) in input {
+        let path = if path.is_empty() {
+          key.clone()
+        } else {
+          format!("{path}.{key}")
+        };
+        match parsed.get(key) {
+          Some(parsed) => unknown_fields(value, parsed, &path, unknown),
+          None => unknown.push(path),
+        }
+      }
+    }
+    (Value::Array(input), Value::Array(parsed)) => {
+      for (i, (value, parsed)) in input.iter().zip(parsed).enumerate() {
+        unknown_fields(value, parsed, &format!("{path}[{i}]"), unknown);
+      }
+    }
+    _ => {}
+  }
 }
 
 impl Config {
+  /// Parse a configuration, refusing unrecognized fields if it's strict.
+  pub fn from_value(value: Value) -> Result<Config> {
+    let config: Config = serde_json::from_value(value.clone())?;
+    if config.strict.unwrap_or(false) {
+      let mut unknown = Vec::new();
+      unknown_fields(&value, &serde_json::to_value(&config)?, "", &mut unknown);
+      if !unknown.is_empty() {
+        bail!("unknown configuration fields: {}", unknown.join(", "));
+      }
+    }
+    Ok(config)
+  }
+
   pub fn populate_defaults(mut self) -> Self {
     self.tls = self.tls.or(Some(TLS::SelfSigned));
+    if let Some(TLS::Acme(acme)) = &mut self.tls {
+      acme.directory_url = acme
+        .directory_url
+        .take()
+        .or(Some("https://acme-v02.api.letsencrypt.org/directory".into()));
+      acme.renew_before_days = acme.renew_before_days.or(Some(30));
+    }
     self.port = self.port.or(self.tls.as_ref().map(|_| 8443).or(Some(8443)));
     self.http_content = self.http_content.or(Some(HttpContent::Embedded));
+    self.index_names = self.index_names.or_else(|| Some(vec!["index.html".into()]));
+    let mut auth = self.auth.unwrap_or_default();
+    auth.max_grant_ttl_secs = auth.max_grant_ttl_secs.or(Some(24 * 60 * 60));
+    self.auth = Some(auth);
+
+    let mut failed_attempts = self.failed_attempts.unwrap_or_default();
+    failed_attempts.max_sources = failed_attempts.max_sources.or(Some(256));
+    failed_attempts.persist = failed_attempts.persist.or(Some(false));
+    self.failed_attempts = Some(failed_attempts);
+
+    if let Some(lockout) = &mut self.lockout {
+      lockout.max_failures = lockout.max_failures.or(Some(10));
+      lockout.window_ms = lockout.window_ms.or(Some(60_000));
+      lockout.ban_ms = lockout.ban_ms.or(Some(300_000));
+    }
+
+    let mut websocket = self.websocket.unwrap_or_default();
+    websocket.read_timeout_ms = websocket.read_timeout_ms.or(Some(1000));
+    websocket.keepalive_interval_ms = websocket.keepalive_interval_ms.or(Some(15000));
+    websocket.close_timeout_ms = websocket.close_timeout_ms.or(Some(5000));
+    websocket.file_transfer = websocket.file_transfer.or(Some(false));
+    websocket.require_integrity = websocket.require_integrity.or(Some(false));
+    websocket.max_merged_sockets = websocket.max_merged_sockets.or(Some(8));
+    self.websocket = Some(websocket);
+
+    if let Some(long_poll) = &mut self.long_poll {
+      long_poll.poll_timeout_ms = long_poll.poll_timeout_ms.or(Some(20_000));
+      long_poll.idle_timeout_ms = long_poll.idle_timeout_ms.or(Some(60_000));
+    }
+
+    if let Some(redirect_listener) = &mut self.redirect_listener {
+      redirect_listener.port = redirect_listener.port.or(Some(8080));
+      redirect_listener.acme_challenges = redirect_listener.acme_challenges.or(Some(false));
+    }
+
+    if let Some(threads) = &mut self.threads {
+      threads.read_queue = threads.read_queue.or(Some(256));
+    }
+    if let Some(webhook) = &mut self.webhook {
+      webhook.max_attempts = webhook.max_attempts.or(Some(5));
+      webhook.max_queued = webhook.max_queued.or(Some(256));
+    }
+
+    self.redirect_directories = self.redirect_directories.or(Some(true));
+
+    let mut limits = self.limits.unwrap_or_default();
+    limits.tls_handshake_timeout_ms = limits.tls_handshake_timeout_ms.or(Some(10_000));
+    limits.max_tls_handshakes = limits.max_tls_handshakes.or(Some(64));
+    limits.header_read_timeout_ms = limits.header_read_timeout_ms.or(Some(10_000));
+    limits.request_timeout_ms = limits.request_timeout_ms.or(Some(30_000));
+    limits.max_body_bytes = limits.max_body_bytes.or(Some(1024 * 1024));
+    limits.memory_budget_bytes = limits.memory_budget_bytes.or(Some(64 * 1024 * 1024));
+    limits.max_hashed_file_bytes = limits.max_hashed_file_bytes.or(Some(256 * 1024 * 1024));
+    if let Some(long_poll) = &mut self.long_poll {
+      let latest = limits.request_timeout_ms.unwrap().saturating_sub(1000);
+      long_poll.poll_timeout_ms = long_poll.poll_timeout_ms.map(|timeout| timeout.min(latest));
+    }
+    self.limits = Some(limits);
     self
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn poll_timeout_ends_before_request_timeout() {
+    let config = Config::from_value(json!({
+      "long_poll": { "poll_timeout_ms": 60_000 },
+      "limits": { "request_timeout_ms": 30_000 },
+    }))
+    .unwrap()
+    .populate_defaults();
+    assert_eq!(config.long_poll.unwrap().poll_timeout_ms, Some(29_000));
+
+    let config = Config::from_value(json!({ "long_poll": {} }))
+      .unwrap()
+      .populate_defaults();
+    assert_eq!(config.long_poll.unwrap().poll_timeout_ms, Some(20_000));
+  }
+
+  fn unknown(config: Value) -> Vec<String> {
+    let parsed = serde_json::to_value(serde_json::from_value::<Config>(config.clone()).unwrap()).unwrap();
+    let mut unknown = Vec::new();
+    unknown_fields(&config, &parsed, "", &mut unknown);
+    unknown
+  }
+
+  #[test]
+  fn strict_accepts_known_fields() {
+    let config = json!({
+      "strict": true,
+      "tls": { "Acme": { "domains": ["example.com"], "cache_dir": "/data/acme" } },
+      "http_content": { "Path": "/data/www" },
+      "auth": { "roles": { "reader": ["read:/*"] }, "tokens": [{ "token": "t", "role": "reader" }] },
+      "virtual_hosts": { "example.com": { "allowed_sockets": ["/cast"] } },
+      "socket_policies": [{ "path": "/cast", "delta_encoding": true }],
+      "limits": { "request_timeout_ms": 1000 },
+    });
+    assert!(unknown(config.clone()).is_empty());
+    assert!(Config::from_value(config).is_ok());
+  }
+
+  #[test]
+  fn finds_unknown_fields_wherever_they_are() {
+    let config = json!({
+      "prot": 8443,
+      "tls": { "Acme": { "domains": [], "cache_dir": "/", "extra": 1 } },
+      "auth": { "roles": { "reader": ["read:/*"] }, "tokens": [{ "token": "t", "role": "reader", "rol": "x" }] },
+      "virtual_hosts": { "example.com": { "allowed_socket": ["/cast"] } },
+      "socket_policies": [{ "path": "/a" }, { "path": "/b", "delta": true }],
+      "limits": { "request_timout_ms": 1000 },
+    });
+    assert_eq!(
+      unknown(config),
+      [
+        "auth.tokens[0].rol",
+        "limits.request_timout_ms",
+        "prot",
+        "socket_policies[1].delta",
+        "tls.Acme.extra",
+        "virtual_hosts.example.com.allowed_socket",
+      ]
+    );
+  }
+
+  #[test]
+  fn only_strict_configs_reject_unknown_fields() {
+    let error = Config::from_value(json!({ "strict": true, "prot": 1, "limits": { "x": 1 } }))
+      .err()
+      .unwrap();
+    assert_eq!(error.to_string(), "unknown configuration fields: limits.x, prot");
+    assert!(Config::from_value(json!({ "prot": 1 })).is_ok());
+    assert!(Config::from_value(json!({ "strict": false, "prot": 1 })).is_ok());
+  }
+}
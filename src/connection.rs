@@ -0,0 +1,78 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::{Stream, TryStreamExt};
+use hyper::{
+  server::accept,
+  service::{make_service_fn, service_fn},
+  Body, Request,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::peer::Peer;
+use crate::server::{handle_request, ServerState};
+use crate::task::ConnectionExecutor;
+use crate::tls::TlsSession;
+
+/// An established connection, along with the identity of its remote end.
+pub struct Connection<IO> {
+  io: IO,
+  peer: Peer,
+  tls: Option<TlsSession>,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Connection<IO> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+  }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Connection<IO> {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().io).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+  }
+}
+
+/// Serve HTTP on each connection yielded by `incoming`.
+///
+/// Requests on TLS connections carry the connection's `TlsInfo` as an extension.
+pub async fn serve_connections<S, IO>(state: Arc<ServerState>, incoming: S) -> Result<()>
+where
+  S: Stream<Item = io::Result<(IO, Peer, Option<TlsSession>)>> + Send,
+  IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let header_read_timeout = state.config.limits.as_ref().unwrap().header_read_timeout_ms.unwrap();
+  let incoming = incoming.map_ok(|(io, peer, tls)| Connection { io, peer, tls });
+  let service = make_service_fn(move |conn: &Connection<IO>| {
+    let state = state.clone();
+    let peer = conn.peer;
+    let tls = conn.tls.clone();
+    let service = service_fn(move |mut req: Request<Body>| {
+      // The handshake has completed by the time we get a request.
+      if let Some(info) = tls.as_ref().and_then(TlsSession::info) {
+        req.extensions_mut().insert(info.clone());
+      }
+      handle_request(state.clone(), req, peer)
+    });
+    async move { Ok::<_, io::Error>(service) }
+  });
+
+  hyper::Server::builder(accept::from_stream(incoming))
+    .executor(ConnectionExecutor)
+    .http1_header_read_timeout(Duration::from_millis(header_read_timeout))
+    .serve(service)
+    .await?;
+  Ok(())
+}
@@ -0,0 +1,79 @@
+// Differing runs closer together than this are sent as one, since each run costs 8 bytes.
+const MERGE_GAP: usize = 8;
+
+/// How a payload is sent to a client with delta encoding.
+pub enum Delta {
+  /// In full, as the baseline with this ID that later patches apply to.
+  Baseline(u64),
+
+  /// As a patch to the baseline with this ID.
+  Patch(u64, Vec<u8>),
+}
+
+/// Delta-encodes payloads for backends that repeatedly send large, mostly identical blobs, like
+/// state snapshots, against the last payload sent in full.
+///
+/// A patch is the big-endian u32 length of the new payload, followed by runs of a big-endian u32
+/// offset, a big-endian u32 length, and that many bytes to write at that offset, applied to the
+/// baseline truncated to the new length. Bytes past the end of the baseline are always in a run.
+/// Patches are always against a baseline rather than the previous payload, so a client that loses a
+/// patch only loses that payload.
+#[derive(Default)]
+pub struct DeltaEncoder {
+  baseline: Option<(u64, Vec<u8>)>,
+  next_id: u64,
+}
+
+impl DeltaEncoder {
+  /// Send the next payload in full, e.g. because the client lost the baseline.
+  pub fn reset(&mut self) {
+    self.baseline = None;
+  }
+
+  pub fn encode(&mut self, data: &[u8]) -> Delta {
+    if let Some((id, baseline)) = &self.baseline {
+      let patch = diff(baseline, data);
+
+      // Start over from a new baseline once patches stop saving much.
+      if patch.len() < data.len() / 2 {
+        return Delta::Patch(*id, patch);
+      }
+    }
+
+    let id = self.next_id;
+    self.next_id += 1;
+    self.baseline = Some((id, data.to_vec()));
+    Delta::Baseline(id)
+  }
+}
+
+fn push_run(patch: &mut Vec<u8>, offset: usize, data: &[u8]) {
+  patch.extend_from_slice(&(offset as u32).to_be_bytes());
+  patch.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  patch.extend_from_slice(data);
+}
+
+fn diff(baseline: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut patch = Vec::new();
+  patch.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+  // The run being built, as its start and end offsets.
+  let mut run: Option<(usize, usize)> = None;
+  for (i, &byte) in data.iter().enumerate() {
+    if baseline.get(i) == Some(&byte) {
+      continue;
+    }
+    run = match run {
+      Some((start, end)) if i - end <= MERGE_GAP => Some((start, i + 1)),
+      Some((start, end)) => {
+        push_run(&mut patch, start, &data[start..end]);
+        Some((i, i + 1))
+      }
+      None => Some((i, i + 1)),
+    };
+  }
+  if let Some((start, end)) = run {
+    push_run(&mut patch, start, &data[start..end]);
+  }
+  patch
+}
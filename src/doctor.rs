@@ -0,0 +1,232 @@
+use std::ffi::c_void;
+use std::fs::File;
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+
+use crate::acme;
+use crate::api::backend_sockets;
+use crate::archive::Archive;
+use crate::config::{Config, HttpContent, TLS};
+use crate::ffi::*;
+use crate::peer;
+use crate::Server;
+
+// How long the backend gets to list its sockets before it's considered hung.
+const BACKEND_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Certificates expiring sooner than this are reported as failing, to catch them before clients do.
+const CERTIFICATE_EXPIRY_MARGIN: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Names and addresses of the backend's functions.
+macro_rules! backend_symbols {
+  ($($name:ident),* $(,)?) => {
+    [$((stringify!($name), $name as *const c_void)),*]
+  };
+}
+
+/// Check that the server could start with a configuration, and print a JSON report of each check
+/// to stdout, for triaging devices where it doesn't.
+///
+/// Returns the exit status: nonzero if any check failed.
+pub fn run(config: &Config) -> i32 {
+  let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+    Ok(runtime) => runtime,
+    Err(e) => {
+      eprintln!("failed to create runtime: {e}");
+      return 1;
+    }
+  };
+
+  let checks = [
+    ("port", check_port(config)),
+    ("certificate", check_certificate(config)),
+    ("ffi_symbols", check_symbols()),
+    ("backend", runtime.block_on(probe_backend())),
+    ("content", check_content(config)),
+  ];
+
+  let ok = checks.iter().all(|(_, result)| result.is_ok());
+  let checks: Vec<_> = checks
+    .into_iter()
+    .map(|(name, result)| match result {
+      Ok(details) => json!({ "name": name, "ok": true, "details": details }),
+      Err(e) => json!({ "name": name, "ok": false, "error": format!("{e:#}") }),
+    })
+    .collect();
+  println!(
+    "{}",
+    serde_json::to_string_pretty(&json!({ "ok": ok, "checks": checks })).unwrap()
+  );
+  if ok {
+    0
+  } else {
+    1
+  }
+}
+
+fn check_port(config: &Config) -> Result<Value> {
+  let port = config.port.unwrap();
+  let address = config.listen_address.as_deref().unwrap_or("0.0.0.0");
+  TcpListener::bind(peer::parse_listen_addr(address, port)?)
+    .map_err(|e| anyhow!("can't listen on {address} port {port}: {e}"))?;
+  Ok(json!({ "address": address, "port": port }))
+}
+
+fn check_certificate(config: &Config) -> Result<Value> {
+  let (cert_path, private_key_path) = match config.tls.as_ref().unwrap_or(&TLS::SelfSigned) {
+    TLS::Disabled => return Ok(json!({ "tls": "disabled" })),
+    TLS::SelfSigned => {
+      Server::load_certs(config)?;
+      return Ok(json!({ "tls": "self_signed" }));
+    }
+    TLS::PersistentSelfSigned { cache_dir } => {
+      Server::load_certs(config)?;
+      return Ok(json!({ "tls": "self_signed", "cache_dir": cache_dir }));
+    }
+    TLS::Certificate {
+      cert_path,
+      private_key_path,
+    }
+    | TLS::CertificateWithClientAuth {
+      cert_path,
+      private_key_path,
+      ..
+    } => (cert_path, private_key_path),
+    // A certificate is requested once the server starts, if there isn't one yet.
+    TLS::Acme(acme) => {
+      let Some((chain, _)) = acme::load_cached(&acme.cache_dir)? else {
+        return Ok(json!({ "tls": "acme", "domains": acme.domains, "issued": false }));
+      };
+      let (not_after, covered) = acme::validity(&chain[0], &acme.domains)?;
+      return Ok(json!({
+        "tls": "acme",
+        "domains": acme.domains,
+        "issued": true,
+        "covers_domains": covered,
+        "not_after": not_after,
+      }));
+    }
+  };
+
+  // Loading the TLS configuration checks the key, but not the certificate's validity period.
+  Server::load_certs(config)?;
+  let cert = rustls_pemfile::certs(&mut std::io::BufReader::new(File::open(cert_path)?))?
+    .into_iter()
+    .next()
+    .ok_or_else(|| anyhow!("no certificate in {}", cert_path.display()))?;
+  let (_, parsed) =
+    x509_parser::parse_x509_certificate(&cert).map_err(|e| anyhow!("failed to parse certificate: {e}"))?;
+  let validity = parsed.validity();
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+  let not_before = validity.not_before.timestamp();
+  let not_after = validity.not_after.timestamp();
+  if now < not_before {
+    bail!("certificate isn't valid until {}", validity.not_before);
+  }
+  if now + CERTIFICATE_EXPIRY_MARGIN.as_secs() as i64 > not_after {
+    bail!("certificate expires at {}", validity.not_after);
+  }
+  Ok(json!({
+    "tls": "certificate",
+    "cert_path": cert_path,
+    "private_key_path": private_key_path,
+    "subject": parsed.subject().to_string(),
+    "not_before": not_before,
+    "not_after": not_after,
+  }))
+}
+
+/// The backend is linked in, so its functions can't be missing, but whether they came from the
+/// real backend or a stub depends on what the binary was linked with.
+fn check_symbols() -> Result<Value> {
+  let symbols = backend_symbols![
+    wardenclyffe_authorize,
+    wardenclyffe_authenticate,
+    wardenclyffe_list_sockets,
+    wardenclyffe_create_socket,
+    wardenclyffe_destroy_socket,
+    wardenclyffe_supports_read,
+    wardenclyffe_read_timeout,
+    wardenclyffe_supports_write,
+    wardenclyffe_write,
+    wardenclyffe_set_parameter,
+    wardenclyffe_pause,
+    wardenclyffe_resume,
+    wardenclyffe_socket_error,
+  ];
+
+  let mut resolved = serde_json::Map::new();
+  for (name, address) in symbols {
+    if address.is_null() {
+      bail!("{name} isn't linked");
+    }
+    resolved.insert(name.to_string(), json!({ "object": object_containing(address) }));
+  }
+  Ok(Value::Object(resolved))
+}
+
+/// The path of the binary or library containing an address.
+#[cfg(unix)]
+fn object_containing(address: *const c_void) -> Option<String> {
+  let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+  (unsafe { libc::dladdr(address, &mut info) } != 0 && !info.dli_fname.is_null()).then(|| {
+    unsafe { std::ffi::CStr::from_ptr(info.dli_fname) }
+      .to_string_lossy()
+      .into_owned()
+  })
+}
+
+#[cfg(not(unix))]
+fn object_containing(_address: *const c_void) -> Option<String> {
+  None
+}
+
+async fn probe_backend() -> Result<Value> {
+  let sockets = tokio::time::timeout(BACKEND_PROBE_TIMEOUT, backend_sockets())
+    .await
+    .map_err(|_| anyhow!("wardenclyffe_list_sockets didn't return within {BACKEND_PROBE_TIMEOUT:?}"))??;
+  let paths: Vec<_> = sockets.iter().map(|socket| &socket.path).collect();
+  Ok(json!({ "sockets": paths }))
+}
+
+fn check_readable(content: &HttpContent) -> Result<Option<&Path>> {
+  match content {
+    HttpContent::Embedded => Ok(None),
+    HttpContent::Path(path) => {
+      std::fs::read_dir(path).map_err(|e| anyhow!("can't read {}: {e}", path.display()))?;
+      Ok(Some(path))
+    }
+    HttpContent::Archive(path) => {
+      Archive::open(path).map_err(|e| anyhow!("can't read {}: {e}", path.display()))?;
+      Ok(Some(path))
+    }
+  }
+}
+
+fn check_content(config: &Config) -> Result<Value> {
+  let vhost_content = config
+    .virtual_hosts
+    .iter()
+    .flatten()
+    .map(|(_, vhost)| vhost.http_content.as_ref());
+  let mount_content = config.mounts.iter().flatten().map(|mount| Some(&mount.content));
+  let contents = std::iter::once(config.http_content.as_ref())
+    .chain(vhost_content)
+    .chain(mount_content);
+
+  let mut roots = Vec::new();
+  for content in contents.flatten() {
+    if let Some(path) = check_readable(content)? {
+      roots.push(path);
+    }
+  }
+  if let Some(uploads) = &config.uploads {
+    std::fs::read_dir(&uploads.directory).map_err(|e| anyhow!("can't read {}: {e}", uploads.directory.display()))?;
+    roots.push(&uploads.directory);
+  }
+  Ok(json!({ "roots": roots }))
+}
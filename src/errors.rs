@@ -0,0 +1,121 @@
+use std::ffi::{c_char, CStr};
+
+use hyper::{
+  header::{HeaderValue, ACCEPT, CONTENT_TYPE},
+  Body, Request, Response,
+};
+use serde_json::json;
+
+use crate::ffi::{wardenclyffe_socket_error, WardenclyffeSocket};
+
+/// Attached to error responses generated by us (as opposed to proxied ones), so that they can be
+/// rendered in a format the client understands.
+#[derive(Clone)]
+pub struct ErrorMessage(pub String);
+
+/// Why the backend failed to read from or write to a socket.
+#[derive(Debug)]
+pub struct BackendError {
+  /// Backend-specific error code, or 0 if it didn't give one.
+  pub code: i32,
+  pub reason: String,
+}
+
+impl BackendError {
+  /// Ask the backend why the last operation on `socket` failed, falling back to `default_reason`.
+  pub unsafe fn last(socket: WardenclyffeSocket, default_reason: &str) -> BackendError {
+    let mut reason = [0 as c_char; 256];
+    let code = wardenclyffe_socket_error(socket, reason.as_mut_ptr(), reason.len());
+    let reason = CStr::from_ptr(reason.as_ptr()).to_string_lossy();
+    BackendError {
+      code,
+      reason: if reason.is_empty() {
+        default_reason.to_string()
+      } else {
+        reason.into_owned()
+      },
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+pub enum ErrorFormat {
+  Text,
+  Json,
+  Html,
+}
+
+impl ErrorFormat {
+  /// Pick the format of error responses to a request for `path`.
+  pub fn negotiate(req: &Request<Body>, path: &str) -> ErrorFormat {
+    let accept = req.headers().get(ACCEPT).and_then(|h| h.to_str().ok()).unwrap_or("");
+    let accepts = |mime: &str| {
+      accept
+        .split(',')
+        .any(|part| part.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(mime))
+    };
+
+    if path.starts_with("/api/") || path.starts_with("/admin/") || accepts("application/json") {
+      ErrorFormat::Json
+    } else if accepts("text/html") {
+      ErrorFormat::Html
+    } else {
+      ErrorFormat::Text
+    }
+  }
+}
+
+fn escape_html(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Replace the body of an error response with `message`, rendered in `format`.
+pub fn render_error(response: &mut Response<Body>, format: ErrorFormat, message: &str, request_id: &str) {
+  let status = response.status();
+  let (body, content_type) = match format {
+    ErrorFormat::Text => (message.to_string(), "text/plain; charset=utf-8"),
+    ErrorFormat::Json => (
+      json!({
+        "error": {
+          "code": status.as_u16(),
+          "message": message,
+          "request_id": request_id,
+        }
+      })
+      .to_string(),
+      "application/json",
+    ),
+    ErrorFormat::Html => {
+      let title = format!(
+        "{} {}",
+        status.as_u16(),
+        escape_html(status.canonical_reason().unwrap_or("Error"))
+      );
+      (
+        format!(
+          "<!DOCTYPE html>\n<html><head><title>{title}</title></head><body><h1>{title}</h1><p>{}</p>\
+           <p><small>Request ID: {}</small></p></body></html>\n",
+          escape_html(message),
+          escape_html(request_id),
+        ),
+        "text/html; charset=utf-8",
+      )
+    }
+  };
+
+  *response.body_mut() = Body::from(body);
+  response
+    .headers_mut()
+    .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+}
@@ -0,0 +1,243 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+
+// Addresses of the connection synthesized for pcapng exports.
+const SERVER_ADDR: [u8; 4] = [10, 0, 0, 1];
+const CLIENT_ADDR: [u8; 4] = [10, 0, 0, 2];
+const SERVER_PORT: u16 = 80;
+const CLIENT_PORT: u16 = 49152;
+
+// The most payload put in one synthesized TCP segment, leaving room for the headers in an IPv4
+// packet. Larger messages span several segments, which analyzers reassemble.
+const MAX_SEGMENT: usize = 65000;
+
+const LINKTYPE_RAW: u16 = 101;
+
+/// Data recorded from a socket, as capture timestamps in microseconds since the Unix epoch and
+/// payloads, oldest first.
+pub type Recording = [(u64, Vec<u8>)];
+
+fn iso8601(timestamp_us: u64) -> String {
+  let secs = (timestamp_us / 1_000_000) as i64;
+  let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+  // Convert days since the epoch to a civil date (Howard Hinnant's algorithm).
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  format!(
+    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+    secs_of_day / 3600,
+    secs_of_day % 3600 / 60,
+    secs_of_day % 60,
+    timestamp_us % 1_000_000 / 1000
+  )
+}
+
+/// Export a recording as a HAR log of one WebSocket connection, with its messages in the
+/// `_webSocketMessages` extension that browsers' developer tools use.
+pub fn har(url: &str, recording: &Recording) -> Value {
+  let started = recording.first().map(|(timestamp_us, _)| *timestamp_us).unwrap_or(0);
+  let messages: Vec<_> = recording
+    .iter()
+    .map(|(timestamp_us, data)| {
+      let time = *timestamp_us as f64 / 1_000_000.0;
+      match std::str::from_utf8(data) {
+        Ok(text) => json!({ "type": "receive", "time": time, "opcode": 1, "data": text }),
+        Err(_) => json!({ "type": "receive", "time": time, "opcode": 2, "data": STANDARD.encode(data) }),
+      }
+    })
+    .collect();
+
+  json!({
+    "log": {
+      "version": "1.2",
+      "creator": { "name": "wardenclyffe", "version": env!("CARGO_PKG_VERSION") },
+      "entries": [{
+        "startedDateTime": iso8601(started),
+        "time": 0,
+        "request": {
+          "method": "GET",
+          "url": url,
+          "httpVersion": "HTTP/1.1",
+          "headers": [],
+          "queryString": [],
+          "cookies": [],
+          "headersSize": -1,
+          "bodySize": 0,
+        },
+        "response": {
+          "status": 101,
+          "statusText": "Switching Protocols",
+          "httpVersion": "HTTP/1.1",
+          "headers": [],
+          "cookies": [],
+          "content": { "size": 0, "mimeType": "" },
+          "redirectURL": "",
+          "headersSize": -1,
+          "bodySize": 0,
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": 0, "receive": 0 },
+        "_resourceType": "websocket",
+        "_webSocketMessages": messages,
+      }],
+    },
+  })
+}
+
+/// Append a pcapng block, padding its body to 32 bits.
+fn push_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+  let padding = (4 - body.len() % 4) % 4;
+  let total_len = (12 + body.len() + padding) as u32;
+  out.extend_from_slice(&block_type.to_le_bytes());
+  out.extend_from_slice(&total_len.to_le_bytes());
+  out.extend_from_slice(body);
+  out.extend(std::iter::repeat_n(0, padding));
+  out.extend_from_slice(&total_len.to_le_bytes());
+}
+
+fn ones_complement_sum(data: &[u8], mut sum: u32) -> u32 {
+  for chunk in data.chunks(2) {
+    let word = u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]);
+    sum += u32::from(word);
+  }
+  sum
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+/// One direction of the synthesized TCP connection.
+struct Flow {
+  src: ([u8; 4], u16),
+  dst: ([u8; 4], u16),
+  seq: u32,
+}
+
+/// Build an IPv4 packet holding a TCP segment from one side of the connection to the other.
+fn segment(from: &mut Flow, ack: u32, payload: &[u8]) -> Vec<u8> {
+  let mut tcp = Vec::with_capacity(20 + payload.len());
+  tcp.extend_from_slice(&from.src.1.to_be_bytes());
+  tcp.extend_from_slice(&from.dst.1.to_be_bytes());
+  tcp.extend_from_slice(&from.seq.to_be_bytes());
+  tcp.extend_from_slice(&ack.to_be_bytes());
+  // 5 words of header, PSH and ACK.
+  tcp.extend_from_slice(&[5 << 4, 0x18]);
+  tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+  tcp.extend_from_slice(&[0, 0, 0, 0]);
+  tcp.extend_from_slice(payload);
+
+  let mut pseudo_header = Vec::with_capacity(12);
+  pseudo_header.extend_from_slice(&from.src.0);
+  pseudo_header.extend_from_slice(&from.dst.0);
+  pseudo_header.extend_from_slice(&[0, 6]);
+  pseudo_header.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+  let checksum = fold_checksum(ones_complement_sum(&tcp, ones_complement_sum(&pseudo_header, 0)));
+  tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+  from.seq = from.seq.wrapping_add(payload.len() as u32);
+
+  let mut ip = Vec::with_capacity(20 + tcp.len());
+  ip.extend_from_slice(&[0x45, 0]);
+  ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+  // No identification, don't fragment, a TTL of 64, and TCP.
+  ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+  ip.extend_from_slice(&from.src.0);
+  ip.extend_from_slice(&from.dst.0);
+  let checksum = fold_checksum(ones_complement_sum(&ip, 0));
+  ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+  ip.extend_from_slice(&tcp);
+  ip
+}
+
+fn push_packet(out: &mut Vec<u8>, timestamp_us: u64, packet: &[u8]) {
+  let mut body = Vec::with_capacity(20 + packet.len());
+  body.extend_from_slice(&0u32.to_le_bytes());
+  body.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+  body.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+  body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+  body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+  body.extend_from_slice(packet);
+  push_block(out, 6, &body);
+}
+
+/// Frame a payload as an unmasked binary WebSocket message, as sent by a server.
+fn websocket_frame(data: &[u8]) -> Vec<u8> {
+  let mut frame = vec![0x82];
+  match data.len() {
+    len if len < 126 => frame.push(len as u8),
+    len if len <= u16::MAX as usize => {
+      frame.push(126);
+      frame.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    len => {
+      frame.push(127);
+      frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+  }
+  frame.extend_from_slice(data);
+  frame
+}
+
+/// Export a recording as a pcapng capture of a plaintext WebSocket connection to `path`, with a
+/// synthesized HTTP upgrade and one binary message per recorded read, which packet analyzers
+/// dissect as WebSocket traffic.
+pub fn pcapng(path: &str, recording: &Recording) -> Vec<u8> {
+  let mut out = Vec::new();
+
+  // Section header: byte order magic, version 1.0, and an unspecified section length.
+  let mut shb = Vec::new();
+  shb.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+  shb.extend_from_slice(&1u16.to_le_bytes());
+  shb.extend_from_slice(&0u16.to_le_bytes());
+  shb.extend_from_slice(&(-1i64).to_le_bytes());
+  push_block(&mut out, 0x0a0d0d0a, &shb);
+
+  // Interface description: raw IP packets, with the default microsecond timestamps.
+  let mut idb = Vec::new();
+  idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+  idb.extend_from_slice(&0u16.to_le_bytes());
+  idb.extend_from_slice(&0u32.to_le_bytes());
+  push_block(&mut out, 1, &idb);
+
+  let mut client = Flow {
+    src: (CLIENT_ADDR, CLIENT_PORT),
+    dst: (SERVER_ADDR, SERVER_PORT),
+    seq: 1,
+  };
+  let mut server = Flow {
+    src: (SERVER_ADDR, SERVER_PORT),
+    dst: (CLIENT_ADDR, CLIENT_PORT),
+    seq: 1,
+  };
+
+  let started = recording.first().map(|(timestamp_us, _)| *timestamp_us).unwrap_or(0);
+  let request = format!(
+    "GET {path} HTTP/1.1\r\nHost: wardenclyffe\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+     Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+  );
+  let packet = segment(&mut client, server.seq, request.as_bytes());
+  push_packet(&mut out, started, &packet);
+  let response = "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                  Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+  let packet = segment(&mut server, client.seq, response.as_bytes());
+  push_packet(&mut out, started, &packet);
+
+  for (timestamp_us, data) in recording {
+    for chunk in websocket_frame(data).chunks(MAX_SEGMENT) {
+      let packet = segment(&mut server, client.seq, chunk);
+      push_packet(&mut out, *timestamp_us, &packet);
+    }
+  }
+  out
+}
@@ -1,4 +1,9 @@
-use std::ffi::{c_char, c_void};
+use std::ffi::{c_char, c_void, CStr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the API between the library and the backend, incremented whenever functions or
+/// structures in this header change incompatibly.
+pub const WARDENCLYFFE_API_VERSION: u32 = 1;
 
 #[repr(transparent)]
 #[derive(Clone, Copy)]
@@ -12,6 +17,56 @@ pub struct WardenclyffeRead {
   pub data: *const c_void,
   pub size: usize,
   pub oob: u8,
+
+  /// Nonzero if this read is a fragment of a larger frame, whose remainder follows in subsequent
+  /// (non-oob) reads. The read that completes the frame has this set to zero.
+  pub more: u8,
+
+  /// For the first fragment of a frame, its total size in bytes, or zero if unknown.
+  pub total_size: usize,
+
+  /// Nonzero if the data is in shared memory (e.g. a memfd or ashmem region) instead of at `data`:
+  /// `size` bytes at `offset` in `fd`. The fd must remain open, and the region unmodified, until the
+  /// next read from the socket, just like `data`.
+  pub shm: u8,
+  pub fd: i32,
+  pub offset: u64,
+
+  /// When the data was captured, in nanoseconds on CLOCK_MONOTONIC (e.g. a graphics buffer's
+  /// timestamp), or zero if the backend doesn't know, in which case the time it was read is used.
+  /// For fragmented frames, only the first fragment's is used.
+  pub timestamp_ns: i64,
+}
+
+impl WardenclyffeRead {
+  /// When the data was captured, in microseconds since the Unix epoch.
+  pub fn capture_time_us(&self) -> u64 {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_micros() as u64)
+      .unwrap_or(0);
+    if self.timestamp_ns <= 0 {
+      return now;
+    }
+    self.monotonic_capture_time_us(now)
+  }
+
+  // Other hosts have no CLOCK_MONOTONIC to compare timestamps against.
+  #[cfg(not(unix))]
+  fn monotonic_capture_time_us(&self, now: u64) -> u64 {
+    now
+  }
+
+  #[cfg(unix)]
+  fn monotonic_capture_time_us(&self, now: u64) -> u64 {
+    let mut monotonic = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut monotonic) };
+    // time_t and c_long are narrower on 32-bit targets.
+    #[allow(clippy::unnecessary_cast)]
+    let monotonic_ns = monotonic.tv_sec as i64 * 1_000_000_000 + monotonic.tv_nsec as i64;
+    let age_us = (monotonic_ns - self.timestamp_ns).max(0) as u64 / 1000;
+    now.saturating_sub(age_us)
+  }
 }
 
 unsafe impl Sync for WardenclyffeRead {}
@@ -23,16 +78,301 @@ pub struct WardenclyffeReads {
   pub read_count: isize,
 }
 
+/// `WardenclyffeReads::read_count` returned by `wardenclyffe_read_timeout` when nothing was read.
+pub const WARDENCLYFFE_READ_TIMEOUT: isize = -2;
+
+/// Error codes with a conventional meaning that `wardenclyffe_socket_error` can return, which are
+/// reported to clients with a matching WebSocket close code unless configured otherwise. Backends
+/// may use other codes, which are passed through as-is.
+pub const WARDENCLYFFE_ERROR_BUSY: i32 = 1;
+pub const WARDENCLYFFE_ERROR_UNAUTHORIZED: i32 = 2;
+pub const WARDENCLYFFE_ERROR_INTERNAL: i32 = 3;
+
 unsafe impl Sync for WardenclyffeReads {}
 unsafe impl Send for WardenclyffeReads {}
 
+/// Flags of a socket listed by `wardenclyffe_list_sockets`.
+pub const WARDENCLYFFE_SOCKET_READ: u32 = 1 << 0;
+pub const WARDENCLYFFE_SOCKET_WRITE: u32 = 1 << 1;
+/// Several clients can have the socket open at once.
+pub const WARDENCLYFFE_SOCKET_SHAREABLE: u32 = 1 << 2;
+
+/// A socket that clients can open.
+#[repr(C)]
+pub struct WardenclyffeSocketInfo {
+  pub path: *const c_char,
+  pub flags: u32,
+  /// MIME type of the data read from the socket (e.g. "video/h264"), or NULL if unknown.
+  pub content_type: *const c_char,
+}
+
+#[repr(C)]
+pub struct WardenclyffeSocketList {
+  pub sockets: *const WardenclyffeSocketInfo,
+  pub count: usize,
+}
+
+/// Identity of the client a socket is being created for.
+#[repr(C)]
+pub struct WardenclyffePeerInfo {
+  /// Remote address of the peer, or NULL for peers on local sockets.
+  pub address: *const c_char,
+  /// Kernel-provided credentials of peers on local sockets, or -1 if unknown.
+  pub uid: i64,
+  pub gid: i64,
+  pub pid: i64,
+
+  /// What was negotiated for peers connected over TLS (e.g. "TLSv1_3", "TLS13_AES_128_GCM_SHA256",
+  /// "h2"), or NULL. The client certificate's subject is NULL if it didn't present one.
+  pub tls_version: *const c_char,
+  pub tls_cipher: *const c_char,
+  pub tls_alpn: *const c_char,
+  pub tls_client_subject: *const c_char,
+
+  /// The client's scopes, space-separated (e.g. "read:/logcat write:/input"), or NULL for anonymous
+  /// clients. The admin token has the "admin" scope, and grants have read and write scopes for
+  /// their path.
+  pub scopes: *const c_char,
+}
+
 extern "C" {
-  pub fn wardenclyffe_create_socket(path: *const c_char) -> WardenclyffeSocket;
+  /// Ask the platform whether `peer` may open `path`, before any socket is created.
+  ///
+  /// On denial, the backend may write a NUL-terminated reason (of at most `reason_len` bytes,
+  /// including the terminator) into `reason`, which is reported to the client.
+  pub fn wardenclyffe_authorize(
+    path: *const c_char,
+    peer: *const WardenclyffePeerInfo,
+    reason: *mut c_char,
+    reason_len: usize,
+  ) -> bool;
+
+  /// Ask the platform to authenticate a bearer token that the server doesn't recognize itself, when
+  /// `auth.platform` is set, e.g. against its own account system. This may block for as long as
+  /// it needs to (e.g. for the user to confirm on the lockscreen).
+  ///
+  /// On success, the platform writes the scopes it grants, space-separated and in the same form as
+  /// `auth.roles` (e.g. "read:/video/* admin"), as a NUL-terminated string of at most `scopes_len`
+  /// bytes (including the terminator) into `scopes`.
+  pub fn wardenclyffe_authenticate(
+    credentials: *const c_char,
+    peer: *const WardenclyffePeerInfo,
+    scopes: *mut c_char,
+    scopes_len: usize,
+  ) -> bool;
+
+  /// List the sockets that clients can open, for discovery. The list must remain valid until the
+  /// next call, and calls are never concurrent.
+  pub fn wardenclyffe_list_sockets() -> WardenclyffeSocketList;
+
+  pub fn wardenclyffe_create_socket(path: *const c_char, peer: *const WardenclyffePeerInfo) -> WardenclyffeSocket;
   pub fn wardenclyffe_destroy_socket(socket: WardenclyffeSocket) -> ();
 
   pub fn wardenclyffe_supports_read(socket: WardenclyffeSocket) -> bool;
-  pub fn wardenclyffe_read(socket: WardenclyffeSocket) -> WardenclyffeReads;
+  pub fn wardenclyffe_read_timeout(socket: WardenclyffeSocket, millis: u32) -> WardenclyffeReads;
 
   pub fn wardenclyffe_supports_write(socket: WardenclyffeSocket) -> bool;
   pub fn wardenclyffe_write(socket: WardenclyffeSocket, data: *const c_void, len: usize) -> bool;
+
+  /// Ask the backend to stop producing data for `socket` until `wardenclyffe_resume`, because the
+  /// client is falling behind. Backends that can't pause cheaply can ignore this, and have data
+  /// dropped instead. Must not block; may be called concurrently with reads.
+  /// Set a parameter of what `socket` produces, like its resolution or frame rate, at the client's
+  /// request. Returns false if the backend refuses, with the reason from `wardenclyffe_socket_error`.
+  pub fn wardenclyffe_set_parameter(socket: WardenclyffeSocket, name: *const c_char, value: *const c_char) -> bool;
+
+  pub fn wardenclyffe_pause(socket: WardenclyffeSocket) -> ();
+  pub fn wardenclyffe_resume(socket: WardenclyffeSocket) -> ();
+
+  /// Describe why the most recent read or write on `socket` failed.
+  ///
+  /// The backend may write a NUL-terminated reason (of at most `reason_len` bytes, including the
+  /// terminator) into `reason`. Returns an error code that is passed along to the client, or 0.
+  pub fn wardenclyffe_socket_error(socket: WardenclyffeSocket, reason: *mut c_char, reason_len: usize) -> i32;
+}
+
+/// Install a UI bundle (a zip file) into the running server, after verifying its Ed25519 signature.
+///
+/// Returns false if no server is running, UI bundle updates aren't enabled, or installation failed.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_install_ui_bundle(
+  bundle: *const u8,
+  bundle_len: usize,
+  signature: *const u8,
+  signature_len: usize,
+) -> bool {
+  let bundle = std::slice::from_raw_parts(bundle, bundle_len);
+  let signature = std::slice::from_raw_parts(signature, signature_len);
+  match crate::bundle::install_ui_bundle(bundle, signature) {
+    Ok(()) => true,
+    Err(err) => {
+      error!("failed to install UI bundle: {err:?}");
+      false
+    }
+  }
+}
+
+/// End the session on `socket` from the backend, e.g. because the screen was locked, so that the
+/// client is told why with `code` (as from `wardenclyffe_socket_error`) and `reason` rather than
+/// just seeing the socket hit EOF. The session's connection is closed once what's already been read
+/// has been sent; call this before returning EOF from reads.
+///
+/// May be called from any thread until the socket is destroyed. Returns false if the socket isn't
+/// open.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_end_session(
+  socket: WardenclyffeSocket,
+  code: i32,
+  reason: *const c_char,
+) -> bool {
+  let reason = CStr::from_ptr(reason).to_string_lossy().into_owned();
+  crate::termination::end_session(socket, code, reason)
+}
+
+/// Set `key` to `value` in the metadata of the WebSocket session on `socket`, which is shown in the
+/// admin sessions listing, or remove it if `value` is NULL. Keys are up to 64 characters from
+/// `[A-Za-z0-9_.-]`, and values up to 256 bytes.
+///
+/// May be called from any thread until the socket is destroyed. Returns false if the socket isn't
+/// a WebSocket session's, or the entry is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_set_metadata(
+  socket: WardenclyffeSocket,
+  key: *const c_char,
+  value: *const c_char,
+) -> bool {
+  let key = CStr::from_ptr(key).to_string_lossy();
+  let value = (!value.is_null()).then(|| CStr::from_ptr(value).to_string_lossy());
+  match crate::metadata::set_metadata(socket, &key, value.as_deref()) {
+    Ok(()) => true,
+    Err(e) => {
+      warn!("failed to set metadata: {e}");
+      false
+    }
+  }
+}
+
+/// Make a socket available to clients at runtime, e.g. when a second display becomes capturable,
+/// announcing it to clients listening on `/api/events`. It's listed with the sockets from
+/// `wardenclyffe_list_sockets` until it's unregistered. `info` is copied.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_register_socket(info: *const WardenclyffeSocketInfo) -> bool {
+  if info.is_null() || (*info).path.is_null() {
+    return false;
+  }
+  crate::announce::register(crate::api::SocketDescription::from_ffi(&*info));
+  true
+}
+
+/// Announce that a socket is no longer available, whether it was registered with
+/// `wardenclyffe_register_socket` or listed by `wardenclyffe_list_sockets`. Returns whether it had
+/// been registered.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_unregister_socket(path: *const c_char) -> bool {
+  let path = CStr::from_ptr(path).to_string_lossy();
+  crate::announce::unregister(&path)
+}
+
+/// Mint a relative URL granting access to `path` for `ttl_secs` seconds, writing it to `out` as a
+/// NUL-terminated string.
+///
+/// Returns the length of the URL (excluding the terminator), or -1 if no server is running or `out`
+/// is too small.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_mint_grant(
+  path: *const c_char,
+  ttl_secs: u32,
+  read_only: bool,
+  out: *mut c_char,
+  out_len: usize,
+) -> isize {
+  let Ok(path) = CStr::from_ptr(path).to_str() else {
+    return -1;
+  };
+
+  match crate::auth::mint_grant_url(path, Duration::from_secs(ttl_secs.into()), read_only) {
+    Ok(url) if url.len() < out_len => {
+      std::ptr::copy_nonoverlapping(url.as_ptr() as *const c_char, out, url.len());
+      *out.add(url.len()) = 0;
+      url.len() as isize
+    }
+
+    Ok(_) => -1,
+
+    Err(err) => {
+      error!("failed to mint grant: {err}");
+      -1
+    }
+  }
+}
+
+/// Checks a message from a client before it's written to a socket, as registered with
+/// `wardenclyffe_add_validator`. Returns true if the message is valid, and otherwise may write a
+/// NUL-terminated reason (of at most `reason_len` bytes, including the terminator) into `reason`,
+/// which is passed along to the client.
+pub type WardenclyffeValidator = unsafe extern "C" fn(
+  context: *mut c_void,
+  data: *const c_void,
+  size: usize,
+  reason: *mut c_char,
+  reason_len: usize,
+) -> bool;
+
+struct ValidatorContext(*mut c_void);
+
+unsafe impl Sync for ValidatorContext {}
+unsafe impl Send for ValidatorContext {}
+
+/// Check what clients write to sockets with paths matching `path` (a glob, where `*` matches any
+/// sequence of characters) with `validator`, which is passed `context`, rejecting messages it
+/// returns false for instead of writing them to the socket. Applies to sessions opened afterwards.
+///
+/// `validator` is called from arbitrary threads, and `context` must stay valid for as long as the
+/// process runs.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_add_validator(
+  path: *const c_char,
+  validator: WardenclyffeValidator,
+  context: *mut c_void,
+) {
+  let path = CStr::from_ptr(path).to_string_lossy();
+  let context = ValidatorContext(context);
+  crate::validate::register(
+    &path,
+    std::sync::Arc::new(move |data: &[u8]| {
+      let context = &context;
+      let mut reason = [0 as c_char; 256];
+      if validator(
+        context.0,
+        data.as_ptr() as *const c_void,
+        data.len(),
+        reason.as_mut_ptr(),
+        reason.len(),
+      ) {
+        return Ok(());
+      }
+      reason[reason.len() - 1] = 0;
+      let reason = CStr::from_ptr(reason.as_ptr()).to_string_lossy();
+      Err(if reason.is_empty() {
+        "message rejected".to_string()
+      } else {
+        reason.into_owned()
+      })
+    }),
+  );
+}
+
+/// Tell the server that a platform condition, like `screen_off` or `user_switched`, started or
+/// ended. Sessions on sockets whose policy lists the condition in `suspend_on` stop reading while
+/// it's active, and resume reading once it's over.
+///
+/// May be called from any thread, at any time.
+#[no_mangle]
+pub unsafe extern "C" fn wardenclyffe_set_condition(condition: *const c_char, active: bool) {
+  let condition = CStr::from_ptr(condition).to_string_lossy();
+  info!(
+    "platform condition {condition} {}",
+    if active { "started" } else { "ended" }
+  );
+  crate::conditions::set(&condition, active);
 }
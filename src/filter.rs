@@ -0,0 +1,114 @@
+use anyhow::{bail, Result};
+use hyper::{Body, Request};
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::auth::query_param;
+use crate::config::SocketPolicy;
+
+// Limits on client-supplied patterns, which are compiled for every session that asks for one.
+const MAX_PATTERN_LEN: usize = 1024;
+const MAX_COMPILED_SIZE: usize = 1 << 20;
+
+// Log levels, from least to most severe.
+const LEVELS: &[u8] = b"VDIWEF";
+
+/// Matches the level of logcat's `threadtime` format (`01-02 03:04:05.678  123  456 W Tag: ...`),
+/// and its `brief` format (`W/Tag( 123): ...`).
+const DEFAULT_LEVEL_PATTERN: &str = r"^(?:\d\d-\d\d \d\d:\d\d:\d\d\.\d+ +\d+ +\d+ ([VDIWEFA]) |([VDIWEFA])/)";
+
+/// Filters what's read from a text socket line by line, before it's sent to a client that only
+/// wants some of it.
+///
+/// Clients ask for lines containing a substring with the `filter` query parameter, matching a
+/// regular expression with `filter_regex`, and at a log level of at least `min_level` (one of
+/// `VDIWEF`). Each read is assumed to hold whole lines.
+pub struct LineFilter {
+  pattern: Option<Regex>,
+  min_level: Option<usize>,
+  level_pattern: Regex,
+}
+
+fn level_rank(level: u8) -> Option<usize> {
+  // Assert is as severe as fatal.
+  let level = if level == b'A' { b'F' } else { level };
+  LEVELS.iter().position(|&l| l == level)
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+  if pattern.len() > MAX_PATTERN_LEN {
+    bail!("filter longer than {MAX_PATTERN_LEN} characters");
+  }
+  Ok(RegexBuilder::new(pattern).size_limit(MAX_COMPILED_SIZE).build()?)
+}
+
+impl LineFilter {
+  /// The filter a client asked for, if any, refusing filters on sockets whose policy doesn't
+  /// allow them.
+  pub fn from_request(req: &Request<Body>, policy: Option<&SocketPolicy>) -> Result<Option<LineFilter>> {
+    let substring = query_param(req, "filter");
+    let regex = query_param(req, "filter_regex");
+    let min_level = query_param(req, "min_level");
+    if substring.is_none() && regex.is_none() && min_level.is_none() {
+      return Ok(None);
+    }
+    if !policy.and_then(|p| p.text_filters).unwrap_or(false) {
+      bail!("this socket can't be filtered");
+    }
+
+    let pattern = match (substring, regex) {
+      (Some(_), Some(_)) => bail!("filter and filter_regex can't be used together"),
+      (Some(substring), None) => Some(compile(&regex::escape(&substring))?),
+      (None, Some(regex)) => Some(compile(&regex)?),
+      (None, None) => None,
+    };
+    let min_level = match min_level.as_deref().map(str::as_bytes) {
+      Some([level]) => match level_rank(level.to_ascii_uppercase()) {
+        Some(rank) => Some(rank),
+        None => bail!("invalid min_level, expected one of V, D, I, W, E, F"),
+      },
+      Some(_) => bail!("invalid min_level, expected one of V, D, I, W, E, F"),
+      None => None,
+    };
+    let level_pattern = policy
+      .and_then(|p| p.level_pattern.as_deref())
+      .unwrap_or(DEFAULT_LEVEL_PATTERN);
+
+    Ok(Some(LineFilter {
+      pattern,
+      min_level,
+      level_pattern: Regex::new(level_pattern)?,
+    }))
+  }
+
+  fn matches(&self, line: &[u8]) -> bool {
+    if let Some(pattern) = &self.pattern {
+      if !pattern.is_match(line) {
+        return false;
+      }
+    }
+    let Some(min_level) = self.min_level else {
+      return true;
+    };
+
+    // Lines without a level, like continuations of a stack trace, can't be told apart from noise,
+    // and are dropped.
+    self
+      .level_pattern
+      .captures(line)
+      .and_then(|captures| captures.iter().skip(1).flatten().next())
+      .and_then(|level| level_rank(*level.as_bytes().first()?))
+      .is_some_and(|rank| rank >= min_level)
+  }
+
+  /// The lines of `data` that pass the filter, or None if none of them do.
+  pub fn apply(&self, data: &[u8]) -> Option<Vec<u8>> {
+    let mut filtered = Vec::new();
+    for line in data.split_inclusive(|&b| b == b'\n') {
+      let text = line.strip_suffix(b"\n").unwrap_or(line);
+      if self.matches(text.strip_suffix(b"\r").unwrap_or(text)) {
+        filtered.extend_from_slice(line);
+      }
+    }
+    (!filtered.is_empty()).then_some(filtered)
+  }
+}
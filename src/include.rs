@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+
+use crate::profile::merge;
+
+// Includes nested deeper than this are assumed to be a cycle.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Read an included configuration file, along with the files it includes in turn.
+fn load(path: &Path, depth: usize) -> Result<Value> {
+  let contents = std::fs::read(path).map_err(|e| anyhow!("failed to read {}: {e}", path.display()))?;
+  let config = serde_json::from_slice(&contents).map_err(|e| anyhow!("failed to parse {}: {e}", path.display()))?;
+  resolve_nested(config, path.parent().unwrap_or(Path::new(".")), depth)
+}
+
+/// The files an include names: the file itself, or a directory's `.json` files in name order.
+fn included_files(path: &Path) -> Result<Vec<PathBuf>> {
+  if !path.is_dir() {
+    return Ok(vec![path.to_path_buf()]);
+  }
+  let mut files = Vec::new();
+  for entry in std::fs::read_dir(path).map_err(|e| anyhow!("failed to read {}: {e}", path.display()))? {
+    let path = entry?.path();
+    if path.extension().is_some_and(|extension| extension == "json") {
+      files.push(path);
+    }
+  }
+  files.sort();
+  Ok(files)
+}
+
+/// Merge the files a configuration's `include` names over it, in order, so that device-specific
+/// overrides can live apart from a common baseline. Each is a configuration file (which can include
+/// others in turn) or a directory of them, and relative paths are relative to `base_dir`.
+pub fn resolve(config: Value, base_dir: &Path) -> Result<Value> {
+  resolve_nested(config, base_dir, 0)
+}
+
+fn resolve_nested(mut config: Value, base_dir: &Path, depth: usize) -> Result<Value> {
+  let include = match config.as_object_mut().and_then(|object| object.remove("include")) {
+    None => return Ok(config),
+    Some(Value::String(path)) => vec![path],
+    Some(Value::Array(paths)) => paths
+      .into_iter()
+      .map(|path| match path {
+        Value::String(path) => Ok(path),
+        _ => bail!("include isn't a list of paths"),
+      })
+      .collect::<Result<_>>()?,
+    Some(_) => bail!("include isn't a list of paths"),
+  };
+  if depth >= MAX_INCLUDE_DEPTH {
+    bail!("includes nested more than {MAX_INCLUDE_DEPTH} deep");
+  }
+
+  for path in include {
+    for file in included_files(&base_dir.join(path))? {
+      merge(&mut config, load(&file, depth + 1)?);
+    }
+  }
+  Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  /// An empty directory for a test's files.
+  fn directory(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wardenclyffe-include-{}-{test}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn write(path: &Path, config: Value) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, config.to_string()).unwrap();
+  }
+
+  #[test]
+  fn merges_included_files_in_order() {
+    let dir = directory("order");
+    write(&dir.join("a.json"), json!({ "port": 1, "auth": { "required": true } }));
+    write(&dir.join("overrides/2.json"), json!({ "port": 3 }));
+    write(
+      &dir.join("overrides/1.json"),
+      json!({ "port": 2, "auth": { "admin_token": "t" } }),
+    );
+    std::fs::write(dir.join("overrides/README"), "not a config").unwrap();
+
+    let config = resolve(json!({ "port": 0, "include": ["a.json", "overrides"] }), &dir).unwrap();
+    assert_eq!(
+      config,
+      json!({ "port": 3, "auth": { "required": true, "admin_token": "t" } })
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn resolves_nested_includes_relative_to_their_file() {
+    let dir = directory("nested");
+    write(&dir.join("sub/a.json"), json!({ "include": "b.json", "port": 1 }));
+    write(&dir.join("sub/b.json"), json!({ "port": 2, "strict": true }));
+
+    // Included files override the file that includes them.
+    let config = resolve(json!({ "include": "sub/a.json" }), &dir).unwrap();
+    assert_eq!(config, json!({ "port": 2, "strict": true }));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn limits_include_depth() {
+    let dir = directory("depth");
+    for i in 1..MAX_INCLUDE_DEPTH {
+      write(
+        &dir.join(format!("{i}.json")),
+        json!({ "include": format!("{}.json", i + 1) }),
+      );
+    }
+    write(&dir.join(format!("{MAX_INCLUDE_DEPTH}.json")), json!({ "port": 1 }));
+    let config = resolve(json!({ "include": "1.json" }), &dir).unwrap();
+    assert_eq!(config, json!({ "port": 1 }));
+
+    write(
+      &dir.join(format!("{MAX_INCLUDE_DEPTH}.json")),
+      json!({ "include": "last.json" }),
+    );
+    write(&dir.join("last.json"), json!({ "port": 2 }));
+    assert!(resolve(json!({ "include": "1.json" }), &dir).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn rejects_include_cycles() {
+    let dir = directory("cycle");
+    write(&dir.join("a.json"), json!({ "include": "b.json" }));
+    write(&dir.join("b.json"), json!({ "include": "a.json" }));
+    write(&dir.join("self.json"), json!({ "include": "self.json" }));
+    assert!(resolve(json!({ "include": "a.json" }), &dir).is_err());
+    assert!(resolve(json!({ "include": "self.json" }), &dir).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn rejects_bad_includes() {
+    let dir = directory("bad");
+    std::fs::write(dir.join("broken.json"), "{").unwrap();
+    assert!(resolve(json!({ "include": "missing.json" }), &dir).is_err());
+    assert!(resolve(json!({ "include": "broken.json" }), &dir).is_err());
+    assert!(resolve(json!({ "include": 1 }), &dir).is_err());
+    assert!(resolve(json!({ "include": ["a.json", 1] }), &dir).is_err());
+    assert_eq!(resolve(json!({ "port": 1 }), &dir).unwrap(), json!({ "port": 1 }));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,142 @@
+use anyhow::{bail, Result};
+use hyper::header::HeaderValue;
+use hyper::{Body, Request};
+use ring::hmac;
+use tungstenite::protocol::Message;
+
+use crate::auth::{credential, query_param, Access};
+
+/// The subprotocol for V2 with every message authenticated, for transports without TLS.
+pub const SUBPROTOCOL: &str = "wardenclyffe.v2+hmac";
+
+// The shortest nonce a client can derive a session key from.
+const MIN_NONCE_LEN: usize = 16;
+
+const TAG_LEN: usize = 32;
+const HEADER_LEN: usize = TAG_LEN + 8 + 1;
+
+const KIND_TEXT: u8 = 1;
+const KIND_BINARY: u8 = 2;
+
+const FROM_SERVER: u8 = b's';
+const FROM_CLIENT: u8 = b'c';
+
+/// The key a connection's messages are authenticated with, kept in its request's extensions once
+/// it's negotiated.
+#[derive(Clone)]
+pub struct SessionKey(pub hmac::Key);
+
+/// Whether the client offered the authenticated subprotocol in its Sec-WebSocket-Protocol header.
+pub fn offered(header: Option<&HeaderValue>) -> bool {
+  header
+    .and_then(|h| h.to_str().ok())
+    .is_some_and(|h| h.split(',').map(str::trim).any(|p| p == SUBPROTOCOL))
+}
+
+/// Derive the key for a connection from the credential it was authorized with, and the nonce the
+/// client chose for it with the `nonce` query parameter.
+///
+/// The credential never travels inside the connection's messages, so an app that can inject into
+/// the connection but never saw it can't forge them. Anonymous clients have no credential, and
+/// can't use the subprotocol.
+pub fn session_key(req: &Request<Body>, access: &Access) -> Result<hmac::Key> {
+  let Some(credential) = credential(req, access) else {
+    bail!("authentication is required for {SUBPROTOCOL}");
+  };
+  let Some(nonce) = query_param(req, "nonce").filter(|nonce| nonce.len() >= MIN_NONCE_LEN) else {
+    bail!("{SUBPROTOCOL} requires a nonce of at least {MIN_NONCE_LEN} characters");
+  };
+
+  let mut context = hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, credential.as_bytes()));
+  context.update(SUBPROTOCOL.as_bytes());
+  context.update(&[0]);
+  context.update(nonce.as_bytes());
+  Ok(hmac::Key::new(hmac::HMAC_SHA256, context.sign().as_ref()))
+}
+
+/// Authenticates the messages of one connection in one direction.
+///
+/// Every data message becomes a binary message of an HMAC-SHA256 tag, a big-endian u64 sequence
+/// number, a byte for the original message's kind (1 for text, 2 for binary), and then its payload.
+/// The tag covers the direction, sequence number, kind and payload, so messages can't be forged,
+/// replayed, reordered, or reflected back at their sender. Control frames are left alone.
+pub struct MessageAuthenticator {
+  key: hmac::Key,
+  direction: u8,
+  seq: u64,
+}
+
+impl MessageAuthenticator {
+  /// Seal messages sent to the client.
+  pub fn sealer(key: hmac::Key) -> Self {
+    MessageAuthenticator {
+      key,
+      direction: FROM_SERVER,
+      seq: 0,
+    }
+  }
+
+  /// Open messages received from the client.
+  pub fn opener(key: hmac::Key) -> Self {
+    MessageAuthenticator {
+      key,
+      direction: FROM_CLIENT,
+      seq: 0,
+    }
+  }
+
+  fn tag(&self, kind: u8, payload: &[u8]) -> hmac::Tag {
+    let mut context = hmac::Context::with_key(&self.key);
+    context.update(&[self.direction]);
+    context.update(&self.seq.to_be_bytes());
+    context.update(&[kind]);
+    context.update(payload);
+    context.sign()
+  }
+
+  pub fn seal(&mut self, msg: Message) -> Message {
+    let (kind, payload) = match msg {
+      Message::Text(text) => (KIND_TEXT, text.into_bytes()),
+      Message::Binary(data) => (KIND_BINARY, data),
+      msg => return msg,
+    };
+
+    let tag = self.tag(kind, &payload);
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(tag.as_ref());
+    buf.extend_from_slice(&self.seq.to_be_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(&payload);
+    self.seq += 1;
+    Message::Binary(buf)
+  }
+
+  pub fn open(&mut self, msg: Message) -> Result<Message> {
+    let data = match msg {
+      Message::Binary(data) => data,
+      Message::Text(_) => bail!("unauthenticated text message"),
+      msg => return Ok(msg),
+    };
+    if data.len() < HEADER_LEN {
+      bail!("truncated message");
+    }
+
+    let (tag, rest) = data.split_at(TAG_LEN);
+    let (seq, rest) = rest.split_at(8);
+    let (kind, payload) = (rest[0], &rest[1..]);
+    if u64::from_be_bytes(seq.try_into().unwrap()) != self.seq {
+      bail!("message out of sequence");
+    }
+    let expected = self.tag(kind, payload);
+    if ring::constant_time::verify_slices_are_equal(expected.as_ref(), tag).is_err() {
+      bail!("invalid message tag");
+    }
+    self.seq += 1;
+
+    match kind {
+      KIND_TEXT => Ok(Message::Text(String::from_utf8(payload.to_vec())?)),
+      KIND_BINARY => Ok(Message::Binary(payload.to_vec())),
+      kind => bail!("unknown message kind {kind}"),
+    }
+  }
+}
@@ -1,27 +1,86 @@
-use std::fs::File;
-use std::io::{self, BufReader};
-use std::net::SocketAddr;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
-use hyper::{
-  server::conn::{AddrIncoming, AddrStream},
-  service::{make_service_fn, service_fn},
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{stream, Future, Stream, TryStreamExt};
+use hyper::server::{
+  accept::Accept,
+  conn::{AddrIncoming, AddrStream},
 };
-use rustls_pemfile::Item;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 #[macro_use]
 extern crate log;
 
+mod acme;
+mod admin;
+mod alloc;
+mod announce;
+mod api;
+mod archive;
+mod attempts;
+mod audit;
+mod auth;
+mod bundle;
 mod cli;
+mod coalesce;
+mod conditions;
 mod config;
+mod connection;
+mod delta;
+mod doctor;
+mod errors;
+mod export;
 mod ffi;
+mod filter;
+mod include;
+mod integrity;
+#[cfg(unix)]
+mod local;
+mod lockout;
+mod logging;
+mod longpoll;
+mod memory;
+mod merge;
+mod metadata;
+mod peer;
+mod peerlimit;
+mod pinning;
+mod pkcs8;
+mod profile;
+mod protocol;
+mod proxy;
+mod ratelimit;
+mod readpool;
+mod redirect;
+mod replay;
+mod roots;
+mod sched;
+mod selfsigned;
 mod server;
+mod session;
+mod shm;
+mod stats;
+mod status;
+mod store;
+mod task;
+mod termination;
 mod tls;
+mod transfer;
+mod upload;
+mod validate;
+mod version;
+mod webhook;
 
+use acme::AcmeResolver;
 use config::Config;
+pub use peer::Peer;
+use selfsigned::SelfSignedResolver;
 use server::*;
-use tls::{TlsAcceptor, TlsStream};
+use tls::{CertificateFiles, CertificateSource, TlsAcceptor, TlsSession, TlsStream};
 
 pub struct Server {
   config: Config,
@@ -60,91 +119,311 @@ impl Server {
     ServerBuilder::from_config(config).build()
   }
 
+  /// Create a server from a JSON configuration, in the same format as the configuration file. Files
+  /// it includes are relative to the working directory, and if it has profiles, the one selected by
+  /// the system property is used.
+  pub fn from_json(json: &str) -> Result<Self> {
+    let config = include::resolve(serde_json::from_str(json)?, Path::new("."))?;
+    let config = profile::resolve(config, profile::from_property().as_deref())?;
+    Ok(Server::from_config(Config::from_value(config)?))
+  }
+
+  /// Mint a relative URL (path and query) granting access to a socket path for `ttl`.
+  ///
+  /// The grant is signed by the currently running server, and fails if no server is running.
+  pub fn mint_grant(path: &str, ttl: Duration, read_only: bool) -> Result<String> {
+    auth::mint_grant_url(path, ttl, read_only)
+  }
+
+  /// Install a signed UI bundle into the currently running server.
+  pub fn install_ui_bundle(bundle: &[u8], signature: &[u8]) -> Result<()> {
+    bundle::install_ui_bundle(bundle, signature)
+  }
+
+  /// Check what clients write to sockets with paths matching the glob `path` with `validator`,
+  /// which returns why a message is invalid, in addition to the configured `validation`. Messages it
+  /// rejects aren't written to the socket. Applies to sessions opened afterwards.
+  pub fn add_validator(path: &str, validator: impl Fn(&[u8]) -> Result<(), String> + Send + Sync + 'static) {
+    validate::register(path, Arc::new(validator));
+  }
+
+  /// The certificate last issued by the configured ACME CA, and its key. Fails if the server isn't
+  /// configured to use ACME, or no certificate has been issued yet.
   pub fn get_acme_certs(&self) -> Result<(rustls::Certificate, rustls::PrivateKey)> {
-    unimplemented!();
+    let Some(config::TLS::Acme(acme)) = &self.config.tls else {
+      bail!("ACME isn't configured");
+    };
+    let (mut chain, key) = acme::load_cached(&acme.cache_dir)?.ok_or_else(|| anyhow!("no certificate issued yet"))?;
+    Ok((chain.remove(0), key))
   }
 
   pub fn load_certs(config: &Config) -> Result<rustls::ServerConfig> {
-    let (cert_chain, key) = match config.tls.as_ref().unwrap_or(&config::TLS::SelfSigned) {
-      config::TLS::SelfSigned => {
-        let self_signed = rcgen::generate_simple_self_signed(vec!["*".into()]).unwrap();
-        let cert = rustls::Certificate(self_signed.serialize_der()?);
-        let key = rustls::PrivateKey(self_signed.serialize_private_key_der());
-        (vec![cert], key)
+    Ok(Server::load_tls(config)?.0)
+  }
+
+  /// Load the TLS configuration, along with the source of the certificate it serves.
+  fn load_tls(config: &Config) -> Result<(rustls::ServerConfig, Arc<dyn CertificateSource>)> {
+    let client_auth = match &config.tls {
+      Some(config::TLS::CertificateWithClientAuth { client_ca_path, .. }) => {
+        rustls::server::AllowAnyAuthenticatedClient::new(roots::load(Some(client_ca_path))?)
       }
+      _ => rustls::server::NoClientAuth::new(),
+    };
+    let (versions, suites) = tls::protocol_settings(config.tls_policy.as_ref())?;
+    let builder = rustls::ServerConfig::builder()
+      .with_cipher_suites(&suites)
+      .with_safe_default_kx_groups()
+      .with_protocol_versions(&versions)?
+      .with_client_cert_verifier(client_auth);
+    let (mut cfg, source): (_, Arc<dyn CertificateSource>) =
+      match config.tls.as_ref().unwrap_or(&config::TLS::SelfSigned) {
+        config::TLS::SelfSigned => {
+          let resolver = Arc::new(SelfSignedResolver::new(config.self_signed.as_ref())?);
+          (builder.with_cert_resolver(resolver.clone()), resolver)
+        }
 
-      config::TLS::Certificate {
-        cert_path,
-        private_key_path,
-      } => {
-        let mut cert_file = BufReader::new(File::open(cert_path)?);
-        let cert_chain = rustls_pemfile::certs(&mut cert_file)?
-          .iter()
-          .map(|vec| rustls::Certificate(vec.clone()))
-          .collect();
-
-        let mut key_file = BufReader::new(File::open(private_key_path)?);
-        let keys = rustls_pemfile::read_all(&mut key_file)?;
-        if keys.len() != 1 {
-          panic!("failed to find key");
+        config::TLS::PersistentSelfSigned { cache_dir } => {
+          let resolver = Arc::new(SelfSignedResolver::persistent(cache_dir, config.self_signed.as_ref())?);
+          (builder.with_cert_resolver(resolver.clone()), resolver)
         }
 
-        if let Item::PKCS8Key(key) = &keys[0] {
-          let key = rustls::PrivateKey(key.clone());
-          (cert_chain, key)
-        } else {
-          panic!("failed to find key");
+        config::TLS::Certificate {
+          cert_path,
+          private_key_path,
+        }
+        | config::TLS::CertificateWithClientAuth {
+          cert_path,
+          private_key_path,
+          ..
+        } => {
+          let resolver = Arc::new(CertificateFiles::load(
+            cert_path,
+            private_key_path,
+            config.private_key_passphrase.clone(),
+          )?);
+          (builder.with_cert_resolver(resolver.clone()), resolver)
         }
-      }
 
-      config::TLS::Disabled => {
-        bail!("TLS not enabled");
-      }
-    };
-    let mut cfg = rustls::ServerConfig::builder()
-      .with_safe_defaults()
-      .with_no_client_auth()
-      .with_single_cert(cert_chain, key)
-      .unwrap();
+        config::TLS::Acme(acme) => {
+          let http_challenges = config
+            .redirect_listener
+            .as_ref()
+            .is_some_and(|listener| listener.acme_challenges.unwrap_or(false));
+          let resolver = Arc::new(AcmeResolver::new(acme, http_challenges)?);
+          (builder.with_cert_resolver(resolver.clone()), resolver)
+        }
+
+        config::TLS::Disabled => {
+          bail!("TLS not enabled");
+        }
+      };
 
     cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-    Ok(cfg)
+    if let Some(config::TLS::Acme(_)) = &config.tls {
+      // Only offered by CAs validating a challenge, so it's never chosen over HTTP.
+      cfg.alpn_protocols.push(acme::ALPN_ACME_TLS.to_vec());
+    }
+    Ok((cfg, source))
   }
 
   pub fn run(self) -> Result<()> {
-    android_logger::init_once(android_logger::Config::default().with_max_level(log::LevelFilter::Info));
-
-    let config = Arc::new(self.config.populate_defaults());
-    let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(async move {
-      let config = config.clone();
-      let addr = format!("0.0.0.0:{}", config.port.unwrap())
-        .parse::<SocketAddr>()
-        .unwrap();
-      if config.tls == Some(config::TLS::Disabled) {
-        let service = make_service_fn(move |conn: &AddrStream| {
-          let config = config.clone();
-          let remote_addr = conn.remote_addr();
-          let service = service_fn(move |req| handle_request(config.clone(), req, remote_addr));
-          async move { Ok::<_, io::Error>(service) }
-        });
-
-        let server = hyper::Server::bind(&addr).serve(service);
-        server.await
+    logging::init();
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(policy) = self.config.threads.as_ref().and_then(|t| t.workers.clone()) {
+      builder.on_thread_start(move || sched::apply(&policy));
+    }
+    let rt = builder.build()?;
+    rt.block_on(self.serve())
+  }
+
+  async fn serve(self) -> Result<()> {
+    version::log_banner();
+    let state = Arc::new(ServerState::new(self.config.populate_defaults())?);
+    state.auth.install();
+    if let Some(ui_bundle) = &state.ui_bundle {
+      ui_bundle.install();
+    }
+
+    #[cfg(not(unix))]
+    if state.config.local_listener.is_some() {
+      warn!("local listeners aren't supported on this platform");
+    }
+    #[cfg(unix)]
+    if let Some(config) = &state.config.local_listener {
+      let listener = local::bind(&config.path)?;
+      let state = state.clone();
+      task::spawn(
+        "local listener",
+        supervise(
+          "local listener",
+          listener,
+          {
+            let state = state.clone();
+            move || local::bind(&state.config.local_listener.as_ref().unwrap().path)
+          },
+          move |listener| local::serve_local(state.clone(), listener),
+        ),
+      );
+    }
+
+    let tls_cfg = if state.config.tls == Some(config::TLS::Disabled) {
+      None
+    } else {
+      let (tls_cfg, certificate) = Server::load_tls(&state.config).expect("failed to load TLS certs");
+      certificate.clone().start();
+      let _ = state.certificate.set(certificate);
+      Some(Arc::new(tls_cfg))
+    };
+    if let Some(config) = &state.config.redirect_listener {
+      if tls_cfg.is_none() {
+        warn!("TLS is disabled, not redirecting HTTP to it");
       } else {
-        let tls_cfg = Arc::new(Server::load_certs(&config).expect("failed to load TLS certs"));
-        let service = make_service_fn(move |conn: &TlsStream| {
-          let config = config.clone();
-          let remote_addr = conn.remote_addr();
-          let service = service_fn(move |req| handle_request(config.clone(), req, remote_addr));
-          async move { Ok::<_, io::Error>(service) }
-        });
-        let incoming = AddrIncoming::bind(&addr).unwrap();
-
-        let server = hyper::Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(service);
-        server.await
+        let port = config.port.unwrap();
+        let incoming = bind(&state.config, port)?;
+        let state = state.clone();
+        task::spawn(
+          "redirect listener",
+          supervise(
+            "redirect listener",
+            incoming,
+            {
+              let state = state.clone();
+              move || bind(&state.config, port)
+            },
+            move |incoming| redirect::serve_redirects(state.clone(), incoming),
+          ),
+        );
       }
-    })?;
-    Ok(())
+    }
+
+    // Fail to start, rather than retrying forever, if the port can't be listened on at all.
+    let incoming = bind(&state.config, state.config.port.unwrap())?;
+    if let Some(webhook) = &state.webhook {
+      webhook.server_started(state.config.port);
+    }
+    task::spawn(
+      "listener",
+      supervise(
+        "listener",
+        incoming,
+        {
+          let state = state.clone();
+          move || bind(&state.config, state.config.port.unwrap())
+        },
+        move |incoming| listen(state.clone(), tls_cfg.clone(), incoming),
+      ),
+    )
+    .await?
   }
+
+  /// Serve connections established by the embedder, rather than listening on a port.
+  ///
+  /// TLS is not applied to these connections, and the configured port is ignored.
+  pub async fn serve_with_incoming<S, IO>(self, incoming: S) -> Result<()>
+  where
+    S: Stream<Item = io::Result<(IO, Peer)>> + Send,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    version::log_banner();
+    let state = Arc::new(ServerState::new(self.config.populate_defaults())?);
+    state.auth.install();
+    let incoming = incoming.map_ok(|(io, peer)| (io, peer, None));
+    connection::serve_connections(state, incoming).await
+  }
+}
+
+/// Bind `port` on the configured address.
+fn bind(config: &Config, port: u16) -> Result<AddrIncoming> {
+  let addr = peer::parse_listen_addr(config.listen_address.as_deref().unwrap_or("0.0.0.0"), port)?;
+  AddrIncoming::bind(&addr).with_context(|| format!("can't listen on {addr}"))
+}
+
+/// Serve connections to the configured port until the server fails.
+async fn listen(
+  state: Arc<ServerState>,
+  tls_cfg: Option<Arc<rustls::ServerConfig>>,
+  incoming: AddrIncoming,
+) -> Result<()> {
+  let config = &state.config;
+  match tls_cfg {
+    None => {
+      let incoming = accept_stream(incoming, |conn: &AddrStream| (Peer::Inet(conn.remote_addr()), None));
+      connection::serve_connections(state.clone(), incoming).await
+    }
+    Some(tls_cfg) => {
+      let limits = config.limits.as_ref().unwrap();
+      let acceptor = TlsAcceptor::new(
+        tls_cfg,
+        incoming,
+        Duration::from_millis(limits.tls_handshake_timeout_ms.unwrap()),
+        limits.max_tls_handshakes.unwrap(),
+        state.stats.clone(),
+        state.failed_attempts.clone(),
+      );
+      let incoming = accept_stream(acceptor, |conn: &TlsStream| {
+        (Peer::Inet(conn.remote_addr()), Some(conn.session()))
+      });
+      connection::serve_connections(state.clone(), incoming).await
+    }
+  }
+}
+
+const MIN_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Run a listener that's been bound successfully forever, restarting it (binding again with `bind`)
+/// with exponential backoff whenever it stops.
+///
+/// A listener that dies (e.g. during a storm of EMFILE) would otherwise leave the device unreachable
+/// until someone restarts us by hand. Failing to bind in the first place is left to the caller, so
+/// that misconfiguration fails loudly.
+async fn supervise<L, B, F, Fut>(name: &str, listener: L, mut bind: B, mut serve: F) -> Result<()>
+where
+  B: FnMut() -> Result<L>,
+  F: FnMut(L) -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  let mut listener = Some(listener);
+  let mut backoff = MIN_RESTART_BACKOFF;
+  loop {
+    let started = Instant::now();
+    let result = match listener.take().map_or_else(&mut bind, Ok) {
+      Ok(listener) => serve(listener).await,
+      Err(e) => Err(e),
+    };
+    match result {
+      Ok(()) => error!("{name} exited unexpectedly"),
+      Err(e) => error!("{name} failed: {e:?}"),
+    }
+
+    // Only back off further if the listener keeps dying quickly.
+    if started.elapsed() >= MAX_RESTART_BACKOFF {
+      backoff = MIN_RESTART_BACKOFF;
+    }
+    warn!("restarting {name} in {backoff:?}");
+    tokio::time::sleep(backoff).await;
+    backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+  }
+}
+
+/// Adapt a hyper acceptor into a stream of connections, their peers, and their TLS sessions.
+fn accept_stream<A, F>(mut acceptor: A, peer: F) -> impl Stream<Item = io::Result<(A::Conn, Peer, Option<TlsSession>)>>
+where
+  A: Accept<Error = io::Error> + Unpin,
+  F: Fn(&A::Conn) -> (Peer, Option<TlsSession>),
+{
+  stream::poll_fn(move |cx| {
+    Pin::new(&mut acceptor).poll_accept(cx).map(|conn| {
+      conn.map(|conn| {
+        conn.map(|conn| {
+          let (peer, session) = peer(&conn);
+          (conn, peer, session)
+        })
+      })
+    })
+  })
 }
@@ -0,0 +1,76 @@
+use std::io;
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixListener as StdUnixListener};
+use std::sync::Arc;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::linux::net::SocketAddrExt;
+
+use anyhow::{Context, Result};
+use futures_util::stream;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::connection::serve_connections;
+use crate::peer::Peer;
+use crate::server::ServerState;
+
+/// Listen on a Unix domain socket, or an abstract socket name prefixed with '@'.
+pub fn bind(path: &str) -> Result<UnixListener> {
+  bind_path(path).with_context(|| format!("can't listen on local socket {path}"))
+}
+
+fn bind_path(path: &str) -> io::Result<UnixListener> {
+  // Names starting with '@' live in the abstract namespace.
+  let addr = match path.strip_prefix('@') {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Some(name) => UnixSocketAddr::from_abstract_name(name)?,
+    _ => {
+      let _ = std::fs::remove_file(path);
+      UnixSocketAddr::from_pathname(path)?
+    }
+  };
+
+  let listener = StdUnixListener::bind_addr(&addr)?;
+  listener.set_nonblocking(true)?;
+  UnixListener::from_std(listener)
+}
+
+fn peer(stream: &UnixStream) -> io::Result<Peer> {
+  let cred = stream.peer_cred()?;
+  Ok(Peer::Local {
+    uid: cred.uid(),
+    gid: cred.gid(),
+    pid: cred.pid(),
+  })
+}
+
+/// Serve plaintext HTTP on a Unix domain socket, identifying peers by their kernel credentials.
+pub async fn serve_local(state: Arc<ServerState>, listener: UnixListener) -> Result<()> {
+  let config = state.config.local_listener.as_ref().unwrap();
+  info!("listening on local socket {}", config.path);
+
+  let allowed_uids = config.allowed_uids.clone();
+  let incoming = stream::unfold(listener, move |listener| {
+    let allowed_uids = allowed_uids.clone();
+    async move {
+      loop {
+        let result = match listener.accept().await {
+          Ok((stream, _)) => match peer(&stream) {
+            Ok(Peer::Local { uid, .. }) if allowed_uids.as_ref().map(|u| !u.contains(&uid)).unwrap_or(false) => {
+              warn!("rejecting local connection from uid {uid}");
+              continue;
+            }
+            Ok(peer) => Ok((stream, peer, None)),
+            Err(e) => {
+              warn!("failed to get peer credentials: {e}");
+              continue;
+            }
+          },
+          Err(e) => Err(e),
+        };
+        return Some((result, listener));
+      }
+    }
+  });
+
+  serve_connections(state, incoming).await
+}
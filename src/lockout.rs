@@ -0,0 +1,79 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::peer::{Host, Peer};
+use crate::stats::Stats;
+
+#[derive(Default)]
+struct Source {
+  /// When recent failures happened, oldest first.
+  failures: VecDeque<Instant>,
+  banned_until: Option<Instant>,
+}
+
+/// Locks out addresses that fail to authenticate too often, fail2ban-style. Local peers are never
+/// locked out.
+pub struct Lockout {
+  max_failures: usize,
+  window: Duration,
+  ban: Duration,
+  sources: Mutex<HashMap<Host, Source>>,
+  stats: Arc<Stats>,
+}
+
+impl Lockout {
+  pub fn new(config: &config::Lockout, stats: Arc<Stats>) -> Lockout {
+    Lockout {
+      max_failures: config.max_failures.unwrap(),
+      window: Duration::from_millis(config.window_ms.unwrap()),
+      ban: Duration::from_millis(config.ban_ms.unwrap()),
+      sources: Default::default(),
+      stats,
+    }
+  }
+
+  pub fn record_failure(&self, peer: &Peer) {
+    let Some(host) = peer.host() else {
+      return;
+    };
+    let now = Instant::now();
+    let mut sources = self.sources.lock().unwrap();
+
+    // Forget about addresses that have gone quiet.
+    sources.retain(|_, source| {
+      source.banned_until.is_some_and(|until| until > now)
+        || source.failures.back().is_some_and(|last| now - *last < self.window)
+    });
+
+    let source = sources.entry(host).or_default();
+    if source.banned_until.is_some_and(|until| until > now) {
+      return;
+    }
+    while source.failures.front().is_some_and(|first| now - *first >= self.window) {
+      source.failures.pop_front();
+    }
+    source.failures.push_back(now);
+    if source.failures.len() >= self.max_failures {
+      warn!(
+        "{}: {} authentication failures within {:?}, locking out for {:?}",
+        host,
+        source.failures.len(),
+        self.window,
+        self.ban
+      );
+      Stats::increment(&self.stats.auth_lockouts);
+      source.failures.clear();
+      source.banned_until = Some(now + self.ban);
+    }
+  }
+
+  /// How much longer a peer is locked out for, if it is.
+  pub fn remaining(&self, peer: &Peer) -> Option<Duration> {
+    let host = peer.host()?;
+    let sources = self.sources.lock().unwrap();
+    let until = sources.get(&host)?.banned_until?;
+    until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero())
+  }
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+
+/// Which log records are emitted: those at `level` or more severe, unless the most specific of
+/// `modules` that contains their target says otherwise.
+#[derive(Clone)]
+pub struct LogFilter {
+  level: LevelFilter,
+
+  /// Levels for targets by module path prefix, e.g. `wardenclyffe::server`.
+  modules: BTreeMap<String, LevelFilter>,
+}
+
+impl LogFilter {
+  /// Parse a filter in `RUST_LOG` syntax, e.g. `info,wardenclyffe::server=debug`.
+  pub fn parse(spec: &str) -> Result<LogFilter> {
+    let mut filter = DEFAULT_FILTER;
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+      let invalid = || anyhow!("invalid log filter directive {directive:?}");
+      match directive.split_once('=') {
+        Some((module, level)) => {
+          filter
+            .modules
+            .insert(module.to_string(), level.parse().map_err(|_| invalid())?);
+        }
+        None => filter.level = directive.parse().map_err(|_| invalid())?,
+      }
+    }
+    Ok(filter)
+  }
+
+  fn level_for(&self, target: &str) -> LevelFilter {
+    // Later prefixes in the BTreeMap are longer, and so more specific.
+    self
+      .modules
+      .iter()
+      .rev()
+      .find(|(module, _)| {
+        target
+          .strip_prefix(module.as_str())
+          .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+      })
+      .map(|(_, level)| *level)
+      .unwrap_or(self.level)
+  }
+
+  fn max_level(&self) -> LevelFilter {
+    self.modules.values().copied().fold(self.level, |a, b| a.max(b))
+  }
+}
+
+impl fmt::Display for LogFilter {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.level.as_str().to_ascii_lowercase())?;
+    for (module, level) in &self.modules {
+      write!(f, ",{module}={}", level.as_str().to_ascii_lowercase())?;
+    }
+    Ok(())
+  }
+}
+
+const DEFAULT_FILTER: LogFilter = LogFilter {
+  level: LevelFilter::Info,
+  modules: BTreeMap::new(),
+};
+
+static FILTER: RwLock<LogFilter> = RwLock::new(DEFAULT_FILTER);
+
+// How many of the most recent warnings and errors are kept for the status page.
+const MAX_RECENT_PROBLEMS: usize = 50;
+
+static RECENT_PROBLEMS: Mutex<VecDeque<Value>> = Mutex::new(VecDeque::new());
+
+/// The most recent warnings and errors logged, oldest first.
+pub fn recent_problems() -> Vec<Value> {
+  RECENT_PROBLEMS.lock().unwrap().iter().cloned().collect()
+}
+
+fn record_problem(record: &Record) {
+  let time = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis())
+    .unwrap_or(0);
+  let mut problems = RECENT_PROBLEMS.lock().unwrap();
+  if problems.len() == MAX_RECENT_PROBLEMS {
+    problems.pop_front();
+  }
+  problems.push_back(json!({
+    "time": time,
+    "level": record.level().as_str(),
+    "target": record.target(),
+    "message": record.args().to_string(),
+  }));
+}
+
+/// The platform's logger, with records filtered by the current `LogFilter` first.
+struct FilteredLogger<L>(L);
+
+impl<L: Log> Log for FilteredLogger<L> {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    let level = FILTER.read().unwrap().level_for(metadata.target());
+    metadata.level() <= level && self.0.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record) {
+    if self.enabled(record.metadata()) {
+      if record.level() <= Level::Warn {
+        record_problem(record);
+      }
+      self.0.log(record);
+    }
+  }
+
+  fn flush(&self) {
+    self.0.flush();
+  }
+}
+
+/// Install the logger, to logcat on Android and to stderr elsewhere, where `RUST_LOG` sets the
+/// initial filter.
+pub fn init() {
+  #[cfg(target_os = "android")]
+  let (logger, filter) = (
+    android_logger::AndroidLogger::new(android_logger::Config::default().with_max_level(LevelFilter::Trace)),
+    DEFAULT_FILTER,
+  );
+  #[cfg(not(target_os = "android"))]
+  let (logger, filter) = (
+    env_logger::Builder::new().filter_level(LevelFilter::Trace).build(),
+    std::env::var("RUST_LOG")
+      .ok()
+      .and_then(|spec| LogFilter::parse(&spec).ok())
+      .unwrap_or(DEFAULT_FILTER),
+  );
+
+  if log::set_boxed_logger(Box::new(FilteredLogger(logger))).is_ok() {
+    set_filter(filter);
+  }
+}
+
+/// The current filter.
+pub fn filter() -> LogFilter {
+  FILTER.read().unwrap().clone()
+}
+
+/// Replace the filter of the running server.
+pub fn set_filter(filter: LogFilter) {
+  log::set_max_level(filter.max_level());
+  *FILTER.write().unwrap() = filter;
+}
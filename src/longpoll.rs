@@ -0,0 +1,435 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{c_void, CString};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+use tokio::sync::{Notify, RwLock};
+
+use crate::api;
+use crate::audit::AuditEvent;
+use crate::auth::{credential, query_param, Access};
+use crate::coalesce::{Assembly, Batch};
+use crate::conditions::Suspension;
+use crate::errors::BackendError;
+use crate::ffi::*;
+use crate::peer::{Peer, PeerInfo};
+use crate::server::{authorize_socket, json_response, socket_policy, text_response, user_agent, ServerState};
+use crate::shm;
+use crate::stats::{CloseReason, Stats};
+use crate::task;
+use crate::termination::Termination;
+use crate::tls::TlsInfo;
+use crate::validate::Validator;
+
+/// Something read from the backend, waiting for the client to poll for it.
+enum Item {
+  Data(Batch),
+  Oob(Vec<u8>),
+}
+
+/// A socket opened by a long-polling client. It's read from in the background, into a queue that
+/// the client drains with each poll.
+struct Session {
+  id: String,
+  peer: Peer,
+  request_path: String,
+  socket_path: String,
+  identity: String,
+
+  /// The token the session was opened with, to close it if that's revoked.
+  credential: Option<String>,
+
+  /// The backend socket, until it's destroyed. Writes hold it for reading, so that it isn't
+  /// destroyed under them.
+  socket: Arc<RwLock<Option<WardenclyffeSocket>>>,
+  termination: Arc<Termination>,
+  supports_write: bool,
+  validator: Mutex<Option<Validator>>,
+
+  queue: Mutex<VecDeque<Item>>,
+  notify: Notify,
+
+  /// Set once nothing more will be read, because the socket hit EOF or failed, or the client closed
+  /// the session.
+  closed: AtomicBool,
+  error: Mutex<Option<BackendError>>,
+  last_poll: Mutex<Instant>,
+}
+
+impl Session {
+  fn push(&self, item: Item) {
+    self.queue.lock().unwrap().push_back(item);
+    self.notify.notify_one();
+  }
+
+  fn close(&self) {
+    self.closed.store(true, Ordering::Relaxed);
+    self.notify.notify_one();
+  }
+}
+
+/// Open long-polling sessions, by ID.
+#[derive(Default)]
+pub struct LongPollSessions(Mutex<HashMap<String, Arc<Session>>>);
+
+impl LongPollSessions {
+  fn get(&self, id: &str) -> Option<Arc<Session>> {
+    self.0.lock().unwrap().get(id).cloned()
+  }
+
+  fn insert(&self, session: Arc<Session>) {
+    self.0.lock().unwrap().insert(session.id.clone(), session);
+  }
+
+  fn remove(&self, id: &str) {
+    self.0.lock().unwrap().remove(id);
+  }
+}
+
+/// Handle a request to `/lp/<request_path>`.
+///
+/// A POST without a session opens one, returning its ID. With `?session=<id>`, GET waits for data
+/// from the socket, POST writes its body to the socket, and DELETE closes the session.
+pub async fn handle_long_poll(
+  state: Arc<ServerState>,
+  req: Request<Body>,
+  peer: Peer,
+  request_path: &str,
+) -> Result<Response<Body>> {
+  let Some(id) = query_param(&req, "session") else {
+    if req.method() != Method::POST {
+      return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+    }
+    return open_session(state, req, peer, request_path).await;
+  };
+
+  let session = match state.long_poll.get(&id) {
+    Some(session) if session.request_path == request_path => session,
+    _ => return Ok(text_response(StatusCode::NOT_FOUND, "no such session")),
+  };
+
+  Ok(match *req.method() {
+    Method::GET => poll(&state, &session).await,
+    Method::POST => write(&state, session, req).await?,
+    Method::DELETE => {
+      info!("{peer}: long-poll session {id} closed by client");
+      session.close();
+      text_response(StatusCode::NO_CONTENT, "")
+    }
+    _ => text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+  })
+}
+
+async fn open_session(
+  state: Arc<ServerState>,
+  req: Request<Body>,
+  peer: Peer,
+  request_path: &str,
+) -> Result<Response<Body>> {
+  let (access, socket_path) = match authorize_socket(&state, &req, peer, request_path).await {
+    Ok(authorized) => authorized,
+    Err(response) => return Ok(response),
+  };
+
+  if !matches!(access, Access::Anonymous) {
+    state.audit.record(
+      &peer,
+      AuditEvent::AuthSuccess {
+        path: request_path,
+        identity: access.identity(),
+      },
+    );
+  }
+
+  let path = CString::new(socket_path.as_str())?;
+  let scopes = access.scopes();
+  let peer_info = PeerInfo::new(&peer, req.extensions().get::<TlsInfo>(), scopes.as_deref());
+  let socket = unsafe { wardenclyffe_create_socket(path.as_ptr(), &peer_info.as_ffi()) };
+  if socket.0.is_null() {
+    error!("{peer}: failed to create socket {socket_path}");
+    return Ok(text_response(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "failed to create socket",
+    ));
+  }
+
+  let supports_read = unsafe { wardenclyffe_supports_read(socket) } && !access.write_only();
+  let supports_write = unsafe { wardenclyffe_supports_write(socket) } && !access.read_only();
+
+  let mut id = [0u8; 16];
+  SystemRandom::new()
+    .fill(&mut id)
+    .map_err(|_| anyhow::anyhow!("failed to generate session ID"))?;
+  let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+
+  let content_type = api::content_type(&state.config, &socket_path).await;
+  let validation = socket_policy(&state.config, &socket_path).and_then(|p| p.validation.as_ref());
+  let validator = Validator::new(validation, &socket_path);
+  let session = Arc::new(Session {
+    id: id.clone(),
+    peer,
+    request_path: request_path.to_string(),
+    socket_path,
+    identity: access.identity(),
+    credential: credential(&req, &access),
+    socket: Arc::new(RwLock::new(Some(socket))),
+    termination: Termination::register(socket),
+    supports_write,
+    validator: Mutex::new(validator),
+    queue: Mutex::new(VecDeque::new()),
+    notify: Notify::new(),
+    closed: AtomicBool::new(false),
+    error: Mutex::new(None),
+    last_poll: Mutex::new(Instant::now()),
+  });
+
+  let user_agent = user_agent(&req);
+  info!(
+    "{peer}: long-poll session {id} opened (socket = {}, access = {access:?}, user_agent = {user_agent:?})",
+    session.socket_path
+  );
+  state.audit.record(
+    &peer,
+    AuditEvent::SessionOpened {
+      path: &session.socket_path,
+      identity: session.identity.clone(),
+      user_agent,
+    },
+  );
+  state.long_poll.insert(session.clone());
+  task::spawn("long-poll session", run_session(state, session, supports_read));
+
+  Ok(json_response(&json!({
+    "session": id,
+    "read": supports_read,
+    "write": supports_write,
+    "content_type": content_type,
+  })))
+}
+
+/// Wait for data to be read from the socket, and return everything that's queued.
+async fn poll(state: &ServerState, session: &Session) -> Response<Body> {
+  let long_poll = state.config.long_poll.as_ref().unwrap();
+  let deadline = tokio::time::Instant::now() + Duration::from_millis(long_poll.poll_timeout_ms.unwrap());
+  *session.last_poll.lock().unwrap() = Instant::now();
+
+  loop {
+    let items: Vec<Item> = session.queue.lock().unwrap().drain(..).collect();
+    let closed = session.closed.load(Ordering::Relaxed);
+    if items.is_empty()
+      && !closed
+      && tokio::time::timeout_at(deadline, session.notify.notified())
+        .await
+        .is_ok()
+    {
+      continue;
+    }
+
+    *session.last_poll.lock().unwrap() = Instant::now();
+    let messages: Vec<_> = items
+      .iter()
+      .map(|item| match item {
+        Item::Data(batch) => json!({
+          "oob": false,
+          "data": STANDARD.encode(&batch.data),
+          "timestamp_us": batch.timestamp_us,
+        }),
+        Item::Oob(data) => json!({ "oob": true, "data": STANDARD.encode(data) }),
+      })
+      .collect();
+
+    // Everything's been delivered once the client sees that the session is closed.
+    let closed = closed && session.queue.lock().unwrap().is_empty();
+    if closed {
+      state.long_poll.remove(&session.id);
+    }
+
+    let error = session.error.lock().unwrap();
+    return json_response(&json!({
+      "messages": messages,
+      "closed": closed,
+      "error": error.as_ref().map(|error| json!({ "code": error.code, "reason": error.reason })),
+    }));
+  }
+}
+
+async fn write(state: &ServerState, session: Arc<Session>, req: Request<Body>) -> Result<Response<Body>> {
+  if !session.supports_write {
+    return Ok(text_response(StatusCode::FORBIDDEN, "socket is read-only"));
+  }
+
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  if let Some(validator) = &mut *session.validator.lock().unwrap() {
+    if let Err(reason) = validator.check(&body) {
+      warn!("{}: rejected message from client: {reason}", session.peer);
+      Stats::increment(&state.stats.inbound_messages_rejected);
+      return Ok(text_response(StatusCode::UNPROCESSABLE_ENTITY, reason));
+    }
+  }
+  let socket = session.socket.clone().read_owned().await;
+  if socket.is_none() {
+    return Ok(text_response(StatusCode::GONE, "session closed"));
+  }
+
+  let result = task::spawn_blocking("wardenclyffe_write", move || {
+    let socket = socket.unwrap();
+    if unsafe { wardenclyffe_write(socket, body.as_ptr() as *const c_void, body.len()) } {
+      Ok(())
+    } else {
+      Err(unsafe { BackendError::last(socket, "write failed") })
+    }
+  })
+  .await
+  .expect("failed to join");
+  let Err(error) = result else {
+    return Ok(text_response(StatusCode::NO_CONTENT, ""));
+  };
+
+  error!("{}: WardenclyffeSocket::write failed: {error:?}", session.peer);
+  let response = text_response(StatusCode::BAD_GATEWAY, error.reason.clone());
+  *session.error.lock().unwrap() = Some(error);
+  session.close();
+  Ok(response)
+}
+
+/// Read from the socket into the session's queue until it's closed, and then destroy the socket.
+async fn run_session(state: Arc<ServerState>, session: Arc<Session>, supports_read: bool) {
+  let peer = session.peer;
+  let id = &session.id;
+  let socket = session.socket.read().await.unwrap();
+  let read_timeout = state.config.websocket.as_ref().unwrap().read_timeout_ms.unwrap();
+  let idle_timeout = Duration::from_millis(state.config.long_poll.as_ref().unwrap().idle_timeout_ms.unwrap());
+  let socket_policy = socket_policy(&state.config, &session.socket_path);
+  let max_duration = socket_policy.and_then(|p| p.max_session_ms).map(Duration::from_millis);
+  let suspension = Suspension::new(socket_policy.and_then(|p| p.suspend_on.as_deref()));
+  let opened = Instant::now();
+
+  // A frame that the backend is reading in fragments, and whether it's being dropped.
+  let mut assembly: Option<Assembly> = None;
+  let mut dropping = false;
+
+  let reason = loop {
+    // Sessions are closed by the client, or when writing to the socket fails.
+    if session.closed.load(Ordering::Relaxed) {
+      break if session.error.lock().unwrap().is_some() {
+        CloseReason::BackendError
+      } else {
+        CloseReason::Client(None)
+      };
+    }
+    if session.last_poll.lock().unwrap().elapsed() >= idle_timeout {
+      info!("{peer}: long-poll session {id} timed out");
+      break CloseReason::IdleTimeout;
+    }
+    if max_duration.is_some_and(|max| opened.elapsed() >= max) {
+      info!("{peer}: long-poll session {id} reached its time limit");
+      *session.error.lock().unwrap() = Some(BackendError {
+        code: 0,
+        reason: "session time limit reached".into(),
+      });
+      break CloseReason::MaxDuration;
+    }
+    if session.credential.as_deref().is_some_and(|c| state.auth.is_revoked(c)) {
+      info!("{peer}: long-poll session {id}'s credentials were revoked");
+      *session.error.lock().unwrap() = Some(BackendError {
+        code: WARDENCLYFFE_ERROR_UNAUTHORIZED,
+        reason: "credentials revoked".into(),
+      });
+      break CloseReason::CredentialsRevoked;
+    }
+    if let Some(error) = session.termination.error() {
+      info!("{peer}: backend ended long-poll session {id}: {error:?}");
+      *session.error.lock().unwrap() = Some(error);
+      break CloseReason::BackendEnded;
+    }
+
+    // Nothing's read while the platform is in a condition that suspends the socket.
+    if !supports_read || suspension.as_ref().is_some_and(|s| s.active().is_some()) {
+      tokio::time::sleep(Duration::from_millis(read_timeout.into())).await;
+      continue;
+    }
+
+    let reads = state.reads.read(socket, read_timeout).await;
+
+    if reads.read_count == WARDENCLYFFE_READ_TIMEOUT || (reads.read_count <= 0 && session.termination.error().is_some())
+    {
+      continue;
+    } else if reads.read_count < 0 {
+      let error = unsafe { BackendError::last(socket, "read failed") };
+      error!(
+        "{peer}: WardenclyffeSocket::read failed: rc = {}, error = {error:?}",
+        reads.read_count
+      );
+      *session.error.lock().unwrap() = Some(error);
+      break CloseReason::BackendError;
+    } else if reads.read_count == 0 {
+      info!("{peer}: WardenclyffeSocket hit EOF");
+      break CloseReason::BackendEof;
+    }
+
+    let reads = unsafe { std::slice::from_raw_parts(reads.reads, reads.read_count as usize) };
+    for read in reads {
+      let read_data = match unsafe { shm::read_data(read) } {
+        Ok(data) => data,
+        Err(e) => {
+          error!("{peer}: failed to map shared memory read: {e}");
+          continue;
+        }
+      };
+      let data = &read_data[..];
+      if read.oob != 0 {
+        session.push(Item::Oob(data.to_vec()));
+        continue;
+      }
+
+      let more = read.more != 0;
+      if dropping {
+        dropping = more;
+        continue;
+      }
+
+      // Like WebSocket clients, long-polling clients that don't keep up lose data.
+      let Some(reservation) = state.memory.try_reserve(read.size as u64) else {
+        Stats::increment(&state.stats.websocket_messages_dropped);
+        debug!("{peer}: memory budget exhausted, dropping {} byte message", read.size);
+        assembly = None;
+        dropping = more;
+        continue;
+      };
+
+      let mut frame = assembly
+        .take()
+        .unwrap_or_else(|| Assembly::with_capacity(read.total_size, read.capture_time_us()));
+      frame.push(data, Some(reservation));
+      if more {
+        assembly = Some(frame);
+      } else {
+        session.push(Item::Data(frame.finish()));
+      }
+    }
+  };
+
+  state.stats.session_closed(reason);
+  session.close();
+  if let Some(socket) = session.socket.write().await.take() {
+    Termination::unregister(socket);
+    unsafe { wardenclyffe_destroy_socket(socket) };
+  }
+  state.audit.record(
+    &peer,
+    AuditEvent::SessionClosed {
+      path: &session.socket_path,
+      identity: session.identity.clone(),
+    },
+  );
+
+  // Give the client a chance to collect what's left before forgetting about the session.
+  tokio::time::sleep(idle_timeout).await;
+  state.long_poll.remove(id);
+}
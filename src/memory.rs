@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// A global budget for memory buffered on behalf of clients.
+///
+/// Buffers are accounted for by holding a `Reservation` for as long as they're alive.
+pub struct MemoryBudget {
+  limit: u64,
+  used: AtomicU64,
+}
+
+/// Bytes reserved from a `MemoryBudget`, released when dropped.
+pub struct Reservation {
+  budget: Arc<MemoryBudget>,
+  bytes: u64,
+}
+
+impl Drop for Reservation {
+  fn drop(&mut self) {
+    self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+  }
+}
+
+impl MemoryBudget {
+  pub fn new(limit: u64) -> MemoryBudget {
+    MemoryBudget {
+      limit,
+      used: AtomicU64::new(0),
+    }
+  }
+
+  /// Reserve `bytes`, or return None if that would exceed the budget.
+  pub fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+    self
+      .used
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+        used.checked_add(bytes).filter(|&total| total <= self.limit)
+      })
+      .ok()?;
+    Some(Reservation {
+      budget: self.clone(),
+      bytes,
+    })
+  }
+
+  pub fn limit(&self) -> u64 {
+    self.limit
+  }
+
+  pub fn used(&self) -> u64 {
+    self.used.load(Ordering::Relaxed)
+  }
+}
@@ -0,0 +1,413 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::sync::mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::frame::CloseFrame;
+use tungstenite::protocol::{Message, Role};
+
+use crate::audit::AuditEvent;
+use crate::auth::{credential, query_param, Access};
+use crate::coalesce::{Assembly, Batch};
+use crate::errors::BackendError;
+use crate::ffi::*;
+use crate::integrity::{MessageAuthenticator, SessionKey};
+use crate::peer::{Peer, PeerInfo};
+use crate::peerlimit::PeerPermit;
+use crate::protocol::{Features, Hello, Protocol};
+use crate::server::{
+  authorize_socket, negotiate_protocol, switching_protocols, text_response, user_agent, ServerState,
+};
+use crate::shm;
+use crate::stats::{CloseReason, Stats};
+use crate::task;
+use crate::termination::Termination;
+use crate::tls::TlsInfo;
+use crate::version;
+
+/// The path of merged streams, which take the request paths of the sockets to read from as a
+/// comma-separated `paths` query parameter.
+pub const PATH: &str = "/merge";
+
+/// One of the sockets read by a merged stream.
+struct Source {
+  /// The path the client asked for, which tags everything read from the socket.
+  request_path: String,
+  socket_path: String,
+}
+
+/// Something read from one of the sockets.
+enum Event {
+  Data(usize, Batch),
+  Oob(usize, Vec<u8>),
+  Closed(usize, Option<BackendError>),
+}
+
+/// Upgrade a request for a merged stream, which interleaves what's read from several sockets into
+/// a single read-only V2 connection, with each payload tagged with the path it was read from.
+pub async fn handle_merge(
+  state: Arc<ServerState>,
+  mut req: Request<Body>,
+  peer: Peer,
+  permit: PeerPermit,
+  accept_key: String,
+) -> Result<Response<Body>> {
+  let mut paths: Vec<String> = Vec::new();
+  for path in query_param(&req, "paths").unwrap_or_default().split(',') {
+    if !path.is_empty() && !paths.iter().any(|p| p == path) {
+      paths.push(path.to_string());
+    }
+  }
+  let max_sources = state.config.websocket.as_ref().unwrap().max_merged_sockets.unwrap();
+  if paths.is_empty() || paths.len() > max_sources {
+    return Ok(text_response(
+      StatusCode::BAD_REQUEST,
+      format!("merged streams read from 1 to {max_sources} comma-separated paths"),
+    ));
+  }
+  if let Some(path) = paths.iter().find(|path| !path.starts_with('/') || *path == PATH) {
+    return Ok(text_response(StatusCode::BAD_REQUEST, format!("invalid path {path}")));
+  }
+
+  // Every socket has to be authorized. They're all authorized with the same credential, which is
+  // all that the session key and revocation need from the access.
+  let mut sources = Vec::new();
+  let mut access = None;
+  for request_path in paths {
+    let (path_access, socket_path) = match authorize_socket(&state, &req, peer, &request_path).await {
+      Ok(authorized) => authorized,
+      Err(response) => return Ok(response),
+    };
+    if path_access.write_only() {
+      warn!("{peer}: can't merge write-only socket {request_path}");
+      return Ok(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    access.get_or_insert(path_access);
+    sources.push(Source {
+      request_path,
+      socket_path,
+    });
+  }
+  let access = access.unwrap();
+
+  let (protocol, negotiated) = match negotiate_protocol(&state, &mut req, &access) {
+    Ok(negotiated) => negotiated,
+    Err((status, reason)) => return Ok(text_response(status, reason)),
+  };
+  if protocol != Protocol::V2 {
+    return Ok(text_response(
+      StatusCode::BAD_REQUEST,
+      format!("merged streams require {}", Protocol::V2.name()),
+    ));
+  }
+
+  if !matches!(access, Access::Anonymous) {
+    state.audit.record(
+      &peer,
+      AuditEvent::AuthSuccess {
+        path: PATH,
+        identity: access.identity(),
+      },
+    );
+  }
+
+  let ver = req.version();
+  task::spawn("websocket merge", async move {
+    let _permit = permit;
+    match hyper::upgrade::on(&mut req).await {
+      Ok(upgraded) => {
+        let ws_stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        if let Err(e) = run_merge(state, ws_stream, req, peer, access, sources, protocol).await {
+          error!("{peer}: merged stream failed: {e:?}");
+        }
+      }
+      Err(e) => error!("upgrade error: {}", e),
+    }
+  });
+  Ok(switching_protocols(ver, accept_key, negotiated))
+}
+
+async fn run_merge(
+  state: Arc<ServerState>,
+  ws_stream: WebSocketStream<hyper::upgrade::Upgraded>,
+  request: Request<Body>,
+  peer: Peer,
+  access: Access,
+  sources: Vec<Source>,
+  protocol: Protocol,
+) -> Result<()> {
+  let tls = request.extensions().get::<TlsInfo>();
+  let identity = access.identity();
+  info!(
+    "{peer}: merged stream established (sockets = {}, access = {access:?})",
+    sources
+      .iter()
+      .map(|source| source.socket_path.as_str())
+      .collect::<Vec<_>>()
+      .join(", ")
+  );
+
+  let mut sockets = Vec::new();
+  for source in &sources {
+    let path = CString::new(source.socket_path.as_str())?;
+    let scopes = access.scopes();
+    let peer_info = PeerInfo::new(&peer, tls, scopes.as_deref());
+    let socket = unsafe { wardenclyffe_create_socket(path.as_ptr(), &peer_info.as_ffi()) };
+    if socket.0.is_null() {
+      for socket in sockets {
+        unsafe { wardenclyffe_destroy_socket(socket) };
+      }
+      bail!("{peer}: failed to create socket {}", source.socket_path);
+    }
+    sockets.push(socket);
+  }
+
+  let (mut sink, mut incoming) = ws_stream.split();
+  let key = request.extensions().get::<SessionKey>().map(|key| key.0.clone());
+  let integrity = key.is_some();
+  let mut sealer = key.map(MessageAuthenticator::sealer);
+  let mut seal = move |msg| match &mut sealer {
+    Some(sealer) => sealer.seal(msg),
+    None => msg,
+  };
+
+  let hello = Hello {
+    server: concat!("wardenclyffe/", env!("CARGO_PKG_VERSION")).into(),
+    build: version::BUILD,
+    features: Features {
+      compression: false,
+      resume: false,
+      multiplexing: false,
+      streaming: false,
+      acked_writes: false,
+      file_transfer: false,
+      integrity,
+      checksums: false,
+      replay: false,
+      text_filters: false,
+      delta_encoding: false,
+      parameters: false,
+    },
+    resumed: false,
+    read: true,
+    write: false,
+    sources: Some(sources.iter().map(|source| source.request_path.clone()).collect()),
+    content_type: None,
+  };
+  if let Some(msg) = protocol.encode_hello(&hello) {
+    if let Err(e) = sink.send(seal(msg)).await {
+      warn!("{peer}: failed to send hello: {e}");
+    }
+  }
+
+  // Each socket is read by its own task, which destroys it once it's done.
+  let cancelled = Arc::new(AtomicBool::new(false));
+  let (tx, mut rx) = mpsc::unbounded_channel();
+  let mut readers = Vec::new();
+  for (index, (source, socket)) in sources.iter().zip(sockets).enumerate() {
+    state.audit.record(
+      &peer,
+      AuditEvent::SessionOpened {
+        path: &source.socket_path,
+        identity: identity.clone(),
+        user_agent: user_agent(&request),
+      },
+    );
+    readers.push(task::spawn(
+      "websocket merge read",
+      read_source(state.clone(), socket, index, peer, tx.clone(), cancelled.clone()),
+    ));
+  }
+  drop(tx);
+
+  let websocket_config = state.config.websocket.as_ref().unwrap();
+  let keepalive_interval = Duration::from_millis(websocket_config.keepalive_interval_ms.unwrap());
+  let close_timeout = Duration::from_millis(websocket_config.close_timeout_ms.unwrap());
+  let credential = credential(&request, &access);
+  let mut open = sources.len();
+
+  // Payloads are sent in the order they were read, and carry their capture timestamps, for clients
+  // that need a stricter order than arrival.
+  let (close, reason) = loop {
+    let msg = tokio::select! {
+      event = rx.recv() => match event {
+        Some(Event::Data(index, batch)) => {
+          protocol.encode_merged_data(&sources[index].request_path, batch.data, batch.timestamp_us)
+        }
+        Some(Event::Oob(index, data)) => protocol.encode_merged_oob(&sources[index].request_path, &data),
+        Some(Event::Closed(index, error)) => {
+          open -= 1;
+          protocol.encode_source_closed(&sources[index].request_path, error.as_ref())
+        }
+        None => break (Some((CloseCode::Normal, "EOF")), CloseReason::BackendEof),
+      },
+
+      msg = incoming.next() => match msg {
+        Some(Ok(Message::Close(frame))) => break (None, CloseReason::Client(frame.map(|f| u16::from(f.code)))),
+        None => break (None, CloseReason::ClientLost),
+        Some(Ok(Message::Text(_) | Message::Binary(_))) => {
+          debug!("{peer}: ignoring message to read-only merged stream");
+          continue;
+        }
+        Some(Ok(_)) => continue,
+        Some(Err(e)) => {
+          warn!("{peer}: merged stream connection failed: {e}");
+          break (None, CloseReason::ClientLost);
+        }
+      },
+
+      _ = state.auth.revoked(credential.as_deref()) => {
+        info!("{peer}: credentials revoked, closing merged stream");
+        break (Some((CloseCode::Policy, "credentials revoked")), CloseReason::CredentialsRevoked);
+      }
+
+      _ = tokio::time::sleep(keepalive_interval) => Message::Ping(Vec::new()),
+    };
+
+    if let Err(e) = sink.send(seal(msg)).await {
+      warn!("{peer}: failed to send: {e}");
+      break (None, CloseReason::ClientLost);
+    }
+    if open == 0 {
+      break (Some((CloseCode::Normal, "EOF")), CloseReason::BackendEof);
+    }
+  };
+  state.stats.session_closed(reason);
+
+  cancelled.store(true, Ordering::Relaxed);
+  if let Some((code, reason)) = close {
+    let frame = CloseFrame {
+      code,
+      reason: reason.into(),
+    };
+    if sink.send(Message::Close(Some(frame))).await.is_ok() {
+      // Wait for the client to reply to the Close frame, which ends the stream.
+      let replied = tokio::time::timeout(close_timeout, async {
+        while let Some(Ok(msg)) = incoming.next().await {
+          if let Message::Close(_) = msg {
+            break;
+          }
+        }
+      });
+      if replied.await.is_err() {
+        warn!("{peer}: timed out waiting for the client to acknowledge close");
+      }
+    }
+  }
+
+  for reader in readers {
+    let _ = reader.await;
+  }
+  for source in &sources {
+    state.audit.record(
+      &peer,
+      AuditEvent::SessionClosed {
+        path: &source.socket_path,
+        identity: identity.clone(),
+      },
+    );
+  }
+  info!("{peer}: merged stream disconnected");
+  Ok(())
+}
+
+/// Read from one socket of a merged stream until it hits EOF or fails, or the stream is closed, and
+/// then destroy it.
+async fn read_source(
+  state: Arc<ServerState>,
+  socket: WardenclyffeSocket,
+  index: usize,
+  peer: Peer,
+  tx: mpsc::UnboundedSender<Event>,
+  cancelled: Arc<AtomicBool>,
+) {
+  let read_timeout = state.config.websocket.as_ref().unwrap().read_timeout_ms.unwrap();
+
+  let termination = Termination::register(socket);
+  let mut error = None;
+  if unsafe { wardenclyffe_supports_read(socket) } {
+    // A frame split across several reads, and whether we're dropping the rest of one.
+    let mut assembly: Option<Assembly> = None;
+    let mut dropping = false;
+
+    while !cancelled.load(Ordering::Relaxed) {
+      if let Some(e) = termination.error() {
+        info!("{peer}: backend ended the session: {e:?}");
+        error = Some(e);
+        break;
+      }
+
+      let reads = state.reads.read(socket, read_timeout).await;
+
+      if reads.read_count == WARDENCLYFFE_READ_TIMEOUT || (reads.read_count <= 0 && termination.error().is_some()) {
+        continue;
+      } else if reads.read_count < 0 {
+        let e = unsafe { BackendError::last(socket, "read failed") };
+        error!(
+          "{peer}: WardenclyffeSocket::read failed: rc = {}, error = {e:?}",
+          reads.read_count
+        );
+        error = Some(e);
+        break;
+      } else if reads.read_count == 0 {
+        info!("{peer}: WardenclyffeSocket hit EOF");
+        break;
+      }
+
+      let reads = unsafe { std::slice::from_raw_parts(reads.reads, reads.read_count as usize) };
+      for read in reads {
+        let read_data = match unsafe { shm::read_data(read) } {
+          Ok(data) => data,
+          Err(e) => {
+            error!("{peer}: failed to map shared memory read: {e}");
+            continue;
+          }
+        };
+        let data = &read_data[..];
+        if read.oob != 0 {
+          let _ = tx.send(Event::Oob(index, data.to_vec()));
+          continue;
+        }
+
+        let more = read.more != 0;
+        if dropping {
+          dropping = more;
+          continue;
+        }
+
+        let Some(reservation) = state.memory.try_reserve(read.size as u64) else {
+          Stats::increment(&state.stats.websocket_messages_dropped);
+          debug!("{peer}: memory budget exhausted, dropping {} byte message", read.size);
+          assembly = None;
+          dropping = more;
+          continue;
+        };
+
+        let mut frame = assembly
+          .take()
+          .unwrap_or_else(|| Assembly::with_capacity(read.total_size, read.capture_time_us()));
+        frame.push(data, Some(reservation));
+        if more {
+          assembly = Some(frame);
+        } else {
+          let _ = tx.send(Event::Data(index, frame.finish()));
+        }
+      }
+    }
+  } else {
+    error = Some(BackendError {
+      code: WARDENCLYFFE_ERROR_UNAUTHORIZED,
+      reason: "socket can't be read from".into(),
+    });
+  }
+
+  let _ = tx.send(Event::Closed(index, error));
+  Termination::unregister(socket);
+  unsafe { wardenclyffe_destroy_socket(socket) };
+}
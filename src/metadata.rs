@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+
+use crate::ffi::WardenclyffeSocket;
+
+// Limits on what can be stored, since clients can set it.
+const MAX_ENTRIES: usize = 32;
+const MAX_KEY_LEN: usize = 64;
+const MAX_VALUE_LEN: usize = 256;
+
+/// Metadata of open WebSocket sessions, by socket, for the backend to set with
+/// `wardenclyffe_set_metadata`.
+static OPEN_SOCKETS: Mutex<BTreeMap<usize, Arc<Metadata>>> = Mutex::new(BTreeMap::new());
+
+/// A small key/value map describing a session, like the client app's version or a label for its
+/// user, set by the client and the backend and shown in the admin sessions listing, to tell
+/// sessions apart when several clients are connected.
+#[derive(Default)]
+pub struct Metadata(Mutex<BTreeMap<String, String>>);
+
+impl Metadata {
+  /// Start accepting metadata from the backend for a newly created socket's session. Sockets must
+  /// be unregistered before they're destroyed.
+  pub fn register(socket: WardenclyffeSocket) -> Arc<Metadata> {
+    let metadata = Arc::new(Metadata::default());
+    OPEN_SOCKETS.lock().unwrap().insert(socket.0 as usize, metadata.clone());
+    metadata
+  }
+
+  pub fn unregister(socket: WardenclyffeSocket) {
+    OPEN_SOCKETS.lock().unwrap().remove(&(socket.0 as usize));
+  }
+
+  /// Set or, without a value, remove an entry.
+  pub fn set(&self, key: &str, value: Option<&str>) -> Result<()> {
+    let valid_key = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.chars().all(valid_key) {
+      bail!("invalid metadata key {key:?}");
+    }
+
+    let mut entries = self.0.lock().unwrap();
+    let Some(value) = value else {
+      entries.remove(key);
+      return Ok(());
+    };
+    if value.len() > MAX_VALUE_LEN {
+      bail!("metadata value for {key} is longer than {MAX_VALUE_LEN} bytes");
+    }
+    if entries.len() >= MAX_ENTRIES && !entries.contains_key(key) {
+      bail!("too many metadata entries");
+    }
+    entries.insert(key.to_string(), value.to_string());
+    Ok(())
+  }
+
+  pub fn snapshot(&self) -> BTreeMap<String, String> {
+    self.0.lock().unwrap().clone()
+  }
+}
+
+/// Set an entry of the metadata of the session on a socket, at the backend's request.
+pub fn set_metadata(socket: WardenclyffeSocket, key: &str, value: Option<&str>) -> Result<()> {
+  let Some(metadata) = OPEN_SOCKETS.lock().unwrap().get(&(socket.0 as usize)).cloned() else {
+    bail!("socket isn't open");
+  };
+  metadata.set(key, value)
+}
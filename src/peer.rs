@@ -0,0 +1,171 @@
+use std::ffi::CString;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use anyhow::{anyhow, Result};
+
+use crate::ffi::WardenclyffePeerInfo;
+use crate::tls::TlsInfo;
+
+/// The identity of the remote end of a connection.
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+  /// A TCP peer.
+  Inet(SocketAddr),
+
+  /// A peer on a local (Unix domain) socket, identified by the kernel via SO_PEERCRED.
+  Local { uid: u32, gid: u32, pid: Option<i32> },
+}
+
+impl Peer {
+  /// The host a TCP peer connected from, or None for a local peer.
+  pub fn host(&self) -> Option<Host> {
+    match self {
+      Peer::Inet(addr) => Some(Host::of(addr)),
+      Peer::Local { .. } => None,
+    }
+  }
+}
+
+impl fmt::Display for Peer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Peer::Inet(addr @ SocketAddr::V6(v6)) if v6.scope_id() != 0 => {
+        write!(f, "[{}]:{}", Host::of(addr), addr.port())
+      }
+      Peer::Inet(addr) => write!(f, "{addr}"),
+      Peer::Local { uid, gid, pid } => {
+        write!(f, "local(uid={uid}, gid={gid}")?;
+        if let Some(pid) = pid {
+          write!(f, ", pid={pid}")?;
+        }
+        write!(f, ")")
+      }
+    }
+  }
+}
+
+/// The address of a TCP peer without the port, along with the zone (interface) of an IPv6
+/// link-local address, since the same link-local address can be different hosts on different links.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Host {
+  pub ip: IpAddr,
+  pub scope_id: u32,
+}
+
+impl Host {
+  pub fn of(addr: &SocketAddr) -> Host {
+    let scope_id = match addr {
+      SocketAddr::V6(v6) => v6.scope_id(),
+      SocketAddr::V4(_) => 0,
+    };
+    Host {
+      ip: addr.ip(),
+      scope_id,
+    }
+  }
+}
+
+/// Formatted with the zone's interface name, e.g. `fe80::1%usb0`.
+impl fmt::Display for Host {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.scope_id {
+      0 => write!(f, "{}", self.ip),
+      scope_id => write!(f, "{}%{}", self.ip, zone_name(scope_id)),
+    }
+  }
+}
+
+/// The name of the interface with index `scope_id`, or the index itself if there isn't one.
+fn zone_name(scope_id: u32) -> String {
+  #[cfg(unix)]
+  {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let result = unsafe { libc::if_indextoname(scope_id, name.as_mut_ptr()) };
+    if !result.is_null() {
+      let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+      return name.to_string_lossy().into_owned();
+    }
+  }
+  scope_id.to_string()
+}
+
+/// The index of the interface named by a zone, which may also be the index itself.
+fn zone_index(zone: &str) -> Result<u32> {
+  if let Ok(index) = zone.parse() {
+    return Ok(index);
+  }
+  #[cfg(unix)]
+  {
+    let name = CString::new(zone)?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index != 0 {
+      return Ok(index);
+    }
+  }
+  Err(anyhow!("no such interface {zone:?}"))
+}
+
+/// Parse an address to listen on, which may be an IPv6 address with a zone, e.g. `fe80::1%usb0`.
+pub fn parse_listen_addr(address: &str, port: u16) -> Result<SocketAddr> {
+  let address = address.trim_start_matches('[').trim_end_matches(']');
+  let Some((ip, zone)) = address.split_once('%') else {
+    let ip: IpAddr = address.parse().map_err(|_| anyhow!("invalid address {address:?}"))?;
+    return Ok(SocketAddr::new(ip, port));
+  };
+  let ip: Ipv6Addr = ip.parse().map_err(|_| anyhow!("invalid IPv6 address {ip:?}"))?;
+  Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, zone_index(zone)?)))
+}
+
+/// Owner of the storage backing a `WardenclyffePeerInfo`.
+pub struct PeerInfo {
+  address: Option<CString>,
+  uid: i64,
+  gid: i64,
+  pid: i64,
+  tls_version: Option<CString>,
+  tls_cipher: Option<CString>,
+  tls_alpn: Option<CString>,
+  tls_client_subject: Option<CString>,
+  scopes: Option<CString>,
+}
+
+fn c_string(s: &str) -> CString {
+  CString::new(s.replace('\0', "")).unwrap()
+}
+
+impl PeerInfo {
+  pub fn new(peer: &Peer, tls: Option<&TlsInfo>, scopes: Option<&str>) -> PeerInfo {
+    let (address, uid, gid, pid) = match *peer {
+      Peer::Inet(_) => (Some(c_string(&peer.to_string())), -1, -1, -1),
+      Peer::Local { uid, gid, pid } => (None, uid.into(), gid.into(), pid.map(i64::from).unwrap_or(-1)),
+    };
+    PeerInfo {
+      address,
+      uid,
+      gid,
+      pid,
+      tls_version: tls.map(|tls| c_string(&tls.version)),
+      tls_cipher: tls.map(|tls| c_string(&tls.cipher)),
+      tls_alpn: tls.and_then(|tls| tls.alpn.as_deref()).map(c_string),
+      tls_client_subject: tls.and_then(|tls| tls.client_subject.as_deref()).map(c_string),
+      scopes: scopes.map(c_string),
+    }
+  }
+
+  /// The FFI view of this peer, valid for as long as `self` is alive.
+  pub fn as_ffi(&self) -> WardenclyffePeerInfo {
+    let ptr = |s: &Option<CString>| s.as_ref().map(|s| s.as_ptr()).unwrap_or(std::ptr::null());
+    WardenclyffePeerInfo {
+      address: ptr(&self.address),
+      uid: self.uid,
+      gid: self.gid,
+      pid: self.pid,
+      tls_version: ptr(&self.tls_version),
+      tls_cipher: ptr(&self.tls_cipher),
+      tls_alpn: ptr(&self.tls_alpn),
+      tls_client_subject: ptr(&self.tls_client_subject),
+      scopes: ptr(&self.scopes),
+    }
+  }
+}
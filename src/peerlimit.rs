@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::peer::{Host, Peer};
+
+/// A limit on how many of something (e.g. requests) each client address can have in progress at once.
+pub struct PeerLimit {
+  limit: Option<usize>,
+  counts: Mutex<HashMap<Host, usize>>,
+}
+
+/// One of a client's slots in a `PeerLimit`, released when dropped.
+pub struct PeerPermit {
+  limit: Arc<PeerLimit>,
+  addr: Option<Host>,
+}
+
+impl Drop for PeerPermit {
+  fn drop(&mut self) {
+    let Some(addr) = self.addr else {
+      return;
+    };
+    let mut counts = self.limit.counts.lock().unwrap();
+    if let Some(count) = counts.get_mut(&addr) {
+      *count -= 1;
+      if *count == 0 {
+        counts.remove(&addr);
+      }
+    }
+  }
+}
+
+impl PeerLimit {
+  /// Create a limit of `limit` per address, or no limit at all if None.
+  pub fn new(limit: Option<usize>) -> PeerLimit {
+    PeerLimit {
+      limit,
+      counts: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Take one of `peer`'s slots, or return None if it's using all of them already.
+  ///
+  /// Local peers aren't limited, since they're already on the device.
+  pub fn try_acquire(self: &Arc<Self>, peer: &Peer) -> Option<PeerPermit> {
+    let (Some(limit), Some(host)) = (self.limit, peer.host()) else {
+      return Some(PeerPermit {
+        limit: self.clone(),
+        addr: None,
+      });
+    };
+
+    let mut counts = self.counts.lock().unwrap();
+    let count = counts.entry(host).or_default();
+    if *count >= limit {
+      return None;
+    }
+    *count += 1;
+    Some(PeerPermit {
+      limit: self.clone(),
+      addr: Some(host),
+    })
+  }
+}
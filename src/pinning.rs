@@ -0,0 +1,73 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rustls::sign::CertifiedKey;
+use serde_json::{json, Value};
+
+use crate::config::Pinning;
+
+/// Where the manifest is served.
+pub const MANIFEST_PATH: &str = "/.well-known/wardenclyffe/pins.json";
+
+/// Signs manifests describing the certificate being served, for clients that pin it.
+///
+/// Clients pin the manifest's signing key rather than the certificate itself, and accept whatever
+/// certificate a validly signed manifest lists, so that the certificate can be rotated.
+pub struct PinManifest {
+  key: Ed25519KeyPair,
+  next_rotation: Option<u64>,
+  upcoming_spki_sha256: Vec<String>,
+}
+
+impl PinManifest {
+  pub fn new(config: &Pinning) -> Result<PinManifest> {
+    let key = Ed25519KeyPair::from_pkcs8(&STANDARD.decode(&config.signing_key)?)
+      .map_err(|e| anyhow!("invalid pinning signing key: {e}"))?;
+    Ok(PinManifest {
+      key,
+      next_rotation: config.next_rotation,
+      upcoming_spki_sha256: config.upcoming_spki_sha256.clone().unwrap_or_default(),
+    })
+  }
+
+  /// Describe the certificate being served, and sign the description.
+  ///
+  /// The description is kept as a string, so that the signature covers exactly the bytes sent.
+  pub fn sign(&self, certificate: &CertifiedKey) -> Result<Value> {
+    let cert = certificate
+      .end_entity_cert()
+      .map_err(|_| anyhow!("no certificate being served"))?;
+    let (_, parsed) =
+      x509_parser::parse_x509_certificate(&cert.0).map_err(|e| anyhow!("failed to parse certificate: {e}"))?;
+    let validity = parsed.validity();
+    let fingerprint: String = digest(&SHA256, &cert.0)
+      .as_ref()
+      .iter()
+      .map(|b| format!("{b:02x}"))
+      .collect();
+
+    let issued_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let manifest = json!({
+      "issued_at": issued_at,
+      "certificate": {
+        "sha256": fingerprint,
+        "spki_sha256": STANDARD.encode(digest(&SHA256, parsed.public_key().raw)),
+        "not_before": validity.not_before.timestamp(),
+        "not_after": validity.not_after.timestamp(),
+      },
+      "next_rotation": self.next_rotation,
+      "upcoming_spki_sha256": self.upcoming_spki_sha256,
+    })
+    .to_string();
+
+    let signature = self.key.sign(manifest.as_bytes());
+    Ok(json!({
+      "manifest": manifest,
+      "signature": STANDARD.encode(signature),
+      "public_key": STANDARD.encode(self.key.public_key()),
+    }))
+  }
+}
@@ -0,0 +1,159 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+/// The profile used when none is selected, if the configuration has one by this name.
+const DEFAULT_PROFILE: &str = "default";
+
+/// The system property selecting a profile on Android, when the command line doesn't.
+#[cfg(target_os = "android")]
+const PROFILE_PROPERTY: &str = "persist.wardenclyffe.profile";
+
+/// The profile selected by the system property, if any.
+#[cfg(target_os = "android")]
+pub fn from_property() -> Option<String> {
+  let name = std::ffi::CString::new(PROFILE_PROPERTY).unwrap();
+  let mut value = [0 as std::ffi::c_char; libc::PROP_VALUE_MAX as usize];
+  let len = unsafe { libc::__system_property_get(name.as_ptr(), value.as_mut_ptr()) };
+  if len <= 0 {
+    return None;
+  }
+  let value = unsafe { std::ffi::CStr::from_ptr(value.as_ptr()) };
+  Some(value.to_string_lossy().into_owned())
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn from_property() -> Option<String> {
+  None
+}
+
+/// Overlay `overrides` onto `base`, merging objects field by field and replacing everything else.
+pub fn merge(base: &mut Value, overrides: Value) {
+  match (base, overrides) {
+    (Value::Object(base), Value::Object(overrides)) => {
+      for (key, value) in overrides {
+        match base.get_mut(&key) {
+          Some(existing) => merge(existing, value),
+          None => {
+            base.insert(key, value);
+          }
+        }
+      }
+    }
+    (base, overrides) => *base = overrides,
+  }
+}
+
+/// Resolve a configuration with profiles into the configuration of one of them.
+///
+/// A configuration's top-level fields apply to every profile. Its `profiles` are named sets of
+/// fields to override them with, which can first inherit those of another profile named by their
+/// `inherits`, so that one file can cover several deployments. With no profile selected, the one
+/// named `default` is used if there is one.
+pub fn resolve(mut config: Value, profile: Option<&str>) -> Result<Value> {
+  let Some(object) = config.as_object_mut() else {
+    bail!("configuration isn't an object");
+  };
+  let profiles = match object.remove("profiles") {
+    Some(Value::Object(profiles)) => profiles,
+    Some(_) => bail!("profiles isn't an object"),
+    None => Map::new(),
+  };
+
+  let name = match profile {
+    Some(name) => name,
+    None if profiles.contains_key(DEFAULT_PROFILE) => DEFAULT_PROFILE,
+    None => return Ok(config),
+  };
+
+  // The chain of profiles from the selected one to the one that inherits nothing.
+  let mut chain = Vec::new();
+  let mut next = Some(name.to_string());
+  while let Some(name) = next {
+    if chain.iter().any(|(n, _)| *n == name) {
+      bail!("profile {name} inherits from itself");
+    }
+    let mut fields = match profiles.get(&name) {
+      Some(Value::Object(fields)) => fields.clone(),
+      Some(_) => bail!("profile {name} isn't an object"),
+      None => bail!("no profile named {name}"),
+    };
+    next = match fields.remove("inherits") {
+      Some(Value::String(parent)) => Some(parent),
+      Some(_) => bail!("inherits of profile {name} isn't a string"),
+      None => None,
+    };
+    chain.push((name, fields));
+  }
+
+  for (_, fields) in chain.into_iter().rev() {
+    merge(&mut config, Value::Object(fields));
+  }
+  Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn profiles() -> Value {
+    json!({
+      "port": 8443,
+      "auth": { "required": false, "admin_token": "base" },
+      "profiles": {
+        "default": { "port": 9000 },
+        "lab": { "auth": { "required": true } },
+        "device": { "inherits": "lab", "auth": { "admin_token": "device" } },
+      },
+    })
+  }
+
+  #[test]
+  fn merges_objects_field_by_field() {
+    let mut base = json!({ "a": { "b": 1, "c": [1, 2] }, "d": 1 });
+    merge(&mut base, json!({ "a": { "c": [3] }, "e": 2 }));
+    assert_eq!(base, json!({ "a": { "b": 1, "c": [3] }, "d": 1, "e": 2 }));
+  }
+
+  #[test]
+  fn applies_inherited_profiles_in_order() {
+    let config = resolve(profiles(), Some("device")).unwrap();
+    assert_eq!(
+      config,
+      json!({ "port": 8443, "auth": { "required": true, "admin_token": "device" } })
+    );
+  }
+
+  #[test]
+  fn uses_the_default_profile() {
+    assert_eq!(resolve(profiles(), None).unwrap()["port"], 9000);
+    let mut config = profiles();
+    config["profiles"].as_object_mut().unwrap().remove("default");
+    assert_eq!(resolve(config, None).unwrap()["port"], 8443);
+    assert_eq!(resolve(json!({ "port": 1 }), None).unwrap(), json!({ "port": 1 }));
+  }
+
+  #[test]
+  fn rejects_inheritance_cycles() {
+    let config = json!({
+      "profiles": {
+        "a": { "inherits": "b" },
+        "b": { "inherits": "c" },
+        "c": { "inherits": "a" },
+        "self": { "inherits": "self" },
+      },
+    });
+    assert!(resolve(config.clone(), Some("a")).is_err());
+    assert!(resolve(config, Some("self")).is_err());
+  }
+
+  #[test]
+  fn rejects_bad_profiles() {
+    assert!(resolve(profiles(), Some("missing")).is_err());
+    assert!(resolve(json!({ "profiles": { "a": { "inherits": "missing" } } }), Some("a")).is_err());
+    assert!(resolve(json!({ "profiles": { "a": { "inherits": 1 } } }), Some("a")).is_err());
+    assert!(resolve(json!({ "profiles": { "a": [] } }), Some("a")).is_err());
+    assert!(resolve(json!({ "profiles": [] }), None).is_err());
+    assert!(resolve(json!([]), None).is_err());
+  }
+}
@@ -0,0 +1,693 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use hyper::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::{CloseFrame, Frame};
+use tungstenite::protocol::Message;
+
+use crate::delta::{Delta, DeltaEncoder};
+use crate::errors::BackendError;
+use crate::version::Build;
+
+// The longest reason that fits in a Close frame.
+const MAX_CLOSE_REASON: usize = 123;
+
+// The longest identification a client can give itself with an `identify` control message.
+const MAX_CLIENT_IDENTIFICATION_LEN: usize = 256;
+
+/// Framing of messages between clients and sockets, selected with Sec-WebSocket-Protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+  /// Backend data is sent as-is in binary frames, and out-of-band data in text frames. Messages
+  /// from the client are forwarded as-is. This is what clients that don't ask for a protocol get.
+  V1,
+
+  /// Text frames carry JSON control messages, and binary frames carry envelopes: a big-endian u32
+  /// header length, a JSON `EnvelopeHeader`, and then the payload.
+  V2,
+}
+
+/// Metadata sent in front of each payload in V2.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct EnvelopeHeader {
+  /// Length of the payload, in bytes.
+  pub length: usize,
+
+  /// An ID chosen by the client for data it sends, echoed back in its write acknowledgement.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id: Option<u64>,
+
+  /// For file data rather than socket data, the transfer it belongs to, and its offset in the file.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub transfer: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub offset: Option<u64>,
+
+  /// With checksums, the payload's sequence number (counting from zero in each direction) and
+  /// CRC32, so that lost and corrupted payloads can be told apart.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub seq: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub crc32: Option<u32>,
+
+  /// For data from the backend, when it was captured (or read, if the backend doesn't say), in
+  /// microseconds since the Unix epoch on the device's clock.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub timestamp_us: Option<u64>,
+
+  /// Set on data from the socket's replay buffer, read before the client connected.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub replayed: Option<bool>,
+
+  /// With delta encoding, the ID of the baseline that a payload sent in full becomes, or that a
+  /// patch applies to.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub baseline: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub delta_of: Option<u64>,
+
+  /// For merged streams, the path of the socket the data was read from.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub source: Option<String>,
+}
+
+/// Numbers and checksums the payloads sent to a client.
+#[derive(Default)]
+pub struct Checksums {
+  next_seq: u64,
+}
+
+/// A payload from the client whose CRC32 didn't match.
+#[derive(Debug)]
+pub struct ChecksumMismatch;
+
+impl fmt::Display for ChecksumMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("payload checksum mismatch")
+  }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// A message from the client.
+pub enum Incoming<'a> {
+  /// Data to write to the backend, and the client's ID and sequence number for it, if any.
+  Data(Cow<'a, [u8]>, Option<u64>, Option<u64>),
+
+  /// What the client has seen go wrong with the payloads sent to it.
+  ChecksumReport { gaps: u64, mismatches: u64 },
+
+  /// The client doesn't have the baseline for delta-encoded payloads, and needs a new one.
+  DeltaReset,
+
+  /// Set entries of the session's metadata, or remove those without a value.
+  SetMetadata(Vec<(String, Option<String>)>),
+
+  /// Set parameters of the stream, with an ID chosen by the client for the acknowledgement.
+  SetParams {
+    id: Option<u64>,
+    params: Vec<(String, String)>,
+  },
+
+  /// Turn acknowledgement of each write on or off.
+  AckWrites(bool),
+
+  /// Write messages waiting to be batched right away.
+  Flush,
+
+  /// What the client says it is, e.g. `viewer/1.4` or `load-test`, to tell clients apart when
+  /// diagnosing.
+  Identify(String),
+
+  /// Start or cancel a file transfer.
+  Transfer(TransferRequest),
+
+  /// Data for a file being uploaded, and its offset in the file.
+  TransferData(u64, u64, Cow<'a, [u8]>),
+}
+
+/// A file transfer control message from the client, in V2. Transfers are identified by an ID chosen
+/// by the client.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "control")]
+pub enum TransferRequest {
+  /// Download a file from a directory mount, from `offset` onwards to resume an earlier download.
+  #[serde(rename = "file_get")]
+  Get {
+    transfer: u64,
+    path: String,
+    #[serde(default)]
+    offset: u64,
+  },
+
+  /// Upload a file into the upload directory. The server replies with the offset to send data from,
+  /// which is past whatever an earlier, interrupted upload of the same file got through.
+  #[serde(rename = "file_put")]
+  Put {
+    transfer: u64,
+    name: String,
+    size: u64,
+    sha256: String,
+  },
+
+  /// Abandon a transfer.
+  #[serde(rename = "file_cancel")]
+  Cancel { transfer: u64 },
+}
+
+/// The progress of a file transfer, sent to the client in V2.
+#[derive(Serialize, Debug)]
+#[serde(tag = "control")]
+pub enum TransferEvent {
+  /// A download is starting at `offset`, or an upload is ready for data from `offset`.
+  #[serde(rename = "file_info")]
+  Info {
+    transfer: u64,
+    size: u64,
+    /// Missing for downloads of files too large to hash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    offset: u64,
+  },
+
+  /// The whole file has been sent, or received and verified.
+  #[serde(rename = "file_done")]
+  Done { transfer: u64 },
+
+  #[serde(rename = "file_error")]
+  Error { transfer: u64, reason: String },
+}
+
+/// Optional features of a session, as announced in `Hello`.
+#[derive(Serialize, Debug)]
+pub struct Features {
+  /// permessage-deflate, which isn't supported yet.
+  pub compression: bool,
+  /// Resuming the session on a new connection, with the `session` query parameter.
+  pub resume: bool,
+  /// Several sockets over one connection, which isn't supported yet.
+  pub multiplexing: bool,
+  /// Frames the backend reads in fragments are sent as WebSocket fragments as they arrive.
+  pub streaming: bool,
+  /// Writes can be acknowledged, after the client sends an `ack_writes` control message.
+  pub acked_writes: bool,
+  /// Files can be downloaded with `file_get`, and uploaded with `file_put` if `uploads` is also set.
+  pub file_transfer: bool,
+  /// Every message is authenticated, with the `wardenclyffe.v2+hmac` subprotocol.
+  pub integrity: bool,
+  /// Payloads carry sequence numbers and CRC32s, and clients may send theirs too.
+  pub checksums: bool,
+  /// Recent data from before the client connected can be sent first, with the `replay` query
+  /// parameter.
+  pub replay: bool,
+  /// What's read can be filtered line by line with the `filter`, `filter_regex` and `min_level`
+  /// query parameters.
+  pub text_filters: bool,
+  /// Payloads may be patches to an earlier one, and the client can ask for a new baseline with a
+  /// `delta_reset` control message.
+  pub delta_encoding: bool,
+  /// Parameters of the stream can be changed with `set-params` control messages.
+  pub parameters: bool,
+}
+
+/// Sent to V2 clients as soon as their session starts, so that they can adapt to what the server
+/// and backend support.
+#[derive(Serialize, Debug)]
+pub struct Hello {
+  pub server: String,
+  pub build: Build,
+  pub features: Features,
+  /// Whether this connection resumed an existing session.
+  pub resumed: bool,
+  /// Whether the client will receive data from the socket, and may send data to it.
+  pub read: bool,
+  pub write: bool,
+  /// For merged streams, the paths of the sockets being read, in the order they were requested.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sources: Option<Vec<String>>,
+  /// MIME type of the data read from the socket, if it's known.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub content_type: Option<String>,
+}
+
+impl Protocol {
+  /// Supported protocols, in order of preference.
+  const ALL: &'static [Protocol] = &[Protocol::V2, Protocol::V1];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Protocol::V1 => "wardenclyffe.v1",
+      Protocol::V2 => "wardenclyffe.v2",
+    }
+  }
+
+  /// Pick the protocol to use, given the client's Sec-WebSocket-Protocol header, if any.
+  ///
+  /// Returns None if the client didn't offer any protocol we support.
+  pub fn negotiate(header: Option<&HeaderValue>) -> Option<Protocol> {
+    let offered: Vec<&str> = header
+      .and_then(|h| h.to_str().ok())
+      .map(|h| h.split(',').map(str::trim).collect())
+      .unwrap_or_default();
+    Protocol::ALL
+      .iter()
+      .copied()
+      .find(|protocol| offered.contains(&protocol.name()))
+  }
+
+  /// Encode a read from the backend as a message to the client.
+  pub fn encode_read(self, data: Vec<u8>, oob: bool) -> Message {
+    match (self, oob) {
+      (Protocol::V1, true) => Message::Text(String::from_utf8_lossy(&data).into_owned()),
+      (Protocol::V1, false) => Message::Binary(data),
+      (Protocol::V2, true) => Message::Text(
+        json!({
+          "control": "oob",
+          "data": String::from_utf8_lossy(&data),
+        })
+        .to_string(),
+      ),
+      (Protocol::V2, false) => {
+        let header = EnvelopeHeader {
+          length: data.len(),
+          ..Default::default()
+        };
+        Message::Binary(encode_envelope(&header, &data))
+      }
+    }
+  }
+
+  /// Encode data read from the backend and when it was captured, numbered and checksummed with
+  /// `checksums` if set. V1 only gets the data.
+  pub fn encode_data(self, data: Vec<u8>, timestamp_us: u64, checksums: Option<&mut Checksums>) -> Message {
+    match self {
+      Protocol::V1 => Message::Binary(data),
+      Protocol::V2 => {
+        let mut header = EnvelopeHeader {
+          length: data.len(),
+          timestamp_us: Some(timestamp_us),
+          ..Default::default()
+        };
+        if let Some(checksums) = checksums {
+          header.seq = Some(checksums.next_seq);
+          header.crc32 = Some(crc32fast::hash(&data));
+          checksums.next_seq += 1;
+        }
+        Message::Binary(encode_envelope(&header, &data))
+      }
+    }
+  }
+
+  /// Encode data read from the backend for a delta-encoded socket, as a new baseline or a patch to
+  /// the current one. With checksums, the CRC32 is of the whole payload, to check that patches
+  /// were applied correctly. Delta encoding is only available in V2.
+  pub fn encode_delta(
+    self,
+    data: Vec<u8>,
+    timestamp_us: u64,
+    checksums: Option<&mut Checksums>,
+    delta: &mut DeltaEncoder,
+  ) -> Message {
+    let mut header = EnvelopeHeader {
+      timestamp_us: Some(timestamp_us),
+      ..Default::default()
+    };
+    if let Some(checksums) = checksums {
+      header.seq = Some(checksums.next_seq);
+      header.crc32 = Some(crc32fast::hash(&data));
+      checksums.next_seq += 1;
+    }
+
+    let payload = match delta.encode(&data) {
+      Delta::Baseline(id) => {
+        header.baseline = Some(id);
+        data
+      }
+      Delta::Patch(id, patch) => {
+        header.delta_of = Some(id);
+        patch
+      }
+    };
+    header.length = payload.len();
+    Message::Binary(encode_envelope(&header, &payload))
+  }
+
+  /// Encode data from a socket's replay buffer. V1 only gets the data.
+  pub fn encode_replayed(self, data: Vec<u8>, timestamp_us: u64) -> Message {
+    match self {
+      Protocol::V1 => Message::Binary(data),
+      Protocol::V2 => {
+        let header = EnvelopeHeader {
+          length: data.len(),
+          timestamp_us: Some(timestamp_us),
+          replayed: Some(true),
+          ..Default::default()
+        };
+        Message::Binary(encode_envelope(&header, &data))
+      }
+    }
+  }
+
+  /// Encode data read from one of the sockets of a merged stream, tagged with its path. Merged
+  /// streams are only available in V2.
+  pub fn encode_merged_data(self, source: &str, data: Vec<u8>, timestamp_us: u64) -> Message {
+    let header = EnvelopeHeader {
+      length: data.len(),
+      timestamp_us: Some(timestamp_us),
+      source: Some(source.to_string()),
+      ..Default::default()
+    };
+    Message::Binary(encode_envelope(&header, &data))
+  }
+
+  /// Encode out-of-band data read from one of the sockets of a merged stream.
+  pub fn encode_merged_oob(self, source: &str, data: &[u8]) -> Message {
+    Message::Text(
+      json!({
+        "control": "oob",
+        "source": source,
+        "data": String::from_utf8_lossy(data),
+      })
+      .to_string(),
+    )
+  }
+
+  /// Encode the message that tells the client one of the sockets of a merged stream has ended, and
+  /// why if it failed.
+  pub fn encode_source_closed(self, source: &str, error: Option<&BackendError>) -> Message {
+    Message::Text(
+      json!({
+        "control": "source_closed",
+        "source": source,
+        "error": error.map(|error| json!({ "code": error.code, "reason": error.reason })),
+      })
+      .to_string(),
+    )
+  }
+
+  /// Encode the message that starts a session, if the protocol has one.
+  pub fn encode_hello(self, hello: &Hello) -> Option<Message> {
+    match self {
+      Protocol::V1 => None,
+      Protocol::V2 => {
+        let mut msg = serde_json::to_value(hello).expect("failed to serialize hello");
+        msg["control"] = "hello".into();
+        msg["protocol"] = self.name().into();
+        Some(Message::Text(msg.to_string()))
+      }
+    }
+  }
+
+  /// Encode the acknowledgement of the `seq`th write from the client (counting from zero), which
+  /// failed with `error` if set.
+  pub fn encode_ack(self, seq: u64, id: Option<u64>, error: Option<&BackendError>) -> Message {
+    Message::Text(
+      json!({
+        "control": "ack",
+        "seq": seq,
+        "id": id,
+        "ok": error.is_none(),
+        "error": error.map(|error| json!({ "code": error.code, "reason": error.reason })),
+      })
+      .to_string(),
+    )
+  }
+
+  /// Encode the message that tells the client that reading from the socket is suspended while a
+  /// platform condition lasts, or has resumed once `condition` is None. V1 has no way to say this.
+  pub fn encode_suspension(self, condition: Option<&str>) -> Option<Message> {
+    let msg = match condition {
+      Some(condition) => json!({ "control": "suspended", "condition": condition }),
+      None => json!({ "control": "resumed" }),
+    };
+    match self {
+      Protocol::V1 => None,
+      Protocol::V2 => Some(Message::Text(msg.to_string())),
+    }
+  }
+
+  /// Encode the message that warns the client that its session will be ended in `remaining`,
+  /// because it's been open for as long as it may be. V1 has no way to say this.
+  pub fn encode_session_expiring(self, remaining: Duration) -> Option<Message> {
+    match self {
+      Protocol::V1 => None,
+      Protocol::V2 => Some(Message::Text(
+        json!({
+          "control": "session_expiring",
+          "remaining_ms": remaining.as_millis() as u64,
+        })
+        .to_string(),
+      )),
+    }
+  }
+
+  /// Encode the message that tells the client that a message it sent (with `id`, if it gave one)
+  /// failed validation and wasn't written to the socket. V1 has no way to say this.
+  pub fn encode_rejected(self, id: Option<u64>, reason: &str) -> Option<Message> {
+    match self {
+      Protocol::V1 => None,
+      Protocol::V2 => Some(Message::Text(
+        json!({
+          "control": "rejected",
+          "id": id,
+          "reason": reason,
+        })
+        .to_string(),
+      )),
+    }
+  }
+
+  /// Encode the acknowledgement of a `set-params` message, which failed at parameter `param` with
+  /// `error` if set. Parameters before the one that failed were still set.
+  pub fn encode_params_ack(self, id: Option<u64>, failed: Option<(&str, &BackendError)>) -> Message {
+    Message::Text(
+      json!({
+        "control": "params_ack",
+        "id": id,
+        "ok": failed.is_none(),
+        "param": failed.map(|(param, _)| param),
+        "error": failed.map(|(_, error)| json!({ "code": error.code, "reason": error.reason })),
+      })
+      .to_string(),
+    )
+  }
+
+  /// Encode the progress of a file transfer. Transfers only happen in V2.
+  pub fn encode_transfer_event(self, event: &TransferEvent) -> Message {
+    Message::Text(serde_json::to_string(event).expect("failed to serialize transfer event"))
+  }
+
+  /// Encode a chunk of a file being downloaded, starting at `offset`.
+  pub fn encode_transfer_data(self, transfer: u64, offset: u64, data: &[u8]) -> Message {
+    let header = EnvelopeHeader {
+      length: data.len(),
+      transfer: Some(transfer),
+      offset: Some(offset),
+      ..Default::default()
+    };
+    Message::Binary(encode_envelope(&header, data))
+  }
+
+  /// Encode the message that tells the client that writes to the socket failed, and that further
+  /// writes will be dropped, while reading continues. V1 has no way to say this.
+  pub fn encode_write_closed(self, error: &BackendError) -> Option<Message> {
+    match self {
+      Protocol::V1 => None,
+      Protocol::V2 => Some(Message::Text(
+        json!({
+          "control": "write_closed",
+          "code": error.code,
+          "reason": error.reason,
+        })
+        .to_string(),
+      )),
+    }
+  }
+
+  /// Encode the messages that tell the client the backend failed, ending with a Close frame.
+  ///
+  /// V2 clients get a control message with the backend's error code first. V1 has no way to tell
+  /// it apart from out-of-band data, so V1 clients only get the Close frame.
+  pub fn encode_error(self, error: &BackendError, close_code: CloseCode, reason: &str) -> Vec<Message> {
+    let mut messages = Vec::new();
+    if self == Protocol::V2 {
+      messages.push(Message::Text(
+        json!({
+          "control": "error",
+          "code": error.code,
+          "reason": error.reason,
+        })
+        .to_string(),
+      ));
+    }
+
+    let mut reason = reason;
+    if reason.len() > MAX_CLOSE_REASON {
+      let mut end = MAX_CLOSE_REASON;
+      while !reason.is_char_boundary(end) {
+        end -= 1;
+      }
+      reason = &reason[..end];
+    }
+    messages.push(Message::Close(Some(CloseFrame {
+      code: close_code,
+      reason: reason.to_string().into(),
+    })));
+    messages
+  }
+
+  /// Whether a frame of `total_size` bytes (zero if unknown) can be sent as WebSocket fragments,
+  /// before all of it has been read. V2 needs to know the total size up front, for the envelope.
+  pub fn can_stream(self, total_size: usize) -> bool {
+    match self {
+      Protocol::V1 => true,
+      Protocol::V2 => total_size != 0,
+    }
+  }
+
+  /// Encode one fragment of a data frame as a WebSocket data or continuation frame.
+  ///
+  /// `total_size` and `timestamp_us` are only used for the first fragment.
+  pub fn encode_fragment(self, data: &[u8], first: bool, last: bool, total_size: usize, timestamp_us: u64) -> Message {
+    let (data, opcode) = match (self, first) {
+      (_, false) => (data.to_vec(), OpCode::Data(Data::Continue)),
+      (Protocol::V1, true) => (data.to_vec(), OpCode::Data(Data::Binary)),
+      (Protocol::V2, true) => {
+        let header = EnvelopeHeader {
+          length: total_size,
+          timestamp_us: Some(timestamp_us),
+          ..Default::default()
+        };
+        (encode_envelope(&header, data), OpCode::Data(Data::Binary))
+      }
+    };
+    Message::Frame(Frame::message(data, opcode, last))
+  }
+
+  /// Decode a message from the client, if it's one we handle.
+  pub fn decode_message(self, msg: &Message) -> Result<Option<Incoming<'_>>> {
+    match (self, msg) {
+      (Protocol::V1, Message::Text(text)) => Ok(Some(Incoming::Data(Cow::Borrowed(text.as_bytes()), None, None))),
+      (Protocol::V1, Message::Binary(data)) => Ok(Some(Incoming::Data(Cow::Borrowed(data), None, None))),
+      (Protocol::V2, Message::Text(text)) => {
+        let control: serde_json::Value = serde_json::from_str(text)?;
+        match control.get("control").and_then(|c| c.as_str()) {
+          Some("ack_writes") => {
+            let enabled = control.get("enabled").and_then(|e| e.as_bool()).unwrap_or(true);
+            Ok(Some(Incoming::AckWrites(enabled)))
+          }
+          Some("checksum_report") => {
+            let count = |name| control.get(name).and_then(|n| n.as_u64()).unwrap_or(0);
+            Ok(Some(Incoming::ChecksumReport {
+              gaps: count("gaps"),
+              mismatches: count("mismatches"),
+            }))
+          }
+          Some("delta_reset") => Ok(Some(Incoming::DeltaReset)),
+          Some("flush") => Ok(Some(Incoming::Flush)),
+          Some("identify") => {
+            let Some(client) = control.get("client").and_then(|c| c.as_str()) else {
+              bail!("identify without client");
+            };
+            if client.len() > MAX_CLIENT_IDENTIFICATION_LEN {
+              bail!("client identification is longer than {MAX_CLIENT_IDENTIFICATION_LEN} bytes");
+            }
+            Ok(Some(Incoming::Identify(client.to_string())))
+          }
+          Some("set-metadata") => {
+            let Some(metadata) = control.get("metadata").and_then(|m| m.as_object()) else {
+              bail!("set-metadata without metadata");
+            };
+            let entries = metadata
+              .iter()
+              .map(|(key, value)| match value {
+                serde_json::Value::String(value) => Ok((key.clone(), Some(value.clone()))),
+                serde_json::Value::Null => Ok((key.clone(), None)),
+                _ => bail!("metadata value for {key} isn't a string or null"),
+              })
+              .collect::<Result<_>>()?;
+            Ok(Some(Incoming::SetMetadata(entries)))
+          }
+          Some("set-params") => {
+            let Some(params) = control.get("params").and_then(|p| p.as_object()) else {
+              bail!("set-params without params");
+            };
+            let params = params
+              .iter()
+              .map(|(name, value)| match value {
+                serde_json::Value::String(value) => Ok((name.clone(), value.clone())),
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Ok((name.clone(), value.to_string())),
+                _ => bail!("parameter {name} isn't a string, number or boolean"),
+              })
+              .collect::<Result<_>>()?;
+            let id = control.get("id").and_then(|id| id.as_u64());
+            Ok(Some(Incoming::SetParams { id, params }))
+          }
+          Some("file_get" | "file_put" | "file_cancel") => {
+            Ok(Some(Incoming::Transfer(serde_json::from_value(control)?)))
+          }
+          name => bail!("unknown control message: {}", name.unwrap_or("<missing>")),
+        }
+      }
+      (Protocol::V2, Message::Binary(data)) => {
+        let (header, payload) = decode_envelope(data)?;
+        if header.crc32.is_some_and(|crc32| crc32 != crc32fast::hash(payload)) {
+          return Err(ChecksumMismatch.into());
+        }
+        match header.transfer {
+          Some(transfer) => Ok(Some(Incoming::TransferData(
+            transfer,
+            header.offset.unwrap_or(0),
+            Cow::Borrowed(payload),
+          ))),
+          None => Ok(Some(Incoming::Data(Cow::Borrowed(payload), header.id, header.seq))),
+        }
+      }
+      _ => Ok(None),
+    }
+  }
+}
+
+impl fmt::Display for Protocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+pub fn encode_envelope(header: &EnvelopeHeader, payload: &[u8]) -> Vec<u8> {
+  let header = serde_json::to_vec(header).expect("failed to serialize envelope header");
+  let mut buf = Vec::with_capacity(4 + header.len() + payload.len());
+  buf.extend_from_slice(&(header.len() as u32).to_be_bytes());
+  buf.extend_from_slice(&header);
+  buf.extend_from_slice(payload);
+  buf
+}
+
+pub fn decode_envelope(buf: &[u8]) -> Result<(EnvelopeHeader, &[u8])> {
+  if buf.len() < 4 {
+    bail!("truncated envelope");
+  }
+  let (len, rest) = buf.split_at(4);
+  let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+  if rest.len() < len {
+    bail!("truncated envelope header");
+  }
+
+  let (header, payload) = rest.split_at(len);
+  let header: EnvelopeHeader = serde_json::from_slice(header)?;
+  if header.length != payload.len() {
+    bail!(
+      "envelope length {} doesn't match payload length {}",
+      header.length,
+      payload.len()
+    );
+  }
+  Ok((header, payload))
+}
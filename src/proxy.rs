@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use futures_util::{future, pin_mut, StreamExt, TryStreamExt};
+use hyper::{
+  client::HttpConnector,
+  header::{HeaderName, HeaderValue, HOST},
+  http::uri::{PathAndQuery, Scheme, Uri},
+  upgrade::Upgraded,
+  Body, Client, HeaderMap, Request, Response,
+};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::config::{Config, ProxyRoute};
+use crate::peer::Peer;
+
+// Headers that apply to a single connection, and must not be forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+  "connection",
+  "keep-alive",
+  "proxy-authenticate",
+  "proxy-authorization",
+  "te",
+  "trailer",
+  "transfer-encoding",
+  "upgrade",
+];
+
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+  for name in HOP_BY_HOP_HEADERS {
+    headers.remove(*name);
+  }
+}
+
+pub fn find_route<'a>(config: &'a Config, path: &str) -> Option<&'a ProxyRoute> {
+  config
+    .proxy_routes
+    .iter()
+    .flatten()
+    .find(|route| path.starts_with(route.prefix.as_str()))
+}
+
+/// Build the upstream URI for a request matched by `route`.
+pub fn upstream_uri(route: &ProxyRoute, uri: &Uri) -> Result<Uri> {
+  let upstream: Uri = route.upstream.parse()?;
+  let path = if route.strip_prefix.unwrap_or(true) {
+    &uri.path()[route.prefix.len()..]
+  } else {
+    uri.path()
+  };
+
+  let base = upstream.path().trim_end_matches('/');
+  let path_and_query = match uri.query() {
+    Some(query) => format!("{base}/{}?{query}", path.trim_start_matches('/')),
+    None => format!("{base}/{}", path.trim_start_matches('/')),
+  };
+
+  let mut parts = upstream.into_parts();
+  parts.path_and_query = Some(PathAndQuery::try_from(path_and_query)?);
+  Ok(Uri::from_parts(parts)?)
+}
+
+/// Forward a request to the route's upstream, streaming both bodies.
+pub async fn proxy_request(
+  client: &Client<HttpConnector>,
+  route: &ProxyRoute,
+  mut req: Request<Body>,
+  peer: Peer,
+  tls: bool,
+) -> Result<Response<Body>> {
+  let uri = upstream_uri(route, req.uri())?;
+  let authority = uri
+    .authority()
+    .ok_or_else(|| anyhow!("upstream has no authority"))?
+    .clone();
+  debug!("{peer}: proxying {} to {uri}", req.uri());
+
+  let original_host = req.headers().get(HOST).cloned();
+  let headers = req.headers_mut();
+  strip_hop_by_hop_headers(headers);
+  headers.insert(HOST, HeaderValue::from_str(authority.as_str())?);
+  if let Some(host) = original_host {
+    headers.insert("x-forwarded-host", host);
+  }
+  if let Peer::Inet(addr) = peer {
+    headers.insert("x-forwarded-for", HeaderValue::from_str(&addr.ip().to_string())?);
+  }
+  headers.insert(
+    "x-forwarded-proto",
+    HeaderValue::from_static(if tls { "https" } else { "http" }),
+  );
+  for (name, value) in route.request_headers.iter().flatten() {
+    headers.insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+  }
+  *req.uri_mut() = uri;
+
+  let mut response = client.request(req).await?;
+  strip_hop_by_hop_headers(response.headers_mut());
+  for (name, value) in route.response_headers.iter().flatten() {
+    response
+      .headers_mut()
+      .insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+  }
+  Ok(response)
+}
+
+/// Relay WebSocket messages between a client and the route's upstream WebSocket server.
+pub async fn proxy_websocket(client_ws: WebSocketStream<Upgraded>, upstream: Uri, peer: Peer) -> Result<()> {
+  let mut parts = upstream.into_parts();
+  parts.scheme = Some(match parts.scheme {
+    Some(scheme) if scheme == Scheme::HTTPS => "wss".parse()?,
+    _ => "ws".parse()?,
+  });
+  let upstream = Uri::from_parts(parts)?;
+
+  let (upstream_ws, _) = tokio_tungstenite::connect_async(&upstream).await?;
+  info!("{peer}: proxying WebSocket to {upstream}");
+
+  // Pings are answered by tungstenite on each side, so only relay everything else.
+  let (client_tx, client_rx) = client_ws.split();
+  let (upstream_tx, upstream_rx) = upstream_ws.split();
+  let to_upstream = client_rx
+    .try_filter(|msg| future::ready(!msg.is_ping() && !msg.is_pong()))
+    .forward(upstream_tx);
+  let to_client = upstream_rx
+    .try_filter(|msg| future::ready(!msg.is_ping() && !msg.is_pong()))
+    .forward(client_tx);
+
+  pin_mut!(to_upstream, to_client);
+  match future::select(to_upstream, to_client).await {
+    future::Either::Left((result, _)) | future::Either::Right((result, _)) => result?,
+  }
+  info!("{peer}: proxied WebSocket closed");
+  Ok(())
+}
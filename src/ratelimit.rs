@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting data to a rate in bytes per second, allowing bursts of a second's worth.
+pub struct RateLimiter {
+  rate: f64,
+  available: f64,
+  updated: Instant,
+}
+
+impl RateLimiter {
+  pub fn new(bytes_per_sec: u64) -> RateLimiter {
+    let rate = bytes_per_sec.max(1) as f64;
+    RateLimiter {
+      rate,
+      available: rate,
+      updated: Instant::now(),
+    }
+  }
+
+  /// Account for sending `bytes`, returning how long to wait before sending them to stay within the
+  /// rate.
+  pub fn delay(&mut self, bytes: usize) -> Duration {
+    let now = Instant::now();
+    let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+    self.available = (self.available + refill).min(self.rate) - bytes as f64;
+    self.updated = now;
+    if self.available >= 0.0 {
+      Duration::ZERO
+    } else {
+      Duration::from_secs_f64(-self.available / self.rate)
+    }
+  }
+
+  /// Account for sending `amount` if the rate allows it now, returning whether it does.
+  pub fn try_take(&mut self, amount: usize) -> bool {
+    let now = Instant::now();
+    let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+    self.available = (self.available + refill).min(self.rate);
+    self.updated = now;
+    if self.available < amount as f64 {
+      return false;
+    }
+    self.available -= amount as f64;
+    true
+  }
+}
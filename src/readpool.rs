@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::Result;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{self, ThreadPolicy};
+use crate::ffi::{wardenclyffe_read_timeout, WardenclyffeReads, WardenclyffeSocket};
+use crate::sched;
+use crate::task;
+
+/// A read waiting for a dedicated thread.
+struct Read {
+  socket: WardenclyffeSocket,
+  timeout_ms: u32,
+  reply: oneshot::Sender<WardenclyffeReads>,
+}
+
+/// Where blocking reads from sockets happen: on tokio's blocking pool, alongside file I/O, or on
+/// threads that do nothing else.
+pub struct ReadPool(Threads);
+
+enum Threads {
+  Shared(Option<ThreadPolicy>),
+  Dedicated(mpsc::Sender<Read>),
+}
+
+impl ReadPool {
+  /// Start the dedicated read threads, if any are configured. They exit once the pool is dropped.
+  pub fn new(threads: Option<&config::Threads>) -> Result<ReadPool> {
+    let policy = threads.and_then(|t| t.blocking.clone());
+    let Some(count) = threads.and_then(|t| t.read_threads).filter(|&count| count > 0) else {
+      return Ok(ReadPool(Threads::Shared(policy)));
+    };
+
+    let queue = threads.and_then(|t| t.read_queue).unwrap_or(256);
+    ReadPool::dedicated("wardenclyffe-read", count, queue, policy)
+  }
+
+  /// Start `count` threads that do nothing but read, waiting for up to `queue` reads.
+  pub fn dedicated(name: &str, count: usize, queue: usize, policy: Option<ThreadPolicy>) -> Result<ReadPool> {
+    let (queue, reads) = mpsc::channel::<Read>(queue.max(1));
+    let reads = Arc::new(Mutex::new(reads));
+    for i in 0..count {
+      let reads = reads.clone();
+      let policy = policy.clone();
+      thread::Builder::new().name(format!("{name}-{i}")).spawn(move || {
+        if let Some(policy) = &policy {
+          sched::apply(policy);
+        }
+        loop {
+          let Some(read) = reads.lock().unwrap().blocking_recv() else {
+            return;
+          };
+          // Nobody is waiting for reads whose session has gone away, and their socket may have
+          // been destroyed.
+          if read.reply.is_closed() {
+            continue;
+          }
+          let result = unsafe { wardenclyffe_read_timeout(read.socket, read.timeout_ms) };
+          let _ = read.reply.send(result);
+        }
+      })?;
+    }
+    Ok(ReadPool(Threads::Dedicated(queue)))
+  }
+
+  /// Read from a socket, waiting up to `timeout_ms` for something to be read.
+  pub async fn read(&self, socket: WardenclyffeSocket, timeout_ms: u32) -> WardenclyffeReads {
+    match &self.0 {
+      Threads::Shared(policy) => {
+        let policy = policy.clone();
+        task::spawn_blocking("wardenclyffe_read", move || {
+          // The thread goes back to the pool afterwards, to run anything else.
+          let _scoped = sched::apply_scoped(policy.as_ref());
+          unsafe { wardenclyffe_read_timeout(socket, timeout_ms) }
+        })
+        .await
+        .expect("failed to join")
+      }
+      Threads::Dedicated(queue) => {
+        let (reply, result) = oneshot::channel();
+        let read = Read {
+          socket,
+          timeout_ms,
+          reply,
+        };
+        if queue.send(read).await.is_err() {
+          panic!("read threads exited");
+        }
+        result.await.expect("read thread exited")
+      }
+    }
+  }
+}
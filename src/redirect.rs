@@ -0,0 +1,76 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use anyhow::Result;
+use hyper::header::{CONTENT_TYPE, HOST, LOCATION};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::acme;
+use crate::server::{text_response, ServerState};
+
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// The host named by a Host header, without its port.
+fn hostname(host: &str) -> &str {
+  if host.starts_with('[') {
+    return host.find(']').map(|end| &host[..=end]).unwrap_or(host);
+  }
+  match host.rsplit_once(':') {
+    Some((hostname, _)) if !hostname.contains(':') => hostname,
+    _ => host,
+  }
+}
+
+fn respond(req: &Request<Body>, tls_port: u16, acme_challenges: bool) -> Response<Body> {
+  let path = req.uri().path();
+  if let Some(token) = path.strip_prefix(ACME_CHALLENGE_PREFIX).filter(|_| acme_challenges) {
+    return match acme::http_challenge(token) {
+      Some(key_authorization) => {
+        let mut response = text_response(StatusCode::OK, key_authorization);
+        response
+          .headers_mut()
+          .insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+        response
+      }
+      None => text_response(StatusCode::NOT_FOUND, "No such challenge"),
+    };
+  }
+
+  let Some(host) = req.headers().get(HOST).and_then(|host| host.to_str().ok()) else {
+    return text_response(StatusCode::BAD_REQUEST, "Host header required");
+  };
+  let port = match tls_port {
+    443 => String::new(),
+    port => format!(":{port}"),
+  };
+  let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+  let location = format!("https://{}{port}{path_and_query}", hostname(host));
+  match location.parse() {
+    Ok(location) => {
+      let mut response = text_response(StatusCode::MOVED_PERMANENTLY, "");
+      response.headers_mut().insert(LOCATION, location);
+      response
+    }
+    Err(_) => text_response(StatusCode::BAD_REQUEST, "Invalid Host header"),
+  }
+}
+
+/// Redirect plaintext HTTP requests to the TLS port, so that people who type the device's address
+/// into a browser end up at the UI rather than a connection reset, and answer ACME HTTP-01
+/// challenges if configured to.
+pub async fn serve_redirects(state: Arc<ServerState>, incoming: AddrIncoming) -> Result<()> {
+  let config = &state.config;
+  let tls_port = config.port.unwrap();
+  let acme_challenges = config.redirect_listener.as_ref().unwrap().acme_challenges.unwrap();
+  info!("redirecting HTTP on {} to port {tls_port}", incoming.local_addr());
+
+  let make_service = make_service_fn(move |_| async move {
+    Ok::<_, Infallible>(service_fn(move |req| async move {
+      Ok::<_, Infallible>(respond(&req, tls_port, acme_challenges))
+    }))
+  });
+  hyper::Server::builder(incoming).serve(make_service).await?;
+  Ok(())
+}
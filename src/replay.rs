@@ -0,0 +1,137 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::SocketPolicy;
+
+// How much data is kept for replay when only `replay_ms` is set.
+const DEFAULT_REPLAY_BYTES: usize = 1024 * 1024;
+
+/// How much of a socket's recent data to keep for new clients.
+#[derive(Clone, Copy)]
+pub struct ReplayLimits {
+  max_age: Option<Duration>,
+  max_bytes: usize,
+}
+
+impl ReplayLimits {
+  /// The limits in a socket's policy, if it keeps data for replay.
+  pub fn from_policy(policy: Option<&SocketPolicy>) -> Option<ReplayLimits> {
+    let policy = policy?;
+    if policy.replay_ms.is_none() && policy.replay_bytes.is_none() {
+      return None;
+    }
+    Some(ReplayLimits {
+      max_age: policy.replay_ms.map(Duration::from_millis),
+      max_bytes: policy.replay_bytes.unwrap_or(DEFAULT_REPLAY_BYTES),
+    })
+  }
+}
+
+struct Entry {
+  recorded: Instant,
+  timestamp_us: u64,
+  data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct ReplayBuffer {
+  entries: VecDeque<Entry>,
+  bytes: usize,
+
+  /// The session whose reads are being recorded. Every session on a path reads the same data from
+  /// its own socket, so only one of them records it at a time.
+  recorder: Option<u64>,
+}
+
+impl ReplayBuffer {
+  fn prune(&mut self, limits: ReplayLimits) {
+    while let Some(entry) = self.entries.front() {
+      let expired = limits.max_age.is_some_and(|max_age| entry.recorded.elapsed() > max_age);
+      if !expired && self.bytes <= limits.max_bytes {
+        break;
+      }
+      self.bytes -= entry.data.len();
+      self.entries.pop_front();
+    }
+  }
+}
+
+/// Recent data read from sockets, by socket path, for clients to catch up on when they connect.
+#[derive(Default)]
+pub struct ReplayBuffers {
+  buffers: Mutex<HashMap<String, ReplayBuffer>>,
+  next_recorder: AtomicU64,
+}
+
+impl ReplayBuffers {
+  /// Start recording what a session reads from a socket, unless another session already is.
+  pub fn start_recording(self: &Arc<Self>, socket_path: &str, limits: ReplayLimits) -> Option<Recorder> {
+    let mut buffers = self.buffers.lock().unwrap();
+    let buffer = buffers.entry(socket_path.to_string()).or_default();
+    if buffer.recorder.is_some() {
+      return None;
+    }
+    let id = self.next_recorder.fetch_add(1, Ordering::Relaxed);
+    buffer.recorder = Some(id);
+    Some(Recorder {
+      buffers: self.clone(),
+      socket_path: socket_path.to_string(),
+      id,
+      limits,
+    })
+  }
+
+  /// The data kept for a socket (as capture timestamps and payloads, oldest first), limited to what
+  /// was read in the last `max_age` if set.
+  pub fn backlog(&self, socket_path: &str, limits: ReplayLimits, max_age: Option<Duration>) -> Vec<(u64, Vec<u8>)> {
+    let mut buffers = self.buffers.lock().unwrap();
+    let Some(buffer) = buffers.get_mut(socket_path) else {
+      return Vec::new();
+    };
+    buffer.prune(limits);
+    buffer
+      .entries
+      .iter()
+      .filter(|entry| !matches!(max_age, Some(max_age) if entry.recorded.elapsed() > max_age))
+      .map(|entry| (entry.timestamp_us, entry.data.clone()))
+      .collect()
+  }
+}
+
+/// Records what a session reads into its socket path's replay buffer, until it's dropped.
+pub struct Recorder {
+  buffers: Arc<ReplayBuffers>,
+  socket_path: String,
+  id: u64,
+  limits: ReplayLimits,
+}
+
+impl Recorder {
+  pub fn record(&self, data: &[u8], timestamp_us: u64) {
+    let mut buffers = self.buffers.buffers.lock().unwrap();
+    let Some(buffer) = buffers.get_mut(&self.socket_path) else {
+      return;
+    };
+    buffer.bytes += data.len();
+    buffer.entries.push_back(Entry {
+      recorded: Instant::now(),
+      timestamp_us,
+      data: data.to_vec(),
+    });
+    buffer.prune(self.limits);
+  }
+}
+
+impl Drop for Recorder {
+  fn drop(&mut self) {
+    // Keep what's been recorded for the next client, and let the next session record.
+    let mut buffers = self.buffers.buffers.lock().unwrap();
+    if let Some(buffer) = buffers.get_mut(&self.socket_path) {
+      if buffer.recorder == Some(self.id) {
+        buffer.recorder = None;
+      }
+    }
+  }
+}
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rustls::RootCertStore;
+
+/// Where Android keeps the system's CAs, which rustls-native-certs doesn't know about.
+#[cfg(target_os = "android")]
+const SYSTEM_CA_CERTS: &str = "/system/etc/security/cacerts";
+
+/// Trust the CAs in a PEM file, or a directory of them.
+fn add_pem_certs(roots: &mut RootCertStore, path: &Path) -> Result<()> {
+  if path.is_dir() {
+    for entry in std::fs::read_dir(path)? {
+      add_pem_certs(roots, &entry?.path())?;
+    }
+    return Ok(());
+  }
+
+  let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+  for cert in rustls_pemfile::certs(&mut file)? {
+    // Skip CAs rustls can't use, rather than trusting none.
+    if let Err(e) = roots.add(&rustls::Certificate(cert)) {
+      debug!("ignoring CA in {}: {e}", path.display());
+    }
+  }
+  Ok(())
+}
+
+#[cfg(target_os = "android")]
+fn add_system_certs(roots: &mut RootCertStore) -> Result<()> {
+  add_pem_certs(roots, Path::new(SYSTEM_CA_CERTS))
+}
+
+#[cfg(not(target_os = "android"))]
+fn add_system_certs(roots: &mut RootCertStore) -> Result<()> {
+  for cert in rustls_native_certs::load_native_certs()? {
+    if let Err(e) = roots.add(&rustls::Certificate(cert.0)) {
+      debug!("ignoring system CA: {e}");
+    }
+  }
+  Ok(())
+}
+
+/// The CAs to trust for outgoing connections: those in `ca_certs` if it's set, or the system's.
+pub fn load(ca_certs: Option<&Path>) -> Result<RootCertStore> {
+  let mut roots = RootCertStore::empty();
+  match ca_certs {
+    Some(path) => add_pem_certs(&mut roots, path)?,
+    None => add_system_certs(&mut roots)?,
+  }
+  if roots.is_empty() {
+    bail!("no CA certificates found");
+  }
+  Ok(roots)
+}
@@ -0,0 +1,211 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::ThreadPolicy;
+
+// Whether failures to apply a scoped policy have been logged already, since that's done for every
+// read.
+static SCOPED_FAILURE_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Apply a scheduling policy to the calling thread, logging any part of it that fails.
+///
+/// Realtime scheduling usually requires privileges we don't have, so failures aren't fatal.
+pub fn apply(policy: &ThreadPolicy) {
+  if let Some(nice) = policy.nice {
+    if let Err(e) = set_nice(nice) {
+      warn!("failed to set nice value {nice}: {e}");
+    }
+  }
+
+  if let Some(priority) = policy.realtime_priority {
+    if let Err(e) = set_realtime_priority(priority) {
+      warn!("failed to set SCHED_FIFO priority {priority}: {e}");
+    }
+  }
+
+  if let Some(cpus) = &policy.cpu_affinity {
+    if let Err(e) = set_cpu_affinity(cpus) {
+      warn!("failed to set CPU affinity {cpus:?}: {e}");
+    }
+  }
+}
+
+/// The parts of a thread's scheduling that a scoped policy changed, restored when dropped.
+#[derive(Default)]
+pub struct Scoped {
+  nice: Option<i32>,
+  scheduler: Option<(i32, i32)>,
+  cpus: Option<Vec<usize>>,
+}
+
+/// Apply a policy to the calling thread until the returned guard is dropped, for work on a thread
+/// that's shared with other work, like tokio's blocking pool, which mustn't inherit it (e.g. a
+/// realtime priority while hashing a large file).
+pub fn apply_scoped(policy: Option<&ThreadPolicy>) -> Scoped {
+  let mut scoped = Scoped::default();
+  let Some(policy) = policy else {
+    return scoped;
+  };
+  let failed = |what: String, e: io::Error| {
+    if !SCOPED_FAILURE_LOGGED.swap(true, Ordering::Relaxed) {
+      warn!("failed to set {what} for blocking reads: {e}");
+    }
+  };
+
+  if let Some(nice) = policy.nice {
+    match get_nice().and_then(|previous| set_nice(nice).map(|()| previous)) {
+      Ok(previous) => scoped.nice = Some(previous),
+      Err(e) => failed(format!("nice value {nice}"), e),
+    }
+  }
+
+  if let Some(priority) = policy.realtime_priority {
+    match get_scheduler().and_then(|previous| set_realtime_priority(priority).map(|()| previous)) {
+      Ok(previous) => scoped.scheduler = Some(previous),
+      Err(e) => failed(format!("SCHED_FIFO priority {priority}"), e),
+    }
+  }
+
+  if let Some(cpus) = &policy.cpu_affinity {
+    match get_cpu_affinity().and_then(|previous| set_cpu_affinity(cpus).map(|()| previous)) {
+      Ok(previous) => scoped.cpus = Some(previous),
+      Err(e) => failed(format!("CPU affinity {cpus:?}"), e),
+    }
+  }
+  scoped
+}
+
+impl Drop for Scoped {
+  fn drop(&mut self) {
+    // Going back to a higher priority can require privileges we don't have, in which case the
+    // thread is left with the lower one.
+    if let Some(scheduler) = self.scheduler {
+      if let Err(e) = set_scheduler(scheduler) {
+        debug!("failed to restore scheduler {scheduler:?}: {e}");
+      }
+    }
+    if let Some(nice) = self.nice {
+      if let Err(e) = set_nice(nice) {
+        debug!("failed to restore nice value {nice}: {e}");
+      }
+    }
+    if let Some(cpus) = &self.cpus {
+      if let Err(e) = set_cpu_affinity(cpus) {
+        debug!("failed to restore CPU affinity {cpus:?}: {e}");
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+fn check(rc: libc::c_int) -> io::Result<()> {
+  if rc == -1 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_nice(nice: i32) -> io::Result<()> {
+  // On Linux, the priority of a tid applies to that thread alone.
+  let tid = unsafe { libc::gettid() };
+  check(unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) })
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn get_nice() -> io::Result<i32> {
+  let tid = unsafe { libc::gettid() };
+  // The system call returns 20 - nice, so that it's never -1, unlike the libc wrapper.
+  let priority = unsafe { libc::syscall(libc::SYS_getpriority, libc::PRIO_PROCESS, tid) };
+  check(priority as libc::c_int)?;
+  Ok(20 - priority as i32)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_realtime_priority(priority: i32) -> io::Result<()> {
+  set_scheduler((libc::SCHED_FIFO, priority))
+}
+
+/// The calling thread's scheduling policy and priority.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn get_scheduler() -> io::Result<(i32, i32)> {
+  let policy = unsafe { libc::sched_getscheduler(0) };
+  check(policy)?;
+  let mut param = libc::sched_param { sched_priority: 0 };
+  check(unsafe { libc::sched_getparam(0, &mut param) })?;
+  Ok((policy, param.sched_priority))
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_scheduler((policy, priority): (i32, i32)) -> io::Result<()> {
+  let param = libc::sched_param {
+    sched_priority: priority,
+  };
+  check(unsafe { libc::sched_setscheduler(0, policy, &param) })
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn get_cpu_affinity() -> io::Result<Vec<usize>> {
+  let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+  check(unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) })?;
+  Ok(
+    (0..libc::CPU_SETSIZE as usize)
+      .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+      .collect(),
+  )
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_cpu_affinity(cpus: &[usize]) -> io::Result<()> {
+  let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+  for &cpu in cpus {
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+  }
+  check(unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) })
+}
+
+#[cfg(all(unix, not(any(target_os = "android", target_os = "linux"))))]
+fn set_nice(nice: i32) -> io::Result<()> {
+  check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })
+}
+
+#[cfg(all(unix, not(any(target_os = "android", target_os = "linux"))))]
+fn get_nice() -> io::Result<i32> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(unix))]
+fn set_nice(_nice: i32) -> io::Result<()> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(unix))]
+fn get_nice() -> io::Result<i32> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn set_realtime_priority(_priority: i32) -> io::Result<()> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn get_scheduler() -> io::Result<(i32, i32)> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn set_scheduler(_scheduler: (i32, i32)) -> io::Result<()> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn get_cpu_affinity() -> io::Result<Vec<usize>> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn set_cpu_affinity(_cpus: &[usize]) -> io::Result<()> {
+  Err(io::ErrorKind::Unsupported.into())
+}
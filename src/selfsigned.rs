@@ -0,0 +1,442 @@
+#[cfg(unix)]
+use std::ffi::{c_char, CStr};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, SanType, PKCS_ECDSA_P256_SHA256};
+use ring::digest::{digest, SHA256};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use x509_parser::extensions::GeneralName;
+
+use crate::config;
+use crate::store::write_file;
+use crate::tls::{self, CertificateSource};
+
+// How often to check whether the device's names or addresses have changed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+// Certificates with a limited validity start this long before they're issued, in case clients'
+// clocks are behind.
+const CLOCK_SKEW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What's configured for self-signed certificates.
+#[derive(Default)]
+struct Options {
+  /// Names in addition to the device's own.
+  names: Vec<SanType>,
+  common_name: Option<String>,
+  validity: Option<Duration>,
+}
+
+impl Options {
+  fn new(config: Option<&config::SelfSignedCertificate>) -> Options {
+    let Some(config) = config else {
+      return Options::default();
+    };
+    let names = config
+      .subject_alt_names
+      .iter()
+      .flatten()
+      .map(|name| match name.parse::<IpAddr>() {
+        Ok(addr) => SanType::IpAddress(addr),
+        Err(_) => SanType::DnsName(name.clone()),
+      })
+      .collect();
+    Options {
+      names,
+      common_name: config.common_name.clone(),
+      validity: config
+        .validity_days
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+    }
+  }
+
+  /// Whether a certificate that expires at `not_after` is due to be reissued: once less than a
+  /// quarter of its validity is left, if it's limited.
+  fn due(&self, not_after: SystemTime) -> bool {
+    self
+      .validity
+      .is_some_and(|validity| SystemTime::now() + validity / 4 >= not_after)
+  }
+}
+
+/// The names a device can be reached by.
+#[derive(PartialEq, Eq, Debug)]
+struct DeviceNames {
+  hostname: Option<String>,
+  addresses: Vec<IpAddr>,
+}
+
+impl DeviceNames {
+  fn current() -> DeviceNames {
+    DeviceNames {
+      hostname: hostname(),
+      addresses: addresses(),
+    }
+  }
+
+  /// Whether a certificate names exactly the hostname, its mDNS name, every address, and the
+  /// configured names, with the configured common name.
+  fn named_by(&self, cert: &rustls::Certificate, options: &Options) -> bool {
+    let Ok((_, parsed)) = x509_parser::parse_x509_certificate(&cert.0) else {
+      return false;
+    };
+    let common_name = parsed
+      .subject()
+      .iter_common_name()
+      .next()
+      .and_then(|cn| cn.as_str().ok());
+    if common_name != Some(&self.common_name(options)) {
+      return false;
+    }
+    let Ok(Some(san)) = parsed.subject_alternative_name() else {
+      return false;
+    };
+    let mut named: Vec<_> = san
+      .value
+      .general_names
+      .iter()
+      .filter_map(|name| match name {
+        GeneralName::DNSName(name) => Some(SanType::DnsName(name.to_string())),
+        GeneralName::IPAddress(&[a, b, c, d]) => Some(SanType::IpAddress(IpAddr::from([a, b, c, d]))),
+        GeneralName::IPAddress(addr) => <[u8; 16]>::try_from(*addr)
+          .ok()
+          .map(|addr| SanType::IpAddress(IpAddr::from(addr))),
+        _ => None,
+      })
+      .collect();
+    let mut expected = self.subject_alt_names(options);
+    let key = |name: &SanType| format!("{name:?}");
+    named.sort_by_key(key);
+    expected.sort_by_key(key);
+    named == expected
+  }
+
+  fn common_name(&self, options: &Options) -> String {
+    options
+      .common_name
+      .clone()
+      .or_else(|| self.hostname.clone())
+      .unwrap_or_else(|| "wardenclyffe".into())
+  }
+
+  /// The hostname, its mDNS name, and every address, along with localhost and the configured names.
+  fn subject_alt_names(&self, options: &Options) -> Vec<SanType> {
+    let mut names = vec![SanType::DnsName("localhost".into())];
+    if let Some(hostname) = self.hostname.as_ref().filter(|hostname| *hostname != "localhost") {
+      names.push(SanType::DnsName(hostname.clone()));
+      if !hostname.contains('.') {
+        names.push(SanType::DnsName(format!("{hostname}.local")));
+      }
+    }
+    names.extend(self.addresses.iter().map(|addr| SanType::IpAddress(*addr)));
+    for name in &options.names {
+      if !names.contains(name) {
+        names.push(name.clone());
+      }
+    }
+    names
+  }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+  let mut buf = [0u8; 256];
+  if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut c_char, buf.len()) } != 0 {
+    return None;
+  }
+  let hostname = CStr::from_bytes_until_nul(&buf).ok()?.to_str().ok()?;
+  (!hostname.is_empty()).then(|| hostname.to_string())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+  std::env::var("COMPUTERNAME")
+    .ok()
+    .filter(|hostname| !hostname.is_empty())
+}
+
+/// The addresses of every network interface.
+#[cfg(unix)]
+fn addresses() -> Vec<IpAddr> {
+  let mut addresses = Vec::new();
+  let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+  if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+    return addresses;
+  }
+
+  let mut ifaddr = ifaddrs;
+  while let Some(ifa) = unsafe { ifaddr.as_ref() } {
+    if let Some(addr) = unsafe { ifa.ifa_addr.as_ref() } {
+      match addr.sa_family as i32 {
+        libc::AF_INET => {
+          let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+          addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+        }
+        libc::AF_INET6 => {
+          let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+          addresses.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+        }
+        _ => {}
+      }
+    }
+    ifaddr = ifa.ifa_next;
+  }
+  unsafe { libc::freeifaddrs(ifaddrs) };
+
+  addresses.sort();
+  addresses.dedup();
+  addresses
+}
+
+// Development servers on other hosts are reached through localhost.
+#[cfg(not(unix))]
+fn addresses() -> Vec<IpAddr> {
+  Vec::new()
+}
+
+/// Issue a certificate for `names`, signed by the key in `key_der` (PKCS#8), returning it and when
+/// it expires.
+fn certify(key_der: &[u8], names: &DeviceNames, options: &Options) -> Result<(rustls::Certificate, SystemTime)> {
+  let mut params = CertificateParams::default();
+  params.alg = &PKCS_ECDSA_P256_SHA256;
+  params.key_pair = Some(KeyPair::from_der(key_der)?);
+  params.subject_alt_names = names.subject_alt_names(options);
+  params.distinguished_name = DistinguishedName::new();
+  params
+    .distinguished_name
+    .push(DnType::CommonName, names.common_name(options));
+
+  // Certificates are valid practically forever, unless configured otherwise.
+  let epoch = rcgen::date_time_ymd(1970, 1, 1);
+  let mut not_after = UNIX_EPOCH + (params.not_after - epoch).unsigned_abs();
+  if let Some(validity) = options.validity {
+    let now = SystemTime::now();
+    not_after = now + validity;
+    params.not_before = epoch + (now - CLOCK_SKEW).duration_since(UNIX_EPOCH)?;
+    params.not_after = epoch + not_after.duration_since(UNIX_EPOCH)?;
+  }
+
+  let cert = rustls::Certificate(Certificate::from_params(params)?.serialize_der()?);
+  Ok((cert, not_after))
+}
+
+fn certified_key(key_der: &[u8], cert: rustls::Certificate) -> Result<Arc<CertifiedKey>> {
+  let key = rustls::sign::any_supported_type(&rustls::PrivateKey(key_der.to_vec()))?;
+  Ok(Arc::new(CertifiedKey::new(vec![cert], key)))
+}
+
+/// Encode DER as PEM, with the label of its contents, e.g. `CERTIFICATE`.
+fn pem(label: &str, der: &[u8]) -> String {
+  let mut pem = format!("-----BEGIN {label}-----\n");
+  for line in STANDARD.encode(der).as_bytes().chunks(64) {
+    pem.push_str(std::str::from_utf8(line).unwrap());
+    pem.push('\n');
+  }
+  pem.push_str(&format!("-----END {label}-----\n"));
+  pem
+}
+
+/// The SHA-256 fingerprint of a certificate, as browsers show it.
+fn fingerprint(cert: &rustls::Certificate) -> String {
+  let hex: Vec<_> = digest(&SHA256, &cert.0)
+    .as_ref()
+    .iter()
+    .map(|b| format!("{b:02X}"))
+    .collect();
+  hex.join(":")
+}
+
+fn cert_path(cache_dir: &Path) -> PathBuf {
+  cache_dir.join("cert.pem")
+}
+
+fn key_path(cache_dir: &Path) -> PathBuf {
+  cache_dir.join("key.pem")
+}
+
+/// The key kept in `cache_dir`, generating and keeping one if there isn't one yet.
+fn load_or_generate_key(cache_dir: &Path) -> Result<Vec<u8>> {
+  let key_path = key_path(cache_dir);
+  if key_path.exists() {
+    let key_der = tls::load_private_key(&key_path, None)?.0;
+    // Only keys we generated can sign reissued certificates.
+    KeyPair::from_der(&key_der).map_err(|e| anyhow!("unusable key in {}: {e}", key_path.display()))?;
+    return Ok(key_der);
+  }
+
+  let key_der = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?.serialize_der();
+  fs::create_dir_all(cache_dir)?;
+  write_file(&key_path, pem("PRIVATE KEY", &key_der).as_bytes())?;
+  info!("generated self-signed certificate key in {}", key_path.display());
+  Ok(key_der)
+}
+
+/// The certificate kept in `cache_dir` and when it expires, if there is one for `names` and the key
+/// that isn't due to be reissued.
+fn load_cert(
+  cache_dir: &Path,
+  key_der: &[u8],
+  names: &DeviceNames,
+  options: &Options,
+) -> Result<Option<(rustls::Certificate, SystemTime)>> {
+  let cert_path = cert_path(cache_dir);
+  if !cert_path.exists() {
+    return Ok(None);
+  }
+  let Some(cert) = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path)?))?
+    .into_iter()
+    .next()
+    .map(rustls::Certificate)
+  else {
+    return Ok(None);
+  };
+
+  let (_, parsed) =
+    x509_parser::parse_x509_certificate(&cert.0).map_err(|e| anyhow!("failed to parse certificate: {e}"))?;
+  let public_key = KeyPair::from_der(key_der)?.public_key_raw().to_vec();
+  if *parsed.public_key().subject_public_key.data != public_key[..] {
+    warn!("{} doesn't match its key, reissuing it", cert_path.display());
+    return Ok(None);
+  }
+  if !names.named_by(&cert, options) {
+    info!("names changed since {} was issued, reissuing it", cert_path.display());
+    return Ok(None);
+  }
+  let not_after = UNIX_EPOCH + Duration::from_secs(parsed.validity().not_after.timestamp().max(0) as u64);
+  if options.due(not_after) {
+    info!("{} is about to expire, reissuing it", cert_path.display());
+    return Ok(None);
+  }
+  Ok(Some((cert, not_after)))
+}
+
+struct Issued {
+  names: DeviceNames,
+  certified: Arc<CertifiedKey>,
+  not_after: SystemTime,
+  checked: Instant,
+}
+
+/// Serves a self-signed certificate naming the device's current hostname and addresses, reissued
+/// (with the same key) when they change or it's about to expire, since many TLS stacks reject a
+/// certificate for `*`.
+pub struct SelfSignedResolver {
+  key_der: Vec<u8>,
+  options: Options,
+  issued: Mutex<Issued>,
+
+  /// Where the key and certificate are kept across restarts, if they are.
+  cache_dir: Option<PathBuf>,
+}
+
+impl SelfSignedResolver {
+  /// Serve a certificate with a new key, which lasts until the server stops.
+  pub fn new(config: Option<&config::SelfSignedCertificate>) -> Result<SelfSignedResolver> {
+    let key_der = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?.serialize_der();
+    SelfSignedResolver::with_key(key_der, Options::new(config), None)
+  }
+
+  /// Serve the certificate kept in `cache_dir`, so that clients that pinned or accepted it keep
+  /// trusting the server after it restarts. It's created there if it doesn't exist yet, and replaced
+  /// when it's reissued.
+  pub fn persistent(cache_dir: &Path, config: Option<&config::SelfSignedCertificate>) -> Result<SelfSignedResolver> {
+    let key_der = load_or_generate_key(cache_dir)?;
+    SelfSignedResolver::with_key(key_der, Options::new(config), Some(cache_dir.to_path_buf()))
+  }
+
+  fn with_key(key_der: Vec<u8>, options: Options, cache_dir: Option<PathBuf>) -> Result<SelfSignedResolver> {
+    let names = DeviceNames::current();
+    let cached = match &cache_dir {
+      Some(cache_dir) => load_cert(cache_dir, &key_der, &names, &options).unwrap_or_else(|e| {
+        warn!("failed to load self-signed certificate: {e:?}");
+        None
+      }),
+      None => None,
+    };
+    let (certified, not_after) = match cached {
+      Some((cert, not_after)) => {
+        info!(
+          "serving self-signed certificate for {names:?}, with SHA-256 fingerprint {}",
+          fingerprint(&cert)
+        );
+        (certified_key(&key_der, cert)?, not_after)
+      }
+      None => issue(&key_der, &names, &options, cache_dir.as_deref())?,
+    };
+    Ok(SelfSignedResolver {
+      key_der,
+      options,
+      issued: Mutex::new(Issued {
+        names,
+        certified,
+        not_after,
+        checked: Instant::now(),
+      }),
+      cache_dir,
+    })
+  }
+}
+
+/// Issue a certificate for `names`, keeping it in `cache_dir` if set.
+fn issue(
+  key_der: &[u8],
+  names: &DeviceNames,
+  options: &Options,
+  cache_dir: Option<&Path>,
+) -> Result<(Arc<CertifiedKey>, SystemTime)> {
+  let (cert, not_after) = certify(key_der, names, options)?;
+  info!(
+    "issued self-signed certificate for {names:?}, with SHA-256 fingerprint {}",
+    fingerprint(&cert)
+  );
+  if let Some(cache_dir) = cache_dir {
+    // The certificate still works until the server restarts.
+    if let Err(e) = write_file(&cert_path(cache_dir), pem("CERTIFICATE", &cert.0).as_bytes()) {
+      error!("failed to keep self-signed certificate: {e:?}");
+    }
+  }
+  Ok((certified_key(key_der, cert)?, not_after))
+}
+
+impl ResolvesServerCert for SelfSignedResolver {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    Some(self.current())
+  }
+}
+
+impl CertificateSource for SelfSignedResolver {
+  fn current(&self) -> Arc<CertifiedKey> {
+    let mut issued = self.issued.lock().unwrap();
+    if issued.checked.elapsed() >= REFRESH_INTERVAL {
+      issued.checked = Instant::now();
+      let names = DeviceNames::current();
+      let changed = names != issued.names;
+      if changed || self.options.due(issued.not_after) {
+        if changed {
+          info!("device names changed, reissuing self-signed certificate");
+        } else {
+          info!("self-signed certificate is about to expire, reissuing it");
+        }
+        match issue(&self.key_der, &names, &self.options, self.cache_dir.as_deref()) {
+          Ok((certified, not_after)) => {
+            issued.certified = certified;
+            issued.names = names;
+            issued.not_after = not_after;
+          }
+          Err(e) => error!("failed to reissue self-signed certificate: {e:?}"),
+        }
+      }
+    }
+    issued.certified.clone()
+  }
+}
@@ -1,153 +1,1967 @@
-use std::ffi::{c_void, CString};
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::convert::Infallible;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use futures_util::{future, pin_mut, SinkExt, StreamExt, TryStreamExt};
+use futures_util::{future, pin_mut, stream, SinkExt, StreamExt, TryStreamExt};
 
 use anyhow::{bail, Result};
 
 use hyper::{
-  header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE},
+  client::HttpConnector,
+  header::{
+    HeaderName, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, HOST, LOCATION, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION, UPGRADE, USER_AGENT, VARY,
+  },
   upgrade::Upgraded,
-  Body, Method, Request, Response, StatusCode, Version,
+  Body, Client, Method, Request, Response, StatusCode, Version,
 };
 
+use tokio::io::AsyncReadExt;
 use tokio_tungstenite::WebSocketStream;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
 use tungstenite::handshake::derive_accept_key;
 use tungstenite::protocol::frame::coding::CloseCode;
 use tungstenite::protocol::frame::CloseFrame;
 use tungstenite::protocol::{Message, Role};
 
-use crate::config::{Config, HttpContent};
+use crate::admin::handle_admin;
+use crate::announce;
+use crate::api::{self, handle_api};
+use crate::archive::{Archive, Archives};
+use crate::attempts::FailedAttempts;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::auth::{credential, query_param, Access, Authenticator};
+use crate::bundle::BundleInstaller;
+use crate::coalesce::{Assembly, Batch, Coalescer};
+use crate::conditions::Suspension;
+use crate::config::{Config, HttpContent, Mount, SocketPolicy, VirtualHost, TLS};
+use crate::delta::DeltaEncoder;
+use crate::errors::{render_error, BackendError, ErrorFormat, ErrorMessage};
 use crate::ffi::*;
+use crate::filter::LineFilter;
+use crate::integrity::{self, MessageAuthenticator, SessionKey};
+use crate::lockout::Lockout;
+use crate::longpoll::{handle_long_poll, LongPollSessions};
+use crate::memory::MemoryBudget;
+use crate::merge;
+use crate::metadata::Metadata;
+use crate::peer::{Peer, PeerInfo};
+use crate::peerlimit::PeerLimit;
+use crate::pinning::{PinManifest, MANIFEST_PATH};
+use crate::protocol::{ChecksumMismatch, Checksums, Features, Hello, Incoming, Protocol};
+use crate::proxy;
+use crate::ratelimit::RateLimiter;
+use crate::readpool::ReadPool;
+use crate::replay::{ReplayBuffers, ReplayLimits};
+use crate::session::{Outbox, Outgoing, OutgoingQueue, WebSocketSession, WebSocketSessions};
+use crate::shm;
+use crate::stats::{CloseReason, Stats};
+use crate::status;
+use crate::store::{with_suffix, StateStore};
+use crate::task;
+use crate::termination::Termination;
+use crate::tls::{CertificateSource, TlsInfo};
+use crate::transfer::{TransferMessage, Transfers};
+use crate::validate::Validator;
+use crate::version;
+use crate::webhook::Webhook;
 
-use include_dir::{include_dir, Dir, File};
+use include_dir::{include_dir, Dir};
+use percent_encoding::percent_decode_str;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+// Coalesced data is sent once this much has been buffered, unless configured otherwise.
+const DEFAULT_COALESCE_BYTES: usize = 64 * 1024;
+
+// Messages from the client waiting to be written to the socket, unless configured otherwise.
+const DEFAULT_INBOUND_QUEUE: usize = 16;
+
+// Data buffered for a suspended session, unless configured otherwise.
+const DEFAULT_RESUME_BUFFER_BYTES: usize = 1024 * 1024;
+
+// How long before their session's time is up clients are warned, unless configured otherwise.
+const DEFAULT_SESSION_WARNING: Duration = Duration::from_secs(60);
+
+// Session IDs are chosen by clients, but must be long enough to be hard to guess, since anyone who
+// knows one (and is authorized for the socket, as the same identity) can take the session over.
+const MIN_SESSION_ID_LEN: usize = 16;
 
 static HTML_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/html");
 
+pub struct ServerState {
+  pub config: Config,
+  pub auth: Arc<Authenticator>,
+  pub audit: AuditLog,
+  pub webhook: Option<Arc<Webhook>>,
+  pub http_client: Client<HttpConnector>,
+  pub stats: Arc<Stats>,
+  pub memory: Arc<MemoryBudget>,
+  pub archives: Arc<Archives>,
+  pub ui_bundle: Option<Arc<BundleInstaller>>,
+  pub long_poll: LongPollSessions,
+  pub websocket_sessions: WebSocketSessions,
+  pub replay: Arc<ReplayBuffers>,
+
+  /// Where blocking reads from sockets happen.
+  pub reads: ReadPool,
+
+  /// Requests being handled, and WebSocket sessions open, for each client address.
+  pub peer_requests: Arc<PeerLimit>,
+  pub peer_sessions: Arc<PeerLimit>,
+
+  /// The certificate being served, once TLS is set up, and the manifest it's described in.
+  pub certificate: OnceLock<Arc<dyn CertificateSource>>,
+  pub pin_manifest: Option<PinManifest>,
+
+  pub failed_attempts: Arc<FailedAttempts>,
+}
+
+impl ServerState {
+  pub fn new(config: Config) -> Result<ServerState> {
+    let store = config
+      .state_path
+      .as_deref()
+      .map(|path| StateStore::open(path).map(Arc::new))
+      .transpose()?;
+    let auth = Arc::new(Authenticator::new(config.auth.as_ref().unwrap(), store.clone())?);
+    let stats = Arc::new(Stats::default());
+    let lockout = config
+      .lockout
+      .as_ref()
+      .map(|lockout| Lockout::new(lockout, stats.clone()));
+    let failed_attempts = Arc::new(FailedAttempts::new(
+      config.failed_attempts.as_ref().unwrap(),
+      store,
+      lockout,
+    ));
+    let webhook = config.webhook.as_ref().map(Webhook::new).transpose()?.map(Arc::new);
+    let reads = ReadPool::new(config.threads.as_ref())?;
+    let audit = AuditLog::new(config.audit_log.as_deref(), failed_attempts.clone(), webhook.clone())?;
+    let limits = config.limits.as_ref().unwrap();
+    let memory = Arc::new(MemoryBudget::new(limits.memory_budget_bytes.unwrap()));
+    let peer_requests = Arc::new(PeerLimit::new(limits.max_requests_per_peer));
+    let peer_sessions = Arc::new(PeerLimit::new(limits.max_websocket_sessions_per_peer));
+
+    // Index every archive up front, rather than on the first request for it.
+    let vhost_content = config
+      .virtual_hosts
+      .iter()
+      .flatten()
+      .map(|(_, vhost)| vhost.http_content.as_ref());
+    let mount_content = config.mounts.iter().flatten().map(|mount| Some(&mount.content));
+    let archives = Arc::new(Archives::default());
+    let contents = std::iter::once(config.http_content.as_ref())
+      .chain(vhost_content)
+      .chain(mount_content);
+    for content in contents.flatten() {
+      if let HttpContent::Archive(path) = content {
+        if !archives.contains(path) {
+          archives.insert(path.clone(), Archive::open(path)?);
+        }
+      }
+    }
+
+    let pin_manifest = config.pinning.as_ref().map(PinManifest::new).transpose()?;
+
+    let ui_bundle = match &config.ui_bundle {
+      Some(ui_bundle) => Some(Arc::new(BundleInstaller::new(
+        ui_bundle,
+        config.http_content.as_ref().unwrap(),
+        archives.clone(),
+      )?)),
+      None => None,
+    };
+
+    Ok(ServerState {
+      config,
+      auth,
+      audit,
+      webhook,
+      http_client: Client::new(),
+      stats,
+      memory,
+      archives,
+      ui_bundle,
+      long_poll: LongPollSessions::default(),
+      websocket_sessions: WebSocketSessions::default(),
+      replay: Default::default(),
+      reads,
+      peer_requests,
+      peer_sessions,
+      certificate: OnceLock::new(),
+      pin_manifest,
+      failed_attempts,
+    })
+  }
+}
+
+/// Ask the backend whether a peer may open a socket path, returning the reason for denial.
+async fn authorize_backend(path: &str, peer: Peer, tls: Option<TlsInfo>, scopes: Option<String>) -> Result<(), String> {
+  let Ok(path) = CString::new(path) else {
+    return Err("invalid path".into());
+  };
+
+  task::spawn_blocking("wardenclyffe_authorize", move || {
+    let peer_info = PeerInfo::new(&peer, tls.as_ref(), scopes.as_deref());
+    let mut reason = [0 as c_char; 256];
+    let allowed =
+      unsafe { wardenclyffe_authorize(path.as_ptr(), &peer_info.as_ffi(), reason.as_mut_ptr(), reason.len()) };
+    if allowed {
+      Ok(())
+    } else {
+      let reason = unsafe { CStr::from_ptr(reason.as_ptr()) };
+      Err(reason.to_string_lossy().into_owned())
+    }
+  })
+  .await
+  .expect("failed to join")
+}
+
+/// A plain text response. Error responses are re-rendered in the format the client asked for.
+pub fn text_response(status: StatusCode, body: impl Into<String>) -> Response<Body> {
+  let body = body.into();
+  let error = (status.is_client_error() || status.is_server_error()).then(|| ErrorMessage(body.clone()));
+  let mut response = Response::new(Body::from(body));
+  *response.status_mut() = status;
+  if let Some(error) = error {
+    response.extensions_mut().insert(error);
+  }
+  response
+}
+
+/// The ID of a request, for correlating errors with logs: either the client's, or a random one.
+fn request_id(req: &Request<Body>) -> String {
+  if let Some(id) = req.headers().get(X_REQUEST_ID).and_then(|id| id.to_str().ok()) {
+    return id.to_string();
+  }
+
+  let mut id = [0u8; 8];
+  let _ = SystemRandom::new().fill(&mut id);
+  id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn json_response(value: &serde_json::Value) -> Response<Body> {
+  let mut response = Response::new(Body::from(value.to_string()));
+  response
+    .headers_mut()
+    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+  response
+}
+
+/// Encode the messages that report a backend error to a client, closing with the close code
+/// configured for the error's code.
+fn encode_backend_error(config: &Config, protocol: Protocol, error: &BackendError) -> Vec<Message> {
+  let configured = config
+    .websocket
+    .as_ref()
+    .unwrap()
+    .error_close_codes
+    .iter()
+    .flatten()
+    .find(|mapping| mapping.code == error.code);
+  let (close_code, reason) = match configured {
+    Some(mapping) => (
+      CloseCode::from(mapping.close_code),
+      mapping.reason.as_deref().unwrap_or(&error.reason),
+    ),
+    None => {
+      let close_code = match error.code {
+        WARDENCLYFFE_ERROR_BUSY => CloseCode::Again,
+        WARDENCLYFFE_ERROR_UNAUTHORIZED => CloseCode::Policy,
+        WARDENCLYFFE_ERROR_INTERNAL => CloseCode::Error,
+        _ => CloseCode::Error,
+      };
+      (close_code, error.reason.as_str())
+    }
+  };
+
+  // Some codes, like 1006, are reserved for reporting locally and can't be sent.
+  let close_code = if close_code.is_allowed() {
+    close_code
+  } else {
+    warn!(
+      "close code {} can't be sent, using {}",
+      u16::from(close_code),
+      u16::from(CloseCode::Error)
+    );
+    CloseCode::Error
+  };
+  protocol.encode_error(error, close_code, reason)
+}
+
+/// Whether data read for a session is delta encoded, which needs V2's envelopes.
+fn delta_encoding(protocol: Protocol, policy: Option<&SocketPolicy>) -> bool {
+  protocol == Protocol::V2 && policy.and_then(|p| p.delta_encoding).unwrap_or(false)
+}
+
+/// The User-Agent a request was made with, if any.
+pub fn user_agent(req: &Request<Body>) -> Option<String> {
+  req
+    .headers()
+    .get(USER_AGENT)
+    .map(|agent| String::from_utf8_lossy(agent.as_bytes()).into_owned())
+}
+
+/// Find the policy for a socket path, if any.
+pub fn socket_policy<'a>(config: &'a Config, socket_path: &str) -> Option<&'a SocketPolicy> {
+  config
+    .socket_policies
+    .iter()
+    .flatten()
+    .find(|policy| glob_match(&policy.path, socket_path))
+}
+
 async fn handle_websocket(
+  state: Arc<ServerState>,
   ws_stream: WebSocketStream<Upgraded>,
-  request: Request<Body>,
-  addr: SocketAddr,
+  mut request: Request<Body>,
+  peer: Peer,
+  access: Access,
+  socket_path: String,
+  protocol: Protocol,
 ) -> Result<()> {
-  info!("{addr}: WebSocket established (uri = {})", request.uri());
-  let path = CString::new(request.uri().path())?;
-
-  let wardenclyffe_socket = unsafe { wardenclyffe_create_socket(path.as_ptr()) };
-  if wardenclyffe_socket.0.is_null() {
-    bail!("{addr}: failed to create socket");
-  }
-
-  let (mut outgoing, incoming) = ws_stream.split();
-  let supports_read = unsafe { wardenclyffe_supports_read(wardenclyffe_socket) };
-  let supports_write = unsafe { wardenclyffe_supports_write(wardenclyffe_socket) };
-
-  let incoming = incoming.try_for_each(|msg| {
-    let msg = msg.to_text().unwrap();
-    if supports_write {
-      debug!("{addr}: received message: {}", msg);
-      let msg_bytes = msg.as_bytes();
-
-      // TODO: The lifetime of the socket seems dubious here...
-      let result = unsafe {
-        wardenclyffe_write(
-          wardenclyffe_socket,
-          msg_bytes.as_ptr() as *const c_void,
-          msg_bytes.len(),
-        )
+  let mode = SessionMode::from_request(&request).expect("mode is checked before upgrading");
+  let tls = request.extensions().get::<TlsInfo>();
+  let user_agent = user_agent(&request);
+  info!(
+    "{peer}: WebSocket established (uri = {}, socket = {socket_path}, access = {access:?}, mode = {mode:?}, protocol = {protocol}, \
+     tls = {}, user_agent = {user_agent:?})",
+    request.uri(),
+    tls.map(ToString::to_string).unwrap_or_else(|| "none".into())
+  );
+
+  let websocket_config = state.config.websocket.as_ref().unwrap();
+  let close_timeout = Duration::from_millis(websocket_config.close_timeout_ms.unwrap());
+  let file_transfer = websocket_config.file_transfer.unwrap() && protocol == Protocol::V2;
+  let socket_policy = socket_policy(&state.config, &socket_path);
+  let identity = access.identity();
+  let can_upload = access.can_upload();
+  let key = request.extensions().get::<SessionKey>().map(|key| key.0.clone());
+  let credential = credential(&request, &access);
+  let integrity = key.is_some();
+
+  // Clients can pick an ID for their session, to resume it on a new connection if they lose this
+  // one, when the socket's policy allows it.
+  let resume_window = socket_policy
+    .and_then(|p| p.resume_window_ms)
+    .map(Duration::from_millis);
+  let session_id = resume_window
+    .and_then(|_| query_param(&request, "session"))
+    .filter(|id| id.len() >= MIN_SESSION_ID_LEN);
+
+  // New clients can ask for what's been read recently with `replay=all`, or `replay=<ms>` for just
+  // the last part of it.
+  let replay_limits = ReplayLimits::from_policy(socket_policy);
+  let replay = replay_limits.and_then(|limits| match query_param(&request, "replay")?.as_str() {
+    "all" => Some((limits, None)),
+    ms => Some((limits, Some(Duration::from_millis(ms.parse().ok()?)))),
+  });
+
+  let existing = match session_id.as_deref().and_then(|id| state.websocket_sessions.get(id)) {
+    Some(session) if session.can_resume(&socket_path, &identity, protocol, integrity).await => Some(session),
+    Some(_) => {
+      warn!("{peer}: can't resume session, starting a new one");
+      None
+    }
+    None => None,
+  };
+  let resumed = existing.is_some();
+
+  let session = match existing {
+    Some(session) => session,
+    None => {
+      let path = CString::new(socket_path.as_str())?;
+      let scopes = access.scopes();
+      let peer_info = PeerInfo::new(&peer, tls, scopes.as_deref());
+      let wardenclyffe_socket = unsafe { wardenclyffe_create_socket(path.as_ptr(), &peer_info.as_ffi()) };
+      if wardenclyffe_socket.0.is_null() {
+        bail!("{peer}: failed to create socket");
+      }
+
+      state.audit.record(
+        &peer,
+        AuditEvent::SessionOpened {
+          path: &socket_path,
+          identity: identity.clone(),
+          user_agent: user_agent.clone(),
+        },
+      );
+
+      let buffer_limit = resume_window.map(|_| {
+        socket_policy
+          .and_then(|p| p.resume_buffer_bytes)
+          .unwrap_or(DEFAULT_RESUME_BUFFER_BYTES)
+      });
+      let session = Arc::new(WebSocketSession {
+        id: session_id,
+        socket: wardenclyffe_socket,
+        socket_path: socket_path.clone(),
+        identity,
+        protocol,
+        integrity,
+        supports_read: unsafe { wardenclyffe_supports_read(wardenclyffe_socket) }
+          && !access.write_only()
+          && mode != SessionMode::WriteOnly,
+        supports_write: unsafe { wardenclyffe_supports_write(wardenclyffe_socket) }
+          && !access.read_only()
+          && mode != SessionMode::ReadOnly,
+        filter: request.extensions_mut().remove::<LineFilter>(),
+        delta_reset: AtomicBool::new(false),
+        termination: Termination::register(wardenclyffe_socket),
+        metadata: Metadata::register(wardenclyffe_socket),
+        peer,
+        opened_at: SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .map(|d| d.as_millis() as u64)
+          .unwrap_or(0),
+        user_agent,
+        client: Default::default(),
+        outbox: Outbox::new(buffer_limit),
+        cancelled: AtomicBool::new(false),
+        finished: CancellationToken::new(),
+        read_loop: Default::default(),
+        attachment: Default::default(),
+        close_reason: Default::default(),
+      });
+      state.websocket_sessions.insert(session.clone());
+      session
+    }
+  };
+  let wardenclyffe_socket = session.socket;
+  let supports_read = session.supports_read;
+  let supports_write = session.supports_write;
+
+  let (mut sink, incoming) = ws_stream.split();
+  let hello = Hello {
+    server: concat!("wardenclyffe/", env!("CARGO_PKG_VERSION")).into(),
+    build: version::BUILD,
+    features: Features {
+      compression: false,
+      resume: session.id.is_some(),
+      multiplexing: false,
+      streaming: socket_policy.and_then(|p| p.stream_fragments).unwrap_or(false) && session.filter.is_none(),
+      acked_writes: protocol == Protocol::V2,
+      file_transfer,
+      integrity,
+      checksums: protocol == Protocol::V2 && socket_policy.and_then(|p| p.checksums).unwrap_or(false),
+      replay: replay_limits.is_some(),
+      text_filters: socket_policy.and_then(|p| p.text_filters).unwrap_or(false),
+      delta_encoding: delta_encoding(protocol, socket_policy),
+      parameters: protocol == Protocol::V2,
+    },
+    sources: None,
+    // Only V2 clients are told, so don't ask the backend for V1's.
+    content_type: match protocol {
+      Protocol::V1 => None,
+      Protocol::V2 => api::content_type(&state.config, &socket_path).await,
+    },
+    resumed,
+    read: supports_read,
+    write: supports_write,
+  };
+  let mut sealer = key.clone().map(MessageAuthenticator::sealer);
+  if let Some(msg) = protocol.encode_hello(&hello) {
+    let msg = match &mut sealer {
+      Some(sealer) => sealer.seal(msg),
+      None => msg,
+    };
+    if let Err(e) = sink.send(msg).await {
+      warn!("{peer}: failed to send hello: {e}");
+    }
+  }
+
+  // Sending to the client goes through the session's outbox from here on, which is shared between
+  // the read loop, and the incoming side which acknowledges and reports failed writes.
+  let Some(attached) = session.attach(sink, sealer).await else {
+    bail!("{peer}: session closed before it could be resumed");
+  };
+  if resumed {
+    info!("{peer}: resumed session");
+    Stats::increment(&state.stats.websocket_sessions_resumed);
+  } else if supports_read {
+    if let Some((limits, max_age)) = replay {
+      let backlog = state.replay.backlog(&session.socket_path, limits, max_age);
+      debug!("{peer}: replaying {} messages", backlog.len());
+      for (timestamp_us, data) in backlog {
+        let data = match &session.filter {
+          Some(filter) => match filter.apply(&data) {
+            Some(data) => data,
+            None => continue,
+          },
+          None => data,
+        };
+        session
+          .outbox
+          .send(protocol.encode_replayed(data, timestamp_us))
+          .await?;
+      }
+    }
+
+    let read_loop = read_socket(state.clone(), session.clone(), peer);
+    let finished = session.finished.clone();
+    *session.read_loop.lock().unwrap() = Some(task::spawn("websocket outgoing", async move {
+      read_loop.await;
+      finished.cancel();
+    }));
+  }
+
+  // Writes from the client are queued for a writer that applies them one at a time, so that a
+  // client sending faster than the backend (or its rate limit) allows stops being read from.
+  let inbound_queue = socket_policy
+    .and_then(|p| p.inbound_queue)
+    .unwrap_or(DEFAULT_INBOUND_QUEUE);
+  let mut rate_limiter = socket_policy
+    .and_then(|p| p.inbound_bytes_per_sec)
+    .map(RateLimiter::new);
+  let (write_tx, mut write_rx) = tokio::sync::mpsc::channel::<WriteRequest>(inbound_queue);
+  let batching = WriteBatching::from_policy(socket_policy);
+  let (transfer_tx, mut transfer_rx) = tokio::sync::mpsc::channel::<TransferMessage>(inbound_queue);
+  let (params_tx, mut params_rx) = tokio::sync::mpsc::channel::<(Option<u64>, Vec<(String, String)>)>(inbound_queue);
+  let write_failed = AtomicBool::new(false);
+  let mut validator = Validator::new(socket_policy.and_then(|p| p.validation.as_ref()), &socket_path);
+
+  // Once the client asks for acknowledgements, failed writes are reported to it rather than ending
+  // the session, so that it can retry them.
+  let mut acked_writes = false;
+  let mut write_seq = 0u64;
+
+  // The sequence number expected on the client's next numbered payload.
+  let mut client_seq = 0u64;
+  let stats = state.stats.clone();
+  let receiving = session.clone();
+
+  // Messages that fail authentication end the connection.
+  let mut opener = key.map(MessageAuthenticator::opener);
+  let incoming = incoming.and_then(move |msg| {
+    future::ready(match &mut opener {
+      Some(opener) => opener.open(msg).map_err(|e| {
+        warn!("{peer}: {e}");
+        tungstenite::Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+      }),
+      None => Ok(msg),
+    })
+  });
+
+  let receive = incoming.try_for_each(move |msg| {
+    if let Message::Close(frame) = &msg {
+      if let Some(frame) = frame {
+        info!(
+          "{peer}: client closed connection: {} {}",
+          u16::from(frame.code),
+          frame.reason
+        );
+      }
+      receiving.closing(CloseReason::Client(frame.as_ref().map(|f| u16::from(f.code))));
+    }
+
+    // Control frames are handled by tungstenite, only forward data.
+    let mut transfer = None;
+    let mut params = None;
+    let mut rejected = None;
+    let write = match protocol.decode_message(&msg) {
+      Ok(Some(Incoming::Data(data, id, seq))) => {
+        if let Some(seq) = seq {
+          if seq != client_seq {
+            warn!("{peer}: expected payload {client_seq} from client, got {seq}");
+            Stats::increment(&stats.websocket_sequence_gaps);
+          }
+          client_seq = seq + 1;
+        }
+        if let Err(reason) = validator.as_mut().map_or(Ok(()), |v| v.check(&data)) {
+          warn!("{peer}: rejected message from client: {reason}");
+          Stats::increment(&stats.inbound_messages_rejected);
+          rejected = protocol
+            .encode_rejected(id, &reason)
+            .map(|msg| (receiving.clone(), msg));
+          None
+        } else {
+          write_seq += 1;
+          Some(WriteRequest::Write(ClientWrite {
+            data: data.into_owned(),
+            id,
+            seq: write_seq - 1,
+            ack: acked_writes,
+          }))
+        }
+      }
+      Ok(Some(Incoming::ChecksumReport { gaps, mismatches })) => {
+        if gaps != 0 || mismatches != 0 {
+          warn!("{peer}: client saw {gaps} gaps and {mismatches} checksum mismatches");
+        }
+        stats.websocket_client_reported_gaps.fetch_add(gaps, Ordering::Relaxed);
+        stats
+          .websocket_client_reported_mismatches
+          .fetch_add(mismatches, Ordering::Relaxed);
+        None
+      }
+      Ok(Some(Incoming::SetMetadata(entries))) => {
+        for (key, value) in entries {
+          if let Err(e) = receiving.metadata.set(&key, value.as_deref()) {
+            warn!("{peer}: {e}");
+          }
+        }
+        None
+      }
+      Ok(Some(Incoming::SetParams { id, params: requested })) => {
+        params = Some((id, requested));
+        None
+      }
+      Ok(Some(Incoming::DeltaReset)) => {
+        receiving.delta_reset.store(true, Ordering::Relaxed);
+        None
+      }
+      Ok(Some(Incoming::AckWrites(enabled))) => {
+        acked_writes = enabled;
+        None
+      }
+      Ok(Some(Incoming::Flush)) => Some(WriteRequest::Flush),
+      Ok(Some(Incoming::Identify(client))) => {
+        info!("{peer}: client identified itself as {client:?}");
+        *receiving.client.lock().unwrap() = Some(client);
+        None
+      }
+      Ok(Some(Incoming::Transfer(request))) if file_transfer => {
+        transfer = Some(TransferMessage::Request(request));
+        None
+      }
+      Ok(Some(Incoming::TransferData(id, offset, data))) if file_transfer => {
+        transfer = Some(TransferMessage::Data(id, offset, data.into_owned()));
+        None
+      }
+      Ok(Some(Incoming::Transfer(_) | Incoming::TransferData(..))) => {
+        warn!("{peer}: file transfer isn't enabled");
+        None
+      }
+      Ok(None) => None,
+      Err(e) => {
+        if e.is::<ChecksumMismatch>() {
+          Stats::increment(&stats.websocket_checksum_mismatches);
+        }
+        warn!("{peer}: invalid message: {e}");
+        None
+      }
+    };
+
+    let delay = match &write {
+      Some(WriteRequest::Write(write)) => rate_limiter.as_mut().map(|limiter| limiter.delay(write.data.len())),
+      _ => None,
+    };
+    let write_tx = write_tx.clone();
+    let transfer_tx = transfer_tx.clone();
+    let params_tx = params_tx.clone();
+    async move {
+      if let Some((session, msg)) = rejected {
+        let _ = session.outbox.send(msg).await;
+      }
+      if let Some(transfer) = transfer {
+        let _ = transfer_tx.send(transfer).await;
+      }
+      if let Some(params) = params {
+        let _ = params_tx.send(params).await;
+      }
+      if let Some(write) = write {
+        if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+          tokio::time::sleep(delay).await;
+        }
+
+        // The writer only goes away after a failure that ends the session anyway.
+        let _ = write_tx.send(write).await;
+      }
+      Ok(())
+    }
+  });
+
+  let apply = async {
+    // Why writes are being refused, if they are.
+    let mut write_error = (!supports_write).then(|| BackendError {
+      code: WARDENCLYFFE_ERROR_UNAUTHORIZED,
+      reason: "socket is read-only".into(),
+    });
+
+    while let Some(batch) = next_write_batch(&mut write_rx, batching).await {
+      // Writes that are acknowledged get the result of the batch they were written in.
+      let acked: Vec<_> = batch
+        .iter()
+        .filter(|write| write.ack)
+        .map(|write| (write.seq, write.id))
+        .collect();
+      let all_acked = acked.len() == batch.len();
+      let data = match batch.len() {
+        1 => batch.into_iter().next().unwrap().data,
+        _ => batch.iter().map(|write| &write.data[..]).collect::<Vec<_>>().concat(),
       };
-      if result {
-        future::ok(())
+
+      let msgs: Vec<_> = if let Some(error) = &write_error {
+        info!("{peer}: received unhandled message: {}", String::from_utf8_lossy(&data));
+        acked
+          .iter()
+          .map(|&(seq, id)| protocol.encode_ack(seq, id, Some(error)))
+          .collect()
       } else {
-        future::err(tungstenite::Error::ConnectionClosed)
+        debug!("{peer}: received message: {}", String::from_utf8_lossy(&data));
+        let result = task::spawn_blocking("wardenclyffe_write", move || unsafe {
+          wardenclyffe_write(wardenclyffe_socket, data.as_ptr() as *const c_void, data.len())
+        })
+        .await
+        .expect("failed to join");
+
+        if result {
+          acked
+            .iter()
+            .map(|&(seq, id)| protocol.encode_ack(seq, id, None))
+            .collect()
+        } else {
+          let error = unsafe { BackendError::last(wardenclyffe_socket, "write failed") };
+          let mut msgs: Vec<_> = acked
+            .iter()
+            .map(|&(seq, id)| protocol.encode_ack(seq, id, Some(&error)))
+            .collect();
+          if all_acked {
+            warn!("{peer}: WardenclyffeSocket::write failed: {error:?}");
+          } else if supports_read {
+            // Keep the session going for the data it's reading, and just stop writing.
+            warn!("{peer}: WardenclyffeSocket::write failed, closing the write half: {error:?}");
+            msgs.extend(protocol.encode_write_closed(&error));
+            write_error = Some(error);
+          } else {
+            write_failed.store(true, Ordering::Relaxed);
+            session.closing(CloseReason::BackendError);
+            return Err(tungstenite::Error::ConnectionClosed);
+          }
+          msgs
+        }
+      };
+
+      for msg in msgs {
+        session.outbox.send(msg).await?;
       }
-    } else {
-      info!("{addr}: received unhandled message: {}", msg);
-      future::ok(())
     }
-  });
+    Ok(())
+  };
 
-  let outgoing = tokio::spawn(async move {
-    if supports_read {
-      loop {
-        let reads = {
-          tokio::task::spawn_blocking(move || unsafe { wardenclyffe_read(wardenclyffe_socket) })
-            .await
-            .expect("failed to join")
-        };
+  // File transfers are handled in the order they arrive, separately from writes to the socket.
+  let transfers = async {
+    let mut transfers = Transfers::new(state.clone(), session.clone(), peer, can_upload);
+    while let Some(msg) = transfer_rx.recv().await {
+      transfers.handle(msg).await;
+    }
+    Ok(())
+  };
+  // Parameters are set in the order they're asked for, and acknowledged once they've all been set or
+  // one of them has failed.
+  let allowed_params = socket_policy.and_then(|p| p.parameters.as_deref());
+  let parameters = async {
+    while let Some((id, params)) = params_rx.recv().await {
+      let mut failed = None;
+      for (name, value) in params {
+        if let Err(error) = set_parameter(wardenclyffe_socket, allowed_params, &name, &value).await {
+          warn!("{peer}: failed to set parameter {name}: {error:?}");
+          failed = Some((name, error));
+          break;
+        }
+      }
+      let failed = failed.as_ref().map(|(name, error)| (name.as_str(), error));
+      session.outbox.send(protocol.encode_params_ack(id, failed)).await?;
+    }
+    Ok(())
+  };
+  let incoming = future::try_join4(receive, apply, transfers, parameters);
+  let expiry = session_expiry(&session, protocol, socket_policy);
 
-        if reads.read_count < 0 {
-          error!("{addr}: WardenclyffeSocket::read failed: rc = {}", reads.read_count);
-          let _ = outgoing
-            .send(Message::Close(Some(CloseFrame {
-              code: CloseCode::Error,
-              reason: "read failed".into(),
-            })))
-            .await;
-          return;
-        } else if reads.read_count == 0 {
-          info!("{addr}: WardenclyffeSocket hit EOF");
-          let _ = outgoing
-            .send(Message::Close(Some(CloseFrame {
-              code: CloseCode::Normal,
-              reason: "EOF".into(),
-            })))
-            .await;
+  pin_mut!(incoming);
+  let lost = tokio::select! {
+    result = &mut incoming => result.is_err() && !write_failed.load(Ordering::Relaxed),
+
+    _ = session.finished.cancelled() => {
+      // We've sent a Close frame, so wait for the client to reply to it (which ends the stream),
+      // rather than dropping the connection out from under it.
+      if tokio::time::timeout(close_timeout, incoming).await.is_err() {
+        warn!("{peer}: timed out waiting for the client to acknowledge close");
+      }
+      false
+    }
+
+    _ = state.auth.revoked(credential.as_deref()) => {
+      info!("{peer}: credentials revoked, closing session");
+      session.closing(CloseReason::CredentialsRevoked);
+      let close = Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "credentials revoked".into(),
+      }));
+      if session.outbox.send(close).await.is_ok()
+        && tokio::time::timeout(close_timeout, incoming).await.is_err()
+      {
+        warn!("{peer}: timed out waiting for the client to acknowledge close");
+      }
+      false
+    }
+
+    _ = expiry => {
+      info!("{peer}: session reached its time limit, closing it");
+      session.closing(CloseReason::MaxDuration);
+      let close = Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "session time limit reached".into(),
+      }));
+      if session.outbox.send(close).await.is_ok()
+        && tokio::time::timeout(close_timeout, incoming).await.is_err()
+      {
+        warn!("{peer}: timed out waiting for the client to acknowledge close");
+      }
+      false
+    }
+
+    // Sessions that read from the socket are ended by their read loop, after what it's read.
+    error = session.termination.requested(), if !supports_read => {
+      info!("{peer}: backend ended the session: {error:?}");
+      session.closing(CloseReason::BackendEnded);
+      let mut sent = true;
+      for msg in encode_backend_error(&state.config, protocol, &error) {
+        sent = session.outbox.send(msg).await.is_ok();
+        if !sent {
+          break;
+        }
+      }
+      if sent && tokio::time::timeout(close_timeout, incoming).await.is_err() {
+        warn!("{peer}: timed out waiting for the client to acknowledge close");
+      }
+      false
+    }
+
+    _ = attached.replaced.cancelled() => {
+      info!("{peer}: session resumed on another connection");
+      return Ok(());
+    }
+  };
+
+  // Keep a resumable session going when its connection drops without being closed, for the client
+  // to come back to.
+  if let (true, Some(window)) = (lost && session.id.is_some(), resume_window) {
+    if session.suspend(attached.generation).await {
+      info!("{peer}: connection lost, holding session for {window:?}");
+      Stats::increment(&state.stats.websocket_sessions_suspended);
+      task::spawn(
+        "websocket session expiry",
+        expire_session(state.clone(), session.clone(), peer, attached.generation, window),
+      );
+    }
+    return Ok(());
+  }
+
+  if !session.close(attached.generation).await {
+    // Another connection has taken the session over.
+    return Ok(());
+  }
+  session.stop_reading().await;
+  if lost {
+    session.closing(CloseReason::ClientLost);
+  }
+
+  if write_failed.load(Ordering::Relaxed) {
+    let error = unsafe { BackendError::last(wardenclyffe_socket, "write failed") };
+    error!("{peer}: WardenclyffeSocket::write failed: {error:?}");
+    for msg in encode_backend_error(&state.config, protocol, &error) {
+      if session.outbox.send(msg).await.is_err() {
+        break;
+      }
+    }
+  }
+
+  info!("{peer}: disconnected");
+  destroy_session(&state, &session, peer);
+  Ok(())
+}
+
+// Limits on the parameters clients can set.
+const MAX_PARAM_NAME_LEN: usize = 64;
+const MAX_PARAM_VALUE_LEN: usize = 1024;
+
+/// Set a parameter of a socket at the client's request, if it's allowed and well-formed.
+async fn set_parameter(
+  socket: WardenclyffeSocket,
+  allowed: Option<&[String]>,
+  name: &str,
+  value: &str,
+) -> Result<(), BackendError> {
+  let refuse = |reason: String| {
+    Err(BackendError {
+      code: WARDENCLYFFE_ERROR_UNAUTHORIZED,
+      reason,
+    })
+  };
+  let valid_name = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+  if name.is_empty() || name.len() > MAX_PARAM_NAME_LEN || !name.chars().all(valid_name) {
+    return refuse(format!("invalid parameter name {name:?}"));
+  }
+  if allowed.is_some_and(|allowed| !allowed.iter().any(|allowed| allowed == name)) {
+    return refuse(format!("parameter {name} can't be set"));
+  }
+  if value.len() > MAX_PARAM_VALUE_LEN {
+    return refuse(format!("value of {name} is longer than {MAX_PARAM_VALUE_LEN} bytes"));
+  }
+  let Ok(value) = CString::new(value) else {
+    return refuse(format!("value of {name} contains a NUL"));
+  };
+  let name = CString::new(name).unwrap();
+
+  let set = task::spawn_blocking("wardenclyffe_set_parameter", move || unsafe {
+    wardenclyffe_set_parameter(socket, name.as_ptr(), value.as_ptr())
+  })
+  .await
+  .expect("failed to join");
+  if set {
+    Ok(())
+  } else {
+    Err(unsafe { BackendError::last(socket, "set_parameter failed") })
+  }
+}
+
+/// Wait until a session has been open for as long as its socket policy allows, warning the client
+/// beforehand. Never finishes if the policy doesn't limit sessions.
+async fn session_expiry(session: &WebSocketSession, protocol: Protocol, socket_policy: Option<&SocketPolicy>) {
+  let Some(max) = socket_policy.and_then(|p| p.max_session_ms).map(Duration::from_millis) else {
+    return future::pending().await;
+  };
+  let warning = socket_policy
+    .and_then(|p| p.session_warning_ms)
+    .map_or(DEFAULT_SESSION_WARNING, Duration::from_millis);
+
+  let opened_at = UNIX_EPOCH + Duration::from_millis(session.opened_at);
+  let open_for = SystemTime::now().duration_since(opened_at).unwrap_or_default();
+  let remaining = max.saturating_sub(open_for);
+  if remaining > warning {
+    tokio::time::sleep(remaining - warning).await;
+  }
+  let remaining = max.saturating_sub(SystemTime::now().duration_since(opened_at).unwrap_or_default());
+  if !remaining.is_zero() {
+    if let Some(msg) = protocol.encode_session_expiring(remaining) {
+      let _ = session.outbox.send(msg).await;
+    }
+    tokio::time::sleep(remaining).await;
+  }
+}
+
+/// Close a suspended session if it isn't resumed within `window`.
+async fn expire_session(
+  state: Arc<ServerState>,
+  session: Arc<WebSocketSession>,
+  peer: Peer,
+  generation: u64,
+  window: Duration,
+) {
+  tokio::time::sleep(window).await;
+  if session.close(generation).await {
+    info!("{peer}: session wasn't resumed within {window:?}, closing it");
+    Stats::increment(&state.stats.websocket_sessions_expired);
+    session.closing(CloseReason::ClientLost);
+    session.stop_reading().await;
+    destroy_session(&state, &session, peer);
+  }
+}
+
+/// Why a session ends after failing to send to its client: resumable sessions only fail to once
+/// they've buffered more for the client than they're allowed to.
+async fn send_failure(session: &WebSocketSession) -> CloseReason {
+  if session.outbox.overflowed().await {
+    CloseReason::SlowClient
+  } else {
+    CloseReason::ClientLost
+  }
+}
+
+/// Destroy a closed session's socket, once nothing is using it anymore.
+fn destroy_session(state: &ServerState, session: &Arc<WebSocketSession>, peer: Peer) {
+  state.websocket_sessions.remove(session);
+  // Connections that end without a close frame or any other reason were lost.
+  let reason = session.close_reason.lock().unwrap().unwrap_or(CloseReason::ClientLost);
+  state.stats.session_closed(reason);
+  state.audit.record(
+    &peer,
+    AuditEvent::SessionClosed {
+      path: &session.socket_path,
+      identity: session.identity.clone(),
+    },
+  );
+  Termination::unregister(session.socket);
+  Metadata::unregister(session.socket);
+  unsafe {
+    wardenclyffe_destroy_socket(session.socket);
+  }
+}
+
+/// Read from a session's socket and send what's read to the client, until the socket hits EOF or
+/// fails, the client can't be sent to, or the session is closed.
+async fn read_socket(state: Arc<ServerState>, session: Arc<WebSocketSession>, peer: Peer) {
+  let socket_policy = socket_policy(&state.config, &session.socket_path);
+  let Some(high) = socket_policy.and_then(|p| p.outgoing_high_watermark_bytes) else {
+    return read_socket_into(&state, &session, peer, Outgoing::Direct(&session.outbox)).await;
+  };
+  let low = socket_policy
+    .and_then(|p| p.outgoing_low_watermark_bytes)
+    .unwrap_or(high / 2)
+    .min(high);
+
+  let queue = OutgoingQueue::new(session.clone(), high, low, state.memory.clone(), state.stats.clone());
+  read_socket_into(&state, &session, peer, Outgoing::Queued(&queue)).await;
+
+  // The socket is destroyed once we return, so the queue must be done pausing and resuming it.
+  queue.finish().await;
+}
+
+async fn read_socket_into(state: &ServerState, session: &WebSocketSession, peer: Peer, outgoing: Outgoing<'_>) {
+  let wardenclyffe_socket = session.socket;
+  let protocol = session.protocol;
+
+  let websocket_config = state.config.websocket.as_ref().unwrap();
+  let read_timeout = websocket_config.read_timeout_ms.unwrap();
+  let keepalive_interval = Duration::from_millis(websocket_config.keepalive_interval_ms.unwrap());
+
+  let socket_policy = socket_policy(&state.config, &session.socket_path);
+  let mut coalescer = Coalescer::new(
+    Duration::from_millis(socket_policy.and_then(|p| p.coalesce_ms).unwrap_or(0)),
+    socket_policy
+      .and_then(|p| p.coalesce_bytes)
+      .unwrap_or(DEFAULT_COALESCE_BYTES),
+  );
+  // Fragments can't be filtered until they've all arrived.
+  let stream_fragments = socket_policy.and_then(|p| p.stream_fragments).unwrap_or(false) && session.filter.is_none();
+  let mut checksums = socket_policy
+    .and_then(|p| p.checksums)
+    .unwrap_or(false)
+    .then(Checksums::default);
+
+  // Real-time sockets are read on a thread of the session's own, which exits with the read loop.
+  let realtime = socket_policy.and_then(|p| p.realtime).unwrap_or(false);
+  let realtime_reads = realtime
+    .then(|| {
+      let threads = state.config.threads.as_ref();
+      let policy = threads.and_then(|t| t.realtime.clone().or_else(|| t.blocking.clone()));
+      ReadPool::dedicated("wardenclyffe-realtime", 1, 1, policy)
+        .inspect_err(|e| warn!("{peer}: failed to start real-time read thread, sharing the pool instead: {e}"))
+        .ok()
+    })
+    .flatten();
+  let read_pool = realtime_reads.as_ref().unwrap_or(&state.reads);
+
+  let mut last_send = Instant::now();
+
+  // A logical frame that's been split across several reads, if we're in the middle of one.
+  let mut fragment: Option<Fragment> = None;
+
+  // Out-of-band messages read while a fragmented frame was in progress, sent after it.
+  let mut deferred = Vec::new();
+
+  // Only one session on a path records its reads for replay at a time, until it stops reading, and
+  // only if it's reading everything.
+  let recorder = ReplayLimits::from_policy(socket_policy)
+    .filter(|_| session.filter.is_none())
+    .and_then(|limits| state.replay.start_recording(&session.socket_path, limits));
+
+  let mut delta = delta_encoding(protocol, socket_policy).then(DeltaEncoder::default);
+  let mut suspension = Suspension::new(socket_policy.and_then(|p| p.suspend_on.as_deref()));
+
+  macro_rules! encode_batch {
+    ($batch:expr) => {{
+      let batch = $batch;
+      if let Some(recorder) = &recorder {
+        recorder.record(&batch.data, batch.timestamp_us);
+      }
+      match &mut delta {
+        Some(delta) => {
+          if session.delta_reset.swap(false, Ordering::Relaxed) {
+            delta.reset();
+          }
+          protocol.encode_delta(batch.data, batch.timestamp_us, checksums.as_mut(), delta)
+        }
+        None => protocol.encode_data(batch.data, batch.timestamp_us, checksums.as_mut()),
+      }
+    }};
+  }
+
+  macro_rules! send {
+    ($msg:expr) => {
+      if let Err(e) = outgoing.send($msg).await {
+        error!("{peer}: failed to send: {e}");
+        session.closing(send_failure(session).await);
+        return;
+      }
+    };
+  }
+
+  macro_rules! send_deferred {
+    () => {
+      if fragment.is_none() && !deferred.is_empty() {
+        if let Some(batch) = coalescer.take() {
+          send!(encode_batch!(batch));
+        }
+        for msg in deferred.drain(..) {
+          send!(msg);
+        }
+      }
+    };
+  }
+
+  // Sends the close the backend asked for, after whatever was read before it did.
+  macro_rules! end_if_requested {
+    () => {
+      if let Some(error) = session.termination.error() {
+        info!("{peer}: backend ended the session: {error:?}");
+        session.closing(CloseReason::BackendEnded);
+        if let Some(batch) = coalescer.take() {
+          let _ = outgoing.send(encode_batch!(batch)).await;
+        }
+        for msg in encode_backend_error(&state.config, protocol, &error) {
+          if outgoing.send(msg).await.is_err() {
+            break;
+          }
+        }
+        return;
+      }
+    };
+  }
+
+  loop {
+    if session.cancelled.load(Ordering::Relaxed) {
+      return;
+    }
+    end_if_requested!();
+
+    // Stop reading while the platform is in a condition that the socket can't be read in, e.g.
+    // with the screen off, checking as often as reads time out for the session to end.
+    if let Some(suspension) = &mut suspension {
+      if let Some(condition) = suspension.active() {
+        info!("{peer}: suspending reads while {condition}");
+        if let Some(batch) = coalescer.take() {
+          send!(encode_batch!(batch));
+        }
+        if let Some(msg) = protocol.encode_suspension(Some(&condition)) {
+          send!(msg);
+        }
+        while suspension.active().is_some() {
+          if session.cancelled.load(Ordering::Relaxed) {
+            return;
+          }
+          end_if_requested!();
+          let wait = Duration::from_millis(read_timeout.into());
+          if tokio::time::timeout(wait, suspension.changed()).await.is_err()
+            && last_send.elapsed() >= keepalive_interval
+          {
+            send!(Message::Ping(Vec::new()));
+            last_send = Instant::now();
+          }
+        }
+        info!("{peer}: resuming reads");
+        if let Some(msg) = protocol.encode_suspension(None) {
+          send!(msg);
+        }
+        last_send = Instant::now();
+      }
+    }
+
+    if let Some(batch) = coalescer.take_if_due() {
+      send!(encode_batch!(batch));
+      last_send = Instant::now();
+    }
+
+    // Don't block past the point where buffered data is due to be sent.
+    let timeout = coalescer
+      .time_left()
+      .map(|left| (left.as_millis() as u32).clamp(1, read_timeout))
+      .unwrap_or(read_timeout);
+
+    let reads = read_pool.read(wardenclyffe_socket, timeout).await;
+
+    if reads.read_count == WARDENCLYFFE_READ_TIMEOUT {
+      if last_send.elapsed() >= keepalive_interval {
+        if let Err(e) = outgoing.send(Message::Ping(Vec::new())).await {
+          error!("{peer}: failed to send keepalive: {e}");
+          session.closing(send_failure(session).await);
           return;
         }
+        last_send = Instant::now();
+      }
+      continue;
+    }
+
+    if reads.read_count <= 0 {
+      end_if_requested!();
+    }
+
+    // Don't lose buffered data when the socket goes away.
+    if reads.read_count <= 0 {
+      if let Some(batch) = coalescer.take() {
+        let _ = outgoing.send(encode_batch!(batch)).await;
+      }
+    }
+
+    if reads.read_count < 0 {
+      let error = unsafe { BackendError::last(wardenclyffe_socket, "read failed") };
+      error!(
+        "{peer}: WardenclyffeSocket::read failed: rc = {}, error = {error:?}",
+        reads.read_count
+      );
+      session.closing(CloseReason::BackendError);
+      for msg in encode_backend_error(&state.config, protocol, &error) {
+        if outgoing.send(msg).await.is_err() {
+          break;
+        }
+      }
+      return;
+    } else if reads.read_count == 0 {
+      info!("{peer}: WardenclyffeSocket hit EOF");
+      session.closing(CloseReason::BackendEof);
+      let _ = outgoing
+        .send(Message::Close(Some(CloseFrame {
+          code: CloseCode::Normal,
+          reason: "EOF".into(),
+        })))
+        .await;
+      return;
+    }
+
+    let reads = unsafe { std::slice::from_raw_parts(reads.reads, reads.read_count as usize) };
+    for read in reads {
+      send_deferred!();
+
+      let read_data = match unsafe { shm::read_data(read) } {
+        Ok(data) => data,
+        Err(e) => {
+          error!("{peer}: failed to map shared memory read: {e}");
+          continue;
+        }
+      };
+      let data = &read_data[..];
+      if read.oob != 0 {
+        let msg = protocol.encode_read(data.to_vec(), true);
+        if fragment.is_some() {
+          deferred.push(msg);
+          continue;
+        }
+
+        // Keep out-of-band messages in order with the data around them.
+        if let Some(batch) = coalescer.take() {
+          send!(encode_batch!(batch));
+        }
+        send!(msg);
+        continue;
+      }
 
-        let reads = unsafe { std::slice::from_raw_parts(reads.reads, reads.read_count as usize) };
-        for read in reads {
-          let buf = unsafe { std::slice::from_raw_parts(read.data as *const u8, read.size) }.to_vec();
-          let result = if read.oob != 0 {
-            let buf_str = unsafe { String::from_utf8_unchecked(buf) };
-            outgoing.send(Message::Text(buf_str))
+      // Data can be dropped when we're buffering too much for clients that aren't keeping up,
+      // except in the middle of a frame that's already being streamed.
+      let reservation = state.memory.try_reserve(read.size as u64);
+      if reservation.is_none() && !matches!(fragment, Some(Fragment::Streaming)) {
+        Stats::increment(&state.stats.websocket_messages_dropped);
+        debug!("{peer}: memory budget exhausted, dropping {} byte message", read.size);
+        fragment = if read.more == 0 { None } else { Some(Fragment::Dropped) };
+        continue;
+      }
+
+      // Frames split across several reads are either streamed as WebSocket fragments, or
+      // assembled and then sent like any other read.
+      let complete = read.more == 0;
+      let batch = match &mut fragment {
+        None if complete => Batch::new(data.to_vec(), read.capture_time_us(), reservation),
+
+        None => {
+          if stream_fragments && protocol.can_stream(read.total_size) {
+            if let Some(batch) = coalescer.take() {
+              send!(encode_batch!(batch));
+            }
+            let timestamp_us = read.capture_time_us();
+            send!(protocol.encode_fragment(data, true, false, read.total_size, timestamp_us));
+            fragment = Some(Fragment::Streaming);
           } else {
-            outgoing.send(Message::Binary(buf))
-          };
-          if let Err(e) = result.await {
-            error!("{addr}: failed to send: {e}");
-            return;
+            let mut assembly = Assembly::with_capacity(read.total_size, read.capture_time_us());
+            assembly.push(data, reservation);
+            fragment = Some(Fragment::Assembling(assembly));
+          }
+          continue;
+        }
+
+        Some(Fragment::Streaming) => {
+          send!(protocol.encode_fragment(data, false, complete, 0, 0));
+          if complete {
+            fragment = None;
           }
+          continue;
+        }
+
+        Some(Fragment::Assembling(assembly)) => {
+          assembly.push(data, reservation);
+          if !complete {
+            continue;
+          }
+          match fragment.take() {
+            Some(Fragment::Assembling(assembly)) => assembly.finish(),
+            _ => unreachable!(),
+          }
+        }
+
+        Some(Fragment::Dropped) => {
+          if complete {
+            fragment = None;
+          }
+          continue;
+        }
+      };
+
+      let mut batch = batch;
+      if let Some(filter) = &session.filter {
+        match filter.apply(&batch.data) {
+          Some(data) => batch.data = data,
+          None => continue,
         }
       }
-    } else {
-      future::pending().await
+
+      if coalescer.enabled() {
+        coalescer.push(batch);
+      } else {
+        send!(encode_batch!(batch));
+      }
     }
-  });
+    send_deferred!();
+    last_send = Instant::now();
+  }
+}
 
-  pin_mut!(incoming, outgoing);
-  future::select(incoming, outgoing).await;
+/// Progress through a logical frame that the backend split across several reads.
+enum Fragment {
+  /// Buffering fragments to send as one message.
+  Assembling(Assembly),
 
-  info!("{addr}: disconnected");
-  unsafe {
-    wardenclyffe_destroy_socket(wardenclyffe_socket);
+  /// Sending each fragment as a WebSocket continuation frame.
+  Streaming,
+
+  /// Discarding the rest of a frame that couldn't be buffered.
+  Dropped,
+}
+
+/// Percent-decode a request path for file lookup, rejecting encoded slashes, NULs, and `..` segments.
+fn decode_path(path: &str) -> Option<String> {
+  let segments: Option<Vec<_>> = path
+    .split('/')
+    .map(|segment| {
+      let decoded = percent_decode_str(segment).decode_utf8().ok()?;
+      if decoded.contains(['/', '\0']) || decoded == ".." {
+        None
+      } else {
+        Some(decoded)
+      }
+    })
+    .collect();
+  Some(segments?.join("/"))
+}
+
+/// Match a path against a glob, where `*` matches any sequence of characters and `?` any one.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+  match pattern.chars().next() {
+    None => path.is_empty(),
+    Some('*') => (0..=path.len())
+      .filter(|&i| path.is_char_boundary(i))
+      .any(|i| glob_match(&pattern[1..], &path[i..])),
+    Some(c) => match path.chars().next() {
+      Some(p) if c == '?' || c == p => glob_match(&pattern[c.len_utf8()..], &path[p.len_utf8()..]),
+      _ => false,
+    },
   }
+}
 
-  Ok(())
+/// Which directions of a socket a WebSocket session uses, chosen with the `mode` query parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionMode {
+  ReadWrite,
+  ReadOnly,
+  WriteOnly,
+}
+
+impl SessionMode {
+  fn from_request(req: &Request<Body>) -> Option<SessionMode> {
+    match query_param(req, "mode").as_deref() {
+      None | Some("read-write") => Some(SessionMode::ReadWrite),
+      Some("read") => Some(SessionMode::ReadOnly),
+      Some("write") => Some(SessionMode::WriteOnly),
+      Some(_) => None,
+    }
+  }
+}
+
+/// A message from the client, waiting to be written to the socket.
+struct ClientWrite {
+  data: Vec<u8>,
+  id: Option<u64>,
+  /// Index of the write in the session, counting from zero.
+  seq: u64,
+  /// Whether to acknowledge the write.
+  ack: bool,
+}
+
+/// What the client asks of the socket's writer.
+enum WriteRequest {
+  Write(ClientWrite),
+
+  /// Write what's being batched right away.
+  Flush,
+}
+
+const DEFAULT_WRITE_BATCH_BYTES: usize = 64 * 1024;
+
+/// How writes to a socket are batched, if they are.
+#[derive(Clone, Copy)]
+struct WriteBatching {
+  delay: Duration,
+  bytes: usize,
+}
+
+impl WriteBatching {
+  fn from_policy(policy: Option<&SocketPolicy>) -> Option<WriteBatching> {
+    let policy = policy?;
+    if policy.write_batch_ms.is_none() && policy.write_batch_bytes.is_none() {
+      return None;
+    }
+    Some(WriteBatching {
+      delay: Duration::from_millis(policy.write_batch_ms.unwrap_or(0)),
+      bytes: policy.write_batch_bytes.unwrap_or(DEFAULT_WRITE_BATCH_BYTES),
+    })
+  }
+}
+
+/// Wait for the next write, and then, if writes are batched, for more to write with it, until the
+/// batch is big enough, the delay is up, or the client asks for it to be flushed.
+async fn next_write_batch(
+  requests: &mut tokio::sync::mpsc::Receiver<WriteRequest>,
+  batching: Option<WriteBatching>,
+) -> Option<Vec<ClientWrite>> {
+  let first = loop {
+    if let WriteRequest::Write(write) = requests.recv().await? {
+      break write;
+    }
+  };
+  let mut bytes = first.data.len();
+  let mut batch = vec![first];
+  let Some(batching) = batching else {
+    return Some(batch);
+  };
+
+  // Writes that are already waiting are taken even once the delay is up.
+  let deadline = tokio::time::Instant::now() + batching.delay;
+  while bytes < batching.bytes {
+    match tokio::time::timeout_at(deadline, requests.recv()).await {
+      Ok(Some(WriteRequest::Write(write))) => {
+        bytes += write.data.len();
+        batch.push(write);
+      }
+      Ok(Some(WriteRequest::Flush) | None) | Err(_) => break,
+    }
+  }
+  Some(batch)
+}
+
+/// Static content, either in memory or in a file to be streamed from disk.
+enum Content {
+  Bytes(Vec<u8>),
+  File(tokio::fs::File, u64),
+
+  /// Content that was compressed ahead of time, with its Content-Encoding.
+  Encoded(&'static str, Box<Content>),
+}
+
+// Precompressed copies of files on disk that we look for, by Content-Encoding and file suffix, in
+// order of preference.
+const PRECOMPRESSED_SUFFIXES: &[(&str, &str)] = &[("br", ".br"), ("gzip", ".gz")];
+
+// Content types of static content, by file suffix.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+  (".html", "text/html; charset=utf-8"),
+  (".js", "text/javascript"),
+  (".css", "text/css"),
+  (".json", "application/json"),
+  (".wasm", "application/wasm"),
+];
+
+// Files on disk at least this large are streamed, rather than read into memory.
+const STREAM_THRESHOLD: u64 = 256 * 1024;
+
+fn static_response(state: &ServerState, request_path: &str, content: Content) -> Response<Body> {
+  let (content, encoding) = match content {
+    Content::Encoded(encoding, content) => (*content, Some(encoding)),
+    content => (content, None),
+  };
+
+  let (body, len) = match content {
+    Content::Bytes(file) => {
+      let Some(reservation) = state.memory.try_reserve(file.len() as u64) else {
+        Stats::increment(&state.stats.http_responses_denied);
+        warn!("memory budget exhausted, refusing {request_path}");
+        return text_response(StatusCode::SERVICE_UNAVAILABLE, "Service unavailable");
+      };
+
+      // Hold the reservation until hyper is done with the body.
+      let len = file.len() as u64;
+      let body = stream::once(future::ready(Ok::<_, Infallible>(file))).map(move |chunk| {
+        let _ = &reservation;
+        chunk
+      });
+      (Body::wrap_stream(body), len)
+    }
+
+    Content::File(file, len) => (Body::wrap_stream(ReaderStream::with_capacity(file, 64 * 1024)), len),
+    Content::Encoded(..) => unreachable!("content encoded twice"),
+  };
+
+  let mut response = Response::new(body);
+  response.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(len));
+
+  // Browsers refuse to load module scripts without a JavaScript content type.
+  let content_type = CONTENT_TYPES
+    .iter()
+    .find(|(suffix, _)| request_path.ends_with(suffix))
+    .map(|(_, content_type)| *content_type);
+  if let Some(content_type) = content_type {
+    response
+      .headers_mut()
+      .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+  }
+  if let Some(encoding) = encoding {
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+  }
+  let policy = state
+    .config
+    .cache_policies
+    .iter()
+    .flatten()
+    .find(|policy| glob_match(&policy.path, request_path));
+  if let Some(value) = policy.and_then(|policy| HeaderValue::from_str(&policy.cache_control).ok()) {
+    response.headers_mut().insert(CACHE_CONTROL, value);
+  }
+  response
+}
+
+/// The maximum size of the body of a request for `request_path`.
+fn body_limit(config: &Config, request_path: &str) -> u64 {
+  let limits = config.limits.as_ref().unwrap();
+  limits
+    .body_limits
+    .iter()
+    .flatten()
+    .find(|limit| glob_match(&limit.path, request_path))
+    .map(|limit| limit.max_bytes)
+    .unwrap_or_else(|| limits.max_body_bytes.unwrap())
+}
+
+/// Wrap a request body so that reading it fails once more than `limit` bytes have been received.
+///
+/// This catches bodies without a Content-Length, or that lie about it.
+fn limit_body(body: Body, limit: u64) -> Body {
+  let mut received = 0u64;
+  Body::wrap_stream(body.map(move |chunk| {
+    let chunk = chunk.map_err(anyhow::Error::from)?;
+    received += chunk.len() as u64;
+    if received > limit {
+      bail!("request body exceeds {limit} bytes");
+    }
+    Ok(chunk)
+  }))
 }
 
-fn get_http_content(http_content: &HttpContent, path: &str) -> Option<Vec<u8>> {
+/// Whether an Accept-Encoding header value allows `encoding`.
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+  accept_encoding.split(',').any(|item| {
+    let mut params = item.split(';').map(str::trim);
+    let name = params.next().unwrap_or("");
+    let refused = params.any(|param| {
+      param
+        .strip_prefix("q=")
+        .and_then(|q| q.parse::<f32>().ok())
+        .is_some_and(|q| q == 0.0)
+    });
+    (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+  })
+}
+
+/// Read a file from disk, without blocking the runtime, since flash can be slow.
+async fn read_file(path: &Path) -> Option<Content> {
+  let mut file = tokio::fs::File::open(path).await.ok()?;
+  let metadata = file.metadata().await.ok()?;
+  if !metadata.is_file() {
+    None
+  } else if metadata.len() >= STREAM_THRESHOLD {
+    Some(Content::File(file, metadata.len()))
+  } else {
+    let mut contents = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut contents).await.ok()?;
+    Some(Content::Bytes(contents))
+  }
+}
+
+/// Look up static content by its decoded path, relative to the content root and without a query string.
+///
+/// On disk, a precompressed copy next to the file (e.g. `app.js.br`) is served instead if the client
+/// accepts its encoding.
+async fn read_content(
+  state: &ServerState,
+  http_content: &HttpContent,
+  path: &str,
+  accept_encoding: &str,
+) -> Option<Content> {
   match http_content {
-    HttpContent::Embedded => HTML_DIR.get_file(path).map(File::contents).map(<[u8]>::to_vec),
-    HttpContent::Path(base_path) => std::fs::read(base_path.join(path)).ok(),
+    HttpContent::Embedded => HTML_DIR
+      .get_file(path)
+      .map(|file| Content::Bytes(file.contents().to_vec())),
+    HttpContent::Path(base_path) => {
+      let file_path = content_path(base_path, path)?;
+      for (encoding, suffix) in PRECOMPRESSED_SUFFIXES {
+        if accepts_encoding(accept_encoding, encoding) {
+          if let Some(content) = read_file(&with_suffix(&file_path, suffix)).await {
+            return Some(Content::Encoded(encoding, Box::new(content)));
+          }
+        }
+      }
+      read_file(&file_path).await
+    }
+    HttpContent::Archive(archive_path) => {
+      let archive = state.archives.get(archive_path)?;
+      let path = path.to_string();
+      let contents = task::spawn_blocking("archive read", move || archive.read(&path))
+        .await
+        .ok()??;
+      Some(Content::Bytes(contents))
+    }
+  }
+}
+
+/// The file under a content root for a decoded path without the leading slash, unless the path
+/// could escape it, e.g. with a drive or backslashes on Windows.
+fn content_path(base_path: &Path, path: &str) -> Option<PathBuf> {
+  let relative = Path::new(path);
+  relative
+    .components()
+    .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    .then(|| base_path.join(relative))
+}
+
+/// The part of a decoded path (without the leading slash) beneath a mount's prefix, if it's under it.
+fn mount_relative<'a>(mount: &Mount, path: &'a str) -> Option<&'a str> {
+  let prefix = mount.prefix.trim_matches('/');
+  match path.strip_prefix(prefix) {
+    Some(rest) if prefix.is_empty() => Some(rest),
+    Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest.trim_start_matches('/')),
+    _ => None,
+  }
+}
+
+/// The names of the files that serve as the index of a directory, given its decoded path without
+/// the leading slash: those of the first mount it's under that has its own, or the global ones.
+fn index_names<'a>(config: &'a Config, path: &str) -> &'a [String] {
+  config
+    .mounts
+    .iter()
+    .flatten()
+    .filter(|mount| mount_relative(mount, path).is_some())
+    .find_map(|mount| mount.index_names.as_deref())
+    .unwrap_or_else(|| config.index_names.as_deref().unwrap())
+}
+
+/// The files on disk that a request path could refer to, in each mount of a directory that it's
+/// under, in the order they're tried when serving it.
+pub fn mounted_files(config: &Config, request_path: &str) -> Vec<PathBuf> {
+  let Some(decoded_path) = decode_path(request_path) else {
+    return Vec::new();
+  };
+  let path = decoded_path.trim_start_matches('/');
+  config
+    .mounts
+    .iter()
+    .flatten()
+    .filter_map(|mount| match &mount.content {
+      HttpContent::Path(base_path) => content_path(base_path, mount_relative(mount, path)?),
+      _ => None,
+    })
+    .collect()
+}
+
+/// Look up static content by its decoded path without the leading slash, trying each mount whose
+/// prefix matches in order, and then the host's content root.
+async fn get_http_content(
+  state: &ServerState,
+  vhost: Option<&VirtualHost>,
+  path: &str,
+  accept_encoding: &str,
+) -> Option<Content> {
+  for mount in state.config.mounts.iter().flatten() {
+    let Some(relative) = mount_relative(mount, path) else {
+      continue;
+    };
+    if let Some(file) = read_content(state, &mount.content, relative, accept_encoding).await {
+      return Some(file);
+    }
+  }
+
+  let http_content = vhost
+    .and_then(|v| v.http_content.as_ref())
+    .unwrap_or_else(|| state.config.http_content.as_ref().unwrap());
+  read_content(state, http_content, path, accept_encoding).await
+}
+
+fn add_response_headers(config: &Config, path: &str, response: &mut Response<Body>) {
+  // BTreeMap iterates shorter prefixes first, so longer ones overwrite them.
+  let prefixes = config.response_headers.iter().flatten();
+  for (_, headers) in prefixes.filter(|(prefix, _)| path.starts_with(prefix.as_str())) {
+    for (name, value) in headers {
+      match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+        (Ok(name), Ok(value)) => {
+          response.headers_mut().insert(name, value);
+        }
+        _ => warn!("invalid response header: {name}: {value}"),
+      }
+    }
+  }
+}
+
+pub async fn handle_request(state: Arc<ServerState>, req: Request<Body>, peer: Peer) -> Result<Response<Body>> {
+  let path = req.uri().path().to_string();
+  let request_id = request_id(&req);
+  let error_format = ErrorFormat::negotiate(&req, &path);
+
+  let mut response = dispatch_request(state.clone(), req, peer, &path).await?;
+  if let Some(ErrorMessage(message)) = response.extensions_mut().remove::<ErrorMessage>() {
+    debug!("{peer}: request {request_id} for {path} failed: {message}");
+    render_error(&mut response, error_format, &message, &request_id);
+  }
+  if let Ok(value) = HeaderValue::from_str(&request_id) {
+    response.headers_mut().insert(X_REQUEST_ID, value);
+  }
+  add_response_headers(&state.config, &path, &mut response);
+  Ok(response)
+}
+
+async fn dispatch_request(
+  state: Arc<ServerState>,
+  req: Request<Body>,
+  peer: Peer,
+  path: &str,
+) -> Result<Response<Body>> {
+  if let Some(remaining) = state.failed_attempts.locked_out(&peer) {
+    Stats::increment(&state.stats.auth_lockout_rejections);
+    debug!("{peer}: locked out, refusing {path}");
+    let mut response = text_response(StatusCode::TOO_MANY_REQUESTS, "Too many authentication failures");
+    response
+      .headers_mut()
+      .insert(RETRY_AFTER, HeaderValue::from(remaining.as_secs().max(1)));
+    return Ok(response);
+  }
+
+  let Some(_permit) = state.peer_requests.try_acquire(&peer) else {
+    Stats::increment(&state.stats.peer_limit_rejections);
+    warn!("{peer}: too many concurrent requests, refusing {path}");
+    return Ok(text_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests"));
+  };
+
+  // Let the platform vouch for credentials we don't recognize, before anything checks them.
+  state.auth.authenticate_platform(&req, peer).await;
+
+  let limit = body_limit(&state.config, path);
+  if req
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+    > Some(limit)
+  {
+    warn!("{peer}: request body for {path} exceeds {limit} bytes");
+    return Ok(text_response(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"));
+  }
+  let req = req.map(|body| limit_body(body, limit));
+
+  // WebSocket sessions are handed off to their own tasks once the handshake is answered, so this
+  // only bounds the handshake for them.
+  let timeout = Duration::from_millis(state.config.limits.as_ref().unwrap().request_timeout_ms.unwrap());
+  match tokio::time::timeout(timeout, route_request(state.clone(), req, peer)).await {
+    Ok(response) => response,
+    Err(_) => {
+      warn!("{peer}: request for {path} timed out");
+      Ok(text_response(StatusCode::REQUEST_TIMEOUT, "Request timed out"))
+    }
+  }
+}
+
+pub fn switching_protocols(version: Version, accept_key: String, protocol: Option<&'static str>) -> Response<Body> {
+  let mut res = Response::new(Body::empty());
+  *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+  *res.version_mut() = version;
+  res
+    .headers_mut()
+    .append(CONNECTION, HeaderValue::from_static("Upgrade"));
+  res.headers_mut().append(UPGRADE, HeaderValue::from_static("websocket"));
+  res
+    .headers_mut()
+    .append(SEC_WEBSOCKET_ACCEPT, accept_key.parse().unwrap());
+  if let Some(protocol) = protocol {
+    res
+      .headers_mut()
+      .append(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+  }
+  res
+}
+
+/// The hostname the request was addressed to, without the port.
+fn request_host(req: &Request<Body>) -> Option<&str> {
+  let host = req
+    .headers()
+    .get(HOST)
+    .and_then(|h| h.to_str().ok())
+    .or_else(|| req.uri().host())?;
+
+  if host.starts_with('[') {
+    // IPv6 literal, possibly followed by a port.
+    host.split_inclusive(']').next()
+  } else {
+    host.split(':').next()
   }
 }
 
-pub async fn handle_request(config: Arc<Config>, mut req: Request<Body>, addr: SocketAddr) -> Result<Response<Body>> {
+/// Whether a socket path has no `.`, `..` or empty segments, which would let it name a socket
+/// outside of the prefixes in `allowed_sockets`.
+fn unambiguous_socket_path(path: &str) -> bool {
+  let path = path.strip_prefix('/').unwrap_or(path);
+  path.split('/').all(|segment| !matches!(segment, "" | "." | ".."))
+}
+
+/// Whether a host may open the socket at `socket_path` (after its `socket_prefix`): it must be one
+/// of the host's `allowed_sockets`, or under one of them, so that `/cast` doesn't allow `/castle`.
+pub fn socket_allowed(vhost: Option<&VirtualHost>, socket_path: &str) -> bool {
+  let Some(allowed) = vhost.and_then(|v| v.allowed_sockets.as_ref()) else {
+    return true;
+  };
+  allowed.iter().any(|prefix| {
+    let prefix = prefix.trim_end_matches('/');
+    socket_path
+      .strip_prefix(prefix)
+      .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+  })
+}
+
+pub fn virtual_host<'a>(config: &'a Config, req: &Request<Body>) -> Option<&'a VirtualHost> {
+  let host = request_host(req)?;
+  config
+    .virtual_hosts
+    .iter()
+    .flatten()
+    .find(|(name, _)| name.eq_ignore_ascii_case(host))
+    .map(|(_, vhost)| vhost)
+}
+
+/// Check whether a request may open the socket at `request_path`, returning what it's allowed to
+/// access and the path to pass to the backend, or the response rejecting it.
+pub async fn authorize_socket(
+  state: &ServerState,
+  req: &Request<Body>,
+  peer: Peer,
+  request_path: &str,
+) -> Result<(Access, String), Response<Body>> {
+  let access = match state.auth.authorize(req, request_path) {
+    Ok(access) => access,
+    Err(e) => {
+      warn!("{peer}: rejected socket {request_path}: {e}");
+      state.audit.record(
+        &peer,
+        AuditEvent::AuthFailure {
+          path: request_path,
+          reason: e.to_string(),
+        },
+      );
+      return Err(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+  };
+
+  let vhost = virtual_host(&state.config, req);
+  let socket_path = format!(
+    "{}{}",
+    vhost.and_then(|v| v.socket_prefix.as_deref()).unwrap_or(""),
+    request_path
+  );
+
+  if !unambiguous_socket_path(&socket_path) {
+    warn!("{peer}: rejected ambiguous socket path {socket_path}");
+    return Err(text_response(StatusCode::BAD_REQUEST, "Invalid socket path"));
+  }
+  if !socket_allowed(vhost, &socket_path) {
+    warn!("{peer}: socket {socket_path} not allowed for host");
+    return Err(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+  }
+
+  let tls = req.extensions().get::<TlsInfo>().cloned();
+  if let Err(reason) = authorize_backend(&socket_path, peer, tls, access.scopes()).await {
+    warn!("{peer}: backend denied socket {request_path}: {reason}");
+    state.audit.record(
+      &peer,
+      AuditEvent::AuthFailure {
+        path: request_path,
+        reason: format!("denied by backend: {reason}"),
+      },
+    );
+    return Err(text_response(StatusCode::FORBIDDEN, reason));
+  }
+
+  Ok((access, socket_path))
+}
+
+/// Pick the protocol for a WebSocket connection, and the subprotocol to tell the client, deriving
+/// its session key into the request's extensions if it's authenticating its messages.
+pub fn negotiate_protocol(
+  state: &ServerState,
+  req: &mut Request<Body>,
+  access: &Access,
+) -> Result<(Protocol, Option<&'static str>), (StatusCode, String)> {
+  // Clients that don't ask for a protocol get V1, which is what they got before negotiation.
+  let offered = req.headers().get(SEC_WEBSOCKET_PROTOCOL);
+  let (protocol, negotiated) = if integrity::offered(offered) {
+    match integrity::session_key(req, access) {
+      Ok(key) => {
+        req.extensions_mut().insert(SessionKey(key));
+        (Protocol::V2, Some(integrity::SUBPROTOCOL))
+      }
+      Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
+    }
+  } else {
+    let negotiated = Protocol::negotiate(offered);
+    (negotiated.unwrap_or(Protocol::V1), negotiated.map(Protocol::name))
+  };
+
+  let websocket_config = state.config.websocket.as_ref().unwrap();
+  if negotiated != Some(integrity::SUBPROTOCOL)
+    && websocket_config.require_integrity.unwrap()
+    && req.extensions().get::<TlsInfo>().is_none()
+  {
+    return Err((
+      StatusCode::FORBIDDEN,
+      format!("connections without TLS must use {}", integrity::SUBPROTOCOL),
+    ));
+  }
+  Ok((protocol, negotiated))
+}
+
+async fn route_request(state: Arc<ServerState>, mut req: Request<Body>, peer: Peer) -> Result<Response<Body>> {
   let upgrade = HeaderValue::from_static("Upgrade");
-  let websocket = HeaderValue::from_static("websocket");
   let headers = req.headers();
   let key = headers.get(SEC_WEBSOCKET_KEY);
   let derived = key.map(|k| derive_accept_key(k.as_bytes()));
 
+  let vhost = virtual_host(&state.config, &req);
+
   if req.method() == Method::GET
     && req.version() >= Version::HTTP_11
     && headers
       .get(CONNECTION)
       .and_then(|h| h.to_str().ok())
       .map(|h| {
-        h.split(|c| c == ' ' || c == ',')
+        h.split([' ', ','])
           .any(|p| p.eq_ignore_ascii_case(upgrade.to_str().unwrap()))
       })
       .unwrap_or(false)
@@ -159,14 +1973,86 @@ pub async fn handle_request(config: Arc<Config>, mut req: Request<Body>, addr: S
     && headers.get(SEC_WEBSOCKET_VERSION).map(|h| h == "13").unwrap_or(false)
     && key.is_some()
   {
+    // Held for as long as the session is open.
+    let Some(permit) = state.peer_sessions.try_acquire(&peer) else {
+      Stats::increment(&state.stats.peer_limit_rejections);
+      warn!("{peer}: too many WebSocket sessions, refusing {}", req.uri());
+      return Ok(text_response(StatusCode::TOO_MANY_REQUESTS, "Too many sessions"));
+    };
+
+    if req.uri().path() == announce::PATH {
+      return announce::handle_events(state, req, peer, permit, derived.unwrap()).await;
+    }
+    if req.uri().path() == merge::PATH {
+      return merge::handle_merge(state, req, peer, permit, derived.unwrap()).await;
+    }
+    if req.uri().path() == status::PATH {
+      return status::handle_status(state, req, peer, permit, derived.unwrap()).await;
+    }
+
+    let (access, socket_path) = match authorize_socket(&state, &req, peer, req.uri().path()).await {
+      Ok(authorized) => authorized,
+      Err(response) => return Ok(response),
+    };
+
+    if let Some(route) = proxy::find_route(&state.config, req.uri().path()) {
+      let upstream = proxy::upstream_uri(route, req.uri())?;
+      let ver = req.version();
+      task::spawn("websocket proxy", async move {
+        let _permit = permit;
+        match hyper::upgrade::on(&mut req).await {
+          Ok(upgraded) => {
+            let ws_stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            if let Err(e) = proxy::proxy_websocket(ws_stream, upstream, peer).await {
+              error!("{peer}: WebSocket proxy failed: {e:?}");
+            }
+          }
+          Err(e) => error!("upgrade error: {}", e),
+        }
+      });
+      return Ok(switching_protocols(ver, derived.unwrap(), None));
+    }
+
+    if !matches!(access, Access::Anonymous) {
+      state.audit.record(
+        &peer,
+        AuditEvent::AuthSuccess {
+          path: req.uri().path(),
+          identity: access.identity(),
+        },
+      );
+    }
+
+    if SessionMode::from_request(&req).is_none() {
+      return Ok(text_response(StatusCode::BAD_REQUEST, "invalid mode"));
+    }
+
+    match LineFilter::from_request(&req, socket_policy(&state.config, &socket_path)) {
+      Ok(Some(filter)) => {
+        req.extensions_mut().insert(filter);
+      }
+      Ok(None) => {}
+      Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, e.to_string())),
+    }
+
+    let (protocol, negotiated) = match negotiate_protocol(&state, &mut req, &access) {
+      Ok(negotiated) => negotiated,
+      Err((status, reason)) => return Ok(text_response(status, reason)),
+    };
+
     let ver = req.version();
-    tokio::task::spawn(async move {
+    task::spawn("websocket", async move {
+      let _permit = permit;
       match hyper::upgrade::on(&mut req).await {
         Ok(upgraded) => {
           if let Err(e) = handle_websocket(
+            state,
             WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await,
             req,
-            addr,
+            peer,
+            access,
+            socket_path,
+            protocol,
           )
           .await
           {
@@ -176,43 +2062,213 @@ pub async fn handle_request(config: Arc<Config>, mut req: Request<Body>, addr: S
         Err(e) => error!("upgrade error: {}", e),
       }
     });
-    let mut res = Response::new(Body::empty());
-    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
-    *res.version_mut() = ver;
-    res.headers_mut().append(CONNECTION, upgrade);
-    res.headers_mut().append(UPGRADE, websocket);
-    res
-      .headers_mut()
-      .append(SEC_WEBSOCKET_ACCEPT, derived.unwrap().parse().unwrap());
-    return Ok(res);
+    return Ok(switching_protocols(ver, derived.unwrap(), negotiated));
   }
 
-  info!("HTTP request for {}", req.uri());
+  match req.extensions().get::<TlsInfo>() {
+    Some(tls) => info!("{peer}: HTTP request for {} (tls = {tls})", req.uri()),
+    None => info!("{peer}: HTTP request for {}", req.uri()),
+  }
   let path = req.uri().path();
   if !path.starts_with('/') {
-    let mut response = Response::new(Body::from("Bad request"));
-    *response.status_mut() = StatusCode::BAD_REQUEST;
-    return Ok(response);
+    return Ok(text_response(StatusCode::BAD_REQUEST, "Bad request"));
+  }
+
+  if state.config.long_poll.is_some() {
+    if let Some(request_path) = path.strip_prefix("/lp/") {
+      let request_path = format!("/{request_path}");
+      return handle_long_poll(state, req, peer, &request_path).await;
+    }
   }
 
-  let http_content = config.http_content.as_ref().unwrap();
+  if path == MANIFEST_PATH {
+    if let (Some(manifest), Some(certificate)) = (&state.pin_manifest, state.certificate.get()) {
+      let mut response = json_response(&manifest.sign(&certificate.current())?);
+      response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+      return Ok(response);
+    }
+  }
 
-  let mut path = &path[1..];
-  if let Some(file) = get_http_content(http_content, path) {
-    return Ok(Response::new(Body::from(file)));
+  if path == status::PAGE_PATH {
+    return Ok(status::page());
+  }
+
+  if let Some(admin_path) = path.strip_prefix("/admin/") {
+    let admin_path = admin_path.to_string();
+    return handle_admin(state, req, peer, &admin_path).await;
+  }
+
+  if let Some(api_path) = path.strip_prefix("/api/") {
+    let api_path = api_path.to_string();
+    return handle_api(state, req, peer, &api_path).await;
+  }
+
+  if let Some(route) = proxy::find_route(&state.config, path) {
+    // Proxied services are protected like sockets are, so that they're no more open than the rest.
+    if let Err(e) = state.auth.authorize(&req, path) {
+      warn!("{peer}: rejected proxy request for {path}: {e}");
+      state.audit.record(
+        &peer,
+        AuditEvent::AuthFailure {
+          path,
+          reason: e.to_string(),
+        },
+      );
+      return Ok(text_response(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    let tls = state.config.tls != Some(TLS::Disabled);
+    return match proxy::proxy_request(&state.http_client, route, req, peer, tls).await {
+      Ok(response) => Ok(response),
+      Err(e) => {
+        error!("{peer}: proxy request failed: {e:?}");
+        Ok(text_response(StatusCode::BAD_GATEWAY, "Bad gateway"))
+      }
+    };
   }
 
-  // Assume it's a directory, look for index.html.
+  let Some(decoded_path) = decode_path(path) else {
+    return Ok(text_response(StatusCode::BAD_REQUEST, "Bad request"));
+  };
+
+  let accept_encoding = req
+    .headers()
+    .get(ACCEPT_ENCODING)
+    .and_then(|h| h.to_str().ok())
+    .unwrap_or("");
+  let mut path = &decoded_path[1..];
+  if let Some(file) = get_http_content(&state, vhost, path, accept_encoding).await {
+    return Ok(static_response(&state, &decoded_path, file));
+  }
+
+  // Assume it's a directory, look for an index.
   while path.ends_with('/') {
     path = &path[..path.len() - 1];
   }
 
-  let index_path = format!("{}/{}", path, "index.html");
-  if let Some(file) = get_http_content(http_content, &index_path) {
-    return Ok(Response::new(Body::from(file)));
+  for index_name in index_names(&state.config, path) {
+    let index_path = format!("{path}/{index_name}");
+    let Some(file) = get_http_content(&state, vhost, &index_path, accept_encoding).await else {
+      continue;
+    };
+
+    // Relative links in the index resolve against the directory only if the URL ends with a slash.
+    if !req.uri().path().ends_with('/') && state.config.redirect_directories.unwrap() {
+      let location = match req.uri().query() {
+        Some(query) => format!("{}/?{query}", req.uri().path()),
+        None => format!("{}/", req.uri().path()),
+      };
+      let mut response = text_response(StatusCode::MOVED_PERMANENTLY, "");
+      response.headers_mut().insert(LOCATION, location.parse()?);
+      return Ok(response);
+    }
+    return Ok(static_response(&state, &decoded_path, file));
   }
 
-  let mut response = Response::new(Body::from(format!("File not found: {path}")));
-  *response.status_mut() = StatusCode::NOT_FOUND;
-  Ok(response)
+  Ok(text_response(StatusCode::NOT_FOUND, format!("File not found: {path}")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_paths() {
+    assert_eq!(decode_path("/a%20b/c.html").as_deref(), Some("/a b/c.html"));
+    assert_eq!(decode_path("/caf%C3%A9").as_deref(), Some("/café"));
+    assert_eq!(decode_path("/").as_deref(), Some("/"));
+  }
+
+  #[test]
+  fn rejects_unsafe_paths() {
+    assert_eq!(decode_path("/a%2fb"), None);
+    assert_eq!(decode_path("/a%2Fb"), None);
+    assert_eq!(decode_path("/a%00b"), None);
+    assert_eq!(decode_path("/../etc/passwd"), None);
+    assert_eq!(decode_path("/a/%2e%2e/b"), None);
+    assert_eq!(decode_path("/a/%2E%2E"), None);
+    assert_eq!(decode_path("/%ff"), None);
+
+    // Only whole segments are parent directories.
+    assert_eq!(decode_path("/a..b/..c").as_deref(), Some("/a..b/..c"));
+  }
+
+  #[test]
+  fn matches_globs() {
+    assert!(glob_match("/socket", "/socket"));
+    assert!(!glob_match("/socket", "/socket/"));
+    assert!(!glob_match("/socket", "/sock"));
+    assert!(glob_match("/sockets/*", "/sockets/a"));
+    assert!(glob_match("/sockets/*", "/sockets/"));
+    assert!(glob_match("/sockets/*", "/sockets/a/b"));
+    assert!(!glob_match("/sockets/*", "/sockets"));
+    assert!(glob_match("*.js", "app.js"));
+    assert!(!glob_match("*.js", "app.json"));
+    assert!(glob_match("/s?cket", "/socket"));
+    assert!(!glob_match("/s?cket", "/scket"));
+    assert!(glob_match("/*/log", "/a/log"));
+    assert!(glob_match("*", ""));
+    assert!(!glob_match("", "/"));
+  }
+
+  #[test]
+  fn matches_globs_by_character() {
+    assert!(glob_match("/caf?", "/café"));
+    assert!(glob_match("/*é", "/café"));
+    assert!(glob_match("/?/x", "/é/x"));
+    assert!(!glob_match("/??", "/é"));
+  }
+
+  fn vhost(allowed_sockets: &[&str]) -> VirtualHost {
+    VirtualHost {
+      http_content: None,
+      socket_prefix: None,
+      allowed_sockets: Some(allowed_sockets.iter().map(|s| s.to_string()).collect()),
+    }
+  }
+
+  #[test]
+  fn allows_sockets_on_segment_boundaries() {
+    let cast = vhost(&["/cast"]);
+    assert!(socket_allowed(Some(&cast), "/cast"));
+    assert!(socket_allowed(Some(&cast), "/cast/video"));
+    assert!(!socket_allowed(Some(&cast), "/castle"));
+    assert!(!socket_allowed(Some(&cast), "/cas"));
+    assert!(!socket_allowed(Some(&cast), "/other/cast"));
+
+    let trailing = vhost(&["/cast/"]);
+    assert!(socket_allowed(Some(&trailing), "/cast"));
+    assert!(socket_allowed(Some(&trailing), "/cast/video"));
+    assert!(!socket_allowed(Some(&trailing), "/castle"));
+
+    assert!(!socket_allowed(Some(&vhost(&[])), "/cast"));
+    assert!(socket_allowed(None, "/anything"));
+  }
+
+  #[test]
+  fn rejects_ambiguous_socket_paths() {
+    assert!(unambiguous_socket_path("/cast/video"));
+    assert!(unambiguous_socket_path("cast"));
+    assert!(unambiguous_socket_path("/cast/..video"));
+    assert!(!unambiguous_socket_path("/cast/../admin"));
+    assert!(!unambiguous_socket_path("/cast/./video"));
+    assert!(!unambiguous_socket_path("/cast/.."));
+    assert!(!unambiguous_socket_path("/cast//video"));
+    assert!(!unambiguous_socket_path("/cast/"));
+    assert!(!unambiguous_socket_path("/"));
+    assert!(!unambiguous_socket_path(""));
+  }
+
+  #[test]
+  fn finds_request_hosts() {
+    let host = |host: &str| {
+      let req = Request::get("/").header(HOST, host).body(Body::empty()).unwrap();
+      request_host(&req).map(str::to_string)
+    };
+    assert_eq!(host("example.com").as_deref(), Some("example.com"));
+    assert_eq!(host("example.com:8443").as_deref(), Some("example.com"));
+    assert_eq!(host("[::1]:8443").as_deref(), Some("[::1]"));
+    assert_eq!(host("[::1]").as_deref(), Some("[::1]"));
+  }
 }
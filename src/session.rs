@@ -0,0 +1,464 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures_util::{stream::SplitSink, SinkExt};
+use hyper::upgrade::Upgraded;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::sync::CancellationToken;
+use tungstenite::protocol::Message;
+
+use crate::ffi::{wardenclyffe_pause, wardenclyffe_resume, WardenclyffeSocket};
+use crate::filter::LineFilter;
+use crate::integrity::MessageAuthenticator;
+use crate::memory::{MemoryBudget, Reservation};
+use crate::metadata::Metadata;
+use crate::peer::Peer;
+use crate::protocol::Protocol;
+use crate::stats::{CloseReason, Stats};
+use crate::task;
+use crate::termination::Termination;
+
+pub type WebSocketSink = SplitSink<WebSocketStream<Upgraded>, Message>;
+
+/// Where messages for a WebSocket client go: straight to its connection while it has one, or, for a
+/// resumable session whose client has dropped off, into a buffer until it comes back.
+pub struct Outbox {
+  state: tokio::sync::Mutex<OutboxState>,
+
+  /// How much to buffer while there's no connection, if the session is resumable.
+  buffer_limit: Option<usize>,
+
+  /// Bytes sent to the client, and waiting in an outgoing queue to be sent, for the status page.
+  pub sent_bytes: AtomicU64,
+  pub queued_bytes: AtomicUsize,
+}
+
+#[derive(Default)]
+struct OutboxState {
+  sink: Option<WebSocketSink>,
+
+  /// Authenticates messages to the connection, if it negotiated integrity.
+  sealer: Option<MessageAuthenticator>,
+
+  buffered: VecDeque<Message>,
+  buffered_bytes: usize,
+
+  /// Set once the buffer overflowed, after which the session can't be resumed.
+  overflowed: bool,
+}
+
+impl Outbox {
+  pub fn new(buffer_limit: Option<usize>) -> Self {
+    Outbox {
+      state: Default::default(),
+      buffer_limit,
+      sent_bytes: AtomicU64::new(0),
+      queued_bytes: AtomicUsize::new(0),
+    }
+  }
+
+  /// Send a message to the client, or buffer it while the client is away.
+  ///
+  /// Failing to send to a resumable session's connection detaches it rather than failing, losing
+  /// the message but keeping the session going for the client to resume.
+  pub async fn send(&self, msg: Message) -> Result<(), tungstenite::Error> {
+    let mut state = self.state.lock().await;
+    let state = &mut *state;
+    if let Some(sink) = &mut state.sink {
+      let msg = match &mut state.sealer {
+        Some(sealer) => sealer.seal(msg),
+        None => msg,
+      };
+      let len = msg.len() as u64;
+      match sink.send(msg).await {
+        Ok(()) => {
+          self.sent_bytes.fetch_add(len, Ordering::Relaxed);
+          return Ok(());
+        }
+        Err(e) if self.buffer_limit.is_none() => return Err(e),
+        Err(e) => {
+          debug!("detaching from connection after failed send: {e}");
+          state.sink = None;
+          return Ok(());
+        }
+      }
+    }
+
+    let Some(limit) = self.buffer_limit else {
+      return Err(tungstenite::Error::ConnectionClosed);
+    };
+
+    // Keepalives are meaningless to a client that isn't there.
+    if matches!(msg, Message::Ping(_)) {
+      return Ok(());
+    }
+
+    if state.overflowed || state.buffered_bytes + msg.len() > limit {
+      state.overflowed = true;
+      state.buffered.clear();
+      state.buffered_bytes = 0;
+      return Err(tungstenite::Error::ConnectionClosed);
+    }
+    state.buffered_bytes += msg.len();
+    state.buffered.push_back(msg);
+    Ok(())
+  }
+
+  /// Send anything that's been buffered to a new connection, and then send to it directly.
+  async fn attach(&self, mut sink: WebSocketSink, mut sealer: Option<MessageAuthenticator>) {
+    let mut state = self.state.lock().await;
+    while let Some(msg) = state.buffered.pop_front() {
+      state.buffered_bytes -= msg.len();
+      let msg = match &mut sealer {
+        Some(sealer) => sealer.seal(msg),
+        None => msg,
+      };
+      if let Err(e) = sink.feed(msg).await {
+        debug!("failed to send buffered message: {e}");
+        return;
+      }
+    }
+    if let Err(e) = sink.flush().await {
+      debug!("failed to send buffered messages: {e}");
+      return;
+    }
+    state.sink = Some(sink);
+    state.sealer = sealer;
+  }
+
+  async fn detach(&self) {
+    self.state.lock().await.sink = None;
+  }
+
+  pub async fn overflowed(&self) -> bool {
+    self.state.lock().await.overflowed
+  }
+
+  /// Bytes buffered while the client is away.
+  pub async fn buffered_bytes(&self) -> usize {
+    self.state.lock().await.buffered_bytes
+  }
+}
+
+/// Where a session's read loop sends messages: straight to the outbox, or through an outgoing
+/// queue.
+pub enum Outgoing<'a> {
+  Direct(&'a Outbox),
+  Queued(&'a OutgoingQueue),
+}
+
+impl Outgoing<'_> {
+  pub async fn send(&self, msg: Message) -> Result<(), tungstenite::Error> {
+    match self {
+      Outgoing::Direct(outbox) => outbox.send(msg).await,
+      Outgoing::Queued(queue) => queue.send(msg).await,
+    }
+  }
+}
+
+/// Messages from a session's read loop waiting to be sent, for sockets with outgoing watermarks.
+///
+/// Rather than the read loop blocking on a slow client, messages queue up here, and the backend is
+/// asked to pause producing data once `high` bytes are queued, until they drain to `low`.
+pub struct OutgoingQueue {
+  tx: mpsc::UnboundedSender<(Message, Option<Reservation>)>,
+  shared: Arc<QueueState>,
+  writer: JoinHandle<()>,
+}
+
+struct QueueState {
+  session: Arc<WebSocketSession>,
+  high: usize,
+  low: usize,
+  memory: Arc<MemoryBudget>,
+  stats: Arc<Stats>,
+
+  /// Bytes queued, and whether the backend is paused.
+  queued: Mutex<(usize, bool)>,
+
+  /// Notified whenever the queue drains to the low watermark.
+  drained: Notify,
+}
+
+impl OutgoingQueue {
+  pub fn new(
+    session: Arc<WebSocketSession>,
+    high: usize,
+    low: usize,
+    memory: Arc<MemoryBudget>,
+    stats: Arc<Stats>,
+  ) -> Self {
+    let shared = Arc::new(QueueState {
+      session: session.clone(),
+      high,
+      low,
+      memory,
+      stats,
+      queued: Mutex::new((0, false)),
+      drained: Notify::new(),
+    });
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Message, Option<Reservation>)>();
+
+    let writer_state = shared.clone();
+    let writer = task::spawn("websocket outgoing queue", async move {
+      while let Some((msg, _reservation)) = rx.recv().await {
+        let len = msg.len();
+        let result = session.outbox.send(msg).await;
+        writer_state.dequeued(len);
+        if let Err(e) = result {
+          error!("failed to send: {e}");
+          return;
+        }
+      }
+    });
+
+    OutgoingQueue { tx, shared, writer }
+  }
+
+  /// Queue a message, waiting for the queue to drain first if there's no memory to hold it.
+  pub async fn send(&self, msg: Message) -> Result<(), tungstenite::Error> {
+    let len = msg.len();
+    let reservation = loop {
+      let drained = self.shared.drained.notified();
+      if let Some(reservation) = self.shared.memory.try_reserve(len as u64) {
+        break Some(reservation);
+      }
+      if self.shared.queued.lock().unwrap().0 == 0 {
+        break None;
+      }
+      drained.await;
+    };
+
+    self.shared.queued(len);
+    self
+      .tx
+      .send((msg, reservation))
+      .map_err(|_| tungstenite::Error::ConnectionClosed)
+  }
+
+  /// Wait for everything queued to be sent, or fail to be, and make sure the backend isn't left
+  /// paused.
+  pub async fn finish(self) {
+    drop(self.tx);
+    let _ = self.writer.await;
+  }
+}
+
+impl QueueState {
+  fn queued(&self, len: usize) {
+    let mut queued = self.queued.lock().unwrap();
+    queued.0 += len;
+    self.session.outbox.queued_bytes.store(queued.0, Ordering::Relaxed);
+    if queued.0 >= self.high && !queued.1 {
+      queued.1 = true;
+      Stats::increment(&self.stats.websocket_backend_pauses);
+      unsafe { wardenclyffe_pause(self.session.socket) };
+    }
+  }
+
+  fn dequeued(&self, len: usize) {
+    let mut queued = self.queued.lock().unwrap();
+    queued.0 -= len;
+    self.session.outbox.queued_bytes.store(queued.0, Ordering::Relaxed);
+    if queued.0 <= self.low {
+      if queued.1 {
+        queued.1 = false;
+        unsafe { wardenclyffe_resume(self.session.socket) };
+      }
+      self.drained.notify_waiters();
+    }
+  }
+}
+
+/// Which connection a session is attached to.
+#[derive(Default)]
+pub struct Attachment {
+  /// Cancelled when another connection takes over the session, if it's attached to one.
+  connection: Option<CancellationToken>,
+
+  /// Incremented each time the session is attached to a connection.
+  generation: u64,
+
+  closed: bool,
+}
+
+/// A connection's hold on a session.
+pub struct Attached {
+  pub generation: u64,
+
+  /// Cancelled when another connection takes over the session.
+  pub replaced: CancellationToken,
+}
+
+/// A WebSocket session on a backend socket. Resumable sessions outlive the connection they were
+/// opened on, so that a client that loses its connection can reconnect and carry on.
+pub struct WebSocketSession {
+  /// The ID chosen by the client, if the session is resumable.
+  pub id: Option<String>,
+
+  pub socket: WardenclyffeSocket,
+  pub socket_path: String,
+  pub identity: String,
+  pub protocol: Protocol,
+
+  /// Whether the session's connections authenticate their messages. A session can't be resumed
+  /// without it once it's been opened with it.
+  pub integrity: bool,
+  pub supports_read: bool,
+  pub supports_write: bool,
+
+  /// What the client wants from what's read, if it doesn't want all of it.
+  pub filter: Option<LineFilter>,
+
+  /// Set when the client asks for a new delta encoding baseline, for the read loop to send one.
+  pub delta_reset: AtomicBool,
+
+  /// Set if the backend ends the session.
+  pub termination: Arc<Termination>,
+
+  /// Describes the session in the admin sessions listing.
+  pub metadata: Arc<Metadata>,
+
+  /// The client that opened the session, and when, in milliseconds since the Unix epoch.
+  pub peer: Peer,
+  pub opened_at: u64,
+
+  /// The User-Agent of the connection that opened the session, and what the client says it is, if
+  /// it's sent an `identify` control message.
+  pub user_agent: Option<String>,
+  pub client: Mutex<Option<String>>,
+
+  pub outbox: Outbox,
+
+  /// Set to stop the read loop before the socket is destroyed.
+  pub cancelled: AtomicBool,
+
+  /// Cancelled once the read loop stops, because the socket hit EOF or failed.
+  pub finished: CancellationToken,
+  pub read_loop: Mutex<Option<JoinHandle<()>>>,
+
+  pub attachment: tokio::sync::Mutex<Attachment>,
+
+  /// Why the session is ending, once that's known, for the stats.
+  pub close_reason: Mutex<Option<CloseReason>>,
+}
+
+impl WebSocketSession {
+  /// Whether a new connection can take over this session.
+  pub async fn can_resume(&self, socket_path: &str, identity: &str, protocol: Protocol, integrity: bool) -> bool {
+    self.socket_path == socket_path
+      && self.identity == identity
+      && self.protocol == protocol
+      && self.integrity == integrity
+      && !self.attachment.lock().await.closed
+      && !self.outbox.overflowed().await
+  }
+
+  /// Attach the session to a connection, taking it over from the previous one if there is one.
+  ///
+  /// Returns None if the session closed in the meantime.
+  ///
+  /// Messages to the connection are sealed with `sealer`, if it negotiated integrity.
+  pub async fn attach(&self, sink: WebSocketSink, sealer: Option<MessageAuthenticator>) -> Option<Attached> {
+    let mut attachment = self.attachment.lock().await;
+    if attachment.closed {
+      return None;
+    }
+    if let Some(previous) = attachment.connection.take() {
+      previous.cancel();
+    }
+
+    let replaced = CancellationToken::new();
+    attachment.connection = Some(replaced.clone());
+    attachment.generation += 1;
+    self.outbox.attach(sink, sealer).await;
+    Some(Attached {
+      generation: attachment.generation,
+      replaced,
+    })
+  }
+
+  /// Detach the session from a lost connection, to wait for the client to resume it.
+  ///
+  /// Returns false if another connection has already taken over.
+  pub async fn suspend(&self, generation: u64) -> bool {
+    let mut attachment = self.attachment.lock().await;
+    if attachment.closed || attachment.generation != generation {
+      return false;
+    }
+    attachment.connection = None;
+    self.outbox.detach().await;
+    true
+  }
+
+  /// Mark the session as closed, so that it can no longer be resumed.
+  ///
+  /// Returns false if the session was already closed, or another connection has taken it over since
+  /// `generation`, in which case it isn't the caller's to close.
+  pub async fn close(&self, generation: u64) -> bool {
+    let mut attachment = self.attachment.lock().await;
+    if attachment.closed || attachment.generation != generation {
+      return false;
+    }
+    attachment.closed = true;
+    true
+  }
+
+  /// Record why the session is ending, unless something else already ended it.
+  pub fn closing(&self, reason: CloseReason) {
+    self.close_reason.lock().unwrap().get_or_insert(reason);
+  }
+
+  /// Stop the read loop, waiting for any in-flight read to finish (it'll time out soon enough).
+  pub async fn stop_reading(&self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+    let read_loop = self.read_loop.lock().unwrap().take();
+    if let Some(read_loop) = read_loop {
+      let _ = read_loop.await;
+    }
+  }
+}
+
+/// Open WebSocket sessions, and the resumable ones by ID.
+#[derive(Default)]
+pub struct WebSocketSessions(Mutex<Sessions>);
+
+#[derive(Default)]
+struct Sessions {
+  open: Vec<Arc<WebSocketSession>>,
+  resumable: HashMap<String, Arc<WebSocketSession>>,
+}
+
+impl WebSocketSessions {
+  pub fn get(&self, id: &str) -> Option<Arc<WebSocketSession>> {
+    self.0.lock().unwrap().resumable.get(id).cloned()
+  }
+
+  pub fn insert(&self, session: Arc<WebSocketSession>) {
+    let mut sessions = self.0.lock().unwrap();
+    if let Some(id) = &session.id {
+      sessions.resumable.insert(id.clone(), session.clone());
+    }
+    sessions.open.push(session);
+  }
+
+  /// Forget about a session, and its ID unless it's since been replaced by another with the same
+  /// one.
+  pub fn remove(&self, session: &Arc<WebSocketSession>) {
+    let mut sessions = self.0.lock().unwrap();
+    sessions.open.retain(|s| !Arc::ptr_eq(s, session));
+    if let Some(id) = &session.id {
+      if sessions.resumable.get(id).is_some_and(|s| Arc::ptr_eq(s, session)) {
+        sessions.resumable.remove(id);
+      }
+    }
+  }
+
+  /// Every open session, oldest first.
+  pub fn list(&self) -> Vec<Arc<WebSocketSession>> {
+    self.0.lock().unwrap().open.clone()
+  }
+}
@@ -0,0 +1,61 @@
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::ffi::WardenclyffeRead;
+
+/// The contents of a read, either passed inline or in shared memory.
+pub enum ReadData<'a> {
+  Inline(&'a [u8]),
+  Mapped(Mmap),
+}
+
+impl Deref for ReadData<'_> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self {
+      ReadData::Inline(data) => data,
+      ReadData::Mapped(map) => map,
+    }
+  }
+}
+
+/// Get the contents of a read from the backend, mapping them if they were passed in shared memory.
+///
+/// # Safety
+/// `read` must have come from the backend, and the memory (or fd) it refers to must remain valid for
+/// the lifetime of the result.
+pub unsafe fn read_data(read: &WardenclyffeRead) -> io::Result<ReadData<'_>> {
+  if read.shm == 0 {
+    return Ok(ReadData::Inline(std::slice::from_raw_parts(
+      read.data as *const u8,
+      read.size,
+    )));
+  }
+
+  if read.size == 0 {
+    return Ok(ReadData::Inline(&[]));
+  }
+
+  map_fd(read.fd, read.offset, read.size)
+}
+
+#[cfg(unix)]
+unsafe fn map_fd(fd: i32, offset: u64, size: usize) -> io::Result<ReadData<'static>> {
+  // The fd belongs to the backend, so don't close it.
+  let file = ManuallyDrop::new(File::from_raw_fd(fd));
+  let map = MmapOptions::new().offset(offset).len(size).map(&*file)?;
+  Ok(ReadData::Mapped(map))
+}
+
+// Mock backends on other hosts have no fds to share memory with.
+#[cfg(not(unix))]
+unsafe fn map_fd(_fd: i32, _offset: u64, _size: usize) -> io::Result<ReadData<'static>> {
+  Err(io::ErrorKind::Unsupported.into())
+}
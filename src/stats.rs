@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+
+use crate::alloc;
+use crate::memory::MemoryBudget;
+
+/// Why a session ended.
+#[derive(Clone, Copy, Debug)]
+pub enum CloseReason {
+  /// The client closed the session, with the close code it sent, if it sent one.
+  Client(Option<u16>),
+
+  /// The client's connection went away without closing the session, and it wasn't resumed.
+  ClientLost,
+
+  /// The client fell further behind than its session could buffer for it.
+  SlowClient,
+
+  /// The client stopped polling a long-polling session.
+  IdleTimeout,
+
+  /// The backend socket hit EOF.
+  BackendEof,
+
+  /// Reading from or writing to the backend socket failed.
+  BackendError,
+
+  /// The backend ended the session with `wardenclyffe_end_session`.
+  BackendEnded,
+
+  /// The credentials the session was opened with were revoked.
+  CredentialsRevoked,
+
+  /// The session reached the longest it may last.
+  MaxDuration,
+}
+
+impl CloseReason {
+  fn name(&self) -> &'static str {
+    match self {
+      CloseReason::Client(_) => "client_closed",
+      CloseReason::ClientLost => "client_lost",
+      CloseReason::SlowClient => "slow_client",
+      CloseReason::IdleTimeout => "idle_timeout",
+      CloseReason::BackendEof => "backend_eof",
+      CloseReason::BackendError => "backend_error",
+      CloseReason::BackendEnded => "backend_ended",
+      CloseReason::CredentialsRevoked => "credentials_revoked",
+      CloseReason::MaxDuration => "max_duration",
+    }
+  }
+}
+
+/// How many sessions ended for each reason, and with each of the close codes clients sent.
+#[derive(Default)]
+struct Closes {
+  reasons: BTreeMap<&'static str, u64>,
+  client_codes: BTreeMap<u16, u64>,
+}
+
+/// Server-wide counters, exported by the stats endpoint.
+#[derive(Default)]
+pub struct Stats {
+  pub tls_handshakes_started: AtomicU64,
+  pub tls_handshakes_failed: AtomicU64,
+  pub tls_handshakes_timed_out: AtomicU64,
+  pub tls_handshakes_rejected: AtomicU64,
+  pub websocket_messages_dropped: AtomicU64,
+  pub websocket_sessions_suspended: AtomicU64,
+  pub websocket_sessions_resumed: AtomicU64,
+  pub websocket_sessions_expired: AtomicU64,
+  pub websocket_sequence_gaps: AtomicU64,
+  pub websocket_checksum_mismatches: AtomicU64,
+  pub websocket_client_reported_gaps: AtomicU64,
+  pub websocket_client_reported_mismatches: AtomicU64,
+  pub websocket_backend_pauses: AtomicU64,
+  pub http_responses_denied: AtomicU64,
+  pub peer_limit_rejections: AtomicU64,
+  pub auth_lockouts: AtomicU64,
+  pub auth_lockout_rejections: AtomicU64,
+  pub inbound_messages_rejected: AtomicU64,
+  closes: Mutex<Closes>,
+}
+
+impl Stats {
+  pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Count a session that's ended.
+  pub fn session_closed(&self, reason: CloseReason) {
+    let mut closes = self.closes.lock().unwrap();
+    *closes.reasons.entry(reason.name()).or_default() += 1;
+    if let CloseReason::Client(Some(code)) = reason {
+      *closes.client_codes.entry(code).or_default() += 1;
+    }
+  }
+
+  pub fn snapshot(&self, memory: &MemoryBudget) -> Value {
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let closes = self.closes.lock().unwrap();
+    json!({
+      "tls": {
+        "handshakes_started": get(&self.tls_handshakes_started),
+        "handshakes_failed": get(&self.tls_handshakes_failed),
+        "handshakes_timed_out": get(&self.tls_handshakes_timed_out),
+        "handshakes_rejected": get(&self.tls_handshakes_rejected),
+      },
+      "websocket": {
+        "sessions_suspended": get(&self.websocket_sessions_suspended),
+        "sessions_resumed": get(&self.websocket_sessions_resumed),
+        "sessions_expired": get(&self.websocket_sessions_expired),
+        "sequence_gaps": get(&self.websocket_sequence_gaps),
+        "checksum_mismatches": get(&self.websocket_checksum_mismatches),
+        "client_reported_gaps": get(&self.websocket_client_reported_gaps),
+        "client_reported_mismatches": get(&self.websocket_client_reported_mismatches),
+        "backend_pauses": get(&self.websocket_backend_pauses),
+      },
+      "memory": {
+        "budget_bytes": memory.limit(),
+        "buffered_bytes": memory.used(),
+        "websocket_messages_dropped": get(&self.websocket_messages_dropped),
+        "http_responses_denied": get(&self.http_responses_denied),
+      },
+      "sessions_closed": {
+        "reasons": closes.reasons,
+        "client_close_codes": closes.client_codes,
+      },
+      "peer_limit_rejections": get(&self.peer_limit_rejections),
+      "auth_lockouts": get(&self.auth_lockouts),
+      "auth_lockout_rejections": get(&self.auth_lockout_rejections),
+      "inbound_messages_rejected": get(&self.inbound_messages_rejected),
+      "allocator": alloc::stats(),
+    })
+  }
+}
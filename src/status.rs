@@ -0,0 +1,108 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::{Message, Role};
+
+use crate::logging;
+use crate::peer::Peer;
+use crate::peerlimit::PeerPermit;
+use crate::server::{switching_protocols, text_response, ServerState};
+use crate::task;
+
+/// The path of the built-in status page, which is served regardless of the configured content.
+pub const PAGE_PATH: &str = "/status";
+
+/// The path of the WebSocket that the status page gets its updates from.
+pub const PATH: &str = "/status/live";
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+static PAGE: &str = include_str!("status.html");
+
+pub fn page() -> Response<Body> {
+  Response::builder()
+    .header(CONTENT_TYPE, "text/html; charset=utf-8")
+    .header(CACHE_CONTROL, "no-store")
+    .body(Body::from(PAGE))
+    .unwrap()
+}
+
+/// Upgrade a request for status updates, which are sent every second until the client goes away.
+/// Requires the admin token, if authentication is required, since updates describe every session.
+pub async fn handle_status(
+  state: Arc<ServerState>,
+  mut req: Request<Body>,
+  peer: Peer,
+  permit: PeerPermit,
+  accept_key: String,
+) -> Result<Response<Body>> {
+  if state.auth.required() && !state.auth.is_admin(&req) {
+    warn!("{peer}: unauthorized request for status updates");
+    return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+  }
+
+  let ver = req.version();
+  task::spawn("websocket status", async move {
+    let _permit = permit;
+    match hyper::upgrade::on(&mut req).await {
+      Ok(upgraded) => {
+        let ws_stream = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        if let Err(e) = send_updates(&state, ws_stream).await {
+          debug!("{peer}: status updates failed: {e}");
+        }
+      }
+      Err(e) => error!("upgrade error: {}", e),
+    }
+  });
+  Ok(switching_protocols(ver, accept_key, None))
+}
+
+async fn snapshot(state: &ServerState) -> Value {
+  let mut sessions = Vec::new();
+  for session in state.websocket_sessions.list() {
+    sessions.push(json!({
+      "socket": session.socket_path,
+      "identity": session.identity,
+      "peer": session.peer.to_string(),
+      "opened_at": session.opened_at,
+      "sent_bytes": session.outbox.sent_bytes.load(Ordering::Relaxed),
+      "queued_bytes": session.outbox.queued_bytes.load(Ordering::Relaxed),
+      "buffered_bytes": session.outbox.buffered_bytes().await,
+    }));
+  }
+  let time = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis())
+    .unwrap_or(0);
+  json!({
+    "time": time,
+    "sessions": sessions,
+    "stats": state.stats.snapshot(&state.memory),
+    "recent_problems": logging::recent_problems(),
+  })
+}
+
+async fn send_updates(state: &ServerState, ws_stream: WebSocketStream<hyper::upgrade::Upgraded>) -> Result<()> {
+  let (mut sink, mut incoming) = ws_stream.split();
+  let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+  loop {
+    tokio::select! {
+      _ = interval.tick() => {
+        sink.send(Message::Text(snapshot(state).await.to_string())).await?;
+      }
+
+      msg = incoming.next() => match msg {
+        Some(Ok(Message::Close(_))) | None => return Ok(()),
+        Some(Ok(_)) => {}
+        Some(Err(e)) => return Err(e.into()),
+      },
+    }
+  }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::attempts::SourceSummary;
+
+/// State that must survive restarts of the server.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PersistentState {
+  /// Base64-encoded grant signing key, generated when none is configured.
+  pub signing_key: Option<String>,
+
+  /// Revoked tokens, as hex-encoded SHA-256 digests, and when they'd have expired anyway (in seconds
+  /// since the Unix epoch), after which they're forgotten.
+  #[serde(default)]
+  pub revoked_tokens: BTreeMap<String, u64>,
+
+  /// Recent failed attempts by source, if they're kept across restarts.
+  #[serde(default)]
+  pub failed_attempts: BTreeMap<String, SourceSummary>,
+}
+
+/// A small JSON file holding `PersistentState`.
+///
+/// Writes replace the file atomically and keep the previous version as a backup, which is used if
+/// the file turns out to be corrupt.
+pub struct StateStore {
+  path: PathBuf,
+  state: Mutex<PersistentState>,
+}
+
+/// Write a file in one go, so that a crash doesn't leave it half-written.
+pub fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+  let tmp = with_suffix(path, ".tmp");
+  let mut file = File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?;
+  file.write_all(contents)?;
+  file.sync_all()?;
+  fs::rename(&tmp, path)?;
+  Ok(())
+}
+
+/// `path` with `suffix` appended to its file name.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = OsString::from(path.as_os_str());
+  name.push(suffix);
+  PathBuf::from(name)
+}
+
+fn load(path: &Path) -> Result<Option<PersistentState>> {
+  match fs::read(path) {
+    Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+impl StateStore {
+  pub fn open(path: &Path) -> Result<StateStore> {
+    let backup = with_suffix(path, ".bak");
+    let state = match load(path) {
+      Ok(state) => state,
+      Err(e) => {
+        let corrupt = with_suffix(path, ".corrupt");
+        error!(
+          "state file {} is unreadable, moving it to {}: {e}",
+          path.display(),
+          corrupt.display()
+        );
+        fs::rename(path, &corrupt)?;
+        load(&backup).unwrap_or_else(|e| {
+          error!("backup state file {} is unreadable: {e}", backup.display());
+          None
+        })
+      }
+    };
+
+    Ok(StateStore {
+      path: path.to_path_buf(),
+      state: Mutex::new(state.unwrap_or_default()),
+    })
+  }
+
+  pub fn get<R>(&self, f: impl FnOnce(&PersistentState) -> R) -> R {
+    f(&self.state.lock().unwrap())
+  }
+
+  /// Modify the state, and write it out before returning.
+  pub fn update<R>(&self, f: impl FnOnce(&mut PersistentState) -> R) -> Result<R> {
+    let mut state = self.state.lock().unwrap();
+    let result = f(&mut state);
+    self.save(&state)?;
+    Ok(result)
+  }
+
+  fn save(&self, state: &PersistentState) -> Result<()> {
+    let tmp = with_suffix(&self.path, ".tmp");
+    let mut file = File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?;
+    file.write_all(&serde_json::to_vec_pretty(state)?)?;
+    file.sync_all()?;
+
+    if self.path.exists() {
+      fs::copy(&self.path, with_suffix(&self.path, ".bak"))?;
+    }
+    fs::rename(&tmp, &self.path)?;
+    Ok(())
+  }
+}
@@ -0,0 +1,63 @@
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+/// Spawn a task on the current runtime, named for tokio-console.
+#[cfg(feature = "console")]
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  tokio::task::Builder::new()
+    .name(name)
+    .spawn(future)
+    .expect("failed to spawn task")
+}
+
+/// Spawn a task on the current runtime, named for tokio-console.
+#[cfg(not(feature = "console"))]
+pub fn spawn<F>(_name: &str, future: F) -> JoinHandle<F::Output>
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  tokio::spawn(future)
+}
+
+/// Run blocking code, e.g. a call into the backend, on a named thread of the blocking pool.
+#[cfg(feature = "console")]
+pub fn spawn_blocking<F, R>(name: &str, f: F) -> JoinHandle<R>
+where
+  F: FnOnce() -> R + Send + 'static,
+  R: Send + 'static,
+{
+  tokio::task::Builder::new()
+    .name(name)
+    .spawn_blocking(f)
+    .expect("failed to spawn blocking task")
+}
+
+/// Run blocking code, e.g. a call into the backend, on a named thread of the blocking pool.
+#[cfg(not(feature = "console"))]
+pub fn spawn_blocking<F, R>(_name: &str, f: F) -> JoinHandle<R>
+where
+  F: FnOnce() -> R + Send + 'static,
+  R: Send + 'static,
+{
+  tokio::task::spawn_blocking(f)
+}
+
+/// An executor for hyper that names the tasks it spawns for each connection.
+#[derive(Clone, Copy)]
+pub struct ConnectionExecutor;
+
+impl<F> hyper::rt::Executor<F> for ConnectionExecutor
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  fn execute(&self, future: F) {
+    spawn("connection", future);
+  }
+}
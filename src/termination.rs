@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::errors::BackendError;
+use crate::ffi::WardenclyffeSocket;
+
+/// Open sockets, by address, for the backend to end their sessions with `wardenclyffe_end_session`.
+static OPEN_SOCKETS: Mutex<BTreeMap<usize, Arc<Termination>>> = Mutex::new(BTreeMap::new());
+
+/// A request from the backend to end the session on a socket, e.g. because the screen was locked,
+/// with the code and reason to tell the client.
+#[derive(Default)]
+pub struct Termination {
+  requested: CancellationToken,
+  error: Mutex<Option<BackendError>>,
+}
+
+impl Termination {
+  /// Start accepting requests to end a newly created socket's session. Sockets must be
+  /// unregistered before they're destroyed.
+  pub fn register(socket: WardenclyffeSocket) -> Arc<Termination> {
+    let termination = Arc::new(Termination::default());
+    OPEN_SOCKETS
+      .lock()
+      .unwrap()
+      .insert(socket.0 as usize, termination.clone());
+    termination
+  }
+
+  pub fn unregister(socket: WardenclyffeSocket) {
+    OPEN_SOCKETS.lock().unwrap().remove(&(socket.0 as usize));
+  }
+
+  /// Why the backend ended the session, if it has.
+  pub fn error(&self) -> Option<BackendError> {
+    self.error.lock().unwrap().as_ref().map(|error| BackendError {
+      code: error.code,
+      reason: error.reason.clone(),
+    })
+  }
+
+  /// Wait for the backend to end the session, returning why.
+  pub async fn requested(&self) -> BackendError {
+    self.requested.cancelled().await;
+    self.error().unwrap()
+  }
+}
+
+/// End the session on a socket at the backend's request. Returns false if the socket isn't open.
+pub fn end_session(socket: WardenclyffeSocket, code: i32, reason: String) -> bool {
+  let sockets = OPEN_SOCKETS.lock().unwrap();
+  let Some(termination) = sockets.get(&(socket.0 as usize)) else {
+    return false;
+  };
+
+  // The first reason given is the one the client gets.
+  let mut error = termination.error.lock().unwrap();
+  if error.is_none() {
+    *error = Some(BackendError { code, reason });
+    termination.requested.cancel();
+  }
+  true
+}
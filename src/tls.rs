@@ -1,8 +1,15 @@
-use std::io;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context as _, Result};
 
 use futures_util::ready;
 use futures_util::Future;
@@ -11,11 +18,272 @@ use hyper::server::{
   accept::Accept,
   conn::{AddrIncoming, AddrStream},
 };
-use rustls::ServerConfig;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+  Certificate, PrivateKey, ServerConfig, ServerConnection, SupportedCipherSuite, SupportedProtocolVersion,
+  ALL_CIPHER_SUITES,
+};
+use rustls_pemfile::Item;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::attempts::FailedAttempts;
+use crate::config::{Passphrase, TlsPolicy};
+use crate::peer::Peer;
+use crate::pkcs8;
+use crate::stats::Stats;
+use crate::task;
+
+/// Where the certificate served to clients comes from.
+pub trait CertificateSource: ResolvesServerCert {
+  /// The certificate currently being served.
+  fn current(&self) -> Arc<CertifiedKey>;
+
+  /// Start whatever keeps the certificate current in the background, once the server is running.
+  fn start(self: Arc<Self>) {}
+}
+
+// How often to check whether certificate files have changed.
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A certificate loaded from disk, and reloaded when its files change, so that it can be rotated
+/// without restarting the server and dropping every session. Connections that are already open
+/// keep the certificate they were established with.
+pub struct CertificateFiles {
+  cert_path: PathBuf,
+  key_path: PathBuf,
+  passphrase: Option<Passphrase>,
+  loaded: RwLock<Loaded>,
+}
+
+struct Loaded {
+  certified: Arc<CertifiedKey>,
+
+  /// When the files were last modified, as of when they were loaded, and as of the last check.
+  modified: Option<SystemTime>,
+  seen: Option<SystemTime>,
+}
+
+/// When either of a certificate's files was last modified.
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+  let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+  modified(cert_path).max(modified(key_path))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path, passphrase: Option<&Passphrase>) -> Result<Arc<CertifiedKey>> {
+  let mut cert_file = BufReader::new(File::open(cert_path)?);
+  let cert_chain: Vec<_> = rustls_pemfile::certs(&mut cert_file)?
+    .into_iter()
+    .map(Certificate)
+    .collect();
+  if cert_chain.is_empty() {
+    bail!("no certificate in {}", cert_path.display());
+  }
+
+  let key = load_private_key(key_path, passphrase)?;
+  let key = rustls::sign::any_supported_type(&key)
+    .map_err(|e| anyhow!("unsupported private key in {}: {e}", key_path.display()))?;
+  Ok(Arc::new(CertifiedKey::new(cert_chain, key)))
+}
+
+/// The TLS versions and cipher suites to allow, as restricted by `policy`.
+pub fn protocol_settings(
+  policy: Option<&TlsPolicy>,
+) -> Result<(Vec<&'static SupportedProtocolVersion>, Vec<SupportedCipherSuite>)> {
+  let versions = match policy.and_then(|p| p.min_version.as_deref()) {
+    None | Some("1.2") => vec![&rustls::version::TLS13, &rustls::version::TLS12],
+    Some("1.3") => vec![&rustls::version::TLS13],
+    Some(version) => bail!("unsupported TLS version {version:?}, expected \"1.2\" or \"1.3\""),
+  };
+  let suites = match policy.and_then(|p| p.cipher_suites.as_ref()) {
+    None => ALL_CIPHER_SUITES.to_vec(),
+    Some(names) => names
+      .iter()
+      .map(|name| {
+        ALL_CIPHER_SUITES
+          .iter()
+          .find(|suite| format!("{:?}", suite.suite()) == *name)
+          .copied()
+          .ok_or_else(|| anyhow!("unsupported cipher suite {name:?}"))
+      })
+      .collect::<Result<_>>()?,
+  };
+  let suites: Vec<_> = suites
+    .into_iter()
+    .filter(|suite| versions.iter().any(|&version| suite.version() == version))
+    .collect();
+  if suites.is_empty() {
+    bail!("no cipher suites allowed for the allowed TLS versions");
+  }
+  Ok((versions, suites))
+}
+
+/// Load the private key from a PEM file, in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) form, as written by
+/// OpenSSL, or encrypted PKCS#8 if there's a `passphrase`. Anything else in the file, like the
+/// certificate, is ignored.
+pub fn load_private_key(path: &Path, passphrase: Option<&Passphrase>) -> Result<PrivateKey> {
+  let pem = std::fs::read_to_string(path).with_context(|| format!("failed to open {}", path.display()))?;
+  let mut keys = Vec::new();
+  for item in
+    rustls_pemfile::read_all(&mut pem.as_bytes()).with_context(|| format!("failed to parse {}", path.display()))?
+  {
+    match item {
+      Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => keys.push(key),
+      _ => {}
+    }
+  }
+  let encrypted = pkcs8::encrypted_keys(&pem).with_context(|| format!("failed to parse {}", path.display()))?;
+  if !encrypted.is_empty() {
+    let Some(passphrase) = passphrase else {
+      bail!(
+        "the private key in {} is encrypted, but no passphrase is configured",
+        path.display()
+      );
+    };
+    let passphrase = pkcs8::read_passphrase(passphrase)?;
+    for key in encrypted {
+      keys.push(pkcs8::decrypt(&key, &passphrase).with_context(|| format!("failed to load {}", path.display()))?);
+    }
+  }
+  match keys.len() {
+    0 => bail!("no private key found in {}", path.display()),
+    1 => Ok(PrivateKey(keys.remove(0))),
+    n => bail!("{} contains {n} private keys, expected one", path.display()),
+  }
+}
+
+impl CertificateFiles {
+  pub fn load(cert_path: &Path, key_path: &Path, passphrase: Option<Passphrase>) -> Result<CertificateFiles> {
+    let modified = last_modified(cert_path, key_path);
+    Ok(CertificateFiles {
+      cert_path: cert_path.to_path_buf(),
+      key_path: key_path.to_path_buf(),
+      loaded: RwLock::new(Loaded {
+        certified: load_certified_key(cert_path, key_path, passphrase.as_ref())?,
+        modified,
+        seen: modified,
+      }),
+      passphrase,
+    })
+  }
+
+  /// Reload the files once they've changed, and then stayed the same for a check, so that a
+  /// certificate isn't paired with the previous one's key while they're being replaced.
+  fn check(&self) {
+    let modified = last_modified(&self.cert_path, &self.key_path);
+    let mut loaded = self.loaded.write().unwrap();
+    if modified == loaded.modified {
+      return;
+    }
+    if modified != loaded.seen {
+      loaded.seen = modified;
+      return;
+    }
+
+    // Don't try again until they change again.
+    loaded.modified = modified;
+    match load_certified_key(&self.cert_path, &self.key_path, self.passphrase.as_ref()) {
+      Ok(certified) => {
+        info!("reloaded certificate from {}", self.cert_path.display());
+        loaded.certified = certified;
+      }
+      Err(e) => error!(
+        "failed to reload certificate from {}, still serving the previous one: {e:?}",
+        self.cert_path.display()
+      ),
+    }
+  }
+}
+
+impl ResolvesServerCert for CertificateFiles {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    Some(self.current())
+  }
+}
+
+impl CertificateSource for CertificateFiles {
+  fn current(&self) -> Arc<CertifiedKey> {
+    self.loaded.read().unwrap().certified.clone()
+  }
+
+  fn start(self: Arc<Self>) {
+    task::spawn("certificate reload", async move {
+      loop {
+        tokio::time::sleep(CERTIFICATE_CHECK_INTERVAL).await;
+        self.check();
+      }
+    });
+  }
+}
+
+/// What was negotiated on a TLS connection.
+#[derive(Clone, Debug)]
+pub struct TlsInfo {
+  pub version: String,
+  pub cipher: String,
+  pub alpn: Option<String>,
+  /// Subject of the client's certificate, if it presented one.
+  pub client_subject: Option<String>,
+}
+
+impl TlsInfo {
+  fn new(conn: &ServerConnection) -> TlsInfo {
+    let client_subject = conn
+      .peer_certificates()
+      .and_then(|certs| certs.first())
+      .and_then(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+      .map(|(_, cert)| cert.subject().to_string());
+    TlsInfo {
+      version: conn.protocol_version().map(|v| format!("{v:?}")).unwrap_or_default(),
+      cipher: conn
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_default(),
+      alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+      client_subject,
+    }
+  }
+}
+
+impl fmt::Display for TlsInfo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {}", self.version, self.cipher)?;
+    if let Some(alpn) = &self.alpn {
+      write!(f, ", alpn = {alpn}")?;
+    }
+    if let Some(subject) = &self.client_subject {
+      write!(f, ", client = {subject}")?;
+    }
+    Ok(())
+  }
+}
+
+/// The details of a TLS connection, available once its handshake has completed.
+#[derive(Clone, Default)]
+pub struct TlsSession(Arc<OnceLock<TlsInfo>>);
+
+impl TlsSession {
+  pub fn info(&self) -> Option<&TlsInfo> {
+    self.0.get()
+  }
+}
+
+// Reservation of one of the limited number of concurrent handshakes.
+struct HandshakeSlot(Arc<AtomicUsize>);
+
+impl Drop for HandshakeSlot {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
 
 enum State {
-  Handshaking(tokio_rustls::Accept<AddrStream>),
+  Handshaking {
+    accept: tokio_rustls::Accept<AddrStream>,
+    deadline: Pin<Box<Sleep>>,
+    _slot: HandshakeSlot,
+  },
   Streaming(tokio_rustls::server::TlsStream<AddrStream>),
 }
 
@@ -25,66 +293,100 @@ enum State {
 pub struct TlsStream {
   addr: SocketAddr,
   state: State,
+  session: TlsSession,
+  stats: Arc<Stats>,
+  failed_attempts: Arc<FailedAttempts>,
 }
 
 impl TlsStream {
-  fn new(stream: AddrStream, config: Arc<ServerConfig>) -> TlsStream {
+  fn new(
+    stream: AddrStream,
+    config: Arc<ServerConfig>,
+    timeout: Duration,
+    slot: HandshakeSlot,
+    stats: Arc<Stats>,
+    failed_attempts: Arc<FailedAttempts>,
+  ) -> TlsStream {
     let addr = stream.remote_addr();
     let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
     TlsStream {
       addr,
-      state: State::Handshaking(accept),
+      state: State::Handshaking {
+        accept,
+        deadline: Box::pin(tokio::time::sleep(timeout)),
+        _slot: slot,
+      },
+      session: TlsSession::default(),
+      stats,
+      failed_attempts,
     }
   }
 
   pub fn remote_addr(&self) -> SocketAddr {
     self.addr
   }
+
+  pub fn session(&self) -> TlsSession {
+    self.session.clone()
+  }
+
+  // Drive the handshake to completion, if it hasn't finished yet.
+  fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<&mut tokio_rustls::server::TlsStream<AddrStream>>> {
+    if let State::Handshaking { accept, deadline, .. } = &mut self.state {
+      match Pin::new(accept).poll(cx) {
+        Poll::Ready(Ok(stream)) => {
+          let _ = self.session.0.set(TlsInfo::new(stream.get_ref().1));
+          self.state = State::Streaming(stream);
+        }
+        Poll::Ready(Err(err)) => {
+          Stats::increment(&self.stats.tls_handshakes_failed);
+          self
+            .failed_attempts
+            .record_tls_failure(&Peer::Inet(self.addr), &err.to_string());
+          return Poll::Ready(Err(err));
+        }
+        Poll::Pending => {
+          ready!(deadline.as_mut().poll(cx));
+          Stats::increment(&self.stats.tls_handshakes_timed_out);
+          warn!("{}: TLS handshake timed out", self.addr);
+          self
+            .failed_attempts
+            .record_tls_failure(&Peer::Inet(self.addr), "TLS handshake timed out");
+          return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")));
+        }
+      }
+    }
+
+    match &mut self.state {
+      State::Streaming(stream) => Poll::Ready(Ok(stream)),
+      State::Handshaking { .. } => unreachable!(),
+    }
+  }
 }
 
 impl AsyncRead for TlsStream {
   fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
-    let pin = self.get_mut();
-    match pin.state {
-      State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
-        Ok(mut stream) => {
-          let result = Pin::new(&mut stream).poll_read(cx, buf);
-          pin.state = State::Streaming(stream);
-          result
-        }
-        Err(err) => Poll::Ready(Err(err)),
-      },
-      State::Streaming(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
-    }
+    let stream = ready!(self.get_mut().poll_handshake(cx))?;
+    Pin::new(stream).poll_read(cx, buf)
   }
 }
 
 impl AsyncWrite for TlsStream {
   fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-    let pin = self.get_mut();
-    match pin.state {
-      State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
-        Ok(mut stream) => {
-          let result = Pin::new(&mut stream).poll_write(cx, buf);
-          pin.state = State::Streaming(stream);
-          result
-        }
-        Err(err) => Poll::Ready(Err(err)),
-      },
-      State::Streaming(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
-    }
+    let stream = ready!(self.get_mut().poll_handshake(cx))?;
+    Pin::new(stream).poll_write(cx, buf)
   }
 
   fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
     match self.state {
-      State::Handshaking(_) => Poll::Ready(Ok(())),
+      State::Handshaking { .. } => Poll::Ready(Ok(())),
       State::Streaming(ref mut stream) => Pin::new(stream).poll_flush(cx),
     }
   }
 
   fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
     match self.state {
-      State::Handshaking(_) => Poll::Ready(Ok(())),
+      State::Handshaking { .. } => Poll::Ready(Ok(())),
       State::Streaming(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
     }
   }
@@ -93,11 +395,31 @@ impl AsyncWrite for TlsStream {
 pub struct TlsAcceptor {
   config: Arc<ServerConfig>,
   incoming: AddrIncoming,
+  handshake_timeout: Duration,
+  max_handshakes: usize,
+  handshakes: Arc<AtomicUsize>,
+  stats: Arc<Stats>,
+  failed_attempts: Arc<FailedAttempts>,
 }
 
 impl TlsAcceptor {
-  pub fn new(config: Arc<ServerConfig>, incoming: AddrIncoming) -> TlsAcceptor {
-    TlsAcceptor { config, incoming }
+  pub fn new(
+    config: Arc<ServerConfig>,
+    incoming: AddrIncoming,
+    handshake_timeout: Duration,
+    max_handshakes: usize,
+    stats: Arc<Stats>,
+    failed_attempts: Arc<FailedAttempts>,
+  ) -> TlsAcceptor {
+    TlsAcceptor {
+      config,
+      incoming,
+      handshake_timeout,
+      max_handshakes,
+      handshakes: Arc::new(AtomicUsize::new(0)),
+      stats,
+      failed_attempts,
+    }
   }
 }
 
@@ -107,10 +429,36 @@ impl Accept for TlsAcceptor {
 
   fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
     let pin = self.get_mut();
-    match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-      Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
-      Some(Err(e)) => Poll::Ready(Some(Err(e))),
-      None => Poll::Ready(None),
+    loop {
+      match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
+        Some(Ok(sock)) => {
+          // Drop connections outright while too many handshakes are pending, so that clients that
+          // stall mid-handshake can't exhaust our file descriptors.
+          if pin.handshakes.fetch_add(1, Ordering::Relaxed) >= pin.max_handshakes {
+            pin.handshakes.fetch_sub(1, Ordering::Relaxed);
+            Stats::increment(&pin.stats.tls_handshakes_rejected);
+            warn!(
+              "{}: too many pending TLS handshakes, rejecting",
+              Peer::Inet(sock.remote_addr())
+            );
+            continue;
+          }
+
+          Stats::increment(&pin.stats.tls_handshakes_started);
+          let slot = HandshakeSlot(pin.handshakes.clone());
+          let stream = TlsStream::new(
+            sock,
+            pin.config.clone(),
+            pin.handshake_timeout,
+            slot,
+            pin.stats.clone(),
+            pin.failed_attempts.clone(),
+          );
+          return Poll::Ready(Some(Ok(stream)));
+        }
+        Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+        None => return Poll::Ready(None),
+      }
     }
   }
 }
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::api::{file_sha256, hash_file};
+use crate::peer::Peer;
+use crate::protocol::{TransferEvent, TransferRequest};
+use crate::server::{mounted_files, ServerState};
+use crate::session::WebSocketSession;
+use crate::task;
+use crate::upload::{directory_size, valid_name};
+
+// Size of the chunks that downloads are sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file transfer message from the client, queued in the order it was received.
+pub enum TransferMessage {
+  Request(TransferRequest),
+
+  /// Data for an upload: its transfer ID, offset in the file, and the data itself.
+  Data(u64, u64, Vec<u8>),
+}
+
+/// An upload in progress, being written to a partial file next to its destination.
+struct Upload {
+  name: String,
+  path: PathBuf,
+  partial_path: PathBuf,
+  file: File,
+  size: u64,
+  sha256: String,
+  written: u64,
+}
+
+/// The file transfers on a WebSocket connection.
+///
+/// Transfers don't survive the connection, but both directions can be resumed on a new one: a
+/// download by asking for the rest of the file, and an upload by putting the same file again.
+pub struct Transfers {
+  state: Arc<ServerState>,
+  session: Arc<WebSocketSession>,
+  peer: Peer,
+
+  /// Whether the client may upload files, if uploads are enabled.
+  can_upload: bool,
+
+  downloads: HashMap<u64, JoinHandle<()>>,
+  uploads: HashMap<u64, Upload>,
+}
+
+impl Drop for Transfers {
+  fn drop(&mut self) {
+    for download in self.downloads.values() {
+      download.abort();
+    }
+  }
+}
+
+impl Transfers {
+  pub fn new(state: Arc<ServerState>, session: Arc<WebSocketSession>, peer: Peer, can_upload: bool) -> Self {
+    Transfers {
+      state,
+      session,
+      peer,
+      can_upload,
+      downloads: HashMap::new(),
+      uploads: HashMap::new(),
+    }
+  }
+
+  async fn send(&self, event: TransferEvent) {
+    let msg = self.session.protocol.encode_transfer_event(&event);
+    if let Err(e) = self.session.outbox.send(msg).await {
+      debug!("{}: failed to send transfer event: {e}", self.peer);
+    }
+  }
+
+  pub async fn handle(&mut self, msg: TransferMessage) {
+    let (transfer, result) = match msg {
+      TransferMessage::Request(TransferRequest::Get { transfer, path, offset }) => {
+        self.downloads.retain(|_, download| !download.is_finished());
+        let state = self.state.clone();
+        let session = self.session.clone();
+        let peer = self.peer;
+        let download = task::spawn("file download", async move {
+          if let Err(e) = download(&state, &session, transfer, &path, offset).await {
+            warn!("{peer}: download of {path} failed: {e:?}");
+            let event = TransferEvent::Error {
+              transfer,
+              reason: e.to_string(),
+            };
+            let _ = session
+              .outbox
+              .send(session.protocol.encode_transfer_event(&event))
+              .await;
+          }
+        });
+        if let Some(previous) = self.downloads.insert(transfer, download) {
+          previous.abort();
+        }
+        return;
+      }
+
+      TransferMessage::Request(TransferRequest::Put {
+        transfer,
+        name,
+        size,
+        sha256,
+      }) => (transfer, self.start_upload(transfer, name, size, sha256).await),
+
+      TransferMessage::Request(TransferRequest::Cancel { transfer }) => {
+        if let Some(download) = self.downloads.remove(&transfer) {
+          download.abort();
+        }
+        // The partial file is kept, for the upload to be resumed later.
+        self.uploads.remove(&transfer);
+        return;
+      }
+
+      TransferMessage::Data(transfer, offset, data) => (transfer, self.receive(transfer, offset, &data).await),
+    };
+
+    if let Err(e) = result {
+      warn!("{}: upload failed: {e:?}", self.peer);
+      self.uploads.remove(&transfer);
+      self
+        .send(TransferEvent::Error {
+          transfer,
+          reason: e.to_string(),
+        })
+        .await;
+    }
+  }
+
+  async fn start_upload(&mut self, transfer: u64, name: String, size: u64, sha256: String) -> Result<()> {
+    let Some(uploads) = self.state.config.uploads.as_ref().filter(|_| self.can_upload) else {
+      bail!("uploads aren't allowed");
+    };
+    if !valid_name(&name) {
+      bail!("invalid file name");
+    }
+    if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+      bail!("invalid sha256");
+    }
+    let sha256 = sha256.to_ascii_lowercase();
+    if uploads.max_file_bytes.is_some_and(|max| size > max) {
+      bail!("file too large");
+    }
+
+    // Partial files are named after the hash, so that only the same file resumes an upload.
+    let path = uploads.directory.join(&name);
+    let partial_path = uploads.directory.join(format!(".{name}.{}.partial", &sha256[..16]));
+    if let Some(quota) = uploads.quota_bytes {
+      let used = directory_size(&uploads.directory, &[&path, &partial_path]).await?;
+      if !matches!(used.checked_add(size), Some(total) if total <= quota) {
+        bail!("upload quota exhausted");
+      }
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(&partial_path).await?;
+    let mut written = file.metadata().await?.len();
+    if written > size {
+      file.set_len(0).await?;
+      written = 0;
+    }
+
+    info!("{}: uploading {name} ({size} bytes, from {written})", self.peer);
+    self.uploads.insert(
+      transfer,
+      Upload {
+        name,
+        path,
+        partial_path,
+        file,
+        size,
+        sha256: sha256.clone(),
+        written,
+      },
+    );
+    self
+      .send(TransferEvent::Info {
+        transfer,
+        size,
+        sha256: Some(sha256),
+        offset: written,
+      })
+      .await;
+
+    if written == size {
+      self.finish_upload(transfer).await?;
+    }
+    Ok(())
+  }
+
+  async fn receive(&mut self, transfer: u64, offset: u64, data: &[u8]) -> Result<()> {
+    let Some(upload) = self.uploads.get_mut(&transfer) else {
+      bail!("no upload {transfer} in progress");
+    };
+    if offset != upload.written {
+      bail!("expected data at offset {}, got {offset}", upload.written);
+    }
+    if upload.written + data.len() as u64 > upload.size {
+      bail!("data past the end of the file");
+    }
+
+    upload.file.write_all(data).await?;
+    upload.written += data.len() as u64;
+    if upload.written == upload.size {
+      self.finish_upload(transfer).await?;
+    }
+    Ok(())
+  }
+
+  /// Check a complete upload against its hash, and move it into place.
+  async fn finish_upload(&mut self, transfer: u64) -> Result<()> {
+    let upload = self.uploads.remove(&transfer).unwrap();
+    upload.file.sync_all().await?;
+    drop(upload.file);
+
+    let partial_path = upload.partial_path.clone();
+    let hash = task::spawn_blocking("hash file", move || hash_file(&partial_path)).await??;
+    if hash != upload.sha256 {
+      let _ = tokio::fs::remove_file(&upload.partial_path).await;
+      bail!("sha256 mismatch, received {hash}");
+    }
+    tokio::fs::rename(&upload.partial_path, &upload.path).await?;
+
+    info!("{}: uploaded {} ({} bytes)", self.peer, upload.name, upload.size);
+    self.send(TransferEvent::Done { transfer }).await;
+    Ok(())
+  }
+}
+
+/// Send a file from a directory mount to the client, from `offset` onwards.
+async fn download(
+  state: &ServerState,
+  session: &WebSocketSession,
+  transfer: u64,
+  request_path: &str,
+  offset: u64,
+) -> Result<()> {
+  let protocol = session.protocol;
+  for path in mounted_files(&state.config, request_path) {
+    let Ok(mut file) = File::open(&path).await else {
+      continue;
+    };
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+      continue;
+    }
+
+    let size = metadata.len();
+    if offset > size {
+      bail!("offset past the end of the file");
+    }
+    let sha256 = file_sha256(&state.config, &path, &metadata).await?;
+    let info = TransferEvent::Info {
+      transfer,
+      size,
+      sha256,
+      offset,
+    };
+    session.outbox.send(protocol.encode_transfer_event(&info)).await?;
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut offset = offset;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+      let len = file.read(&mut buf).await?;
+      if len == 0 {
+        break;
+      }
+      let msg = protocol.encode_transfer_data(transfer, offset, &buf[..len]);
+      session.outbox.send(msg).await?;
+      offset += len as u64;
+    }
+
+    let done = TransferEvent::Done { transfer };
+    session.outbox.send(protocol.encode_transfer_event(&done)).await?;
+    return Ok(());
+  }
+  bail!("no such file")
+}
@@ -0,0 +1,154 @@
+use std::path::{Component, Path};
+
+use anyhow::Result;
+use hyper::{body::HttpBody, Body, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Uploads;
+use crate::peer::Peer;
+use crate::server::{json_response, text_response};
+
+/// Check that an upload's name is a plain file name, which can't escape the upload directory or
+/// collide with the temporary files of uploads in progress.
+pub fn valid_name(name: &str) -> bool {
+  // Windows also treats names like `C:name` as paths elsewhere.
+  !name.is_empty()
+    && !name.starts_with('.')
+    && !name.contains(['/', '\\', '\0'])
+    && matches!(
+      Path::new(name).components().collect::<Vec<_>>()[..],
+      [Component::Normal(_)]
+    )
+}
+
+/// Total size of the files in the upload directory, other than those in `except`.
+pub async fn directory_size(directory: &Path, except: &[&Path]) -> Result<u64> {
+  let mut total = 0;
+  let mut entries = tokio::fs::read_dir(directory).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let metadata = entry.metadata().await?;
+    if metadata.is_file() && !except.contains(&entry.path().as_path()) {
+      total += metadata.len();
+    }
+  }
+  Ok(total)
+}
+
+/// Handle `PUT /api/upload/<name>`, streaming the body into the upload directory.
+///
+/// The file is written under a temporary name and renamed into place once it's complete, so that
+/// nothing ever sees a partial upload.
+pub async fn handle_upload(uploads: &Uploads, req: Request<Body>, peer: Peer, name: &str) -> Result<Response<Body>> {
+  let name = percent_decode_str(name).decode_utf8_lossy();
+  if !valid_name(&name) {
+    return Ok(text_response(StatusCode::BAD_REQUEST, "invalid file name"));
+  }
+
+  let path = uploads.directory.join(name.as_ref());
+  let used = directory_size(&uploads.directory, &[&path]).await?;
+  let allowed = match uploads.quota_bytes {
+    Some(quota) if used >= quota => {
+      return Ok(text_response(
+        StatusCode::INSUFFICIENT_STORAGE,
+        "upload quota exhausted",
+      ));
+    }
+    Some(quota) => (quota - used).min(uploads.max_file_bytes.unwrap_or(u64::MAX)),
+    None => uploads.max_file_bytes.unwrap_or(u64::MAX),
+  };
+
+  let mut suffix = [0u8; 8];
+  SystemRandom::new()
+    .fill(&mut suffix)
+    .map_err(|_| anyhow::anyhow!("failed to generate temporary file name"))?;
+  let suffix: String = suffix.iter().map(|b| format!("{b:02x}")).collect();
+  let temp_path = uploads.directory.join(format!(".{name}.{suffix}.upload"));
+
+  let result = write_upload(req.into_body(), &temp_path, allowed).await;
+  let (size, hash) = match result {
+    Ok(Some(written)) => written,
+    Ok(None) => {
+      let _ = tokio::fs::remove_file(&temp_path).await;
+      warn!("{peer}: upload of {name} exceeds {allowed} bytes");
+      return Ok(text_response(StatusCode::PAYLOAD_TOO_LARGE, "upload too large"));
+    }
+    Err(e) => {
+      let _ = tokio::fs::remove_file(&temp_path).await;
+      warn!("{peer}: upload of {name} failed: {e:?}");
+      return Ok(text_response(StatusCode::BAD_REQUEST, format!("upload failed: {e}")));
+    }
+  };
+  tokio::fs::rename(&temp_path, &path).await?;
+
+  info!("{peer}: uploaded {name} ({size} bytes)");
+  let mut response = json_response(&json!({
+    "name": name,
+    "size": size,
+    "sha256": hash,
+  }));
+  *response.status_mut() = StatusCode::CREATED;
+  Ok(response)
+}
+
+/// Write a request body to `path`, returning its size and SHA-256, or None if it exceeds `limit`.
+async fn write_upload(mut body: Body, path: &Path, limit: u64) -> Result<Option<(u64, String)>> {
+  let mut file = tokio::fs::File::create(path).await?;
+  let mut context = digest::Context::new(&digest::SHA256);
+  let mut size = 0u64;
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    size += chunk.len() as u64;
+    if size > limit {
+      return Ok(None);
+    }
+    context.update(&chunk);
+    file.write_all(&chunk).await?;
+  }
+  file.sync_all().await?;
+
+  let hash = context.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect();
+  Ok(Some((size, hash)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accepts_plain_names() {
+    assert!(valid_name("firmware.bin"));
+    assert!(valid_name("log 2023-01-01.txt"));
+    assert!(valid_name("a..b"));
+    assert!(valid_name("café"));
+  }
+
+  #[test]
+  fn rejects_paths_and_hidden_names() {
+    for name in [
+      "",
+      ".",
+      "..",
+      ".hidden",
+      ".firmware.bin.0123456789abcdef.partial",
+      "a/b",
+      "/etc/passwd",
+      "../escape",
+      "a\\b",
+      "..\\escape",
+      "a\0b",
+    ] {
+      assert!(!valid_name(name), "{name:?}");
+    }
+  }
+
+  #[test]
+  fn rejects_encoded_paths() {
+    for name in ["..%2Fescape", "a%2fb", "%2E%2E", "a%00b", "a%5Cb"] {
+      assert!(!valid_name(&percent_decode_str(name).decode_utf8_lossy()), "{name}");
+    }
+  }
+}
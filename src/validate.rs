@@ -0,0 +1,198 @@
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use crate::config::InboundValidation;
+use crate::ratelimit::RateLimiter;
+use crate::server::glob_match;
+
+/// A check registered by the embedder, which returns why a message is invalid.
+pub type CustomValidator = dyn Fn(&[u8]) -> Result<(), String> + Send + Sync;
+
+/// Checks registered by the embedder, with the globs of the socket paths they apply to.
+static REGISTERED: Mutex<Vec<(String, Arc<CustomValidator>)>> = Mutex::new(Vec::new());
+
+/// Apply `validator` to what clients write to sockets with paths matching `path`, in addition to
+/// the configured checks. Applies to sessions opened after it's registered.
+pub fn register(path: &str, validator: Arc<CustomValidator>) {
+  REGISTERED.lock().unwrap().push((path.to_string(), validator));
+}
+
+/// The checks applied to what a session's client writes, before it reaches the backend.
+pub struct Validator {
+  max_bytes: Option<usize>,
+  utf8: bool,
+  json_schema: Option<Value>,
+  rate: Option<RateLimiter>,
+  custom: Vec<Arc<CustomValidator>>,
+}
+
+impl Validator {
+  /// The checks for a session on `socket_path`, or None if there aren't any.
+  pub fn new(config: Option<&InboundValidation>, socket_path: &str) -> Option<Validator> {
+    let custom: Vec<_> = REGISTERED
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|(path, _)| glob_match(path, socket_path))
+      .map(|(_, validator)| validator.clone())
+      .collect();
+    if config.is_none() && custom.is_empty() {
+      return None;
+    }
+
+    Some(Validator {
+      max_bytes: config.and_then(|c| c.max_bytes),
+      utf8: config.and_then(|c| c.utf8).unwrap_or(false),
+      json_schema: config.and_then(|c| c.json_schema.clone()),
+      rate: config.and_then(|c| c.max_messages_per_sec).map(RateLimiter::new),
+      custom,
+    })
+  }
+
+  /// Check a message from the client, returning why it's rejected if it is.
+  pub fn check(&mut self, data: &[u8]) -> Result<(), String> {
+    if let Some(max_bytes) = self.max_bytes.filter(|&max| data.len() > max) {
+      return Err(format!("message is {} bytes, more than {max_bytes}", data.len()));
+    }
+    if let Some(rate) = &mut self.rate {
+      if !rate.try_take(1) {
+        return Err("too many messages".to_string());
+      }
+    }
+    if self.utf8 || self.json_schema.is_some() {
+      let text = std::str::from_utf8(data).map_err(|_| "message isn't UTF-8".to_string())?;
+      if let Some(schema) = &self.json_schema {
+        let value: Value = serde_json::from_str(text).map_err(|e| format!("message isn't JSON: {e}"))?;
+        check_schema(schema, &value, "$")?;
+      }
+    }
+    for validator in &self.custom {
+      validator(data)?;
+    }
+    Ok(())
+  }
+}
+
+fn type_matches(ty: &str, value: &Value) -> bool {
+  match ty {
+    "null" => value.is_null(),
+    "boolean" => value.is_boolean(),
+    "object" => value.is_object(),
+    "array" => value.is_array(),
+    "string" => value.is_string(),
+    "number" => value.is_number(),
+    "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+    _ => false,
+  }
+}
+
+/// Check `value` against a JSON schema, supporting the keywords that describe the shape of a
+/// message: `type`, `enum`, `const`, `properties`, `required`, `additionalProperties` (as a boolean
+/// or schema), `items`, `minItems`, `maxItems`, `minLength`, `maxLength`, `minimum` and `maximum`.
+/// Other keywords are ignored.
+fn check_schema(schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+  let schema = match schema {
+    Value::Bool(true) => return Ok(()),
+    Value::Bool(false) => return Err(format!("{at} isn't allowed")),
+    Value::Object(schema) => schema,
+    _ => return Ok(()),
+  };
+
+  match schema.get("type") {
+    Some(Value::String(ty)) if !type_matches(ty, value) => return Err(format!("{at} isn't of type {ty}")),
+    Some(Value::Array(types)) if !types.iter().filter_map(Value::as_str).any(|ty| type_matches(ty, value)) => {
+      return Err(format!("{at} isn't of an allowed type"));
+    }
+    _ => {}
+  }
+  if let Some(Value::Array(allowed)) = schema.get("enum") {
+    if !allowed.contains(value) {
+      return Err(format!("{at} isn't one of the allowed values"));
+    }
+  }
+  if schema.get("const").is_some_and(|expected| expected != value) {
+    return Err(format!("{at} isn't the expected value"));
+  }
+
+  match value {
+    Value::Object(object) => {
+      if let Some(Value::Array(required)) = schema.get("required") {
+        if let Some(missing) = required
+          .iter()
+          .filter_map(Value::as_str)
+          .find(|key| !object.contains_key(*key))
+        {
+          return Err(format!("{at}.{missing} is missing"));
+        }
+      }
+      let properties = schema.get("properties").and_then(Value::as_object);
+      for (key, item) in object {
+        let at = format!("{at}.{key}");
+        match (properties.and_then(|p| p.get(key)), schema.get("additionalProperties")) {
+          (Some(property), _) => check_schema(property, item, &at)?,
+          (None, Some(additional)) => check_schema(additional, item, &at)?,
+          (None, None) => {}
+        }
+      }
+    }
+    Value::Array(array) => {
+      let len = array.len() as u64;
+      if schema
+        .get("minItems")
+        .and_then(Value::as_u64)
+        .is_some_and(|min| len < min)
+      {
+        return Err(format!("{at} has too few items"));
+      }
+      if schema
+        .get("maxItems")
+        .and_then(Value::as_u64)
+        .is_some_and(|max| len > max)
+      {
+        return Err(format!("{at} has too many items"));
+      }
+      if let Some(items) = schema.get("items") {
+        for (i, item) in array.iter().enumerate() {
+          check_schema(items, item, &format!("{at}[{i}]"))?;
+        }
+      }
+    }
+    Value::String(string) => {
+      let len = string.chars().count() as u64;
+      if schema
+        .get("minLength")
+        .and_then(Value::as_u64)
+        .is_some_and(|min| len < min)
+      {
+        return Err(format!("{at} is too short"));
+      }
+      if schema
+        .get("maxLength")
+        .and_then(Value::as_u64)
+        .is_some_and(|max| len > max)
+      {
+        return Err(format!("{at} is too long"));
+      }
+    }
+    Value::Number(number) => {
+      let number = number.as_f64().unwrap_or(f64::NAN);
+      if schema
+        .get("minimum")
+        .and_then(Value::as_f64)
+        .is_some_and(|min| number < min)
+      {
+        return Err(format!("{at} is less than the minimum"));
+      }
+      if schema
+        .get("maximum")
+        .and_then(Value::as_f64)
+        .is_some_and(|max| number > max)
+      {
+        return Err(format!("{at} is more than the maximum"));
+      }
+    }
+    _ => {}
+  }
+  Ok(())
+}
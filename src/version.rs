@@ -0,0 +1,42 @@
+use serde::Serialize;
+
+use crate::ffi::WARDENCLYFFE_API_VERSION;
+
+/// What's running, for bug reports: served at /api/version and in each session's `Hello`.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Build {
+  pub version: &'static str,
+
+  /// The commit the library was built from, or `unknown` if it wasn't built from a git checkout.
+  pub git_hash: &'static str,
+
+  /// Cargo features the library was built with.
+  pub features: &'static [&'static str],
+
+  /// Version of the FFI API implemented, `WARDENCLYFFE_API_VERSION` in wardenclyffe.h.
+  pub api_version: u32,
+}
+
+const FEATURES: &[&str] = &[
+  #[cfg(feature = "console")]
+  "console",
+  #[cfg(feature = "jemalloc")]
+  "jemalloc",
+  #[cfg(feature = "mimalloc")]
+  "mimalloc",
+];
+
+pub const BUILD: Build = Build {
+  version: env!("CARGO_PKG_VERSION"),
+  git_hash: env!("WARDENCLYFFE_GIT_HASH"),
+  features: FEATURES,
+  api_version: WARDENCLYFFE_API_VERSION,
+};
+
+/// Log what's running, once the server starts.
+pub fn log_banner() {
+  info!(
+    "wardenclyffe {} ({}), features {:?}, FFI API version {}",
+    BUILD.version, BUILD.git_hash, BUILD.features, BUILD.api_version
+  );
+}
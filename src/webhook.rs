@@ -0,0 +1,132 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, HeaderMap, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustls::{ClientConfig, RootCertStore};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::config;
+use crate::roots;
+use crate::task;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Sends events to the configured webhook in the background, in the order they happened, retrying
+/// each with exponential backoff until it's delivered or runs out of attempts.
+pub struct Webhook {
+  events: Option<Vec<String>>,
+  queue: mpsc::Sender<Value>,
+}
+
+impl Webhook {
+  /// Start sending events to a webhook. Must be called from within the runtime.
+  pub fn new(config: &config::Webhook) -> Result<Webhook> {
+    let uri: Uri = config.url.parse()?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in config.headers.iter().flatten() {
+      let name = HeaderName::from_bytes(name.as_bytes())?;
+      headers.insert(name, HeaderValue::from_str(value)?);
+    }
+
+    // Only load CAs when they're needed, since hosts may not have any.
+    let roots = if uri.scheme_str() == Some("https") {
+      roots::load(config.ca_certs.as_deref())?
+    } else {
+      RootCertStore::empty()
+    };
+    let tls = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    let connector = HttpsConnectorBuilder::new()
+      .with_tls_config(tls)
+      .https_or_http()
+      .enable_http1()
+      .build();
+
+    let (queue, events) = mpsc::channel(config.max_queued.unwrap());
+    let delivery = Delivery {
+      client: Client::builder().build(connector),
+      uri,
+      headers,
+      max_attempts: config.max_attempts.unwrap().max(1),
+    };
+    task::spawn("webhook", delivery.run(events));
+    Ok(Webhook {
+      events: config.events.clone(),
+      queue,
+    })
+  }
+
+  /// Queue an event for delivery, if it's one the webhook wants. Events have their name in their
+  /// `event` field.
+  pub fn notify(&self, event: Value) {
+    let name = event["event"].as_str().unwrap_or_default().to_string();
+    if self.events.as_ref().is_some_and(|events| !events.contains(&name)) {
+      return;
+    }
+    if self.queue.try_send(event).is_err() {
+      warn!("webhook is falling behind, dropping {name} event");
+    }
+  }
+
+  /// Announce that the server has started.
+  pub fn server_started(&self, port: Option<u16>) {
+    let time = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis())
+      .unwrap_or(0);
+    self.notify(json!({ "time": time, "event": "server_started", "port": port }));
+  }
+}
+
+struct Delivery {
+  client: Client<HttpsConnector<HttpConnector>>,
+  uri: Uri,
+  headers: HeaderMap,
+  max_attempts: u32,
+}
+
+impl Delivery {
+  async fn run(self, mut events: mpsc::Receiver<Value>) {
+    while let Some(event) = events.recv().await {
+      let body = event.to_string();
+      let mut backoff = INITIAL_BACKOFF;
+      for attempt in 1..=self.max_attempts {
+        match self.post(body.clone()).await {
+          Ok(()) => break,
+          Err(e) if attempt == self.max_attempts => {
+            warn!("giving up on webhook event after {attempt} attempts: {e}");
+          }
+          Err(e) => {
+            debug!("webhook failed, retrying in {backoff:?}: {e}");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+          }
+        }
+      }
+    }
+  }
+
+  async fn post(&self, body: String) -> Result<()> {
+    let mut req = Request::builder()
+      .method(Method::POST)
+      .uri(self.uri.clone())
+      .header(CONTENT_TYPE, "application/json")
+      .body(Body::from(body))?;
+    req.headers_mut().extend(self.headers.clone());
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, self.client.request(req))
+      .await
+      .map_err(|_| anyhow!("timed out"))??;
+    if !response.status().is_success() {
+      bail!("webhook responded with {}", response.status());
+    }
+    Ok(())
+  }
+}